@@ -79,6 +79,16 @@ function swizzle_raw(rgbData, buffer) {
   }
 }
 
+function swizzle_raw16(rawData, buffer) {
+  // Little endian, so the high byte of each pixel is the second one
+  for (let i = 0, j = 0; i < rawData.length; i += 2, j += 4) {
+    buffer[j] = rawData[i + 1]; // Red
+    buffer[j + 1] = rawData[i + 1]; // Green
+    buffer[j + 2] = rawData[i + 1]; // Blue
+    buffer[j + 3] = 255; // Alpha
+  }
+}
+
 function swizzle_rgb(rgbData, buffer) {
   for (let i = 0, j = 0; i < rgbData.length; i += 3, j += 4) {
     buffer[j] = rgbData[i]; // Red
@@ -88,6 +98,33 @@ function swizzle_rgb(rgbData, buffer) {
   }
 }
 
+function update_control_caps(caps) {
+  const inputs = {
+    GAIN: "gainInput",
+    EXPOSURE: "exposureInput",
+    WB_R: "wbrInput",
+    WB_B: "wbbInput",
+  };
+  for (let control of caps) {
+    let id = inputs[control["control_type"]];
+    if (!id) {
+      continue;
+    }
+    let elm = document.getElementById(id);
+    let min = control["min_value"];
+    let max = control["max_value"];
+    // Exposure is entered in ms but the camera reports it in us
+    if (control["control_type"] == "EXPOSURE") {
+      min /= 1000;
+      max /= 1000;
+    }
+    elm.min = min;
+    elm.max = max;
+    elm.disabled = !control["is_writable"];
+    elm.title = `${control["description"]} (${min} - ${max})`;
+  }
+}
+
 function update_controls(controls) {
   const inputs = {
     gainInput: "gain",
@@ -129,6 +166,7 @@ let imageData = ctx.createImageData(canvas.width, canvas.height);
 let buffer = imageData.data;
 
 async function draw_image_data(imageData) {
+  // ImageData for raw previews, a Blob for encoded ones
   let image_bitmap = await window.createImageBitmap(imageData);
 
   // Get the aspect ratios of the image and canvas
@@ -174,9 +212,25 @@ async function handle_message(event) {
       let controls = rawData["controls"];
       // update_controls(controls);
       setDebugValues(controls);
+      let stats = rawData["stats"];
+      setDebugValues({
+        min: stats["min"],
+        max: stats["max"],
+        mean: stats["mean"].toFixed(1),
+        median: stats["median"],
+      });
 
       let w = rawData["w"];
       let h = rawData["h"];
+
+      const JPEG = 4;
+      const WEBP = 5;
+      if (rawData["pix"] == JPEG || rawData["pix"] == WEBP) {
+        const mime = rawData["pix"] == JPEG ? "image/jpeg" : "image/webp";
+        setDebugValues({ img_width: w, img_height: h, img_kb: (rawData["img"].length / 1024).toFixed(0) });
+        draw_image_data(new Blob([rawData["img"]], { type: mime }));
+        return;
+      }
       let change_size = imageData.width != w || imageData.height != h;
       if (change_size) {
         imageData = ctx.createImageData(w, h);
@@ -189,8 +243,11 @@ async function handle_message(event) {
       const BGR = 0;
       const RGB = 1;
       const RAW8 = 2;
+      const RAW16 = 3;
       if (rawData["pix"] == RAW8) {
         bytes_per_pix = 1;
+      } else if (rawData["pix"] == RAW16) {
+        bytes_per_pix = 2;
       }
       if (
         !bgrData ||
@@ -207,6 +264,8 @@ async function handle_message(event) {
         swizzle_rgb(bgrData, buffer);
       } else if (rawData["pix"] == RAW8) {
         swizzle_raw(bgrData, buffer);
+      } else if (rawData["pix"] == RAW16) {
+        swizzle_raw16(bgrData, buffer);
       }
 
       draw_image_data(imageData);
@@ -218,40 +277,113 @@ async function handle_message(event) {
       });
 
       // image_bitmap.close();
+    } else if (type == "PreviewStats") {
+      setDebugValues({
+        previews_sent: rawData["sent"],
+        previews_dropped: rawData["dropped"],
+      });
+    } else if (type == "CoolerStatus") {
+      let state = rawData["state"];
+      setDebugValues({
+        temperature: rawData["temperature"].toFixed(1) + " °C",
+        cooler_power: rawData["power"] == null ? "-" : rawData["power"] + " %",
+        cooler_state: rawData["capture_waiting"] ? `${state} (capture waiting)` : state,
+        cooler_setpoint: rawData["setpoint"] == null ? "-" : rawData["setpoint"] + " °C",
+      });
+      if (state != cooler_state) {
+        log(`Cooler ${state}`);
+        cooler_state = state;
+      }
+    } else if (type == "ControlCaps") {
+      update_control_caps(rawData["controls"]);
+    } else if (type == "ExposureStatus") {
+      let state = rawData["state"];
+      let frame = `${rawData["frame"]} / ${rawData["total_frames"]}`;
+      setDebugValues({
+        exposure_state: state,
+        exposure_frame: frame,
+        exposure_remaining: rawData["remaining"].toFixed(1) + " s",
+      });
+      if (state != "Exposing") {
+        log(`Exposure ${frame}: ${state}`);
+      }
     } else if (type == "CaptureStatus") {
       log(
         `Captured ${rawData["captured_frames"]} / ${rawData["total_frames"]} frames`
       );
+    } else if (type == "Connection") {
+      let state = rawData["connected"] ? "connected" : "disconnected";
+      log(`${rawData["name"]} (${rawData["serial"]}) ${state}`);
+    } else if (type == "Response") {
+      if (!rawData["ok"]) {
+        let error = rawData["error"];
+        log(`Request ${rawData["id"]} failed: ${error["code"]}: ${error["message"]}`);
+      }
     }
   } catch (error) {
     console.error("Error processing image data:", error);
   }
 }
 
+let server_url = "";
 let ws_url = "/ws";
 if (document.URL.includes(":5173")) {
+  server_url = "http://localhost:3000";
   ws_url = "ws://localhost:3000/ws";
 }
+// Each camera has its own socket, picked with ?camera= on the page URL
+const camera = new URLSearchParams(window.location.search).get("camera");
+if (camera) {
+  ws_url += `?camera=${encodeURIComponent(camera)}`;
+}
 const ws = new WebSocket(ws_url);
+let cooler_state = null;
+
+// Commands are ControlMessages variants, see src/protocol.rs
+const PROTOCOL_VERSION = 1;
+let next_request_id = 0;
+function send(cmd, args) {
+  ws.send(JSON.stringify({ v: PROTOCOL_VERSION, id: next_request_id++, cmd, args }));
+}
+
+const cameraSelect = document.getElementById("cameraSelect");
+fetch(`${server_url}/cameras`)
+  .then((response) => response.json())
+  .then((cameras) => {
+    for (let info of cameras) {
+      let option = document.createElement("option");
+      option.value = info.camera_id;
+      option.text = info.custom_id || `${info.name} (${info.serial})`;
+      cameraSelect.add(option);
+    }
+    if (camera) {
+      cameraSelect.value = camera;
+    }
+  })
+  .catch((error) => console.error("Listing cameras failed:", error));
+cameraSelect.onchange = () => {
+  window.location.search = `?camera=${cameraSelect.value}`;
+};
 
 ws.onopen = () => {
   log("WebSocket connected");
+  sendPreviewSettings();
 
   for (let key in inputs) {
     let elm = document.getElementById(key);
     let value = elm.value;
     if (value) {
-      ws.send(inputs[key] + `:${value}`);
+      send(inputs[key], Number(value));
     }
   }
 };
 ws.onmessage = handle_message;
 
 const inputs = {
-  gainInput: "SET_GAIN",
-  exposureInput: "SET_EXPOSURE",
-  wbrInput: "SET_WB_R",
-  wbbInput: "SET_WB_B",
+  gainInput: "SetGain",
+  exposureInput: "SetExposure",
+  wbrInput: "SetWbR",
+  wbbInput: "SetWbB",
 };
 console.log(inputs);
 for (let key in inputs) {
@@ -260,20 +392,117 @@ for (let key in inputs) {
   elm.oninput = () => {
     let value = elm.value;
     if (value) {
-      ws.send(inputs[key] + `:${value}`);
+      send(inputs[key], Number(value));
     }
   };
   // let value = elm.value;
   // log(`${key} value = ${value}`);
   // if (value) {
-  //   ws.send(inputs[key] + ":" + value);
+  //   send(inputs[key], Number(value));
   // }
 }
 
+const selects = {
+  binSelect: "SetBin",
+  imgTypeSelect: "SetImageType",
+  captureFormatSelect: "SetCaptureFormat",
+  cameraModeSelect: "SetCameraMode",
+};
+for (let key in selects) {
+  let elm = document.getElementById(key);
+  elm.onchange = () =>
+    send(selects[key], key == "binSelect" ? Number(elm.value) : elm.value);
+}
+
+// Highlights metering keeps bright disks like the sun and moon from clipping
+const autoExposureSelect = document.getElementById("autoExposureSelect");
+autoExposureSelect.onchange = () => {
+  let config = {
+    median: { metering: "median", target: 0.4 },
+    highlights: { metering: { percentile: 0.999 }, target: 0.8 },
+  }[autoExposureSelect.value];
+  send("SetAutoExposure", config || null);
+};
+for (let key of ["gainInput", "exposureInput"]) {
+  document
+    .getElementById(key)
+    .addEventListener("input", () => (autoExposureSelect.value = ""));
+}
+
+const stretchInput = document.getElementById("stretchInput");
+stretchInput.onchange = () =>
+  send("SetPreviewStretch", stretchInput.checked);
+
+for (let [key, cmd] of [
+  ["debayerSelect", "SetDebayer"],
+  ["colorFilesSelect", "SetColorFiles"],
+]) {
+  let elm = document.getElementById(key);
+  elm.onchange = () => send(cmd, elm.value || null);
+}
+
+// Encoded previews are shrunk on the server to fit the canvas
+const previewEncodingSelect = document.getElementById("previewEncodingSelect");
+const previewQualityInput = document.getElementById("previewQualityInput");
+const previewFpsInput = document.getElementById("previewFpsInput");
+const displayStretchSelect = document.getElementById("displayStretchSelect");
+const linkChannelsInput = document.getElementById("linkChannelsInput");
+function sendPreviewSettings() {
+  if (ws.readyState != WebSocket.OPEN) {
+    return;
+  }
+  send("SetPreview", {
+    encoding: previewEncodingSelect.value,
+    max_width: canvas.width,
+    max_height: canvas.height,
+    quality: Number(previewQualityInput.value) || 80,
+    max_fps: Number(previewFpsInput.value) || null,
+    stretch: displayStretchSelect.value
+      ? { mode: displayStretchSelect.value, linked: linkChannelsInput.checked }
+      : null,
+  });
+}
+previewEncodingSelect.onchange = sendPreviewSettings;
+previewQualityInput.onchange = sendPreviewSettings;
+previewFpsInput.onchange = sendPreviewSettings;
+displayStretchSelect.onchange = sendPreviewSettings;
+linkChannelsInput.onchange = sendPreviewSettings;
+window.addEventListener("resize", sendPreviewSettings);
+
+// The ramp and settle settings go along with every target
+document.getElementById("coolerStart").onclick = () => {
+  let target = document.getElementById("coolerTargetInput").value;
+  if (!target) {
+    return;
+  }
+  send("SetCoolerConfig", {
+    ramp_rate: Number(document.getElementById("coolerRampInput").value) || 3,
+    wait_to_settle: document.getElementById("coolerSettleInput").checked,
+  });
+  send("SetTargetTemperature", Number(target));
+};
+document.getElementById("coolerWarmUp").onclick = () => send("WarmUp");
+
+document.getElementById("startExposure").onclick = () => {
+  let seconds = document.getElementById("snapshotInput").value;
+  if (seconds) {
+    send("StartExposure", { seconds: Number(seconds) });
+  }
+};
+document.getElementById("abortExposure").onclick = () =>
+  send("AbortExposure");
+
 document.getElementById("switchOutput").onclick = () =>
-  ws.send(`SWITCH_OUTPUT:`);
+  send("SwitchOutput");
 document.getElementById("startCapture").onclick = () =>
-  ws.send(`START_CAPTURE:10`);
+  send("StartCapture", 10);
+document.getElementById("stopCapture").onclick = () =>
+  send("StopCapture");
+
+// Level triggers expose for as long as the button is held, edge triggers ignore the release
+const softTrigger = document.getElementById("softTrigger");
+softTrigger.onpointerdown = () => send("SoftTrigger", true);
+softTrigger.onpointerup = () => send("SoftTrigger", false);
 document.getElementById("fullScreen").onclick = fs;
 // Example usage
 // debug_values["FPS"] = 60;
@@ -0,0 +1,965 @@
+//! ASCOM Alpaca camera devices, so capture software like N.I.N.A. can drive the
+//! cameras over the network.
+//!
+//! Every camera the server has seen is a device, numbered in the order of
+//! `GET /cameras`: `GET /api/v1/camera/0/gain`, `PUT /api/v1/camera/0/startexposure`
+//! and so on. Properties are read straight from the camera, while everything that
+//! changes it goes through its [`crate::camera_controller::CameraController`] like
+//! websocket commands do, so both can be used at the same time. Clients find the
+//! server with [`discovery`].
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Form, Json, Router,
+};
+use serde_json::{json, Value};
+use tokio::net::UdpSocket;
+use tracing::info;
+
+use crate::{
+    asi::{self, CameraInfo, ControlCaps, CONTROL_TYPE, GUIDE_DIRECTION, IMG_TYPE},
+    camera_controller::{ControlMessages, ControllerState, ExposureState, RawFrame, Roi},
+    fits, frame,
+    protocol::{ErrorCode, ProtocolError, Reply},
+    server::{AppState, CameraSlot},
+    CameraBackend,
+};
+
+/// The UDP port Alpaca clients broadcast discovery requests to.
+pub const DISCOVERY_PORT: u16 = 32227;
+const DISCOVERY_REQUEST: &[u8] = b"alpacadiscovery1";
+
+/// `ICameraV3`.
+const INTERFACE_VERSION: i32 = 3;
+/// Exposures are taken as RAW16, which the SDK scales to the full 16 bits.
+const MAX_ADU: i32 = 65535;
+
+// ASCOM error numbers
+const NOT_IMPLEMENTED: i32 = 0x400;
+const INVALID_VALUE: i32 = 0x401;
+const VALUE_NOT_SET: i32 = 0x402;
+const NOT_CONNECTED: i32 = 0x407;
+const INVALID_OPERATION: i32 = 0x40B;
+const ACTION_NOT_IMPLEMENTED: i32 = 0x40C;
+const UNSPECIFIED_ERROR: i32 = 0x500;
+
+// `CameraState` values
+const CAMERA_IDLE: i32 = 0;
+const CAMERA_WAITING: i32 = 1;
+const CAMERA_EXPOSING: i32 = 2;
+const CAMERA_DOWNLOAD: i32 = 4;
+const CAMERA_ERROR: i32 = 5;
+
+// `SensorType` values
+const SENSOR_MONOCHROME: i32 = 0;
+const SENSOR_RGGB: i32 = 2;
+
+static SERVER_TRANSACTION: AtomicU32 = AtomicU32::new(0);
+
+/// Why a request failed. Only malformed requests get an HTTP error, everything else
+/// is answered with an ASCOM error number.
+enum Failure {
+    BadRequest(String),
+    NotFound(String),
+    Ascom(i32, String),
+}
+
+fn ascom(number: i32, message: impl Into<String>) -> Failure {
+    Failure::Ascom(number, message.into())
+}
+
+fn not_implemented(member: &str) -> Failure {
+    ascom(NOT_IMPLEMENTED, format!("{member} is not implemented"))
+}
+
+impl From<ProtocolError> for Failure {
+    fn from(error: ProtocolError) -> Self {
+        match error.code {
+            ErrorCode::InvalidRequest => ascom(INVALID_VALUE, error.message),
+            ErrorCode::CameraUnavailable => ascom(NOT_CONNECTED, error.message),
+            ErrorCode::CommandFailed => ascom(INVALID_OPERATION, error.message),
+            _ => ascom(UNSPECIFIED_ERROR, error.message),
+        }
+    }
+}
+
+impl From<asi::ASI_ERROR> for Failure {
+    fn from(e: asi::ASI_ERROR) -> Self {
+        ascom(UNSPECIFIED_ERROR, e.to_string())
+    }
+}
+
+enum Answer {
+    Value(Value),
+    /// Methods that don't return anything.
+    Done,
+    Image(Box<RawFrame>),
+}
+
+fn value(value: impl Into<Value>) -> Result<Answer, Failure> {
+    Ok(Answer::Value(value.into()))
+}
+
+/// Request parameters, whose names Alpaca clients may send in any case.
+struct Params(HashMap<String, String>);
+
+impl Params {
+    fn new(raw: HashMap<String, String>) -> Self {
+        Self(
+            raw.into_iter()
+                .map(|(name, value)| (name.to_ascii_lowercase(), value))
+                .collect(),
+        )
+    }
+
+    fn client_transaction_id(&self) -> u32 {
+        self.0
+            .get("clienttransactionid")
+            .and_then(|id| id.parse().ok())
+            .unwrap_or(0)
+    }
+
+    fn get<T: FromStr>(&self, name: &str) -> Result<T, Failure> {
+        let raw = self
+            .0
+            .get(&name.to_ascii_lowercase())
+            .ok_or_else(|| Failure::BadRequest(format!("Missing parameter {name}")))?;
+        raw.trim()
+            .parse()
+            .map_err(|_| Failure::BadRequest(format!("Invalid {name} {raw}")))
+    }
+
+    /// Alpaca sends `True` and `False`.
+    fn bool(&self, name: &str) -> Result<bool, Failure> {
+        let raw: String = self.get(name)?;
+        match raw.to_ascii_lowercase().as_str() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(Failure::BadRequest(format!("Invalid {name} {raw}"))),
+        }
+    }
+}
+
+/// What a client set up on a device. The frame is only applied when it starts an
+/// exposure, since clients set its parts one at a time.
+struct Device {
+    connected: bool,
+    bin: i32,
+    start_x: i32,
+    start_y: i32,
+    num_x: i32,
+    num_y: i32,
+    /// Start and length in seconds of the last exposure started here.
+    exposure: Option<(SystemTime, f64)>,
+    /// `SetCCDTemperature` while the cooler is off, used once `CoolerOn` is set.
+    target_temperature: Option<f64>,
+}
+
+impl Device {
+    fn new(info: &CameraInfo) -> Self {
+        Self {
+            connected: false,
+            bin: 1,
+            start_x: 0,
+            start_y: 0,
+            num_x: info.max_width,
+            num_y: info.max_height,
+            exposure: None,
+            target_temperature: None,
+        }
+    }
+
+    fn roi(&self) -> Roi {
+        Roi {
+            x: self.start_x,
+            y: self.start_y,
+            w: self.num_x,
+            h: self.num_y,
+            bin: self.bin,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Alpaca {
+    app: AppState,
+    /// By device number.
+    devices: Arc<Mutex<HashMap<usize, Device>>>,
+}
+
+/// The management and device API, to be served next to the rest of the server.
+pub fn router(app: AppState) -> Router {
+    let alpaca = Alpaca {
+        app,
+        devices: Arc::new(Mutex::new(HashMap::new())),
+    };
+    Router::new()
+        .route("/management/apiversions", get(api_versions_handler))
+        .route("/management/v1/description", get(description_handler))
+        .route(
+            "/management/v1/configureddevices",
+            get(configured_devices_handler),
+        )
+        .route(
+            "/api/v1/camera/{device}/{member}",
+            get(get_handler).put(put_handler),
+        )
+        .with_state(alpaca)
+}
+
+/// Answers Alpaca discovery broadcasts with the HTTP port the API is served on.
+pub async fn discovery(http_port: u16) -> io::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", DISCOVERY_PORT)).await?;
+    info!("Answering Alpaca discovery on port {DISCOVERY_PORT}");
+    let answer = json!({ "AlpacaPort": http_port }).to_string();
+    let mut buf = [0; 64];
+    loop {
+        let (len, addr) = socket.recv_from(&mut buf).await?;
+        if buf[..len].starts_with(DISCOVERY_REQUEST) {
+            socket.send_to(answer.as_bytes(), addr).await?;
+        }
+    }
+}
+
+fn respond(params: &Params, result: Result<Answer, Failure>) -> Response {
+    let server_transaction_id = SERVER_TRANSACTION.fetch_add(1, Ordering::Relaxed) + 1;
+    let (value, number, message) = match result {
+        Ok(Answer::Value(value)) => (Some(value), 0, String::new()),
+        Ok(Answer::Done) => (None, 0, String::new()),
+        Ok(Answer::Image(_)) => unreachable!("Images are answered by image_response"),
+        Err(Failure::BadRequest(message)) => {
+            return (StatusCode::BAD_REQUEST, message).into_response()
+        }
+        Err(Failure::NotFound(message)) => return (StatusCode::NOT_FOUND, message).into_response(),
+        Err(Failure::Ascom(number, message)) => (None, number, message),
+    };
+    let mut body = json!({
+        "ClientTransactionID": params.client_transaction_id(),
+        "ServerTransactionID": server_transaction_id,
+        "ErrorNumber": number,
+        "ErrorMessage": message,
+    });
+    if let Some(value) = value {
+        body["Value"] = value;
+    }
+    Json(body).into_response()
+}
+
+/// `ImageArray`, as JSON or in the `application/imagebytes` format. Both are
+/// indexed `[x][y]`.
+fn image_response(frame: &RawFrame, client_transaction_id: u32, image_bytes: bool) -> Response {
+    let server_transaction_id = SERVER_TRANSACTION.fetch_add(1, Ordering::Relaxed) + 1;
+    let (w, h) = (frame.w as usize, frame.h as usize);
+    let pixels: Vec<u16> = match frame.img_type {
+        IMG_TYPE::RAW16 => frame::pixels_u16(&frame.img).collect(),
+        _ => frame.img.iter().map(|pixel| *pixel as u16).collect(),
+    };
+    let pixels = &pixels;
+    let columns = (0..w).map(|x| (0..h).map(move |y| pixels[y * w + x]));
+
+    if image_bytes {
+        let (transmission_type, pixel_size) = match frame.img_type {
+            IMG_TYPE::RAW16 => (8, 2), // UInt16
+            _ => (6, 1),               // Byte
+        };
+        let mut body = Vec::with_capacity(44 + w * h * pixel_size);
+        for field in [
+            1, // Metadata version
+            0, // Error number
+            client_transaction_id as i32,
+            server_transaction_id as i32,
+            44, // Data start
+            2,  // Image element type, Int32
+            transmission_type,
+            2, // Rank
+            w as i32,
+            h as i32,
+            0,
+        ] {
+            body.extend_from_slice(&field.to_le_bytes());
+        }
+        for column in columns {
+            for pixel in column {
+                body.extend_from_slice(&pixel.to_le_bytes()[..pixel_size]);
+            }
+        }
+        return ([(header::CONTENT_TYPE, "application/imagebytes")], body).into_response();
+    }
+
+    let mut body = String::with_capacity(w * h * 6 + 256);
+    write!(
+        body,
+        r#"{{"Type":2,"Rank":2,"ClientTransactionID":{client_transaction_id},"ServerTransactionID":{server_transaction_id},"ErrorNumber":0,"ErrorMessage":"","Value":["#
+    )
+    .unwrap();
+    for (x, column) in columns.enumerate() {
+        body.push_str(if x == 0 { "[" } else { ",[" });
+        for (y, pixel) in column.enumerate() {
+            if y > 0 {
+                body.push(',');
+            }
+            write!(body, "{pixel}").unwrap();
+        }
+        body.push(']');
+    }
+    body.push_str("]}");
+    ([(header::CONTENT_TYPE, "application/json")], body).into_response()
+}
+
+async fn get_handler(
+    State(alpaca): State<Alpaca>,
+    Path((device, member)): Path<(usize, String)>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let params = Params::new(params);
+    let image_bytes = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/imagebytes"));
+    match alpaca.get(device, &member.to_ascii_lowercase()).await {
+        Ok(Answer::Image(frame)) => {
+            let client_transaction_id = params.client_transaction_id();
+            // Large frames take a while to format
+            tokio::task::spawn_blocking(move || {
+                image_response(&frame, client_transaction_id, image_bytes)
+            })
+            .await
+            .unwrap_or_else(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())
+        }
+        result => respond(&params, result),
+    }
+}
+
+async fn put_handler(
+    State(alpaca): State<Alpaca>,
+    Path((device, member)): Path<(usize, String)>,
+    Form(params): Form<HashMap<String, String>>,
+) -> Response {
+    let params = Params::new(params);
+    let result = alpaca
+        .put(device, &member.to_ascii_lowercase(), &params)
+        .await;
+    respond(&params, result)
+}
+
+async fn api_versions_handler(Query(params): Query<HashMap<String, String>>) -> Response {
+    respond(&Params::new(params), value(json!([1])))
+}
+
+async fn description_handler(Query(params): Query<HashMap<String, String>>) -> Response {
+    let description = json!({
+        "ServerName": "zwo_asi_rs",
+        "Manufacturer": "zwo_asi_rs",
+        "ManufacturerVersion": env!("CARGO_PKG_VERSION"),
+        "Location": "",
+    });
+    respond(&Params::new(params), value(description))
+}
+
+async fn configured_devices_handler(
+    State(alpaca): State<Alpaca>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let devices: Vec<Value> = alpaca
+        .app
+        .cameras
+        .read()
+        .unwrap()
+        .iter()
+        .enumerate()
+        .map(|(number, slot)| {
+            json!({
+                "DeviceName": slot.info.name,
+                "DeviceType": "Camera",
+                "DeviceNumber": number,
+                "UniqueID": unique_id(&slot.info),
+            })
+        })
+        .collect();
+    respond(&Params::new(params), value(devices))
+}
+
+fn unique_id(info: &CameraInfo) -> String {
+    match &info.serial {
+        Some(serial) => serial.clone(),
+        None => format!("{}-{}", info.name, info.camera_id),
+    }
+}
+
+fn bayer_offset(info: &CameraInfo) -> Result<(i32, i32), Failure> {
+    info.bayer_offset()
+        .ok_or_else(|| not_implemented("BayerOffset on a mono camera"))
+}
+
+fn control_caps(
+    camera: &dyn CameraBackend,
+    control_type: CONTROL_TYPE,
+) -> Result<ControlCaps, Failure> {
+    camera
+        .controls()?
+        .into_iter()
+        .find(|caps| caps.control_type == control_type)
+        .ok_or_else(|| not_implemented(&format!("{control_type:?}")))
+}
+
+fn control_value(camera: &dyn CameraBackend, control_type: CONTROL_TYPE) -> Result<i64, Failure> {
+    control_caps(camera, control_type)?;
+    Ok(camera.get_control_value(control_type)?.0)
+}
+
+/// Range of the camera's `TARGET_TEMP`, for cameras with a cooler.
+fn target_range(camera: &dyn CameraBackend) -> Result<(i64, i64), Failure> {
+    control_caps(camera, CONTROL_TYPE::COOLER_ON)?;
+    let caps = control_caps(camera, CONTROL_TYPE::TARGET_TEMP)?;
+    Ok((caps.min_value, caps.max_value))
+}
+
+fn guide_direction(direction: i32) -> Result<GUIDE_DIRECTION, Failure> {
+    match direction {
+        0 => Ok(GUIDE_DIRECTION::NORTH),
+        1 => Ok(GUIDE_DIRECTION::SOUTH),
+        2 => Ok(GUIDE_DIRECTION::EAST),
+        3 => Ok(GUIDE_DIRECTION::WEST),
+        _ => Err(ascom(
+            INVALID_VALUE,
+            format!("Invalid guide direction {direction}"),
+        )),
+    }
+}
+
+impl Alpaca {
+    fn slot(&self, device: usize) -> Result<CameraSlot, Failure> {
+        self.app
+            .cameras
+            .read()
+            .unwrap()
+            .get(device)
+            .cloned()
+            .ok_or_else(|| Failure::NotFound(format!("No camera {device}")))
+    }
+
+    fn with_device<T>(
+        &self,
+        device: usize,
+        info: &CameraInfo,
+        f: impl FnOnce(&mut Device) -> T,
+    ) -> T {
+        let mut devices = self.devices.lock().unwrap();
+        f(devices.entry(device).or_insert_with(|| Device::new(info)))
+    }
+
+    /// The camera of a device the client connected to.
+    fn camera(&self, device: usize, slot: &CameraSlot) -> Result<Arc<dyn CameraBackend>, Failure> {
+        let connected = self.with_device(device, &slot.info, |d| d.connected);
+        match (&slot.camera, connected) {
+            (Some(camera), true) => Ok(camera.clone()),
+            (None, _) => Err(ascom(NOT_CONNECTED, "The camera is unplugged")),
+            (_, false) => Err(ascom(NOT_CONNECTED, "Not connected")),
+        }
+    }
+
+    /// Whether the last exposure this device started finished and can be downloaded.
+    async fn image_ready(&self, device: usize, slot: &CameraSlot) -> Result<bool, Failure> {
+        if self.with_device(device, &slot.info, |d| d.exposure.is_none()) {
+            return Ok(false);
+        }
+        let status = slot.status().await?;
+        Ok(status.state != ControllerState::Exposure
+            && !status.capture_waiting()
+            && status
+                .last_exposure
+                .is_some_and(|last| last.state == ExposureState::Complete))
+    }
+
+    async fn camera_state(&self, device: usize, slot: &CameraSlot) -> Result<i32, Failure> {
+        let status = slot.status().await?;
+        let started = self.with_device(device, &slot.info, |d| d.exposure.is_some());
+        if status.capture_waiting() {
+            return Ok(CAMERA_WAITING);
+        }
+        Ok(match (&status.exposure, &status.last_exposure) {
+            (Some(exposure), _) if exposure.remaining > 0. => CAMERA_EXPOSING,
+            (Some(_), _) => CAMERA_DOWNLOAD,
+            (None, Some(last)) if started && last.state == ExposureState::Failed => CAMERA_ERROR,
+            _ => CAMERA_IDLE,
+        })
+    }
+
+    async fn get(&self, device: usize, member: &str) -> Result<Answer, Failure> {
+        let slot = self.slot(device)?;
+        let info = &slot.info;
+        // Members that work without connecting
+        match member {
+            "connected" => {
+                let connected = self.with_device(device, info, |d| d.connected);
+                return value(connected && slot.camera.is_some());
+            }
+            "description" | "name" | "sensorname" => return value(info.name.clone()),
+            "driverinfo" => return value("zwo_asi_rs Alpaca camera driver"),
+            "driverversion" => return value(env!("CARGO_PKG_VERSION")),
+            "interfaceversion" => return value(INTERFACE_VERSION),
+            "supportedactions" => return value(json!([])),
+            _ => {}
+        }
+
+        let camera = self.camera(device, &slot)?;
+        let camera = camera.as_ref();
+        let device_value = |f: fn(&Device) -> i32| value(self.with_device(device, info, |d| f(d)));
+        match member {
+            "bayeroffsetx" => value(bayer_offset(info)?.0),
+            "bayeroffsety" => value(bayer_offset(info)?.1),
+            "binx" | "biny" => device_value(|d| d.bin),
+            "camerastate" => value(self.camera_state(device, &slot).await?),
+            "cameraxsize" => value(info.max_width),
+            "cameraysize" => value(info.max_height),
+            "canabortexposure" => value(true),
+            "canasymmetricbin" | "canfastreadout" | "canstopexposure" => value(false),
+            "cangetcoolerpower" => {
+                value(control_caps(camera, CONTROL_TYPE::COOLER_POWER_PERC).is_ok())
+            }
+            "cansetccdtemperature" => value(target_range(camera).is_ok()),
+            "canpulseguide" => value(info.st4_port),
+            "ccdtemperature" => {
+                value(control_value(camera, CONTROL_TYPE::TEMPERATURE)? as f64 / 10.)
+            }
+            "cooleron" => value(control_value(camera, CONTROL_TYPE::COOLER_ON)? != 0),
+            "coolerpower" => value(control_value(camera, CONTROL_TYPE::COOLER_POWER_PERC)? as f64),
+            "electronsperadu" => value(info.elec_per_adu),
+            "exposuremax" => {
+                value(control_caps(camera, CONTROL_TYPE::EXPOSURE)?.max_value as f64 / 1e6)
+            }
+            "exposuremin" => {
+                value(control_caps(camera, CONTROL_TYPE::EXPOSURE)?.min_value as f64 / 1e6)
+            }
+            "exposureresolution" => value(1e-6),
+            "fullwellcapacity" => value(info.elec_per_adu as f64 * (1 << info.bit_depth) as f64),
+            "gain" => value(control_value(camera, CONTROL_TYPE::GAIN)?),
+            "gainmax" => value(control_caps(camera, CONTROL_TYPE::GAIN)?.max_value),
+            "gainmin" => value(control_caps(camera, CONTROL_TYPE::GAIN)?.min_value),
+            "hasshutter" => value(info.mechanical_shutter),
+            "imagearray" => {
+                if !self.image_ready(device, &slot).await? {
+                    return Err(ascom(INVALID_OPERATION, "No image is ready"));
+                }
+                match slot.run(ControlMessages::GetExposure).await? {
+                    Some(Reply::Frame(frame)) if frame.img_type != IMG_TYPE::RGB24 => {
+                        Ok(Answer::Image(Box::new(frame)))
+                    }
+                    _ => Err(ascom(
+                        INVALID_OPERATION,
+                        "Only RAW8 and RAW16 frames can be downloaded",
+                    )),
+                }
+            }
+            "imageready" => value(self.image_ready(device, &slot).await?),
+            "ispulseguiding" => value(
+                GUIDE_DIRECTION::ALL
+                    .into_iter()
+                    .any(|direction| camera.is_pulse_guiding(direction)),
+            ),
+            "lastexposureduration" | "lastexposurestarttime" => {
+                let exposure = self.with_device(device, info, |d| d.exposure);
+                let Some((start, duration)) = exposure else {
+                    return Err(ascom(VALUE_NOT_SET, "No exposure has been taken yet"));
+                };
+                match member {
+                    "lastexposureduration" => value(duration),
+                    _ => value(fits::fits_date(start)),
+                }
+            }
+            "maxadu" => value(MAX_ADU),
+            "maxbinx" | "maxbiny" => value(info.supported_bins.iter().max().copied().unwrap_or(1)),
+            "numx" => device_value(|d| d.num_x),
+            "numy" => device_value(|d| d.num_y),
+            "offset" => value(control_value(camera, CONTROL_TYPE::OFFSET)?),
+            "offsetmax" => value(control_caps(camera, CONTROL_TYPE::OFFSET)?.max_value),
+            "offsetmin" => value(control_caps(camera, CONTROL_TYPE::OFFSET)?.min_value),
+            "percentcompleted" => {
+                let status = slot.status().await?;
+                match status.exposure {
+                    Some(exposure) if exposure.duration > 0. => {
+                        value((100. * exposure.elapsed / exposure.duration).round() as i32)
+                    }
+                    Some(_) => value(0),
+                    None if self.image_ready(device, &slot).await? => value(100),
+                    None => Err(ascom(INVALID_OPERATION, "No exposure is running")),
+                }
+            }
+            "pixelsizex" | "pixelsizey" => value(info.pixel_size),
+            "readoutmode" => value(0),
+            "readoutmodes" => value(json!(["Normal"])),
+            "sensortype" => value(match info.bayer_pattern {
+                Some(_) => SENSOR_RGGB,
+                None => SENSOR_MONOCHROME,
+            }),
+            "setccdtemperature" => {
+                target_range(camera)?;
+                let status = slot.status().await?;
+                let stored = self.with_device(device, info, |d| d.target_temperature);
+                match status.cooler.and_then(|cooler| cooler.target).or(stored) {
+                    Some(target) => value(target),
+                    None => value(control_value(camera, CONTROL_TYPE::TARGET_TEMP)? as f64),
+                }
+            }
+            "startx" => device_value(|d| d.start_x),
+            "starty" => device_value(|d| d.start_y),
+            "fastreadout"
+            | "gains"
+            | "heatsinktemperature"
+            | "imagearrayvariant"
+            | "offsets"
+            | "subexposureduration" => Err(not_implemented(member)),
+            _ => Err(Failure::NotFound(format!("Unknown member {member}"))),
+        }
+    }
+
+    async fn put(&self, device: usize, member: &str, params: &Params) -> Result<Answer, Failure> {
+        let slot = self.slot(device)?;
+        let info = &slot.info;
+        match member {
+            "connected" => {
+                let connected = params.bool("Connected")?;
+                if connected && slot.camera.is_none() {
+                    return Err(ascom(NOT_CONNECTED, "The camera is unplugged"));
+                }
+                info!(
+                    "Alpaca client {} {}",
+                    if connected {
+                        "connected to"
+                    } else {
+                        "disconnected from"
+                    },
+                    info.name
+                );
+                self.with_device(device, info, |d| d.connected = connected);
+                return Ok(Answer::Done);
+            }
+            "action" => return Err(ascom(ACTION_NOT_IMPLEMENTED, "No actions are supported")),
+            "commandblind" | "commandbool" | "commandstring" => {
+                return Err(not_implemented(member))
+            }
+            _ => {}
+        }
+
+        let camera = self.camera(device, &slot)?;
+        let camera = camera.as_ref();
+        match member {
+            "binx" | "biny" => {
+                let bin: i32 = params.get(if member == "binx" { "BinX" } else { "BinY" })?;
+                if !info.supported_bins.contains(&bin) {
+                    return Err(ascom(INVALID_VALUE, format!("Bin {bin} is not supported")));
+                }
+                self.with_device(device, info, |d| d.bin = bin);
+            }
+            "numx" => {
+                let num_x = params.get("NumX")?;
+                self.with_device(device, info, |d| d.num_x = num_x);
+            }
+            "numy" => {
+                let num_y = params.get("NumY")?;
+                self.with_device(device, info, |d| d.num_y = num_y);
+            }
+            "startx" => {
+                let start_x = params.get("StartX")?;
+                self.with_device(device, info, |d| d.start_x = start_x);
+            }
+            "starty" => {
+                let start_y = params.get("StartY")?;
+                self.with_device(device, info, |d| d.start_y = start_y);
+            }
+            "gain" => {
+                slot.run(ControlMessages::SetGain(params.get("Gain")?))
+                    .await?;
+            }
+            "offset" => {
+                slot.run(ControlMessages::SetOffset(params.get("Offset")?))
+                    .await?;
+            }
+            "readoutmode" => {
+                let mode: i32 = params.get("ReadoutMode")?;
+                if mode != 0 {
+                    return Err(ascom(INVALID_VALUE, format!("Invalid readout mode {mode}")));
+                }
+            }
+            "startexposure" => {
+                let seconds: f64 = params.get("Duration")?;
+                let light = params.bool("Light")?;
+                self.start_exposure(device, &slot, seconds, light).await?;
+            }
+            "abortexposure" => {
+                slot.run(ControlMessages::AbortExposure).await?;
+            }
+            "pulseguide" => {
+                let direction = guide_direction(params.get("Direction")?)?;
+                let duration_ms: u64 = params.get("Duration")?;
+                slot.pulse_guide(direction, std::time::Duration::from_millis(duration_ms))?;
+            }
+            "cooleron" => {
+                target_range(camera)?;
+                // Turning it off warms the sensor up first
+                let cmd = match params.bool("CoolerOn")? {
+                    true => {
+                        let stored = self.with_device(device, info, |d| d.target_temperature);
+                        let target = match stored {
+                            Some(target) => target,
+                            None => control_value(camera, CONTROL_TYPE::TARGET_TEMP)? as f64,
+                        };
+                        ControlMessages::SetTargetTemperature(target)
+                    }
+                    false => ControlMessages::WarmUp,
+                };
+                slot.run(cmd).await?;
+            }
+            "setccdtemperature" => {
+                let (min, max) = target_range(camera)?;
+                let target: f64 = params.get("SetCCDTemperature")?;
+                if !(min as f64..=max as f64).contains(&target) {
+                    return Err(ascom(
+                        INVALID_VALUE,
+                        format!("{target} °C is outside of {min}..={max} °C"),
+                    ));
+                }
+                self.with_device(device, info, |d| d.target_temperature = Some(target));
+                if control_value(camera, CONTROL_TYPE::COOLER_ON)? != 0 {
+                    slot.run(ControlMessages::SetTargetTemperature(target))
+                        .await?;
+                }
+            }
+            "fastreadout" | "stopexposure" | "subexposureduration" => {
+                return Err(not_implemented(member))
+            }
+            _ => return Err(Failure::NotFound(format!("Unknown member {member}"))),
+        }
+        Ok(Answer::Done)
+    }
+
+    /// Exposes the frame the client set up, checking it is on the sensor first.
+    async fn start_exposure(
+        &self,
+        device: usize,
+        slot: &CameraSlot,
+        seconds: f64,
+        light: bool,
+    ) -> Result<(), Failure> {
+        let info = &slot.info;
+        if seconds < 0. {
+            return Err(ascom(INVALID_VALUE, format!("Invalid duration {seconds}")));
+        }
+        let roi = self.with_device(device, info, |d| d.roi());
+        if roi.x < 0
+            || roi.y < 0
+            || roi.w < 1
+            || roi.h < 1
+            || roi.x + roi.w > info.max_width / roi.bin
+            || roi.y + roi.h > info.max_height / roi.bin
+        {
+            return Err(ascom(
+                INVALID_VALUE,
+                format!("Frame {roi:?} is outside the sensor"),
+            ));
+        }
+
+        slot.expose(roi, seconds, !light).await?;
+        self.with_device(device, info, |d| {
+            d.exposure = Some((SystemTime::now(), seconds))
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::simulator::{SimulatedCamera, SimulatorConfig};
+
+    /// A server with one simulated 320x240 camera.
+    fn start() -> (AppState, Router, usize, std::thread::JoinHandle<()>) {
+        let state = AppState::default();
+        let (index, thread) = state.connect(Arc::new(SimulatedCamera::new(SimulatorConfig {
+            max_width: 320,
+            max_height: 240,
+            ..Default::default()
+        })));
+        (state.clone(), router(state), index, thread)
+    }
+
+    async fn stop(state: AppState, index: usize, thread: std::thread::JoinHandle<()>) {
+        tokio::task::spawn_blocking(move || state.disconnect(index, thread))
+            .await
+            .unwrap();
+    }
+
+    async fn send(app: &Router, request: Request<Body>) -> (StatusCode, Vec<u8>) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    async fn get(app: &Router, path: &str) -> Value {
+        let request = Request::get(path).body(Body::empty()).unwrap();
+        let (status, body) = send(app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    async fn put(app: &Router, path: &str, form: &str) -> Value {
+        let request = Request::put(path)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(form.to_string()))
+            .unwrap();
+        let (status, body) = send(app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn answers_echo_the_client_transaction() {
+        let (state, app, index, thread) = start();
+        let answer = get(&app, "/api/v1/camera/0/name?clienttransactionid=42").await;
+        assert_eq!(answer["ClientTransactionID"], 42);
+        assert_eq!(answer["ErrorNumber"], 0);
+        assert!(answer["ServerTransactionID"].as_u64().unwrap() > 0);
+        assert!(answer["Value"].is_string());
+
+        let answer = put(
+            &app,
+            "/api/v1/camera/0/connected",
+            "Connected=True&ClientTransactionID=7",
+        )
+        .await;
+        assert_eq!(answer["ClientTransactionID"], 7);
+        assert_eq!(answer["ErrorNumber"], 0);
+        stop(state, index, thread).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn members_need_a_connected_camera() {
+        let (state, app, index, thread) = start();
+        let answer = get(&app, "/api/v1/camera/0/gain").await;
+        assert_eq!(answer["ErrorNumber"], NOT_CONNECTED);
+        let answer = put(&app, "/api/v1/camera/0/binx", "BinX=2").await;
+        assert_eq!(answer["ErrorNumber"], NOT_CONNECTED);
+
+        put(&app, "/api/v1/camera/0/connected", "Connected=True").await;
+        let answer = get(&app, "/api/v1/camera/0/gain").await;
+        assert_eq!(answer["ErrorNumber"], 0);
+
+        // Unplugged, the device stays but can't be used
+        stop(state.clone(), index, thread).await;
+        let answer = get(&app, "/api/v1/camera/0/connected").await;
+        assert_eq!(answer["Value"], false);
+        let answer = get(&app, "/api/v1/camera/0/gain").await;
+        assert_eq!(answer["ErrorNumber"], NOT_CONNECTED);
+        let answer = put(&app, "/api/v1/camera/0/connected", "Connected=True").await;
+        assert_eq!(answer["ErrorNumber"], NOT_CONNECTED);
+
+        let request = Request::get("/api/v1/camera/1/name")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&app, request).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn invalid_values_are_rejected() {
+        let (state, app, index, thread) = start();
+        put(&app, "/api/v1/camera/0/connected", "Connected=True").await;
+        let answer = put(&app, "/api/v1/camera/0/binx", "BinX=7").await;
+        assert_eq!(answer["ErrorNumber"], INVALID_VALUE);
+        let answer = put(
+            &app,
+            "/api/v1/camera/0/startexposure",
+            "Duration=-1&Light=True",
+        )
+        .await;
+        assert_eq!(answer["ErrorNumber"], INVALID_VALUE);
+        put(&app, "/api/v1/camera/0/startx", "StartX=100").await;
+        let answer = put(
+            &app,
+            "/api/v1/camera/0/startexposure",
+            "Duration=0.01&Light=True",
+        )
+        .await;
+        assert_eq!(answer["ErrorNumber"], INVALID_VALUE);
+        // Sizes the camera can't take get through the checks here, the controller's
+        // answer still makes them invalid values
+        put(&app, "/api/v1/camera/0/startx", "StartX=0").await;
+        put(&app, "/api/v1/camera/0/numx", "NumX=100").await;
+        let answer = put(
+            &app,
+            "/api/v1/camera/0/startexposure",
+            "Duration=0.01&Light=True",
+        )
+        .await;
+        assert_eq!(answer["ErrorNumber"], INVALID_VALUE);
+
+        let request = Request::put("/api/v1/camera/0/gain")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from("Gain=lots"))
+            .unwrap();
+        assert_eq!(send(&app, request).await.0, StatusCode::BAD_REQUEST);
+        stop(state, index, thread).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exposures_download_as_image_bytes() {
+        let (state, app, index, thread) = start();
+        put(&app, "/api/v1/camera/0/connected", "Connected=True").await;
+        put(&app, "/api/v1/camera/0/binx", "BinX=2").await;
+        put(&app, "/api/v1/camera/0/numx", "NumX=160").await;
+        put(&app, "/api/v1/camera/0/numy", "NumY=120").await;
+        let answer = get(&app, "/api/v1/camera/0/imageready").await;
+        assert_eq!(answer["Value"], false);
+
+        let answer = put(
+            &app,
+            "/api/v1/camera/0/startexposure",
+            "Duration=0.01&Light=True",
+        )
+        .await;
+        assert_eq!(answer["ErrorNumber"], 0);
+        for _ in 0..1000 {
+            if get(&app, "/api/v1/camera/0/imageready").await["Value"] == true {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let request = Request::get("/api/v1/camera/0/imagearray?ClientTransactionID=9")
+            .header(header::ACCEPT, "application/imagebytes")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        let header: Vec<i32> = body[..44]
+            .chunks(4)
+            .map(|field| i32::from_le_bytes(field.try_into().unwrap()))
+            .collect();
+        assert_eq!(header[..3], [1, 0, 9]);
+        assert_eq!(header[4..], [44, 2, 8, 2, 160, 120, 0]);
+        assert_eq!(body.len(), 44 + 160 * 120 * 2);
+        stop(state, index, thread).await;
+    }
+}
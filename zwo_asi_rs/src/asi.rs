@@ -1,9 +1,14 @@
 #![allow(non_upper_case_globals)]
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
+// The unsafe wrappers call straight into the SDK and are only meant for
+// `crate::OpenCamera`, which keeps the camera open and its calls from overlapping
+#![allow(clippy::missing_safety_doc)]
 
-use std::ffi::c_int;
+use std::ffi::{c_char, c_int};
 
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
@@ -99,6 +104,35 @@ pub unsafe fn get_camera_property(
     check_error_code(ASIGetCameraProperty(pASICameraInfo, iCameraIndex))
 }
 
+/// USB vendor ID of every ZWO camera.
+pub const ASI_VID: i32 = 0x03c3;
+
+/// Version of the loaded SDK library, e.g. "1, 37, 0, 0".
+pub unsafe fn get_sdk_version() -> String {
+    unsafe {
+        std::ffi::CStr::from_ptr(ASIGetSDKVersion())
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// USB product IDs of the cameras this SDK supports.
+pub unsafe fn get_product_ids() -> Vec<i32> {
+    unsafe {
+        let len = ASIGetProductIDs(std::ptr::null_mut());
+        let mut pids = vec![0; len.max(0) as usize];
+        if !pids.is_empty() {
+            ASIGetProductIDs(pids.as_mut_ptr());
+        }
+        pids
+    }
+}
+
+/// Whether the USB device `vid`:`pid` is a camera this SDK supports.
+pub unsafe fn camera_check(vid: i32, pid: i32) -> bool {
+    unsafe { ASICameraCheck(vid, pid) != 0 }
+}
+
 pub unsafe fn open_camera(iCameraID: ::std::os::raw::c_int) -> Result<(), ASI_ERROR> {
     check_error_code(ASIOpenCamera(iCameraID))
 }
@@ -108,7 +142,7 @@ pub unsafe fn init_camera(iCameraID: ::std::os::raw::c_int) -> Result<(), ASI_ER
 }
 
 #[repr(i32)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum IMG_TYPE {
     //Supported Video Format
     RAW8 = ASI_IMG_TYPE_ASI_IMG_RAW8,
@@ -128,6 +162,19 @@ impl IMG_TYPE {
     }
 }
 
+impl std::str::FromStr for IMG_TYPE {
+    type Err = ASI_ERROR;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "RAW8" => Ok(Self::RAW8),
+            "RGB24" => Ok(Self::RGB24),
+            "RAW16" => Ok(Self::RAW16),
+            "Y8" => Ok(Self::Y8),
+            _ => Err(ASI_ERROR::INVALID_IMGTYPE),
+        }
+    }
+}
+
 impl TryFrom<i32> for IMG_TYPE {
     type Error = ASI_ERROR;
     fn try_from(value: i32) -> Result<Self, Self::Error> {
@@ -157,11 +204,15 @@ pub unsafe fn set_roi_format(
     ))
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct ROIFormat {
     pub width: i32,
     pub height: i32,
     pub bin: i32,
     pub img_type: IMG_TYPE,
+    /// Top left corner of the ROI, in binned pixels.
+    pub start_x: i32,
+    pub start_y: i32,
 }
 
 pub unsafe fn get_roi_format(iCameraID: ::std::os::raw::c_int) -> Result<ROIFormat, ASI_ERROR> {
@@ -178,16 +229,34 @@ pub unsafe fn get_roi_format(iCameraID: ::std::os::raw::c_int) -> Result<ROIForm
     ))?;
 
     let img_type = IMG_TYPE::try_from(img_type)?;
+    let (start_x, start_y) = get_start_pos(iCameraID)?;
     Ok(ROIFormat {
         width,
         height,
         bin,
         img_type,
+        start_x,
+        start_y,
     })
 }
 
+pub unsafe fn set_start_pos(
+    iCameraID: ::std::os::raw::c_int,
+    iStartX: ::std::os::raw::c_int,
+    iStartY: ::std::os::raw::c_int,
+) -> Result<(), ASI_ERROR> {
+    check_error_code(ASISetStartPos(iCameraID, iStartX, iStartY))
+}
+
+pub unsafe fn get_start_pos(iCameraID: ::std::os::raw::c_int) -> Result<(i32, i32), ASI_ERROR> {
+    let mut start_x: c_int = 0;
+    let mut start_y: c_int = 0;
+    check_error_code(ASIGetStartPos(iCameraID, &mut start_x, &mut start_y))?;
+    Ok((start_x, start_y))
+}
+
 #[repr(u32)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize)]
 pub enum CONTROL_TYPE {
     //Control type//
     GAIN = ASI_CONTROL_TYPE_ASI_GAIN,
@@ -221,6 +290,109 @@ pub enum CONTROL_TYPE {
     ROLLING_INTERVAL = ASI_CONTROL_TYPE_ASI_ROLLING_INTERVAL, //microsecond
 }
 
+impl TryFrom<u32> for CONTROL_TYPE {
+    type Error = ASI_ERROR;
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        use CONTROL_TYPE::*;
+        const ALL: [CONTROL_TYPE; 29] = [
+            GAIN,
+            EXPOSURE,
+            GAMMA,
+            WB_R,
+            WB_B,
+            OFFSET,
+            BANDWIDTHOVERLOAD,
+            OVERCLOCK,
+            TEMPERATURE,
+            FLIP,
+            AUTO_MAX_GAIN,
+            AUTO_MAX_EXP,
+            AUTO_TARGET_BRIGHTNESS,
+            HARDWARE_BIN,
+            HIGH_SPEED_MODE,
+            COOLER_POWER_PERC,
+            TARGET_TEMP,
+            COOLER_ON,
+            MONO_BIN,
+            FAN_ON,
+            PATTERN_ADJUST,
+            ANTI_DEW_HEATER,
+            FAN_ADJUST,
+            PWRLED_BRIGNT,
+            USBHUB_RESET,
+            GPS_SUPPORT,
+            GPS_START_LINE,
+            GPS_END_LINE,
+            ROLLING_INTERVAL,
+        ];
+        ALL.into_iter()
+            .find(|c| *c as u32 == value)
+            .ok_or(ASI_ERROR::INVALID_CONTROL_TYPE)
+    }
+}
+
+/// Decodes a fixed size, NUL padded C string from the SDK structs.
+pub fn c_chars_to_string(chars: &[c_char]) -> String {
+    let bytes: Vec<u8> = chars
+        .iter()
+        .map(|c| *c as u8)
+        .take_while(|c| *c != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Typed version of `ASI_CONTROL_CAPS`, describing one control the camera supports.
+#[derive(Clone, Debug, Serialize)]
+pub struct ControlCaps {
+    pub name: String,
+    pub description: String,
+    pub min_value: i64,
+    pub max_value: i64,
+    pub default_value: i64,
+    pub is_auto_supported: bool,
+    pub is_writable: bool,
+    pub control_type: CONTROL_TYPE,
+}
+
+impl ControlCaps {
+    /// Clamps `value` into the range the camera accepts for this control.
+    pub fn clamp(&self, value: i64) -> i64 {
+        value.clamp(self.min_value, self.max_value)
+    }
+}
+
+impl TryFrom<&ASI_CONTROL_CAPS> for ControlCaps {
+    type Error = ASI_ERROR;
+    fn try_from(caps: &ASI_CONTROL_CAPS) -> Result<Self, Self::Error> {
+        Ok(Self {
+            name: c_chars_to_string(&caps.Name),
+            description: c_chars_to_string(&caps.Description),
+            min_value: caps.MinValue,
+            max_value: caps.MaxValue,
+            default_value: caps.DefaultValue,
+            is_auto_supported: caps.IsAutoSupported != 0,
+            is_writable: caps.IsWritable != 0,
+            control_type: CONTROL_TYPE::try_from(caps.ControlType)?,
+        })
+    }
+}
+
+pub unsafe fn get_num_of_controls(iCameraID: ::std::os::raw::c_int) -> Result<i32, ASI_ERROR> {
+    let mut num_controls: c_int = 0;
+    check_error_code(ASIGetNumOfControls(iCameraID, &mut num_controls))?;
+    Ok(num_controls)
+}
+
+/// The caps as the SDK has them, see [`ControlCaps::try_from`] for the typed version.
+pub unsafe fn get_control_caps(
+    iCameraID: ::std::os::raw::c_int,
+    iControlIndex: ::std::os::raw::c_int,
+) -> Result<ASI_CONTROL_CAPS, ASI_ERROR> {
+    let mut caps: ASI_CONTROL_CAPS = std::mem::zeroed();
+    check_error_code(ASIGetControlCaps(iCameraID, iControlIndex, &mut caps))?;
+    Ok(caps)
+}
+
 pub unsafe fn set_control_value(
     iCameraID: ::std::os::raw::c_int,
     ControlType: CONTROL_TYPE,
@@ -256,6 +428,10 @@ pub unsafe fn start_exposure(
     unsafe { check_error_code(ASIStartExposure(iCameraID, bIsDark as i32)) }
 }
 
+pub unsafe fn stop_exposure(iCameraID: ::std::os::raw::c_int) -> Result<(), ASI_ERROR> {
+    unsafe { check_error_code(ASIStopExposure(iCameraID)) }
+}
+
 #[repr(u32)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum EXPOSURE_STATUS {
@@ -315,3 +491,432 @@ pub unsafe fn get_video_data(
 ) -> Result<(), ASI_ERROR> {
     unsafe { check_error_code(ASIGetVideoData(iCameraID, pBuffer, lBuffSize, iWaitms)) }
 }
+
+#[repr(i32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CAMERA_MODE {
+    NORMAL = ASI_CAMERA_MODE_ASI_MODE_NORMAL,
+    TRIG_SOFT_EDGE = ASI_CAMERA_MODE_ASI_MODE_TRIG_SOFT_EDGE,
+    TRIG_RISE_EDGE = ASI_CAMERA_MODE_ASI_MODE_TRIG_RISE_EDGE,
+    TRIG_FALL_EDGE = ASI_CAMERA_MODE_ASI_MODE_TRIG_FALL_EDGE,
+    TRIG_SOFT_LEVEL = ASI_CAMERA_MODE_ASI_MODE_TRIG_SOFT_LEVEL,
+    TRIG_HIGH_LEVEL = ASI_CAMERA_MODE_ASI_MODE_TRIG_HIGH_LEVEL,
+    TRIG_LOW_LEVEL = ASI_CAMERA_MODE_ASI_MODE_TRIG_LOW_LEVEL,
+}
+
+impl CAMERA_MODE {
+    pub fn is_soft(&self) -> bool {
+        matches!(self, Self::TRIG_SOFT_EDGE | Self::TRIG_SOFT_LEVEL)
+    }
+}
+
+impl std::str::FromStr for CAMERA_MODE {
+    type Err = ASI_ERROR;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "NORMAL" => Ok(Self::NORMAL),
+            "TRIG_SOFT_EDGE" | "SOFT_EDGE" => Ok(Self::TRIG_SOFT_EDGE),
+            "TRIG_RISE_EDGE" | "RISE_EDGE" => Ok(Self::TRIG_RISE_EDGE),
+            "TRIG_FALL_EDGE" | "FALL_EDGE" => Ok(Self::TRIG_FALL_EDGE),
+            "TRIG_SOFT_LEVEL" | "SOFT_LEVEL" => Ok(Self::TRIG_SOFT_LEVEL),
+            "TRIG_HIGH_LEVEL" | "HIGH_LEVEL" => Ok(Self::TRIG_HIGH_LEVEL),
+            "TRIG_LOW_LEVEL" | "LOW_LEVEL" => Ok(Self::TRIG_LOW_LEVEL),
+            _ => Err(ASI_ERROR::INVALID_MODE),
+        }
+    }
+}
+
+impl TryFrom<i32> for CAMERA_MODE {
+    type Error = ASI_ERROR;
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            ASI_CAMERA_MODE_ASI_MODE_NORMAL => Ok(Self::NORMAL),
+            ASI_CAMERA_MODE_ASI_MODE_TRIG_SOFT_EDGE => Ok(Self::TRIG_SOFT_EDGE),
+            ASI_CAMERA_MODE_ASI_MODE_TRIG_RISE_EDGE => Ok(Self::TRIG_RISE_EDGE),
+            ASI_CAMERA_MODE_ASI_MODE_TRIG_FALL_EDGE => Ok(Self::TRIG_FALL_EDGE),
+            ASI_CAMERA_MODE_ASI_MODE_TRIG_SOFT_LEVEL => Ok(Self::TRIG_SOFT_LEVEL),
+            ASI_CAMERA_MODE_ASI_MODE_TRIG_HIGH_LEVEL => Ok(Self::TRIG_HIGH_LEVEL),
+            ASI_CAMERA_MODE_ASI_MODE_TRIG_LOW_LEVEL => Ok(Self::TRIG_LOW_LEVEL),
+            _ => Err(ASI_ERROR::INVALID_MODE),
+        }
+    }
+}
+
+#[repr(i32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TRIG_OUTPUT {
+    PINA = ASI_TRIG_OUTPUT_ASI_TRIG_OUTPUT_PINA,
+    PINB = ASI_TRIG_OUTPUT_ASI_TRIG_OUTPUT_PINB,
+}
+
+impl std::str::FromStr for TRIG_OUTPUT {
+    type Err = ASI_ERROR;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "A" | "PINA" => Ok(Self::PINA),
+            "B" | "PINB" => Ok(Self::PINB),
+            _ => Err(ASI_ERROR::GENERAL_ERROR),
+        }
+    }
+}
+
+/// Output pin settings. Delay and duration are in µs, 0 to 2e9.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TriggerOutputConf {
+    /// Pin level during the pulse; the pin idles at the opposite level.
+    pub pin_high: bool,
+    /// From the start of the exposure to the start of the pulse.
+    pub delay_us: i64,
+    pub duration_us: i64,
+}
+
+pub unsafe fn get_camera_support_mode(
+    iCameraID: ::std::os::raw::c_int,
+) -> Result<Vec<CAMERA_MODE>, ASI_ERROR> {
+    unsafe {
+        let mut supported = ASI_SUPPORTED_MODE {
+            SupportedCameraMode: [ASI_CAMERA_MODE_ASI_MODE_END; 16],
+        };
+        check_error_code(ASIGetCameraSupportMode(iCameraID, &mut supported))?;
+        Ok(supported
+            .SupportedCameraMode
+            .iter()
+            .take_while(|mode| **mode != ASI_CAMERA_MODE_ASI_MODE_END)
+            .filter_map(|mode| CAMERA_MODE::try_from(*mode).ok())
+            .collect())
+    }
+}
+
+pub unsafe fn get_camera_mode(iCameraID: ::std::os::raw::c_int) -> Result<CAMERA_MODE, ASI_ERROR> {
+    unsafe {
+        let mut mode: ASI_CAMERA_MODE = 0;
+        check_error_code(ASIGetCameraMode(iCameraID, &mut mode))?;
+        CAMERA_MODE::try_from(mode)
+    }
+}
+
+pub unsafe fn set_camera_mode(
+    iCameraID: ::std::os::raw::c_int,
+    mode: CAMERA_MODE,
+) -> Result<(), ASI_ERROR> {
+    unsafe { check_error_code(ASISetCameraMode(iCameraID, mode as i32)) }
+}
+
+pub unsafe fn send_soft_trigger(
+    iCameraID: ::std::os::raw::c_int,
+    bStart: bool,
+) -> Result<(), ASI_ERROR> {
+    unsafe { check_error_code(ASISendSoftTrigger(iCameraID, bStart as i32)) }
+}
+
+pub unsafe fn set_trigger_output_io_conf(
+    iCameraID: ::std::os::raw::c_int,
+    pin: TRIG_OUTPUT,
+    conf: TriggerOutputConf,
+) -> Result<(), ASI_ERROR> {
+    unsafe {
+        check_error_code(ASISetTriggerOutputIOConf(
+            iCameraID,
+            pin as i32,
+            conf.pin_high as i32,
+            conf.delay_us,
+            conf.duration_us,
+        ))
+    }
+}
+
+pub unsafe fn get_trigger_output_io_conf(
+    iCameraID: ::std::os::raw::c_int,
+    pin: TRIG_OUTPUT,
+) -> Result<TriggerOutputConf, ASI_ERROR> {
+    unsafe {
+        let mut pin_high: c_int = 0;
+        let mut delay_us: ::std::os::raw::c_long = 0;
+        let mut duration_us: ::std::os::raw::c_long = 0;
+        check_error_code(ASIGetTriggerOutputIOConf(
+            iCameraID,
+            pin as i32,
+            &mut pin_high,
+            &mut delay_us,
+            &mut duration_us,
+        ))?;
+        Ok(TriggerOutputConf {
+            pin_high: pin_high != 0,
+            delay_us,
+            duration_us,
+        })
+    }
+}
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GUIDE_DIRECTION {
+    NORTH = ASI_GUIDE_DIRECTION_ASI_GUIDE_NORTH,
+    SOUTH = ASI_GUIDE_DIRECTION_ASI_GUIDE_SOUTH,
+    EAST = ASI_GUIDE_DIRECTION_ASI_GUIDE_EAST,
+    WEST = ASI_GUIDE_DIRECTION_ASI_GUIDE_WEST,
+}
+
+impl GUIDE_DIRECTION {
+    pub const ALL: [Self; 4] = [Self::NORTH, Self::SOUTH, Self::EAST, Self::WEST];
+
+    /// The other direction on the same axis.
+    pub fn opposite(&self) -> Self {
+        match self {
+            Self::NORTH => Self::SOUTH,
+            Self::SOUTH => Self::NORTH,
+            Self::EAST => Self::WEST,
+            Self::WEST => Self::EAST,
+        }
+    }
+}
+
+impl std::str::FromStr for GUIDE_DIRECTION {
+    type Err = ASI_ERROR;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "N" | "NORTH" => Ok(Self::NORTH),
+            "S" | "SOUTH" => Ok(Self::SOUTH),
+            "E" | "EAST" => Ok(Self::EAST),
+            "W" | "WEST" => Ok(Self::WEST),
+            _ => Err(ASI_ERROR::GENERAL_ERROR),
+        }
+    }
+}
+
+pub unsafe fn pulse_guide_on(
+    iCameraID: ::std::os::raw::c_int,
+    direction: GUIDE_DIRECTION,
+) -> Result<(), ASI_ERROR> {
+    unsafe { check_error_code(ASIPulseGuideOn(iCameraID, direction as i32)) }
+}
+
+pub unsafe fn pulse_guide_off(
+    iCameraID: ::std::os::raw::c_int,
+    direction: GUIDE_DIRECTION,
+) -> Result<(), ASI_ERROR> {
+    unsafe { check_error_code(ASIPulseGuideOff(iCameraID, direction as i32)) }
+}
+
+/// A GPS fix latched by the camera while it read out a frame.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GpsData {
+    /// UTC, to 0.1 µs.
+    pub time: DateTime<Utc>,
+    /// Degrees, north positive.
+    pub latitude: f64,
+    /// Degrees, east positive.
+    pub longitude: f64,
+    /// As reported by the receiver.
+    pub altitude: i32,
+    pub satellites: i32,
+}
+
+impl TryFrom<&ASI_GPS_DATA> for GpsData {
+    type Error = ASI_ERROR;
+    fn try_from(data: &ASI_GPS_DATA) -> Result<Self, Self::Error> {
+        let t = &data.Datetime;
+        // Usecond counts tenths of a microsecond within the millisecond
+        let time = NaiveDate::from_ymd_opt(t.Year, t.Month as u32, t.Day as u32)
+            .and_then(|date| {
+                date.and_hms_nano_opt(
+                    t.Hour as u32,
+                    t.Minute as u32,
+                    t.Second as u32,
+                    (t.Msecond * 1_000_000 + t.Usecond * 100) as u32,
+                )
+            })
+            .ok_or(ASI_ERROR::GPS_DATA_INVALID)?
+            .and_utc();
+        Ok(Self {
+            time,
+            latitude: data.Latitude,
+            longitude: data.Longitude,
+            altitude: data.Altitude,
+            satellites: data.SatelliteNum,
+        })
+    }
+}
+
+/// GPS fixes latched at the `GPS_START_LINE` and `GPS_END_LINE` rows of a frame.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FrameGps {
+    pub start: GpsData,
+    pub end: GpsData,
+}
+
+pub unsafe fn get_video_data_gps(
+    iCameraID: ::std::os::raw::c_int,
+    pBuffer: *mut ::std::os::raw::c_uchar,
+    lBuffSize: ::std::os::raw::c_long,
+    iWaitms: ::std::os::raw::c_int,
+) -> Result<GpsData, ASI_ERROR> {
+    unsafe {
+        let mut gps: ASI_GPS_DATA = std::mem::zeroed();
+        check_error_code(ASIGetVideoDataGPS(
+            iCameraID, pBuffer, lBuffSize, iWaitms, &mut gps,
+        ))?;
+        GpsData::try_from(&gps)
+    }
+}
+
+pub unsafe fn get_data_after_exp_gps(
+    iCameraID: ::std::os::raw::c_int,
+    pBuffer: *mut ::std::os::raw::c_uchar,
+    lBuffSize: ::std::os::raw::c_long,
+) -> Result<GpsData, ASI_ERROR> {
+    unsafe {
+        let mut gps: ASI_GPS_DATA = std::mem::zeroed();
+        check_error_code(ASIGetDataAfterExpGPS(
+            iCameraID, pBuffer, lBuffSize, &mut gps,
+        ))?;
+        GpsData::try_from(&gps)
+    }
+}
+
+pub unsafe fn gps_get_data(iCameraID: ::std::os::raw::c_int) -> Result<FrameGps, ASI_ERROR> {
+    unsafe {
+        let mut start: ASI_GPS_DATA = std::mem::zeroed();
+        let mut end: ASI_GPS_DATA = std::mem::zeroed();
+        check_error_code(ASIGPSGetData(iCameraID, &mut start, &mut end))?;
+        Ok(FrameGps {
+            start: GpsData::try_from(&start)?,
+            end: GpsData::try_from(&end)?,
+        })
+    }
+}
+
+/// Length of the serial number and of the custom ID stored in the camera's flash.
+pub const ASI_ID_LEN: usize = 8;
+
+/// The serial number as the hex string ZWO prints on the camera.
+pub unsafe fn get_serial_number(iCameraID: ::std::os::raw::c_int) -> Result<String, ASI_ERROR> {
+    unsafe {
+        let mut sn = ASI_SN {
+            id: [0; ASI_ID_LEN],
+        };
+        check_error_code(ASIGetSerialNumber(iCameraID, &mut sn))?;
+        Ok(sn.id.iter().map(|b| format!("{b:02x}")).collect())
+    }
+}
+
+/// The user settable ID stored in flash, only available on USB3 cameras.
+pub unsafe fn get_id(iCameraID: ::std::os::raw::c_int) -> Result<String, ASI_ERROR> {
+    unsafe {
+        let mut id = ASI_ID {
+            id: [0; ASI_ID_LEN],
+        };
+        check_error_code(ASIGetID(iCameraID, &mut id))?;
+        let len = id.id.iter().position(|b| *b == 0).unwrap_or(ASI_ID_LEN);
+        Ok(String::from_utf8_lossy(&id.id[..len]).into_owned())
+    }
+}
+
+/// Writes `custom_id`, at most 8 bytes, to the camera's flash.
+pub unsafe fn set_id(iCameraID: ::std::os::raw::c_int, custom_id: &str) -> Result<(), ASI_ERROR> {
+    if custom_id.len() > ASI_ID_LEN {
+        return Err(ASI_ERROR::GENERAL_ERROR);
+    }
+    let mut id = ASI_ID {
+        id: [0; ASI_ID_LEN],
+    };
+    id.id[..custom_id.len()].copy_from_slice(custom_id.as_bytes());
+    unsafe { check_error_code(ASISetID(iCameraID, id)) }
+}
+
+/// Typed version of `ASI_CAMERA_INFO`, plus the identity read from the open camera.
+#[derive(Clone, Debug, Serialize)]
+pub struct CameraInfo {
+    pub name: String,
+    /// The SDK's index for the camera, which changes as cameras are plugged in.
+    pub camera_id: i32,
+    pub serial: Option<String>,
+    /// The ID set with [`set_id`], `None` if the camera can't store one.
+    pub custom_id: Option<String>,
+    pub max_width: i32,
+    pub max_height: i32,
+    /// Unbinned pixel size in µm.
+    pub pixel_size: f64,
+    pub bit_depth: i32,
+    pub elec_per_adu: f32,
+    pub usb3_host: bool,
+    pub usb3_camera: bool,
+    pub cooler: bool,
+    pub mechanical_shutter: bool,
+    pub st4_port: bool,
+    pub trigger: bool,
+    /// `None` for mono cameras.
+    pub bayer_pattern: Option<&'static str>,
+    pub supported_bins: Vec<i32>,
+    pub supported_formats: Vec<IMG_TYPE>,
+}
+
+impl From<&ASI_CAMERA_INFO> for CameraInfo {
+    fn from(info: &ASI_CAMERA_INFO) -> Self {
+        Self {
+            name: c_chars_to_string(&info.Name),
+            camera_id: info.CameraID,
+            serial: None,
+            custom_id: None,
+            max_width: info.MaxWidth as i32,
+            max_height: info.MaxHeight as i32,
+            pixel_size: info.PixelSize,
+            bit_depth: info.BitDepth,
+            elec_per_adu: info.ElecPerADU,
+            usb3_host: info.IsUSB3Host != 0,
+            usb3_camera: info.IsUSB3Camera != 0,
+            cooler: info.IsCoolerCam != 0,
+            mechanical_shutter: info.MechanicalShutter != 0,
+            st4_port: info.ST4Port != 0,
+            trigger: info.IsTriggerCam != 0,
+            bayer_pattern: (info.IsColorCam != 0)
+                .then(|| crate::debayer::CfaPattern::from_asi(info.BayerPattern).name()),
+            supported_bins: info.supported_bins(),
+            supported_formats: info.supported_img_types(),
+        }
+    }
+}
+
+impl CameraInfo {
+    /// Whether both describe the same physical camera, for recognising it when it is
+    /// plugged back in. Cameras without a serial number are told apart by name.
+    pub fn is_same_camera(&self, other: &CameraInfo) -> bool {
+        match (&self.serial, &other.serial) {
+            (Some(serial), Some(other_serial)) => serial == other_serial,
+            _ => self.name == other.name,
+        }
+    }
+
+    /// Whether `selector` names this camera, by serial, custom ID or name, which stay
+    /// the same when it is plugged back in.
+    pub fn matches(&self, selector: &str) -> bool {
+        self.serial
+            .as_deref()
+            .is_some_and(|serial| serial.eq_ignore_ascii_case(selector))
+            || self.custom_id.as_deref() == Some(selector)
+            || self.name == selector
+    }
+
+    /// Whether `selector` is the SDK index of this camera. Only a fallback for when no
+    /// camera [`CameraInfo::matches`] it, as the SDK hands out indices again when
+    /// cameras are plugged in.
+    pub fn matches_index(&self, selector: &str) -> bool {
+        selector.parse() == Ok(self.camera_id)
+    }
+
+    /// What a selector that picked this camera by index is replaced with, so it keeps
+    /// picking the same camera after a replug.
+    pub fn stable_selector(&self) -> String {
+        self.serial.clone().unwrap_or_else(|| self.name.clone())
+    }
+
+    /// Column and row of the first red pixel, `None` for mono cameras.
+    pub fn bayer_offset(&self) -> Option<(i32, i32)> {
+        match self.bayer_pattern? {
+            "GRBG" => Some((1, 0)),
+            "GBRG" => Some((0, 1)),
+            "BGGR" => Some((1, 1)),
+            _ => Some((0, 0)),
+        }
+    }
+}
@@ -0,0 +1,333 @@
+//! Automatic exposure and gain.
+//!
+//! Solar and lunar sessions change brightness all the time, with clouds passing and
+//! the altitude changing. In video mode the camera handles this itself: its auto
+//! `EXPOSURE` and `GAIN` follow `AUTO_TARGET_BRIGHTNESS` up to `AUTO_MAX_EXP` and
+//! `AUTO_MAX_GAIN`, which are set from [`AutoExposureConfig`]. The SDK has no auto
+//! mode for single exposures, so [`AutoExposure`] meters every finished exposure and
+//! picks the exposure and gain of the next one.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{asi::IMG_TYPE, frame::DepthHistogram};
+
+/// Largest change of brightness from one exposure to the next, so a passing cloud
+/// doesn't send the exposure off to its limit.
+const MAX_STEP: f64 = 8.;
+/// What an exposure with clipped highlights is darkened by, as there's no telling
+/// how far over it is.
+const CLIPPED_STEP: f64 = 0.25;
+
+/// The level of the frame that is held at [`AutoExposureConfig::target`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metering {
+    #[default]
+    Median,
+    /// The level at or below which this fraction of the pixels are, e.g. `0.999` to
+    /// keep the brightest part of the solar disk from clipping.
+    Percentile(f64),
+}
+
+/// Set with `SetAutoExposure`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AutoExposureConfig {
+    #[serde(default)]
+    pub metering: Metering,
+    /// Where the metered level should be, as a fraction of white. In video mode the
+    /// camera meters the frame its own way and only gets this as its target brightness.
+    #[serde(default = "default_target")]
+    pub target: f64,
+    /// How far off the target the level may be before anything changes, as a fraction
+    /// of the target.
+    #[serde(default = "default_tolerance")]
+    pub tolerance: f64,
+    /// Exposure limits in seconds.
+    #[serde(default = "default_min_exposure")]
+    pub min_exposure: f64,
+    #[serde(default = "default_max_exposure")]
+    pub max_exposure: f64,
+    /// Gain only goes up once the exposure is at `max_exposure`, and comes down before
+    /// the exposure does.
+    #[serde(default)]
+    pub min_gain: i64,
+    /// The camera's highest gain if `None`.
+    #[serde(default)]
+    pub max_gain: Option<i64>,
+}
+
+fn default_target() -> f64 {
+    0.4
+}
+
+fn default_tolerance() -> f64 {
+    0.1
+}
+
+fn default_min_exposure() -> f64 {
+    0.0001
+}
+
+fn default_max_exposure() -> f64 {
+    1.
+}
+
+impl Default for AutoExposureConfig {
+    fn default() -> Self {
+        Self {
+            metering: Metering::default(),
+            target: default_target(),
+            tolerance: default_tolerance(),
+            min_exposure: default_min_exposure(),
+            max_exposure: default_max_exposure(),
+            min_gain: 0,
+            max_gain: None,
+        }
+    }
+}
+
+impl AutoExposureConfig {
+    /// Rejects configs the loop and the camera's own auto exposure can't work with.
+    pub fn check(&self) -> Result<()> {
+        if !(0. ..=1.).contains(&self.target) {
+            return Err(anyhow!(
+                "target is {}, it must be within 0..=1",
+                self.target
+            ));
+        }
+        let percentile_ok = match self.metering {
+            Metering::Median => true,
+            Metering::Percentile(fraction) => (0. ..=1.).contains(&fraction),
+        };
+        if !percentile_ok
+            || self.tolerance < 0.
+            || self.min_exposure <= 0.
+            || self.max_exposure < self.min_exposure
+            || self.max_gain.is_some_and(|max| max < self.min_gain)
+        {
+            return Err(anyhow!("Invalid auto exposure config {self:?}"));
+        }
+        Ok(())
+    }
+}
+
+/// The software loop for single exposures, see the [module docs](self).
+pub struct AutoExposure {
+    pub config: AutoExposureConfig,
+    /// What the loop picked last in seconds, where the next run of exposures starts.
+    exposure: Option<f64>,
+}
+
+impl AutoExposure {
+    pub fn new(config: AutoExposureConfig) -> Result<Self> {
+        config.check()?;
+        Ok(Self {
+            config,
+            exposure: None,
+        })
+    }
+
+    /// Exposure in seconds for a new run of exposures, `seconds` until the loop has
+    /// metered a frame.
+    pub fn start_exposure(&self, seconds: f64) -> f64 {
+        self.exposure.unwrap_or(seconds)
+    }
+
+    /// Meters `frame`, exposed for `exposure` seconds at `gain`, and returns the
+    /// exposure and gain for the next one. `None` if the level is close enough to the
+    /// target to leave them be.
+    ///
+    /// `gain_range` are the camera's limits. ZWO gain is in steps of 0.1 dB, so 200
+    /// steps are ten times the brightness.
+    pub fn next(
+        &mut self,
+        frame: &[u8],
+        img_type: IMG_TYPE,
+        exposure: f64,
+        gain: i64,
+        gain_range: (i64, i64),
+    ) -> Option<(f64, i64)> {
+        self.exposure = Some(exposure);
+        let hist = DepthHistogram::new(frame, img_type);
+        let white = hist.stats().white as f64;
+        let level = match self.config.metering {
+            Metering::Median => hist.percentile(0.5),
+            Metering::Percentile(fraction) => hist.percentile(fraction),
+        } as f64;
+        let target = self.config.target * white;
+        if (level - target).abs() <= self.config.tolerance * target {
+            return None;
+        }
+        let mut factor = match level >= white {
+            true => CLIPPED_STEP,
+            false => (target / level.max(1.)).clamp(1. / MAX_STEP, MAX_STEP),
+        };
+
+        let min_gain = self.config.min_gain.max(gain_range.0);
+        let max_gain = self
+            .config
+            .max_gain
+            .map_or(gain_range.1, |max| max.min(gain_range.1))
+            .max(min_gain);
+        let gain_by = |gain: i64, factor: f64| {
+            (gain as f64 + 200. * factor.log10())
+                .round()
+                .clamp(min_gain as f64, max_gain as f64) as i64
+        };
+        let gain_factor = |from: i64, to: i64| 10f64.powf((to - from) as f64 / 200.);
+        // A gain outside of the limits is brought inside first
+        let start_gain = gain.clamp(min_gain, max_gain);
+        factor /= gain_factor(gain, start_gain);
+        let (next_exposure, next_gain) = if factor > 1. {
+            // Brighter with a longer exposure first, as gain adds noise
+            let next_exposure = (exposure * factor).min(self.config.max_exposure);
+            (
+                next_exposure,
+                gain_by(start_gain, factor * exposure / next_exposure),
+            )
+        } else {
+            let next_gain = gain_by(start_gain, factor);
+            let factor = factor / gain_factor(start_gain, next_gain);
+            (exposure * factor, next_gain)
+        };
+        let next_exposure = next_exposure.clamp(self.config.min_exposure, self.config.max_exposure);
+        if next_exposure == exposure && next_gain == gain {
+            return None;
+        }
+        self.exposure = Some(next_exposure);
+        Some((next_exposure, next_gain))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAIN_RANGE: (i64, i64) = (0, 500);
+
+    fn auto() -> AutoExposure {
+        AutoExposure::new(AutoExposureConfig::default()).unwrap()
+    }
+
+    /// A RAW8 frame with every pixel at `level`.
+    fn frame(level: u8) -> Vec<u8> {
+        vec![level; 64]
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() <= expected * 1e-3,
+            "{actual} is not {expected}"
+        );
+    }
+
+    #[test]
+    fn leaves_frames_on_target_alone() {
+        assert_eq!(
+            auto().next(&frame(102), IMG_TYPE::RAW8, 0.1, 0, GAIN_RANGE),
+            None
+        );
+        // Within the tolerance
+        assert_eq!(
+            auto().next(&frame(110), IMG_TYPE::RAW8, 0.1, 0, GAIN_RANGE),
+            None
+        );
+    }
+
+    #[test]
+    fn lengthens_exposure_before_raising_gain() {
+        let (exposure, gain) = auto()
+            .next(&frame(51), IMG_TYPE::RAW8, 0.1, 0, GAIN_RANGE)
+            .unwrap();
+        assert_close(exposure, 0.2);
+        assert_eq!(gain, 0);
+
+        // Already at the longest exposure, so twice as bright takes 6 dB more gain
+        let (exposure, gain) = auto()
+            .next(&frame(51), IMG_TYPE::RAW8, 1., 0, GAIN_RANGE)
+            .unwrap();
+        assert_close(exposure, 1.);
+        assert_eq!(gain, 60);
+    }
+
+    #[test]
+    fn lowers_gain_before_shortening_exposure() {
+        let (exposure, gain) = auto()
+            .next(&frame(204), IMG_TYPE::RAW8, 0.1, 100, GAIN_RANGE)
+            .unwrap();
+        assert_eq!(gain, 40);
+        assert_close(exposure, 0.1 * 0.5 / 10f64.powf(-0.3));
+
+        // With no gain left to take off, only the exposure changes
+        let (exposure, gain) = auto()
+            .next(&frame(204), IMG_TYPE::RAW8, 0.1, 0, GAIN_RANGE)
+            .unwrap();
+        assert_eq!(gain, 0);
+        assert_close(exposure, 0.05);
+    }
+
+    #[test]
+    fn limits_each_step() {
+        let (exposure, _) = auto()
+            .next(&frame(1), IMG_TYPE::RAW8, 0.01, 0, GAIN_RANGE)
+            .unwrap();
+        assert_close(exposure, 0.01 * MAX_STEP);
+
+        let (exposure, _) = auto()
+            .next(&frame(255), IMG_TYPE::RAW8, 0.1, 0, GAIN_RANGE)
+            .unwrap();
+        assert_close(exposure, 0.1 * CLIPPED_STEP);
+    }
+
+    #[test]
+    fn stops_at_the_limits() {
+        let config = AutoExposureConfig {
+            max_gain: Some(100),
+            ..Default::default()
+        };
+        let mut auto = AutoExposure::new(config).unwrap();
+        assert_eq!(
+            auto.next(&frame(1), IMG_TYPE::RAW8, 1., 100, GAIN_RANGE),
+            None
+        );
+        assert_eq!(
+            auto.next(&frame(255), IMG_TYPE::RAW8, 0.0001, 0, GAIN_RANGE),
+            None
+        );
+    }
+
+    #[test]
+    fn next_run_starts_where_the_loop_left_off() {
+        let mut auto = auto();
+        assert_eq!(auto.start_exposure(2.), 2.);
+        let (exposure, _) = auto
+            .next(&frame(51), IMG_TYPE::RAW8, 0.1, 0, GAIN_RANGE)
+            .unwrap();
+        assert_eq!(auto.start_exposure(2.), exposure);
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        for config in [
+            AutoExposureConfig {
+                target: 1.5,
+                ..Default::default()
+            },
+            AutoExposureConfig {
+                target: f64::NAN,
+                ..Default::default()
+            },
+            AutoExposureConfig {
+                min_exposure: 2.,
+                ..Default::default()
+            },
+            AutoExposureConfig {
+                metering: Metering::Percentile(99.9),
+                ..Default::default()
+            },
+        ] {
+            assert!(AutoExposure::new(config).is_err());
+        }
+    }
+}
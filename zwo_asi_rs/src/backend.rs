@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use crate::asi::{
+    self, CameraInfo, ControlCaps, FrameGps, GpsData, ROIFormat, TriggerOutputConf, ASI_ERROR,
+    CAMERA_MODE, CONTROL_TYPE, EXPOSURE_STATUS, GUIDE_DIRECTION, TRIG_OUTPUT,
+};
+
+/// Everything the controller needs from a camera.
+///
+/// The ZWO SDK ([`crate::OpenCamera`]) is one implementation, the
+/// [`crate::simulator::SimulatedCamera`] is another, so the controller and server can
+/// run without any hardware attached. Implementations are shared between the
+/// controller and the video streaming thread, so every method takes `&self`.
+pub trait CameraBackend: Send + Sync {
+    /// The `_ASI_CAMERA_INFO` describing this camera.
+    fn info(&self) -> asi::ASI_CAMERA_INFO;
+
+    fn name(&self) -> String;
+
+    /// The serial number, unique to every camera.
+    fn serial_number(&self) -> Result<String, ASI_ERROR>;
+
+    /// The ID stored in the camera's flash, which survives replugging and restarts.
+    /// Only USB3 cameras have one.
+    fn get_custom_id(&self) -> Result<String, ASI_ERROR>;
+
+    /// Stores `id`, at most 8 bytes, in the camera's flash.
+    fn set_custom_id(&self, id: &str) -> Result<(), ASI_ERROR>;
+
+    /// [`CameraBackend::info`] along with the serial number and custom ID, which are
+    /// left out when the camera can't report them.
+    fn camera_info(&self) -> CameraInfo {
+        CameraInfo {
+            serial: self.serial_number().ok(),
+            custom_id: self.get_custom_id().ok(),
+            ..CameraInfo::from(&self.info())
+        }
+    }
+
+    fn init(&self) -> Result<(), ASI_ERROR>;
+
+    /// Every control this camera supports along with its range and defaults.
+    fn controls(&self) -> Result<Vec<ControlCaps>, ASI_ERROR>;
+
+    fn set_control_value(
+        &self,
+        control_type: CONTROL_TYPE,
+        value: i64,
+        auto: bool,
+    ) -> Result<(), ASI_ERROR>;
+
+    fn get_control_value(&self, control_type: CONTROL_TYPE) -> Result<(i64, bool), ASI_ERROR>;
+
+    fn set_roi_format(
+        &self,
+        width: i32,
+        height: i32,
+        bin: i32,
+        img_type: asi::IMG_TYPE,
+    ) -> Result<(), ASI_ERROR>;
+
+    fn get_roi_format(&self) -> Result<ROIFormat, ASI_ERROR>;
+
+    /// Moves the ROI, in binned pixels. Unlike the format this can change while video
+    /// is running.
+    fn set_start_pos(&self, x: i32, y: i32) -> Result<(), ASI_ERROR>;
+
+    fn get_start_pos(&self) -> Result<(i32, i32), ASI_ERROR>;
+
+    /// Starts a single exposure with the current `EXPOSURE` value. `is_dark` closes the
+    /// mechanical shutter on cameras that have one and is ignored otherwise.
+    fn start_exposure(&self, is_dark: bool) -> Result<(), ASI_ERROR>;
+
+    /// Cancels the exposure started by [`CameraBackend::start_exposure`].
+    fn stop_exposure(&self) -> Result<(), ASI_ERROR>;
+
+    fn get_exp_status(&self) -> Result<EXPOSURE_STATUS, ASI_ERROR>;
+
+    fn get_data_after_exp(&self, data: &mut [u8]) -> Result<(), ASI_ERROR>;
+
+    fn start_video_capture(&self) -> Result<(), ASI_ERROR>;
+
+    fn stop_video_capture(&self) -> Result<(), ASI_ERROR>;
+
+    fn get_dropped_frames(&self) -> Result<i32, ASI_ERROR>;
+
+    fn get_video_data(&self, data: &mut [u8], wait_ms: i32) -> Result<(), ASI_ERROR>;
+
+    /// [`CameraBackend::get_video_data`] along with the GPS fix at the frame's
+    /// `GPS_START_LINE`. Fails with `GPS_NOT_SUPPORTED` on cameras without GPS.
+    fn get_video_data_gps(&self, data: &mut [u8], wait_ms: i32) -> Result<GpsData, ASI_ERROR>;
+
+    /// [`CameraBackend::get_data_after_exp`] along with the GPS fix at the frame's
+    /// `GPS_START_LINE`.
+    fn get_data_after_exp_gps(&self, data: &mut [u8]) -> Result<GpsData, ASI_ERROR>;
+
+    /// The GPS fixes at the start and end lines of the last frame read.
+    fn get_gps_data(&self) -> Result<FrameGps, ASI_ERROR>;
+
+    /// The trigger modes this camera can be put in. Always contains
+    /// [`CAMERA_MODE::NORMAL`].
+    fn supported_camera_modes(&self) -> Result<Vec<CAMERA_MODE>, ASI_ERROR>;
+
+    fn get_camera_mode(&self) -> Result<CAMERA_MODE, ASI_ERROR>;
+
+    /// Switches between free running video and triggered frames. Video capture must
+    /// be stopped first.
+    fn set_camera_mode(&self, mode: CAMERA_MODE) -> Result<(), ASI_ERROR>;
+
+    /// In the soft edge mode `start` begins an exposure of the current `EXPOSURE`
+    /// length. In the soft level mode the exposure lasts from a `true` to a `false`.
+    /// Triggered frames are read with [`CameraBackend::get_video_data`].
+    fn send_soft_trigger(&self, start: bool) -> Result<(), ASI_ERROR>;
+
+    fn set_trigger_output(
+        &self,
+        pin: TRIG_OUTPUT,
+        conf: TriggerOutputConf,
+    ) -> Result<(), ASI_ERROR>;
+
+    fn get_trigger_output(&self, pin: TRIG_OUTPUT) -> Result<TriggerOutputConf, ASI_ERROR>;
+
+    /// Guides through the ST4 port in `direction` for `duration`, without blocking.
+    ///
+    /// A new pulse in the same direction replaces the running one, and starting one
+    /// in the opposite direction ends it. Fails on cameras without an ST4 port.
+    fn pulse_guide(&self, direction: GUIDE_DIRECTION, duration: Duration) -> Result<(), ASI_ERROR>;
+
+    /// Ends a pulse started by [`CameraBackend::pulse_guide`] early.
+    fn stop_pulse_guide(&self, direction: GUIDE_DIRECTION) -> Result<(), ASI_ERROR>;
+
+    fn is_pulse_guiding(&self, direction: GUIDE_DIRECTION) -> bool;
+}
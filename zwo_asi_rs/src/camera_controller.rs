@@ -1,15 +1,18 @@
 use std::{
+    borrow::Cow,
     ops::Deref,
-    sync::{atomic::AtomicBool, Arc, Mutex},
+    path::PathBuf,
+    str::FromStr,
+    sync::{atomic::AtomicBool, Arc, Mutex, MutexGuard},
     thread::{sleep, JoinHandle},
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use opencv::{
-    core::{Mat, MatTraitManual, Scalar, CV_8UC3},
+    core::{Mat, MatTraitManual, Scalar, CV_16UC1, CV_16UC3, CV_8UC1, CV_8UC3},
     imgcodecs,
 };
-use serde::Serialize;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_repr::Serialize_repr;
 
 use anyhow::{anyhow, Context, Result};
@@ -18,36 +21,297 @@ use image::{ImageBuffer, Luma, Rgb, RgbImage};
 use imageproc::stats::ChannelHistogram;
 
 use plotters::prelude::*;
-use tokio::sync::broadcast::{self, Receiver, Sender};
-use tracing::{error, info};
+use tokio::sync::{
+    broadcast::{self, Receiver, Sender},
+    watch,
+};
+use tracing::{error, info, warn};
 
 use crate::{
-    asi::{self, ASI_ERROR},
-    Camera, OpenCamera,
+    asi::{self, ControlCaps, FrameGps, GpsData, ROIFormat, ASI_ERROR},
+    auto_exposure::{AutoExposure, AutoExposureConfig},
+    cooler::{Cooler, CoolerConfig, CoolerStatus},
+    debayer::{self, CfaPattern, DebayerAlgorithm},
+    fits::{self, FitsMetadata},
+    frame::{self, DepthHistogram, FrameStats},
+    guide,
+    preview::{LatestPreview, PreviewSettings, PreviewStats},
+    protocol::{ErrorCode, ProtocolError, Reply, Response},
+    ser::{SerInfo, SerWriter},
+    CameraBackend,
 };
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+/// Commands for the [`CameraController`]. Clients send these as described in
+/// [`crate::protocol`], tagged with the variant name.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "cmd", content = "args")]
 pub enum ControlMessages {
     StartPreview,
     StopPreview,
+    /// Setting gain or exposure by hand turns auto exposure off.
     SetGain(i32),
     SetExposure(f32),
+    /// Lets the camera pick exposure and gain in video mode, and the controller between
+    /// single exposures, see [`crate::auto_exposure`]. `None` turns it off, leaving
+    /// them where they are.
+    SetAutoExposure(Option<AutoExposureConfig>),
     SetWbR(i32),
     SetWbB(i32),
+    /// The black level the camera adds to every pixel.
+    SetOffset(i32),
     SwitchOutput,
     StartCapture(i32),
+    /// Full sensor at the given bin.
+    SetBin(i32),
+    SetImageType(asi::IMG_TYPE),
+    /// Whether RAW16 previews are stretched to 8 bits or sent at full depth. Clients
+    /// with a display stretch of their own, see [`crate::stretch`], get more out of
+    /// full depth previews.
+    SetPreviewStretch(bool),
+    /// How RAW8 and RAW16 frames from colour cameras are debayered for previews and
+    /// histograms, see [`crate::debayer`]. `None` sends previews as the bayer mosaic,
+    /// with its pattern for the client to debayer.
+    SetDebayer(Option<DebayerAlgorithm>),
+    /// Debayers TIFF and FITS files of colour cameras to three channels. `None`, the
+    /// default, saves the bayer mosaic. SER captures always hold the mosaic, which
+    /// stacking software debayers itself.
+    SetColorFiles(Option<DebayerAlgorithm>),
+    /// How previews are sent to the client asking, handled by its connection rather
+    /// than the controller, see [`crate::preview`].
+    SetPreview(PreviewSettings),
+    /// Sub-frame position and size, in binned pixels.
+    SetRoi {
+        x: i32,
+        y: i32,
+        w: i32,
+        h: i32,
+        bin: i32,
+    },
+    SetCaptureFormat(CaptureFormat),
+    /// Recorded in the header of SER captures.
+    SetObserver(String),
+    SetTelescope(String),
+    /// Takes `count` single exposures of `seconds` each, stopping preview while they
+    /// run. Frames are saved as FITS, or TIFF when that is the capture format.
+    StartExposure {
+        seconds: f64,
+        #[serde(default = "one")]
+        count: i32,
+        #[serde(default)]
+        dark: bool,
+        /// Off for clients that download the frame with `GetExposure` instead.
+        #[serde(default = "yes")]
+        save: bool,
+        /// Takes the exposures in this format rather than the current one, which is
+        /// put back once they are done. For front ends like Alpaca and INDI, whose
+        /// clients pick the frame of each exposure.
+        #[serde(skip)]
+        format: Option<ROIFormat>,
+    },
+    AbortExposure,
+    /// Switches between free running video and one of the camera's trigger modes.
+    /// In a trigger mode `StartCapture` saves each triggered frame as it arrives.
+    SetCameraMode(asi::CAMERA_MODE),
+    /// Sends a soft trigger, see [`CameraBackend::send_soft_trigger`].
+    SoftTrigger(bool),
+    SetTriggerOutput {
+        pin: asi::TRIG_OUTPUT,
+        conf: asi::TriggerOutputConf,
+    },
+    /// Ends a capture before all of its frames have arrived.
+    StopCapture,
+    /// Sends a guide pulse through the camera's ST4 port. The server starts it right
+    /// away with [`crate::server::CameraSlot::pulse_guide`] instead of queueing it.
+    PulseGuide {
+        direction: asi::GUIDE_DIRECTION,
+        #[serde(rename = "duration_ms", deserialize_with = "duration_from_ms")]
+        duration: Duration,
+    },
+    /// Ends any running guide pulses.
+    StopGuiding,
+    /// Turns the cooler on and ramps to the given °C, see [`crate::cooler`].
+    SetTargetTemperature(f64),
+    /// Ramps the cooler back up to where it started, then turns it off.
+    WarmUp,
+    SetCoolerConfig(CoolerConfig),
+    SetFan(bool),
+    SetAntiDewHeater(bool),
+    /// Rows the GPS start and end times are latched at, on cameras with GPS.
+    SetGpsLines {
+        start: i32,
+        end: i32,
+    },
+    /// Answered with a [`CameraStatus`].
+    GetStatus,
+    /// Answered with the latest [`RawFrame`], from preview or the last exposure.
+    GetFrame,
+    /// Answered with the [`RawFrame`] of the last finished exposure.
+    GetExposure,
+    /// Stops video and any exposure, then returns from [`CameraController::run`].
+    #[serde(skip)]
+    Shutdown,
+}
+
+fn one() -> i32 {
+    1
+}
+
+fn yes() -> bool {
+    true
+}
+
+fn duration_from_ms<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+/// A command for the controller, and who to answer once it ran.
+#[derive(Clone, Debug)]
+pub struct ControlRequest {
+    pub command: ControlMessages,
+    /// Client and request ID of commands sent through the [`crate::protocol`], which
+    /// get a [`Response`] back.
+    pub reply_to: Option<(u64, u64)>,
+    /// When the client stops waiting for the answer. The command is dropped after
+    /// that rather than run late.
+    pub expires: Option<Instant>,
+}
+
+impl From<ControlMessages> for ControlRequest {
+    fn from(command: ControlMessages) -> Self {
+        Self {
+            command,
+            reply_to: None,
+            expires: None,
+        }
+    }
+}
+
+impl ControlMessages {
+    /// Whether this changes a camera setting, which should be applied again when the
+    /// camera reconnects.
+    pub fn is_setting(&self) -> bool {
+        use ControlMessages::*;
+        matches!(
+            self,
+            SetGain(_)
+                | SetExposure(_)
+                | SetAutoExposure(_)
+                | SetWbR(_)
+                | SetWbB(_)
+                | SetOffset(_)
+                | SetBin(_)
+                | SetImageType(_)
+                | SetPreviewStretch(_)
+                | SetDebayer(_)
+                | SetColorFiles(_)
+                | SetRoi { .. }
+                | SetCaptureFormat(_)
+                | SetObserver(_)
+                | SetTelescope(_)
+                | SetCameraMode(_)
+                | SetTriggerOutput { .. }
+                | SetGpsLines { .. }
+                | SetTargetTemperature(_)
+                | WarmUp
+                | SetCoolerConfig(_)
+                | SetFan(_)
+                | SetAntiDewHeater(_)
+        )
+    }
+
+    /// Whether this setting makes an earlier `other` pointless to apply again.
+    pub fn replaces(&self, other: &Self) -> bool {
+        use ControlMessages::*;
+        match (self, other) {
+            (SetTriggerOutput { pin, .. }, SetTriggerOutput { pin: other_pin, .. }) => {
+                pin == other_pin
+            }
+            // A ROI has its own bin, and a new bin resets the ROI to the full sensor
+            (SetBin(_) | SetRoi { .. }, SetBin(_) | SetRoi { .. }) => true,
+            (SetTargetTemperature(_) | WarmUp, SetTargetTemperature(_) | WarmUp) => true,
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+
+/// Whether `e` was caused by the camera being unplugged.
+pub fn is_camera_removed(e: &anyhow::Error) -> bool {
+    e.chain()
+        .any(|cause| cause.downcast_ref::<ASI_ERROR>() == Some(&ASI_ERROR::CAMERA_REMOVED))
+}
+
+/// File format frames are saved in by `StartCapture`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureFormat {
+    /// One TIFF per frame, without metadata.
+    Tiff,
+    /// One FITS per frame, with the acquisition details in its header.
+    Fits,
+    /// A single SER video, for planetary stacking software.
+    Ser,
+}
+
+impl FromStr for CaptureFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tiff" | "tif" => Ok(Self::Tiff),
+            "fits" | "fit" => Ok(Self::Fits),
+            "ser" => Ok(Self::Ser),
+            _ => Err(anyhow!("Unknown capture format {s}")),
+        }
+    }
+}
+
+/// Where a capture is writing frames to.
+enum CaptureOutput {
+    Tiff,
+    Fits(FitsMetadata),
+    Ser(SerWriter),
+}
+
+/// Writes a frame read with `roi`, or one debayered from it to BGR.
+fn write_tiff(file_name: &str, frame: &[u8], roi: &ROIFormat, debayered: bool) -> Result<()> {
+    let typ = match (roi.img_type, debayered) {
+        (asi::IMG_TYPE::RAW8 | asi::IMG_TYPE::Y8, false) => CV_8UC1,
+        (asi::IMG_TYPE::RGB24, _) | (asi::IMG_TYPE::RAW8 | asi::IMG_TYPE::Y8, true) => CV_8UC3,
+        (asi::IMG_TYPE::RAW16, false) => CV_16UC1,
+        (asi::IMG_TYPE::RAW16, true) => CV_16UC3,
+    };
+    let mut mat = Mat::new_rows_cols_with_default(roi.height, roi.width, typ, Scalar::all(0.))?;
+    let data = mat.data_bytes_mut()?;
+    if data.len() != frame.len() {
+        return Err(anyhow!(
+            "Frame has {} bytes, a {}x{} TIFF of it needs {}",
+            frame.len(),
+            roi.width,
+            roi.height,
+            data.len()
+        ));
+    }
+    data.copy_from_slice(frame);
+
+    let mut params = opencv::core::Vector::<i32>::new();
+    params.push(opencv::imgcodecs::IMWRITE_TIFF_COMPRESSION);
+    params.push(1);
+    imgcodecs::imwrite(file_name, &mat, &params)?;
+    Ok(())
 }
 
+/// 256 bin histogram of each channel. 16 bit images are bucketed by their high byte.
 pub fn histogram<P, Container>(image: &ImageBuffer<P, Container>) -> ChannelHistogram
 where
-    P: image::Pixel<Subpixel = u8>,
+    P: image::Pixel,
+    P::Subpixel: Into<u32>,
     Container: Deref<Target = [P::Subpixel]>,
 {
+    let shift = 8 * (std::mem::size_of::<P::Subpixel>() - 1);
     let mut hist = vec![[0u32; 256]; P::CHANNEL_COUNT as usize];
 
     for pix in image.pixels() {
         for (i, c) in pix.channels().iter().enumerate() {
-            hist[i][*c as usize] += 1;
+            hist[i][((*c).into() >> shift) as usize] += 1;
         }
     }
 
@@ -65,7 +329,7 @@ fn make_hist_plot(hist: &ChannelHistogram) -> RgbImage {
         let max_value = hist
             .channels
             .iter()
-            .filter_map(|v| (v.iter().max().copied()))
+            .filter_map(|v| v.iter().max().copied())
             .max()
             .unwrap_or(1);
 
@@ -108,8 +372,53 @@ fn make_hist_plot(hist: &ChannelHistogram) -> RgbImage {
 #[derive(Clone, Debug, Serialize)]
 #[serde(tag = "type")]
 pub enum ClientPacket {
-    Preview(ImagePacket),
+    /// Sent by each connection from the camera's [`LatestPreview`].
+    Preview(Arc<ImagePacket>),
+    PreviewStats(PreviewStats),
     CaptureStatus(CaptureStatus),
+    ControlCaps(ControlCapsPacket),
+    ExposureStatus(ExposureStatus),
+    Connection(ConnectionEvent),
+    CoolerStatus(CoolerStatus),
+    Response(Response),
+}
+
+/// Sent to every client when a camera is plugged in or removed.
+#[derive(Clone, Debug, Serialize)]
+pub struct ConnectionEvent {
+    pub camera_id: i32,
+    pub name: String,
+    pub serial: Option<String>,
+    pub connected: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum ExposureState {
+    Exposing,
+    /// The camera reported a failed exposure and it is being taken again.
+    Retrying,
+    /// The frame was downloaded and saved.
+    Complete,
+    Failed,
+    Aborted,
+}
+
+/// Progress of the running exposure, sent about once a second and on every change of
+/// state. Times are in seconds.
+#[derive(Clone, Debug, Serialize)]
+pub struct ExposureStatus {
+    pub state: ExposureState,
+    pub frame: i32,
+    pub total_frames: i32,
+    pub dark: bool,
+    pub duration: f64,
+    pub elapsed: f64,
+    pub remaining: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ControlCapsPacket {
+    pub controls: Vec<ControlCaps>,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -124,6 +433,48 @@ pub enum PixelOrder {
     BGR = 0,
     RGB = 1,
     RAW8 = 2,
+    /// Little endian 16 bit mono or bayer data.
+    RAW16 = 3,
+    /// Encoded images, see [`crate::preview`].
+    JPEG = 4,
+    WEBP = 5,
+}
+
+/// Converts a frame read with `roi` into something the preview can display.
+///
+/// RAW16 frames are stretched to 8 bits between the 0.1% and 99.9% levels unless
+/// `stretch` is off, in which case the full depth data is sent as is. Colour frames
+/// are debayered with `debayer`, except unstretched RAW16 ones, which stay a mosaic
+/// as previews have no 16 bit colour format.
+fn preview_pixels(
+    frame: &[u8],
+    roi: &ROIFormat,
+    hist: &DepthHistogram,
+    stretch: bool,
+    debayer: Option<(CfaPattern, DebayerAlgorithm)>,
+) -> (PixelOrder, Vec<u8>) {
+    let (w, h) = (roi.width as usize, roi.height as usize);
+    let (black, white) = (hist.percentile(0.001), hist.percentile(0.999));
+    match (roi.img_type, debayer) {
+        (asi::IMG_TYPE::RAW8, Some((pattern, algorithm))) => (
+            PixelOrder::BGR,
+            debayer::debayer_u8(frame, w, h, pattern, algorithm),
+        ),
+        (asi::IMG_TYPE::RAW8 | asi::IMG_TYPE::Y8, _) => (PixelOrder::RAW8, frame.to_vec()),
+        (asi::IMG_TYPE::RGB24, _) => (PixelOrder::BGR, frame.to_vec()),
+        (asi::IMG_TYPE::RAW16, Some((pattern, algorithm))) if stretch => {
+            let bgr = debayer::debayer_frame(frame, roi, pattern, algorithm);
+            (
+                PixelOrder::BGR,
+                frame::stretch_u16_to_u8(&bgr, black, white),
+            )
+        }
+        (asi::IMG_TYPE::RAW16, _) if stretch => (
+            PixelOrder::RAW8,
+            frame::stretch_u16_to_u8(frame, black, white),
+        ),
+        (asi::IMG_TYPE::RAW16, _) => (PixelOrder::RAW16, frame.to_vec()),
+    }
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -133,7 +484,12 @@ pub struct ImagePacket {
     pub pix: PixelOrder,
     #[serde(with = "serde_bytes")]
     pub img: Vec<u8>,
+    /// Bayer pattern at the top left of RAW8 and RAW16 previews from colour cameras
+    /// that weren't debayered, e.g. `"RGGB"`.
+    pub bayer: Option<&'static str>,
     pub controls: ControlValues,
+    /// Statistics of the frame at the camera's full bit depth.
+    pub stats: FrameStats,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -144,49 +500,304 @@ pub struct ControlValues {
     pub wb_b: i64,
 }
 
+/// Sub-frame position and size in binned pixels, as taken by `SetRoi`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Roi {
+    pub x: i32,
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+    pub bin: i32,
+}
+
+impl From<&ROIFormat> for Roi {
+    fn from(roi: &ROIFormat) -> Self {
+        Self {
+            x: roi.start_x,
+            y: roi.start_y,
+            w: roi.width,
+            h: roi.height,
+            bin: roi.bin,
+        }
+    }
+}
+
+/// What the controller is busy with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum ControllerState {
+    Stopped,
+    Preview,
+    Capture,
+    Exposure,
+    Triggered,
+}
+
+/// The controller's settings and progress, the answer to `GetStatus`.
+#[derive(Clone, Debug, Serialize)]
+pub struct CameraStatus {
+    pub state: ControllerState,
+    pub controls: ControlValues,
+    pub roi: Roi,
+    pub img_type: asi::IMG_TYPE,
+    pub capture_format: CaptureFormat,
+    pub camera_mode: asi::CAMERA_MODE,
+    pub stretch_preview: bool,
+    pub debayer: Option<DebayerAlgorithm>,
+    pub color_files: Option<DebayerAlgorithm>,
+    /// Progress of the running exposure.
+    pub exposure: Option<ExposureStatus>,
+    /// How the last exposure to end did, as `Complete`, `Failed` or `Aborted`.
+    pub last_exposure: Option<ExposureStatus>,
+    /// Progress of the running triggered capture.
+    pub capture: Option<CaptureStatus>,
+    /// `None` for cameras without a temperature sensor.
+    pub cooler: Option<CoolerStatus>,
+    pub auto_exposure: Option<AutoExposureConfig>,
+}
+
+impl CameraStatus {
+    /// Whether a capture or exposure is waiting for the cooler before it starts.
+    pub fn capture_waiting(&self) -> bool {
+        self.cooler
+            .as_ref()
+            .is_some_and(|cooler| cooler.capture_waiting)
+    }
+}
+
+/// A full depth frame as the camera sent it, the answer to `GetFrame`.
+#[derive(Clone, Debug, Serialize)]
+pub struct RawFrame {
+    pub w: u32,
+    pub h: u32,
+    pub img_type: asi::IMG_TYPE,
+    #[serde(with = "serde_bytes")]
+    pub img: Vec<u8>,
+    /// Header for saving the frame as FITS.
+    #[serde(skip)]
+    pub meta: FitsMetadata,
+}
+
+/// Formats a [`RawFrame`] can be downloaded in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameFormat {
+    /// 8 or 16 bits, as deep as the frame.
+    Png,
+    /// Stretched to 8 bits and debayered like the preview.
+    Jpeg,
+    Fits,
+}
+
+impl FrameFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Fits => "application/fits",
+        }
+    }
+}
+
+impl RawFrame {
+    pub fn encode(&self, format: FrameFormat) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let image_format = match format {
+            FrameFormat::Fits => {
+                fits::write_fits_to(&mut out, &self.img, &self.meta)?;
+                return Ok(out);
+            }
+            FrameFormat::Png => image::ImageFormat::Png,
+            FrameFormat::Jpeg => image::ImageFormat::Jpeg,
+        };
+        let image = match (self.img_type, format) {
+            (asi::IMG_TYPE::RAW16, FrameFormat::Png) => {
+                let pixels: Vec<u16> = frame::pixels_u16(&self.img).collect();
+                ImageBuffer::<Luma<u16>, _>::from_raw(self.w, self.h, pixels)
+                    .map(image::DynamicImage::from)
+            }
+            _ => {
+                let hist = DepthHistogram::new(&self.img, self.img_type);
+                let debayer = match format {
+                    FrameFormat::Jpeg => self.meta.cfa.zip(Some(DebayerAlgorithm::default())),
+                    FrameFormat::Png | FrameFormat::Fits => None,
+                };
+                match preview_pixels(&self.img, &self.meta.roi, &hist, true, debayer) {
+                    (PixelOrder::BGR, bgr) => {
+                        let rgb = bgr
+                            .chunks_exact(3)
+                            .flat_map(|pix| [pix[2], pix[1], pix[0]])
+                            .collect();
+                        RgbImage::from_raw(self.w, self.h, rgb).map(image::DynamicImage::from)
+                    }
+                    (_, mono) => ImageBuffer::<Luma<u8>, _>::from_raw(self.w, self.h, mono)
+                        .map(image::DynamicImage::from),
+                }
+            }
+        }
+        .ok_or(anyhow!("ImageBuffer::from_raw failed!"))?;
+        image.write_to(&mut std::io::Cursor::new(&mut out), image_format)?;
+        Ok(out)
+    }
+}
+
 enum CamState {
     Stopped,
     Preview { show_hist: bool },
     Capture { total_frames: i32 },
+    Exposure(ExposureRun),
+    Triggered(TriggerRun),
+}
+
+/// How long the preview waits for a frame before checking for commands again.
+const FRAME_WAIT: Duration = Duration::from_millis(50);
+
+/// How often exposure progress is sent to clients.
+const EXPOSURE_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// How many times a frame is retried after the camera reports `EXP_FAILED`.
+const MAX_EXPOSURE_RETRIES: u32 = 3;
+
+/// A run of single exposures, started by [`ControlMessages::StartExposure`].
+struct ExposureRun {
+    duration: Duration,
+    dark: bool,
+    /// 1 based number of the frame being exposed.
+    frame: i32,
+    total_frames: i32,
+    started: Instant,
+    started_at: SystemTime,
+    last_report: Instant,
+    retries: u32,
+    /// Output file names start with this, `None` when frames aren't saved.
+    file_prefix: Option<String>,
+    /// The `EXPOSURE` value to put back for preview once the run is over.
+    preview_exposure: i64,
+    /// The format to put back once the run is over, if it was taken in its own.
+    restore_format: Option<ROIFormat>,
+    resume_preview: bool,
+}
+
+impl ExposureRun {
+    fn progress(&self, state: ExposureState) -> ExposureStatus {
+        let elapsed = self.started.elapsed().min(self.duration);
+        ExposureStatus {
+            state,
+            frame: self.frame,
+            total_frames: self.total_frames,
+            dark: self.dark,
+            duration: self.duration.as_secs_f64(),
+            elapsed: elapsed.as_secs_f64(),
+            remaining: (self.duration - elapsed).as_secs_f64(),
+        }
+    }
+}
+
+/// A capture in one of the trigger modes, started by [`ControlMessages::StartCapture`].
+///
+/// Video keeps running and frames are saved as triggers produce them, so commands,
+/// soft triggers included, are handled between frames.
+struct TriggerRun {
+    captured_frames: i32,
+    total_frames: i32,
+    file_prefix: String,
+    output: CaptureOutput,
+}
+
+/// Reads the next video frame, along with its GPS times if `gps` is set. `None` when
+/// the camera had no GPS fix.
+fn read_video_frame_gps(
+    ccd: &dyn CameraBackend,
+    gps: bool,
+    frame: &mut [u8],
+    wait_ms: i32,
+) -> Result<Option<FrameGps>, ASI_ERROR> {
+    if !gps {
+        return ccd.get_video_data(frame, wait_ms).map(|_| None);
+    }
+    match ccd.get_video_data_gps(frame, wait_ms) {
+        Ok(start) => frame_gps(ccd, start),
+        Err(ASI_ERROR::GPS_DATA_INVALID) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The GPS times of the frame just read, whose start came with it. The end line's fix
+/// only comes from `get_gps_data`, so it is asked for before anything else is read.
+fn frame_gps(ccd: &dyn CameraBackend, start: GpsData) -> Result<Option<FrameGps>, ASI_ERROR> {
+    match ccd.get_gps_data() {
+        Ok(gps) => Ok(Some(FrameGps {
+            start,
+            end: gps.end,
+        })),
+        Err(ASI_ERROR::GPS_DATA_INVALID) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// A frame read by the [`VideoStreamer`], with the GPS times it came with.
+#[derive(Default)]
+struct StreamedFrame {
+    data: Vec<u8>,
+    gps: Option<FrameGps>,
 }
 
-struct VideoStreamer<'a> {
-    ccd: OpenCamera<'a>,
-    latest_frame: Arc<Mutex<Vec<u8>>>,
-    current_frame: Vec<u8>,
+struct VideoStreamer {
+    ccd: Arc<dyn CameraBackend>,
+    /// The camera has GPS, so frames are read along with their GPS times.
+    gps: bool,
+    latest_frame: Arc<Mutex<StreamedFrame>>,
+    current_frame: StreamedFrame,
     stop_msg: Receiver<bool>,
     frame_available: Arc<AtomicBool>,
 }
 
-impl<'a> VideoStreamer<'a> {
+impl VideoStreamer {
     pub fn new(
-        ccd: OpenCamera<'a>,
+        ccd: Arc<dyn CameraBackend>,
+        gps: bool,
         stop_msg: Receiver<bool>,
-        latest_frame: Arc<Mutex<Vec<u8>>>,
+        latest_frame: Arc<Mutex<StreamedFrame>>,
         frame_available: Arc<AtomicBool>,
-    ) -> Result<VideoStreamer<'a>> {
+    ) -> Result<VideoStreamer> {
         let roi_format = ccd.get_roi_format()?;
         let buf_size = roi_format.width * roi_format.height * roi_format.img_type.bytes_per_pixel();
         let buf_one = vec![0; buf_size as usize];
         let buf_two = vec![0; buf_size as usize];
         {
             let mut locked_latest = latest_frame.lock().unwrap();
-            *locked_latest = buf_one;
+            *locked_latest = StreamedFrame {
+                data: buf_one,
+                gps: None,
+            };
         }
 
         Ok(Self {
             ccd,
+            gps,
             latest_frame,
-            current_frame: buf_two,
+            current_frame: StreamedFrame {
+                data: buf_two,
+                gps: None,
+            },
             stop_msg,
             frame_available,
         })
     }
 
+    /// Reads frames until told to stop. Video capture must already be started.
     pub fn run(&mut self) -> Result<()> {
-        self.ccd.start_video_capture()?;
         loop {
-            self.ccd.get_video_data(&mut self.current_frame, 500)?;
+            let frame = &mut self.current_frame;
+            match read_video_frame_gps(self.ccd.as_ref(), self.gps, &mut frame.data, 500) {
+                Ok(gps) => frame.gps = gps,
+                // Normal while waiting for a trigger or a long exposure
+                Err(ASI_ERROR::TIMEOUT) => {
+                    if !self.stop_msg.is_empty() {
+                        break;
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            }
 
             let mut latest_frame = self.latest_frame.lock().unwrap();
             std::mem::swap(&mut self.current_frame, &mut latest_frame);
@@ -205,78 +816,372 @@ impl<'a> VideoStreamer<'a> {
     }
 }
 
-pub struct CameraController<'a> {
-    camera: &'a Camera,
-    ccd: OpenCamera<'a>,
+pub struct CameraController {
+    ccd: Arc<dyn CameraBackend>,
     tx: Sender<ClientPacket>,
-    rx: Receiver<ControlMessages>,
+    rx: Receiver<ControlRequest>,
+    previews: watch::Sender<Option<LatestPreview>>,
     state: CamState,
-    width: u32,
-    height: u32,
+    controls: Vec<ControlCaps>,
+    info: asi::ASI_CAMERA_INFO,
+    roi: ROIFormat,
+    camera_mode: asi::CAMERA_MODE,
+    /// The camera has a GPS receiver, so captured frames get GPS timestamps.
+    gps: bool,
+    stretch_preview: bool,
+    debayer: Option<DebayerAlgorithm>,
+    color_files: Option<DebayerAlgorithm>,
+    capture_format: CaptureFormat,
+    observer: String,
+    telescope: String,
+    /// Where captures are written, `./images` unless set with
+    /// [`CameraController::set_image_dir`].
+    image_dir: PathBuf,
     stop_msg: Option<Sender<bool>>,
-    latest_frame: Arc<Mutex<Vec<u8>>>,
+    latest_frame: Arc<Mutex<StreamedFrame>>,
+    /// The frame of the last finished exposure, for `GetFrame` and `GetExposure`.
+    last_exposure: Option<RawFrame>,
+    /// How the last exposure ended.
+    last_exposure_status: Option<ExposureStatus>,
     frame_available: Arc<AtomicBool>,
+    /// Set by the streamer when the camera stops responding.
+    camera_removed: Arc<AtomicBool>,
     streamer_thread: Option<JoinHandle<()>>,
+    /// `None` for cameras without a temperature sensor.
+    cooler: Option<Cooler>,
+    /// A `StartCapture` or `StartExposure` held back until the temperature settled.
+    waiting_capture: Option<ControlMessages>,
+    /// Set by `StopCapture` during a free running capture.
+    stop_capture: bool,
+    auto_exposure: Option<AutoExposure>,
+    /// Stops [`CameraController::run`] once the cooler is off.
+    shutdown: bool,
 }
 
-impl<'a> CameraController<'a> {
+impl CameraController {
     pub fn new(
-        camera: &'a Camera,
+        ccd: Arc<dyn CameraBackend>,
         tx: Sender<ClientPacket>,
-        rx: Receiver<ControlMessages>,
+        rx: Receiver<ControlRequest>,
+        previews: watch::Sender<Option<LatestPreview>>,
     ) -> Result<Self> {
-        println!("Camera: {}", camera.get_name());
-        let ccd = camera.open()?;
+        info!("Opening {}", ccd.name());
         ccd.init()?;
+        let info = ccd.info();
+        let roi = ccd.get_roi_format()?;
+        let controls = ccd.controls()?;
+        let camera_mode = ccd.get_camera_mode()?;
+        let gps = controls
+            .iter()
+            .any(|caps| caps.control_type == asi::CONTROL_TYPE::GPS_SUPPORT)
+            && ccd.get_control_value(asi::CONTROL_TYPE::GPS_SUPPORT)?.0 != 0;
+        for caps in &controls {
+            info!(
+                "Control {} ({:?}): {}..={}, default {}, auto: {}, writable: {}",
+                caps.name,
+                caps.control_type,
+                caps.min_value,
+                caps.max_value,
+                caps.default_value,
+                caps.is_auto_supported,
+                caps.is_writable
+            );
+        }
+        let cooler = Cooler::new(ccd.as_ref(), &controls)?;
         Ok(Self {
-            camera,
             ccd,
             tx,
             rx,
+            previews,
             state: CamState::Stopped,
-            width: 1920,
-            height: 1080,
+            controls,
+            info,
+            roi,
+            camera_mode,
+            gps,
+            stretch_preview: true,
+            debayer: Some(DebayerAlgorithm::default()),
+            color_files: None,
+            capture_format: CaptureFormat::Tiff,
+            observer: String::new(),
+            telescope: String::new(),
+            image_dir: PathBuf::from("./images"),
             stop_msg: None,
-            latest_frame: Arc::new(Mutex::new(Vec::new())),
+            latest_frame: Arc::new(Mutex::new(StreamedFrame::default())),
+            last_exposure: None,
+            last_exposure_status: None,
             frame_available: Arc::new(AtomicBool::new(false)),
+            camera_removed: Arc::new(AtomicBool::new(false)),
             streamer_thread: None,
+            cooler,
+            waiting_capture: None,
+            stop_capture: false,
+            auto_exposure: None,
+            shutdown: false,
         })
     }
 
-    fn set_gain(&self, gain: i32, auto: bool) -> Result<(), ASI_ERROR> {
-        self.ccd
-            .set_control_value(asi::CONTROL_TYPE::GAIN, gain as i64, auto)
+    /// Writes captures to `dir`, so that several cameras don't share one directory.
+    pub fn set_image_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.image_dir = dir.into();
+    }
+
+    fn control_caps(&self, control_type: asi::CONTROL_TYPE) -> Option<&ControlCaps> {
+        self.controls
+            .iter()
+            .find(|caps| caps.control_type == control_type)
+    }
+
+    /// Sets a control after checking it against the camera's reported caps.
+    ///
+    /// Values outside of the supported range are clamped rather than rejected, so a
+    /// stale slider in the UI can't put the controller into an error state.
+    fn set_control(&self, control_type: asi::CONTROL_TYPE, value: i64, auto: bool) -> Result<()> {
+        let caps = self
+            .control_caps(control_type)
+            .ok_or(anyhow!("Camera does not support control {control_type:?}"))?;
+        if !caps.is_writable {
+            return Err(anyhow!("Control {} is read only", caps.name));
+        }
+        if auto && !caps.is_auto_supported {
+            return Err(anyhow!("Control {} does not support auto mode", caps.name));
+        }
+
+        let clamped = caps.clamp(value);
+        if clamped != value {
+            warn!(
+                "Value {value} for {} is outside of {}..={}, using {clamped}",
+                caps.name, caps.min_value, caps.max_value
+            );
+        }
+        self.ccd.set_control_value(control_type, clamped, auto)?;
+        Ok(())
+    }
+
+    fn set_control_to_default(&self, control_type: asi::CONTROL_TYPE) -> Result<()> {
+        match self.control_caps(control_type) {
+            Some(caps) if caps.is_writable => {
+                self.set_control(control_type, caps.default_value, false)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn set_gain(&self, gain: i32, auto: bool) -> Result<()> {
+        self.set_control(asi::CONTROL_TYPE::GAIN, gain as i64, auto)
     }
 
-    fn set_exposure(&self, exp: f32, auto: bool) -> Result<(), ASI_ERROR> {
-        self.ccd.set_control_value(
+    fn set_exposure(&self, exp: f32, auto: bool) -> Result<()> {
+        self.set_control(
             asi::CONTROL_TYPE::EXPOSURE,
             (exp * 1000.).trunc() as i64,
             auto,
         )
     }
 
-    fn set_white_balance_red(&self, r: i32, auto: bool) -> Result<(), ASI_ERROR> {
-        self.ccd
-            .set_control_value(asi::CONTROL_TYPE::WB_R, r as i64, auto)
+    /// Hands exposure and gain to the camera's own auto mode, which runs during video.
+    fn start_hardware_auto(&self) -> Result<()> {
+        let Some(auto) = &self.auto_exposure else {
+            return Ok(());
+        };
+        let config = &auto.config;
+        let max_gain = match (config.max_gain, self.control_caps(asi::CONTROL_TYPE::GAIN)) {
+            (Some(max_gain), _) => max_gain,
+            (None, Some(caps)) => caps.max_value,
+            (None, None) => 0,
+        };
+        // The config has its own units and limits, the camera may take less
+        for (control_type, value) in [
+            // In ms, despite what the SDK header says
+            (
+                asi::CONTROL_TYPE::AUTO_MAX_EXP,
+                (config.max_exposure * 1000.).round() as i64,
+            ),
+            (asi::CONTROL_TYPE::AUTO_MAX_GAIN, max_gain),
+            (
+                asi::CONTROL_TYPE::AUTO_TARGET_BRIGHTNESS,
+                (config.target * 255.).round() as i64,
+            ),
+        ] {
+            if let Some(caps) = self.control_caps(control_type) {
+                self.set_control(control_type, caps.clamp(value), false)?;
+            }
+        }
+        for control_type in [asi::CONTROL_TYPE::EXPOSURE, asi::CONTROL_TYPE::GAIN] {
+            if self
+                .control_caps(control_type)
+                .is_some_and(|caps| caps.is_auto_supported)
+            {
+                self.set_control(control_type, self.get_control(control_type)?, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Turns auto exposure off, keeping the exposure and gain it picked last.
+    fn stop_auto_exposure(&mut self) -> Result<()> {
+        if self.auto_exposure.take().is_none() {
+            return Ok(());
+        }
+        info!("Turning auto exposure off");
+        for control_type in [asi::CONTROL_TYPE::EXPOSURE, asi::CONTROL_TYPE::GAIN] {
+            if self.control_caps(control_type).is_some() {
+                self.set_control(control_type, self.get_control(control_type)?, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Meters the exposure `run` just finished and sets exposure and gain for the next.
+    fn auto_expose(&mut self, run: &mut ExposureRun) -> Result<()> {
+        let gain = self.get_control(asi::CONTROL_TYPE::GAIN)?;
+        let gain_range = self
+            .control_caps(asi::CONTROL_TYPE::GAIN)
+            .filter(|caps| caps.is_writable)
+            .map_or((gain, gain), |caps| (caps.min_value, caps.max_value));
+        let (Some(auto), Some(frame)) = (&mut self.auto_exposure, &self.last_exposure) else {
+            return Ok(());
+        };
+        let exposure = run.duration.as_secs_f64();
+        let Some((exposure, gain)) =
+            auto.next(&frame.img, frame.img_type, exposure, gain, gain_range)
+        else {
+            return Ok(());
+        };
+        self.set_control(
+            asi::CONTROL_TYPE::EXPOSURE,
+            (exposure * 1_000_000.).round() as i64,
+            false,
+        )?;
+        if gain_range.0 != gain_range.1 {
+            self.set_control(asi::CONTROL_TYPE::GAIN, gain, false)?;
+        }
+        let exposure = self.get_control(asi::CONTROL_TYPE::EXPOSURE)?;
+        run.duration = Duration::from_micros(exposure.max(0) as u64);
+        info!("Auto exposure picked {:?} at gain {gain}", run.duration);
+        Ok(())
+    }
+
+    fn set_white_balance_red(&self, r: i32, auto: bool) -> Result<()> {
+        self.set_control(asi::CONTROL_TYPE::WB_R, r as i64, auto)
+    }
+
+    fn set_white_balance_blue(&self, b: i32, auto: bool) -> Result<()> {
+        self.set_control(asi::CONTROL_TYPE::WB_B, b as i64, auto)
+    }
+
+    /// The whole sensor at `bin`, with the size rounded down to what the SDK accepts.
+    fn full_frame(&self, bin: i32, img_type: asi::IMG_TYPE) -> ROIFormat {
+        ROIFormat {
+            width: self.info.MaxWidth as i32 / bin / 8 * 8,
+            height: self.info.MaxHeight as i32 / bin / 2 * 2,
+            bin,
+            img_type,
+            start_x: 0,
+            start_y: 0,
+        }
+    }
+
+    /// Checks a format against what the camera reports it supports, so a bad request
+    /// gets a useful error rather than a bare `INVALID_SIZE` from the SDK.
+    fn check_format(&self, format: &ROIFormat) -> Result<()> {
+        let bins = self.info.supported_bins();
+        if !bins.contains(&format.bin) {
+            return Err(anyhow!("Bin {} is not one of {bins:?}", format.bin));
+        }
+        let img_types = self.info.supported_img_types();
+        if !img_types.contains(&format.img_type) {
+            return Err(anyhow!(
+                "Image type {:?} is not one of {img_types:?}",
+                format.img_type
+            ));
+        }
+
+        let max_width = self.info.MaxWidth as i32 / format.bin;
+        let max_height = self.info.MaxHeight as i32 / format.bin;
+        if format.width <= 0 || format.width % 8 != 0 || format.width > max_width {
+            return Err(anyhow!(
+                "Width {} must be a multiple of 8 up to {max_width}",
+                format.width
+            ));
+        }
+        if format.height <= 0 || format.height % 2 != 0 || format.height > max_height {
+            return Err(anyhow!(
+                "Height {} must be a multiple of 2 up to {max_height}",
+                format.height
+            ));
+        }
+        if format.start_x < 0
+            || format.start_y < 0
+            || format.start_x + format.width > max_width
+            || format.start_y + format.height > max_height
+        {
+            return Err(anyhow!(
+                "ROI at ({}, {}) does not fit on the {max_width}x{max_height} sensor",
+                format.start_x,
+                format.start_y
+            ));
+        }
+        Ok(())
     }
 
-    fn set_white_balance_blue(&self, b: i32, auto: bool) -> Result<(), ASI_ERROR> {
-        self.ccd
-            .set_control_value(asi::CONTROL_TYPE::WB_B, b as i64, auto)
+    /// Changes the ROI and image type. Moving the ROI happens while video is running,
+    /// but anything else needs the video stopped first, so preview is restarted around it.
+    fn set_format(&mut self, format: ROIFormat) -> Result<()> {
+        match self.state {
+            CamState::Exposure(_) => {
+                return Err(anyhow!("Can't change the image format during an exposure"))
+            }
+            CamState::Triggered(_) => {
+                return Err(anyhow!("Can't change the image format during a capture"))
+            }
+            _ => {}
+        }
+        self.check_format(&format)?;
+
+        let current = self.ccd.get_roi_format()?;
+        if (current.width, current.height, current.bin, current.img_type)
+            != (format.width, format.height, format.bin, format.img_type)
+        {
+            let previewing = matches!(self.state, CamState::Preview { .. });
+            if previewing {
+                self.stop_video()?;
+            }
+            // Set the position before video starts, so the first frame is already in place
+            let result = self
+                .ccd
+                .set_roi_format(format.width, format.height, format.bin, format.img_type)
+                .and_then(|_| self.ccd.set_start_pos(format.start_x, format.start_y));
+            if previewing {
+                self.start_video()?;
+            }
+            result?;
+        } else {
+            self.ccd.set_start_pos(format.start_x, format.start_y)?;
+        }
+
+        self.roi = self.ccd.get_roi_format()?;
+        info!("Image format is now {:?}", self.roi);
+        Ok(())
     }
 
     fn start_video(&mut self) -> Result<()> {
         match self.state {
             CamState::Stopped => {
+                // Started here rather than on the thread so triggers sent right after
+                // this aren't lost
+                self.ccd.start_video_capture()?;
                 let (tx, rx) = broadcast::channel(1);
 
-                let static_camera = OpenCamera::make_static(&self.ccd);
+                let thread_camera = self.ccd.clone();
+                let thread_gps = self.gps;
                 let thread_frame_avail = self.frame_available.clone();
                 let thread_latest_frame = self.latest_frame.clone();
+                let thread_removed = self.camera_removed.clone();
                 let thread = std::thread::spawn(move || {
                     let mut streamer = match VideoStreamer::new(
-                        static_camera,
+                        thread_camera.clone(),
+                        thread_gps,
                         rx,
                         thread_latest_frame,
                         thread_frame_avail,
@@ -284,23 +1189,50 @@ impl<'a> CameraController<'a> {
                         Ok(t) => t,
                         Err(e) => {
                             error!("Starting video streamer failed with error {:?}", e);
+                            let _ = thread_camera.stop_video_capture();
                             return;
                         }
                     };
-                    let _ = streamer.run();
+                    if let Err(e) = streamer.run() {
+                        error!("Video streamer failed with {e:?}");
+                        if is_camera_removed(&e) {
+                            thread_removed.store(true, std::sync::atomic::Ordering::Relaxed);
+                        }
+                    }
                 });
                 self.stop_msg = Some(tx);
                 self.streamer_thread = Some(thread);
-                // self.ccd.start_video_capture()?;
                 self.state = CamState::Preview { show_hist: false };
-                println!("Starting camera video");
+                info!("Started preview");
                 Ok(())
             }
             CamState::Preview { show_hist: _ } => Ok(()),
             CamState::Capture { total_frames: _ } => {
-                panic!("Tried to start the video while capturing?")
+                Err(anyhow!("Tried to start the video while capturing"))
+            }
+            // Preview comes back once the exposures are done
+            CamState::Exposure(ref mut run) => {
+                run.resume_preview = true;
+                Ok(())
             }
+            CamState::Triggered(_) => Ok(()),
+        }
+    }
+
+    fn stop_streamer(&mut self) -> Result<()> {
+        if let Some(tx) = self.stop_msg.take() {
+            tx.send(true)?;
         }
+        let joined = match self.streamer_thread.take() {
+            Some(handle) => handle
+                .join()
+                .map_err(|_| anyhow!("The video streamer thread panicked")),
+            None => Ok(()),
+        };
+        // Don't hand out the last frame once the ROI may have changed under it
+        self.frame_available
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+        joined
     }
 
     fn stop_video(&mut self) -> Result<()> {
@@ -308,233 +1240,1573 @@ impl<'a> CameraController<'a> {
             CamState::Stopped => Ok(()),
             CamState::Preview { show_hist: _ } => {
                 info!("Stopping preview");
-                if let Some(tx) = self.stop_msg.take() {
-                    tx.send(true)?;
-                }
-                if let Some(handle) = self.streamer_thread.take() {
-                    handle.join().unwrap();
-                }
-                // self.ccd.stop_video_capture()?;
+                self.stop_streamer()?;
                 self.state = CamState::Stopped;
-                println!("Stopped camera video");
                 Ok(())
             }
             CamState::Capture { total_frames: _ } => {
-                panic!("Tried to start the video while capturing?")
+                Err(anyhow!("Tried to stop the video while capturing"))
+            }
+            // A client going away shouldn't throw away a long exposure
+            CamState::Exposure(ref mut run) => {
+                run.resume_preview = false;
+                Ok(())
             }
+            // Like exposures, only `StopCapture` ends a triggered capture
+            CamState::Triggered(_) => Ok(()),
         }
     }
 
-    fn handle_command(&mut self, cmd: ControlMessages) -> Result<()> {
-        info!("Received command {:?}", cmd);
-        match cmd {
-            ControlMessages::SetGain(gain) => self.set_gain(gain, false)?,
-            ControlMessages::SetExposure(exp) => self.set_exposure(exp, false)?,
-            ControlMessages::SetWbR(r) => self.set_white_balance_red(r, false)?,
-            ControlMessages::SetWbB(b) => self.set_white_balance_blue(b, false)?,
-            ControlMessages::SwitchOutput => {
-                if let CamState::Preview { show_hist } = &mut self.state {
-                    *show_hist = !*show_hist;
-                }
-            }
-            ControlMessages::StartPreview => self.start_video()?,
-            ControlMessages::StopPreview => self.stop_video()?,
-            ControlMessages::StartCapture(total_frames) => {
-                if let CamState::Preview { show_hist: _ } = self.state {
-                    self.stop_video()?;
-                }
-                self.state = CamState::Capture { total_frames }
-            }
+    /// Changes the trigger mode, restarting preview around it since the SDK only
+    /// allows this with video stopped.
+    fn set_camera_mode(&mut self, mode: asi::CAMERA_MODE) -> Result<()> {
+        match self.state {
+            CamState::Stopped | CamState::Preview { .. } => {}
+            _ => return Err(anyhow!("Can't change the camera mode while capturing")),
+        }
+        let modes = self.ccd.supported_camera_modes()?;
+        if !modes.contains(&mode) {
+            return Err(anyhow!("Camera mode {mode:?} is not one of {modes:?}"));
+        }
+
+        let previewing = matches!(self.state, CamState::Preview { .. });
+        if previewing {
+            self.stop_video()?;
+        }
+        let result = self.ccd.set_camera_mode(mode);
+        if previewing {
+            self.start_video()?;
         }
+        result?;
+        self.camera_mode = self.ccd.get_camera_mode()?;
+        info!("Camera mode is now {:?}", self.camera_mode);
         Ok(())
     }
 
-    fn handle_commands(&mut self) -> Result<()> {
-        loop {
-            if self.rx.is_empty() {
-                return Ok(());
-            }
-            if let Ok(cmd) = self.rx.blocking_recv() {
-                self.handle_command(cmd)?;
-            }
+    /// Starts saving triggered frames. Video is left running, since that is what
+    /// delivers them.
+    fn start_triggered(&mut self, total_frames: i32) -> Result<()> {
+        if total_frames < 1 {
+            return Err(anyhow!("Asked for {total_frames} frames"));
         }
+        self.start_video()?;
+        let file_prefix = self.new_file_prefix("")?;
+        let output = self.capture_output(&file_prefix)?;
+        info!(
+            "Waiting for {total_frames} triggered frames in {:?}",
+            self.camera_mode
+        );
+        self.state = CamState::Triggered(TriggerRun {
+            captured_frames: 0,
+            total_frames,
+            file_prefix,
+            output,
+        });
+        Ok(())
     }
 
-    fn get_controls(&self) -> Result<ControlValues> {
-        Ok(ControlValues {
-            gain: self.ccd.get_control_value(asi::CONTROL_TYPE::GAIN)?.0,
-            exposure: self.ccd.get_control_value(asi::CONTROL_TYPE::EXPOSURE)?.0 as f64 / 1000.,
-            wb_b: self.ccd.get_control_value(asi::CONTROL_TYPE::WB_B)?.0,
-            wb_r: self.ccd.get_control_value(asi::CONTROL_TYPE::WB_R)?.0,
-        })
-    }
-    fn make_preview(&self) -> Result<ClientPacket> {
-        // let start = std::time::Instant::now();
-        //self.ccd.take_exposure(img_buffer);
-        // self.ccd.get_video_data(img_buffer, 500)?;
-        while !self
-            .frame_available
-            .load(std::sync::atomic::Ordering::Relaxed)
-        {
-            sleep(Duration::from_micros(500));
+    /// Ends a triggered capture, going back to preview.
+    fn stop_triggered(&mut self) -> Result<()> {
+        let CamState::Triggered(run) =
+            std::mem::replace(&mut self.state, CamState::Preview { show_hist: false })
+        else {
+            return Ok(());
+        };
+        info!(
+            "Triggered capture finished with {} of {} frames",
+            run.captured_frames, run.total_frames
+        );
+        // Keep whatever was recorded, even if the capture was cut short
+        if let CaptureOutput::Ser(writer) = run.output {
+            writer.finish()?;
         }
-        let img_buffer = self.latest_frame.lock().unwrap();
-        self.frame_available
-            .store(false, std::sync::atomic::Ordering::Release);
-        // let end = std::time::Instant::now();
-        // let dropped_frames = self.ccd.get_dropped_frames()?;
-        // println!(
-        //     "Get video data took {:?}, dropped {dropped_frames} frames",
-        //     end - start
-        // );
-        // println!("Get data for preview in {:?}", end - start);
-
-        Ok(ClientPacket::Preview(ImagePacket {
-            w: self.width,
-            h: self.height,
-            pix: PixelOrder::RAW8,
-            img: img_buffer.to_vec(),
-            controls: self.get_controls()?,
-        }))
+        Ok(())
     }
 
-    fn make_histogram(&self) -> Result<ClientPacket> {
-        let start = std::time::Instant::now();
-        while !self
-            .frame_available
-            .load(std::sync::atomic::Ordering::Relaxed)
-        {
-            sleep(Duration::from_micros(500));
-        }
-        let img_buffer = self.latest_frame.lock().unwrap();
-        self.frame_available
-            .store(false, std::sync::atomic::Ordering::Release);
-        //self.ccd.take_exposure(img.as_flat_samples_mut().samples);
-        // self.ccd
-        //     .get_video_data(img.as_flat_samples_mut().samples, 500)?;
-        let hist_result = if self.width * self.height == img_buffer.len() as u32 {
-            let img =
-                ImageBuffer::<Luma<u8>, _>::from_raw(self.width, self.height, &img_buffer[..])
-                    .ok_or(anyhow!("ImageBuffer::from_raw failed!"))?;
-            histogram(&img)
-        } else {
-            let img = ImageBuffer::<Rgb<u8>, _>::from_raw(self.width, self.height, &img_buffer[..])
-                .ok_or(anyhow!("ImageBuffer::from_raw failed!"))?;
-            let mut hist_result = histogram(&img);
-            hist_result.channels.swap(0, 2);
-            hist_result
+    /// Saves the next triggered frame, if one arrived within [`FRAME_WAIT`].
+    fn poll_triggered(&mut self) -> Result<()> {
+        let CamState::Triggered(mut run) =
+            std::mem::replace(&mut self.state, CamState::Preview { show_hist: false })
+        else {
+            return Ok(());
+        };
+        let saved = match self.next_frame(FRAME_WAIT) {
+            Some(guard) => {
+                let frame = guard.data.to_vec();
+                let gps = self.checked_gps(guard.gps);
+                // Let the streamer store the next frame while this one is written
+                drop(guard);
+                let received = SystemTime::now();
+                let file_name = format!("{}_{}", run.file_prefix, run.captured_frames);
+                self.write_capture_frame(&mut run.output, &file_name, &frame, received, gps)
+                    .and_then(|_| {
+                        run.captured_frames += 1;
+                        let _ = self.tx.send(ClientPacket::CaptureStatus(CaptureStatus {
+                            captured_frames: run.captured_frames,
+                            total_frames: run.total_frames,
+                        }));
+                        self.publish_preview(self.preview_packet(&frame)?);
+                        Ok(())
+                    })
+            }
+            None => Ok(()),
         };
-        let end = std::time::Instant::now();
-        // let dropped_frames = self.ccd.get_dropped_frames()?;
-        // println!("Get image data for histogram took {:?}", end - start);
-        // let mut img = ImageBuffer::<Rgb<u8>, &[u8]>::new(self.width, self.height);
 
-        // let mut hist_result = histogram(&img);
-        // handle bgr -> rgb conversion
+        let done = run.captured_frames == run.total_frames;
+        self.state = CamState::Triggered(run);
+        if saved.is_err() || done {
+            self.stop_triggered()?;
+        }
+        saved
+    }
 
+    /// Stops preview and starts the first of `total_frames` exposures, in `format` if
+    /// given.
+    fn start_exposures(
+        &mut self,
+        seconds: f64,
+        total_frames: i32,
+        dark: bool,
+        save: bool,
+        format: Option<ROIFormat>,
+    ) -> Result<()> {
+        match self.state {
+            CamState::Exposure(_) => return Err(anyhow!("An exposure is already running")),
+            CamState::Capture { .. } | CamState::Triggered(_) => {
+                return Err(anyhow!("Can't expose while capturing"))
+            }
+            CamState::Stopped | CamState::Preview { .. } => {}
+        }
+        if total_frames < 1 {
+            return Err(anyhow!("Asked for {total_frames} exposures"));
+        }
+        if let Some(format) = &format {
+            self.check_format(format)
+                .map_err(|e| ProtocolError::new(ErrorCode::InvalidRequest, format!("{e:#}")))?;
+        }
+        if dark && self.info.MechanicalShutter == 0 {
+            warn!("Camera has no shutter, cover the telescope for dark frames");
+        }
+
+        let seconds = match &self.auto_exposure {
+            Some(auto) => auto.start_exposure(seconds),
+            None => seconds,
+        };
+        let resume_preview = matches!(self.state, CamState::Preview { .. });
+        self.stop_video()?;
+        let restore_format = match format {
+            Some(format) if format != self.roi => {
+                let current = self.roi;
+                if let Err(e) = self.set_format(format) {
+                    self.set_format(current)?;
+                    if resume_preview {
+                        self.start_video()?;
+                    }
+                    return Err(e);
+                }
+                Some(current)
+            }
+            _ => None,
+        };
+        let preview_exposure = self.get_control(asi::CONTROL_TYPE::EXPOSURE)?;
+        let started = self
+            .set_control(
+                asi::CONTROL_TYPE::EXPOSURE,
+                (seconds * 1_000_000.).round() as i64,
+                false,
+            )
+            .and_then(|_| Ok(self.get_control(asi::CONTROL_TYPE::EXPOSURE)?))
+            .and_then(|exposure| {
+                self.ccd.start_exposure(dark)?;
+                Ok(exposure)
+            });
+        let exposure = match started {
+            Ok(exposure) => exposure,
+            Err(e) => {
+                self.set_control(asi::CONTROL_TYPE::EXPOSURE, preview_exposure, false)?;
+                if let Some(format) = restore_format {
+                    self.set_format(format)?;
+                }
+                if resume_preview {
+                    self.start_video()?;
+                }
+                return Err(e);
+            }
+        };
+
+        let file_prefix = match save {
+            true => Some(self.new_file_prefix(if dark { "_dark" } else { "_light" })?),
+            false => None,
+        };
+        let run = ExposureRun {
+            duration: Duration::from_micros(exposure.max(0) as u64),
+            dark,
+            frame: 1,
+            total_frames,
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            last_report: Instant::now(),
+            retries: 0,
+            file_prefix,
+            preview_exposure,
+            restore_format,
+            resume_preview,
+        };
+        info!(
+            "Starting {total_frames} exposures of {:?}, dark: {dark}",
+            run.duration
+        );
+        self.report(&run, ExposureState::Exposing);
+        self.state = CamState::Exposure(run);
+        Ok(())
+    }
+
+    /// Sends the progress of `run` to clients, remembering it if the frame is done.
+    fn report(&mut self, run: &ExposureRun, state: ExposureState) {
+        let status = run.progress(state);
+        if matches!(
+            state,
+            ExposureState::Complete | ExposureState::Failed | ExposureState::Aborted
+        ) {
+            self.last_exposure_status = Some(status.clone());
+        }
+        let _ = self.tx.send(ClientPacket::ExposureStatus(status));
+    }
+
+    fn abort_exposures(&mut self) -> Result<()> {
+        let CamState::Exposure(run) = std::mem::replace(&mut self.state, CamState::Stopped) else {
+            return Ok(());
+        };
+        info!("Aborting exposure {} of {}", run.frame, run.total_frames);
+        let stopped = self.ccd.stop_exposure();
+        self.report(&run, ExposureState::Aborted);
+        self.finish_exposures(run)?;
+        Ok(stopped?)
+    }
+
+    /// Puts back what `start_exposures` changed.
+    fn finish_exposures(&mut self, run: ExposureRun) -> Result<()> {
+        self.state = CamState::Stopped;
+        let restored = self
+            .set_control(asi::CONTROL_TYPE::EXPOSURE, run.preview_exposure, false)
+            .and_then(|_| self.start_hardware_auto());
+        let restored_format = match run.restore_format {
+            Some(format) => self.set_format(format),
+            None => Ok(()),
+        };
+        if run.resume_preview {
+            self.start_video()?;
+        }
+        restored.and(restored_format)
+    }
+
+    /// Ends `run` after `e`, telling clients its frame failed.
+    fn fail_exposures(&mut self, run: ExposureRun, e: anyhow::Error) -> Result<()> {
+        let _ = self.ccd.stop_exposure();
+        self.report(&run, ExposureState::Failed);
+        if let Err(finish) = self.finish_exposures(run) {
+            error!("Couldn't restore the camera after failed exposures: {finish:?}");
+        }
+        Err(e)
+    }
+
+    /// Checks on the running exposure, downloading and saving the frame once it's done.
+    ///
+    /// Only sleeps for a short while, so commands like `AbortExposure` are still
+    /// handled during long exposures.
+    fn poll_exposure(&mut self) -> Result<()> {
+        let CamState::Exposure(mut run) = std::mem::replace(&mut self.state, CamState::Stopped)
+        else {
+            return Ok(());
+        };
+
+        let status = match self.ccd.get_exp_status() {
+            Ok(status) => status,
+            Err(e) => return self.fail_exposures(run, e.into()),
+        };
+        match status {
+            asi::EXPOSURE_STATUS::EXP_WORKING => {
+                if run.last_report.elapsed() >= EXPOSURE_REPORT_INTERVAL {
+                    self.report(&run, ExposureState::Exposing);
+                    run.last_report = Instant::now();
+                }
+                let remaining = run.duration.saturating_sub(run.started.elapsed());
+                sleep(remaining.clamp(Duration::from_millis(1), Duration::from_millis(50)));
+            }
+            asi::EXPOSURE_STATUS::EXP_SUCCESS => {
+                if let Err(e) = self.save_exposure(&run) {
+                    return self.fail_exposures(run, e);
+                }
+                self.report(&run, ExposureState::Complete);
+                if let Err(e) = self.auto_expose(&mut run) {
+                    self.finish_exposures(run)?;
+                    return Err(e);
+                }
+
+                if run.frame == run.total_frames {
+                    info!("Finished {} exposures", run.total_frames);
+                    return self.finish_exposures(run);
+                }
+                run.frame += 1;
+                run.retries = 0;
+                run.started = Instant::now();
+                run.started_at = SystemTime::now();
+                run.last_report = Instant::now();
+                if let Err(e) = self.ccd.start_exposure(run.dark) {
+                    return self.fail_exposures(run, e.into());
+                }
+                self.report(&run, ExposureState::Exposing);
+            }
+            asi::EXPOSURE_STATUS::EXP_FAILED if run.retries < MAX_EXPOSURE_RETRIES => {
+                run.retries += 1;
+                warn!(
+                    "Exposure {} failed, retrying ({}/{MAX_EXPOSURE_RETRIES})",
+                    run.frame, run.retries
+                );
+                self.report(&run, ExposureState::Retrying);
+                run.started = Instant::now();
+                run.started_at = SystemTime::now();
+                if let Err(e) = self.ccd.start_exposure(run.dark) {
+                    return self.fail_exposures(run, e.into());
+                }
+            }
+            asi::EXPOSURE_STATUS::EXP_FAILED => {
+                let e = anyhow!(
+                    "Exposure {} failed {MAX_EXPOSURE_RETRIES} times, giving up",
+                    run.frame
+                );
+                return self.fail_exposures(run, e);
+            }
+            // Something else stopped the exposure
+            asi::EXPOSURE_STATUS::EXP_IDLE => {
+                self.report(&run, ExposureState::Aborted);
+                return self.finish_exposures(run);
+            }
+        }
+
+        self.state = CamState::Exposure(run);
+        Ok(())
+    }
+
+    /// Downloads a finished exposure, saves it unless told not to and sends it to clients
+    /// as a preview.
+    fn save_exposure(&mut self, run: &ExposureRun) -> Result<()> {
+        let buf_size = self.roi.width * self.roi.height * self.roi.img_type.bytes_per_pixel();
+        let mut frame = vec![0; buf_size as usize];
+        let gps = match self.gps {
+            true => match self.ccd.get_data_after_exp_gps(&mut frame) {
+                Ok(start) => frame_gps(self.ccd.as_ref(), start)?,
+                Err(ASI_ERROR::GPS_DATA_INVALID) => None,
+                Err(e) => return Err(e.into()),
+            },
+            false => {
+                self.ccd.get_data_after_exp(&mut frame)?;
+                None
+            }
+        };
+        let gps = self.checked_gps(gps);
+
+        let meta = FitsMetadata {
+            date_obs: run.started_at,
+            exposure: run.duration,
+            frame_type: if run.dark {
+                "Dark Frame"
+            } else {
+                "Light Frame"
+            }
+            .to_string(),
+            gps,
+            ..self.fits_metadata()?
+        };
+        if let Some(file_prefix) = &run.file_prefix {
+            let file_name = format!("{file_prefix}_{}", run.frame);
+            let (file_frame, debayered) = self.file_frame(&frame)?;
+            match self.capture_format {
+                CaptureFormat::Tiff => write_tiff(
+                    &format!("{file_name}.tiff"),
+                    &file_frame,
+                    &self.roi,
+                    debayered,
+                )?,
+                CaptureFormat::Fits | CaptureFormat::Ser => fits::write_fits(
+                    format!("{file_name}.fits"),
+                    &file_frame,
+                    &FitsMetadata {
+                        debayered,
+                        ..meta.clone()
+                    },
+                )?,
+            }
+            info!("Saved exposure {} to {file_name}", run.frame);
+        }
+
+        self.publish_preview(self.preview_packet(&frame)?);
+        self.last_exposure = Some(RawFrame {
+            w: self.roi.width as u32,
+            h: self.roi.height as u32,
+            img_type: self.roi.img_type,
+            img: frame,
+            meta,
+        });
+        Ok(())
+    }
+
+    fn cooler(&mut self) -> Result<&mut Cooler> {
+        self.cooler
+            .as_mut()
+            .ok_or(anyhow!("Camera has no temperature sensor"))
+    }
+
+    /// Sends the cooler's status every [`crate::cooler::REPORT_INTERVAL`], and starts
+    /// the capture waiting for it once the temperature settled.
+    fn step_cooler(&mut self) -> Result<()> {
+        let Some(cooler) = &mut self.cooler else {
+            return Ok(());
+        };
+        if let Some(mut status) = cooler.step(self.ccd.as_ref())? {
+            status.capture_waiting = self.waiting_capture.is_some();
+            let _ = self.tx.send(ClientPacket::CoolerStatus(status));
+        }
+        if !cooler.holds_captures() {
+            if let Some(cmd) = self.waiting_capture.take() {
+                match cooler.settle_timed_out() {
+                    true => warn!("Temperature didn't settle in time, starting {cmd:?}"),
+                    false => info!("Temperature settled, starting {cmd:?}"),
+                }
+                if let Err(e) = self.handle_command(cmd) {
+                    error!("Starting the waiting capture failed with {e:?}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_command(&mut self, cmd: ControlMessages) -> Result<()> {
+        info!("Received command {:?}", cmd);
+        if let CamState::Capture { .. } = self.state {
+            return self.handle_capture_command(cmd);
+        }
+        if matches!(
+            cmd,
+            ControlMessages::StartCapture(_) | ControlMessages::StartExposure { .. }
+        ) && self.cooler.as_ref().is_some_and(Cooler::holds_captures)
+        {
+            if self.waiting_capture.is_some() {
+                return Err(anyhow!(
+                    "A capture is already waiting for the temperature to settle"
+                ));
+            }
+            info!("Waiting for the temperature to settle");
+            self.waiting_capture = Some(cmd);
+            return Ok(());
+        }
+        self.run_command(cmd)
+    }
+
+    /// Runs `cmd` once [`CameraController::handle_command`] found it can run now.
+    fn run_command(&mut self, cmd: ControlMessages) -> Result<()> {
+        match cmd {
+            ControlMessages::SetGain(gain) => {
+                self.stop_auto_exposure()?;
+                self.set_gain(gain, false)?
+            }
+            ControlMessages::SetExposure(exp) => {
+                self.stop_auto_exposure()?;
+                self.set_exposure(exp, false)?
+            }
+            ControlMessages::SetAutoExposure(Some(config)) => {
+                info!("Turning auto exposure on with {config:?}");
+                let auto = AutoExposure::new(config)
+                    .map_err(|e| ProtocolError::new(ErrorCode::InvalidRequest, format!("{e:#}")))?;
+                self.auto_exposure = Some(auto);
+                self.start_hardware_auto()?
+            }
+            ControlMessages::SetAutoExposure(None) => self.stop_auto_exposure()?,
+            ControlMessages::SetWbR(r) => self.set_white_balance_red(r, false)?,
+            ControlMessages::SetWbB(b) => self.set_white_balance_blue(b, false)?,
+            ControlMessages::SetOffset(offset) => {
+                self.set_control(asi::CONTROL_TYPE::OFFSET, offset as i64, false)?
+            }
+            ControlMessages::SwitchOutput => {
+                if let CamState::Preview { show_hist } = &mut self.state {
+                    *show_hist = !*show_hist;
+                }
+            }
+            ControlMessages::StartPreview => {
+                let _ = self.tx.send(ClientPacket::ControlCaps(ControlCapsPacket {
+                    controls: self.controls.clone(),
+                }));
+                self.start_video()?
+            }
+            ControlMessages::StopPreview => self.stop_video()?,
+            ControlMessages::StartCapture(total_frames) => {
+                match self.state {
+                    CamState::Exposure(_) => {
+                        return Err(anyhow!("Can't start a capture during an exposure"))
+                    }
+                    CamState::Triggered(_) => return Err(anyhow!("A capture is already running")),
+                    _ => {}
+                }
+                if self.camera_mode != asi::CAMERA_MODE::NORMAL {
+                    return self.start_triggered(total_frames);
+                }
+                if let CamState::Preview { show_hist: _ } = self.state {
+                    self.stop_video()?;
+                }
+                self.state = CamState::Capture { total_frames }
+            }
+            ControlMessages::SetRoi { x, y, w, h, bin } => self.set_format(ROIFormat {
+                width: w,
+                height: h,
+                bin,
+                img_type: self.roi.img_type,
+                start_x: x,
+                start_y: y,
+            })?,
+            ControlMessages::SetBin(bin) => {
+                self.set_format(self.full_frame(bin, self.roi.img_type))?
+            }
+            ControlMessages::SetPreviewStretch(stretch) => self.stretch_preview = stretch,
+            ControlMessages::SetDebayer(algorithm) => self.debayer = algorithm,
+            ControlMessages::SetColorFiles(algorithm) => self.color_files = algorithm,
+            ControlMessages::SetCaptureFormat(format) => self.capture_format = format,
+            ControlMessages::SetObserver(observer) => self.observer = observer,
+            ControlMessages::SetTelescope(telescope) => self.telescope = telescope,
+            ControlMessages::SetImageType(img_type) => self.set_format(ROIFormat {
+                img_type,
+                ..self.roi
+            })?,
+            ControlMessages::StartExposure {
+                seconds,
+                count,
+                dark,
+                save,
+                format,
+            } => self.start_exposures(seconds, count, dark, save, format)?,
+            ControlMessages::AbortExposure => {
+                self.cancel_waiting_capture();
+                self.abort_exposures()?
+            }
+            ControlMessages::SetCameraMode(mode) => self.set_camera_mode(mode)?,
+            ControlMessages::SoftTrigger(start) => {
+                if !self.camera_mode.is_soft() {
+                    return Err(anyhow!(
+                        "Soft triggers need a soft trigger mode, not {:?}",
+                        self.camera_mode
+                    ));
+                }
+                self.ccd.send_soft_trigger(start)?
+            }
+            ControlMessages::SetTriggerOutput { pin, conf } => {
+                if self.info.IsTriggerCam == 0 {
+                    return Err(anyhow!("Camera has no trigger outputs"));
+                }
+                self.ccd.set_trigger_output(pin, conf)?;
+                info!(
+                    "Trigger output {pin:?} is now {:?}",
+                    self.ccd.get_trigger_output(pin)?
+                );
+            }
+            ControlMessages::StopCapture => {
+                self.cancel_waiting_capture();
+                self.stop_triggered()?
+            }
+            ControlMessages::PulseGuide {
+                direction,
+                duration,
+            } => guide::pulse(self.ccd.as_ref(), direction, duration)?,
+            ControlMessages::SetGpsLines { start, end } => {
+                if !self.gps {
+                    return Err(anyhow!("Camera has no GPS"));
+                }
+                self.set_control(asi::CONTROL_TYPE::GPS_START_LINE, start as i64, false)?;
+                self.set_control(asi::CONTROL_TYPE::GPS_END_LINE, end as i64, false)?;
+            }
+            ControlMessages::StopGuiding => guide::stop(self.ccd.as_ref())?,
+            ControlMessages::SetTargetTemperature(target) => {
+                if self.shutdown {
+                    return Err(anyhow!("The camera is warming up to shut down"));
+                }
+                let ccd = self.ccd.clone();
+                self.cooler()?.cool_to(ccd.as_ref(), target)?
+            }
+            ControlMessages::WarmUp => self.cooler()?.warm_up(),
+            ControlMessages::SetCoolerConfig(config) => self.cooler()?.set_config(config)?,
+            ControlMessages::SetFan(on) => {
+                self.set_control(asi::CONTROL_TYPE::FAN_ON, on as i64, false)?
+            }
+            ControlMessages::SetAntiDewHeater(on) => {
+                self.set_control(asi::CONTROL_TYPE::ANTI_DEW_HEATER, on as i64, false)?
+            }
+            // Handled by the client's connection
+            ControlMessages::SetPreview(_) => {}
+            // Answered by handle_commands
+            ControlMessages::GetStatus
+            | ControlMessages::GetFrame
+            | ControlMessages::GetExposure => {}
+            ControlMessages::Shutdown => {
+                self.shutdown = true;
+                self.cancel_waiting_capture();
+                if let Some(cooler) = &mut self.cooler {
+                    cooler.warm_up();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Commands between the frames of a free running capture, which can only stop it
+    /// or change what doesn't affect the frames.
+    fn handle_capture_command(&mut self, cmd: ControlMessages) -> Result<()> {
+        use ControlMessages::*;
+        match cmd {
+            StopCapture => {
+                info!("Stopping capture");
+                self.stop_capture = true;
+                Ok(())
+            }
+            // Preview comes back once the capture is done
+            StartPreview => {
+                let _ = self.tx.send(ClientPacket::ControlCaps(ControlCapsPacket {
+                    controls: self.controls.clone(),
+                }));
+                Ok(())
+            }
+            // A client going away shouldn't end the capture
+            StopPreview => Ok(()),
+            SetTargetTemperature(_)
+            | WarmUp
+            | SetCoolerConfig(_)
+            | SetFan(_)
+            | SetAntiDewHeater(_)
+            | PulseGuide { .. }
+            | StopGuiding
+            | SetPreview(_)
+            | SetObserver(_)
+            | SetTelescope(_)
+            | GetStatus
+            | GetFrame
+            | GetExposure
+            | Shutdown => self.run_command(cmd),
+            _ => Err(anyhow!("Can't run {cmd:?} during a capture")),
+        }
+    }
+
+    fn cancel_waiting_capture(&mut self) {
+        if let Some(cmd) = self.waiting_capture.take() {
+            info!("Cancelled {cmd:?}, which was waiting for the temperature to settle");
+        }
+    }
+
+    fn handle_commands(&mut self) -> Result<()> {
+        loop {
+            if self.rx.is_empty() {
+                return Ok(());
+            }
+            if let Ok(request) = self.rx.blocking_recv() {
+                if request
+                    .expires
+                    .is_some_and(|expires| Instant::now() >= expires)
+                {
+                    warn!("Dropped {:?}, its client stopped waiting", request.command);
+                    continue;
+                }
+                let cmd = request.command;
+                let result = match cmd {
+                    ControlMessages::GetStatus => self.status().map(|s| Some(Reply::Status(s))),
+                    ControlMessages::GetFrame => {
+                        self.latest_raw_frame().map(|f| Some(Reply::Frame(f)))
+                    }
+                    ControlMessages::GetExposure => self
+                        .last_exposure
+                        .clone()
+                        .ok_or(anyhow!("No exposure has been taken yet"))
+                        .map(|f| Some(Reply::Frame(f))),
+                    _ => self.handle_command(cmd.clone()).map(|()| None),
+                };
+                if let Err(e) = &result {
+                    error!("Handling command {cmd:?} failed with {e:?}");
+                }
+                if let Some((client, id)) = request.reply_to {
+                    let _ = self
+                        .tx
+                        .send(ClientPacket::Response(Response::new(client, id, result)));
+                }
+            }
+        }
+    }
+
+    fn status(&self) -> Result<CameraStatus> {
+        let (state, exposure, capture) = match &self.state {
+            CamState::Stopped => (ControllerState::Stopped, None, None),
+            CamState::Preview { .. } => (ControllerState::Preview, None, None),
+            CamState::Capture { .. } => (ControllerState::Capture, None, None),
+            CamState::Exposure(run) => (
+                ControllerState::Exposure,
+                Some(run.progress(ExposureState::Exposing)),
+                None,
+            ),
+            CamState::Triggered(run) => (
+                ControllerState::Triggered,
+                None,
+                Some(CaptureStatus {
+                    captured_frames: run.captured_frames,
+                    total_frames: run.total_frames,
+                }),
+            ),
+        };
+        let cooler = match &self.cooler {
+            Some(cooler) => Some(CoolerStatus {
+                capture_waiting: self.waiting_capture.is_some(),
+                ..cooler.status(self.ccd.as_ref())?
+            }),
+            None => None,
+        };
+        Ok(CameraStatus {
+            state,
+            controls: self.get_controls()?,
+            roi: Roi::from(&self.roi),
+            img_type: self.roi.img_type,
+            capture_format: self.capture_format,
+            camera_mode: self.camera_mode,
+            stretch_preview: self.stretch_preview,
+            debayer: self.debayer,
+            color_files: self.color_files,
+            exposure,
+            last_exposure: self.last_exposure_status.clone(),
+            capture,
+            cooler,
+            auto_exposure: self.auto_exposure.as_ref().map(|auto| auto.config.clone()),
+        })
+    }
+
+    /// The last video frame while video runs, otherwise the last exposure.
+    fn latest_raw_frame(&self) -> Result<RawFrame> {
+        let video_running = self.stop_msg.is_some();
+        if let Some(exposure) = self.last_exposure.as_ref().filter(|_| !video_running) {
+            return Ok(exposure.clone());
+        }
+        let img = self.latest_frame.lock().unwrap().data.clone();
+        let buf_size = self.roi.width * self.roi.height * self.roi.img_type.bytes_per_pixel();
+        // Empty until video first ran, and stale after the format changed
+        if img.len() != buf_size as usize {
+            return self
+                .last_exposure
+                .clone()
+                .ok_or(anyhow!("No frame has been taken yet"));
+        }
+        Ok(RawFrame {
+            w: self.roi.width as u32,
+            h: self.roi.height as u32,
+            img_type: self.roi.img_type,
+            img,
+            meta: self.fits_metadata()?,
+        })
+    }
+
+    fn get_control(&self, control_type: asi::CONTROL_TYPE) -> Result<i64, ASI_ERROR> {
+        match self.control_caps(control_type) {
+            Some(_) => Ok(self.ccd.get_control_value(control_type)?.0),
+            None => Ok(0),
+        }
+    }
+
+    fn get_controls(&self) -> Result<ControlValues> {
+        Ok(ControlValues {
+            gain: self.get_control(asi::CONTROL_TYPE::GAIN)?,
+            exposure: self.get_control(asi::CONTROL_TYPE::EXPOSURE)? as f64 / 1000.,
+            wb_b: self.get_control(asi::CONTROL_TYPE::WB_B)?,
+            wb_r: self.get_control(asi::CONTROL_TYPE::WB_R)?,
+        })
+    }
+    /// Waits up to `timeout` for the streamer to hand over a new frame.
+    fn next_frame(&self, timeout: Duration) -> Option<MutexGuard<'_, StreamedFrame>> {
+        let deadline = Instant::now() + timeout;
+        while !self
+            .frame_available
+            .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            if Instant::now() >= deadline {
+                return None;
+            }
+            sleep(Duration::from_micros(500));
+        }
+        let img_buffer = self.latest_frame.lock().unwrap();
+        self.frame_available
+            .store(false, std::sync::atomic::Ordering::Release);
+        Some(img_buffer)
+    }
+
+    /// The next preview, or `None` if no frame arrived within [`FRAME_WAIT`], as happens
+    /// with long exposures and in the trigger modes.
+    fn make_preview(&self) -> Result<Option<ImagePacket>> {
+        let Some(frame) = self.next_frame(FRAME_WAIT) else {
+            return Ok(None);
+        };
+        self.preview_packet(&frame.data).map(Some)
+    }
+
+    fn preview_packet(&self, frame: &[u8]) -> Result<ImagePacket> {
+        let hist = DepthHistogram::new(frame, self.roi.img_type);
+        let cfa = self.cfa_pattern()?;
+        let debayer = cfa.zip(self.debayer);
+        let (pix, img) = preview_pixels(frame, &self.roi, &hist, self.stretch_preview, debayer);
+        let bayer = match pix {
+            PixelOrder::RAW8 | PixelOrder::RAW16 => cfa.map(CfaPattern::name),
+            _ => None,
+        };
+        Ok(ImagePacket {
+            w: self.roi.width as u32,
+            h: self.roi.height as u32,
+            pix,
+            img,
+            bayer,
+            controls: self.get_controls()?,
+            stats: hist.stats(),
+        })
+    }
+
+    /// Replaces the camera's [`LatestPreview`], for clients to take when they are ready.
+    fn publish_preview(&self, packet: ImagePacket) {
+        self.previews.send_modify(|latest| {
+            let seq = latest.as_ref().map_or(0, |latest| latest.seq + 1);
+            *latest = Some(LatestPreview {
+                seq,
+                packet: Arc::new(packet),
+            });
+        });
+    }
+
+    fn make_histogram(&self) -> Result<Option<ImagePacket>> {
+        let Some(frame) = self.next_frame(FRAME_WAIT) else {
+            return Ok(None);
+        };
+        let img_buffer = &frame.data;
+        let (width, height) = (self.roi.width as u32, self.roi.height as u32);
+        let stats = DepthHistogram::new(img_buffer, self.roi.img_type).stats();
+        // Colour frames get a histogram per channel, like RGB24 ones
+        let debayer = self.cfa_pattern()?.map(|pattern| {
+            let algorithm = self.debayer.unwrap_or_default();
+            debayer::debayer_frame(img_buffer, &self.roi, pattern, algorithm)
+        });
+        let hist_result = match (self.roi.img_type, debayer) {
+            (asi::IMG_TYPE::RAW8, Some(bgr)) => {
+                let img = ImageBuffer::<Rgb<u8>, _>::from_raw(width, height, bgr)
+                    .ok_or(anyhow!("ImageBuffer::from_raw failed!"))?;
+                let mut hist_result = histogram(&img);
+                hist_result.channels.swap(0, 2);
+                hist_result
+            }
+            (asi::IMG_TYPE::RAW16, Some(bgr)) => {
+                let pixels: Vec<u16> = frame::pixels_u16(&bgr).collect();
+                let img = ImageBuffer::<Rgb<u16>, _>::from_raw(width, height, pixels)
+                    .ok_or(anyhow!("ImageBuffer::from_raw failed!"))?;
+                let mut hist_result = histogram(&img);
+                hist_result.channels.swap(0, 2);
+                hist_result
+            }
+            (asi::IMG_TYPE::RAW8 | asi::IMG_TYPE::Y8, _) => {
+                let img = ImageBuffer::<Luma<u8>, _>::from_raw(width, height, &img_buffer[..])
+                    .ok_or(anyhow!("ImageBuffer::from_raw failed!"))?;
+                histogram(&img)
+            }
+            (asi::IMG_TYPE::RAW16, _) => {
+                let pixels: Vec<u16> = frame::pixels_u16(img_buffer).collect();
+                let img = ImageBuffer::<Luma<u16>, _>::from_raw(width, height, pixels)
+                    .ok_or(anyhow!("ImageBuffer::from_raw failed!"))?;
+                histogram(&img)
+            }
+            (asi::IMG_TYPE::RGB24, _) => {
+                let img = ImageBuffer::<Rgb<u8>, _>::from_raw(width, height, &img_buffer[..])
+                    .ok_or(anyhow!("ImageBuffer::from_raw failed!"))?;
+                let mut hist_result = histogram(&img);
+                hist_result.channels.swap(0, 2);
+                hist_result
+            }
+        };
         let hist_img = make_hist_plot(&hist_result);
-        // hist_img.write_to(&mut Cursor::new(&mut png_bytes), image::ImageFormat::Jpeg)?;
-        Ok(ClientPacket::Preview(ImagePacket {
-            w: self.width,
-            h: self.height,
+        Ok(Some(ImagePacket {
+            w: hist_img.width(),
+            h: hist_img.height(),
             pix: PixelOrder::RGB,
             img: hist_img.into_vec(),
+            bayer: None,
             controls: self.get_controls()?,
+            stats,
         }))
     }
 
-    fn capture_loop(&self, total_frames: i32) -> Result<()> {
-        println!("Starting capture loop");
+    fn bayer_pattern(&self) -> Option<asi::ASI_BAYER_PATTERN> {
+        (self.info.IsColorCam != 0).then_some(self.info.BayerPattern)
+    }
 
-        self.ccd.start_video_capture()?;
-        let typ = CV_8UC3;
-        let mut frame = Mat::new_rows_cols_with_default(
-            self.width as i32,
-            self.height as i32,
-            typ,
-            Scalar::all(0.),
-        )
-        .unwrap();
+    /// The bayer pattern at the top left of frames read with the current ROI and flip,
+    /// `None` unless they are RAW8 or RAW16 from a colour camera.
+    fn cfa_pattern(&self) -> Result<Option<CfaPattern>> {
+        let Some(pattern) = self.bayer_pattern() else {
+            return Ok(None);
+        };
+        match self.roi.img_type {
+            asi::IMG_TYPE::RAW8 | asi::IMG_TYPE::RAW16 => {
+                let flip = self.get_control(asi::CONTROL_TYPE::FLIP)?;
+                Ok(Some(CfaPattern::for_frame(pattern, &self.roi, flip)))
+            }
+            asi::IMG_TYPE::RGB24 | asi::IMG_TYPE::Y8 => Ok(None),
+        }
+    }
+
+    /// `frame` as it is saved to TIFF and FITS files, and whether it was debayered for
+    /// `SetColorFiles`.
+    fn file_frame<'a>(&self, frame: &'a [u8]) -> Result<(Cow<'a, [u8]>, bool)> {
+        match self.cfa_pattern()?.zip(self.color_files) {
+            Some((pattern, algorithm)) => Ok((
+                debayer::debayer_frame(frame, &self.roi, pattern, algorithm).into(),
+                true,
+            )),
+            None => Ok((frame.into(), false)),
+        }
+    }
+
+    /// Header values for frames captured with the current settings. `date_obs` is
+    /// filled in per frame.
+    fn fits_metadata(&self) -> Result<FitsMetadata> {
+        let ccd_temp = match self.control_caps(asi::CONTROL_TYPE::TEMPERATURE) {
+            Some(_) => Some(self.get_control(asi::CONTROL_TYPE::TEMPERATURE)? as f64 / 10.),
+            None => None,
+        };
+        Ok(FitsMetadata {
+            instrument: self.ccd.name(),
+            date_obs: SystemTime::now(),
+            exposure: Duration::from_micros(
+                self.get_control(asi::CONTROL_TYPE::EXPOSURE)?.max(0) as u64
+            ),
+            gain: self.get_control(asi::CONTROL_TYPE::GAIN)?,
+            offset: self.get_control(asi::CONTROL_TYPE::OFFSET)?,
+            ccd_temp,
+            pixel_size: self.info.PixelSize,
+            cfa: self.cfa_pattern()?,
+            debayered: false,
+            frame_type: "Light Frame".to_string(),
+            roi: self.roi,
+            gps: None,
+        })
+    }
 
-        let mut params = opencv::core::Vector::<i32>::new();
-        params.push(opencv::imgcodecs::IMWRITE_TIFF_COMPRESSION);
-        params.push(1);
-        std::fs::DirBuilder::new().create("./images/")?;
-        let start = SystemTime::now();
-        let since_the_epoch = start
+    /// Passes on the GPS times a frame came with, warning about frames that should
+    /// have had them. Frames taken without a GPS fix are still worth keeping, so that
+    /// isn't an error.
+    fn checked_gps(&self, gps: Option<FrameGps>) -> Option<FrameGps> {
+        if self.gps && gps.is_none() {
+            warn!("No GPS fix, the frame has no GPS times");
+        }
+        gps
+    }
+
+    /// Reads the next video frame, along with its GPS times on cameras with GPS.
+    fn read_video_frame(&self, frame: &mut [u8], wait_ms: i32) -> Result<Option<FrameGps>> {
+        let gps = read_video_frame_gps(self.ccd.as_ref(), self.gps, frame, wait_ms)?;
+        Ok(self.checked_gps(gps))
+    }
+
+    /// Creates the image directory and returns the start of the file names for a
+    /// capture starting now, e.g. `./images/1700000000_dark`.
+    fn new_file_prefix(&self, suffix: &str) -> Result<String> {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .create(&self.image_dir)?;
+        let epoch = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards");
+        Ok(self
+            .image_dir
+            .join(format!("{}{suffix}", epoch.as_secs()))
+            .to_string_lossy()
+            .into_owned())
+    }
+
+    /// Opens the output for a capture whose files start with `file_prefix`.
+    fn capture_output(&self, file_prefix: &str) -> Result<CaptureOutput> {
+        Ok(match self.capture_format {
+            CaptureFormat::Tiff => CaptureOutput::Tiff,
+            CaptureFormat::Fits => CaptureOutput::Fits(self.fits_metadata()?),
+            CaptureFormat::Ser => CaptureOutput::Ser(SerWriter::create(
+                format!("{file_prefix}.ser"),
+                &self.roi,
+                self.cfa_pattern()?,
+                &SerInfo {
+                    observer: self.observer.clone(),
+                    instrument: self.ccd.name(),
+                    telescope: self.telescope.clone(),
+                },
+            )?),
+        })
+    }
+
+    /// Saves one captured frame, received from the camera at `received`. Formats that
+    /// write a file per frame name it `file_name`. The exposure start comes from `gps`
+    /// when there is one, and is estimated from `received` otherwise.
+    fn write_capture_frame(
+        &self,
+        output: &mut CaptureOutput,
+        file_name: &str,
+        frame: &[u8],
+        received: SystemTime,
+        gps: Option<FrameGps>,
+    ) -> Result<()> {
+        let exposure =
+            Duration::from_micros(self.get_control(asi::CONTROL_TYPE::EXPOSURE)?.max(0) as u64);
+        let exposure_start = match &gps {
+            Some(gps) => gps.start.time.into(),
+            None => received.checked_sub(exposure).unwrap_or(received),
+        };
+        match output {
+            CaptureOutput::Tiff => {
+                let (frame, debayered) = self.file_frame(frame)?;
+                write_tiff(&format!("{file_name}.tiff"), &frame, &self.roi, debayered)?
+            }
+            CaptureOutput::Fits(meta) => {
+                let (frame, debayered) = self.file_frame(frame)?;
+                meta.debayered = debayered;
+                meta.date_obs = exposure_start;
+                meta.exposure = exposure;
+                meta.gps = gps;
+                if meta.ccd_temp.is_some() {
+                    meta.ccd_temp =
+                        Some(self.get_control(asi::CONTROL_TYPE::TEMPERATURE)? as f64 / 10.);
+                }
+                fits::write_fits(format!("{file_name}.fits"), &frame, meta)?
+            }
+            CaptureOutput::Ser(writer) => writer.write_frame(frame, exposure_start)?,
+        }
+        Ok(())
+    }
 
-        for i in 0..total_frames {
-            self.ccd.get_video_data(frame.data_bytes_mut()?, 500)?;
+    fn capture_loop(&mut self, total_frames: i32) -> Result<()> {
+        info!("Starting a capture of {total_frames} frames");
 
-            let file_name = format!("./images/{}_{i}.tiff", since_the_epoch.as_secs());
-            imgcodecs::imwrite(&file_name, &frame, &params)?;
-            self.tx.send(ClientPacket::CaptureStatus(CaptureStatus {
-                captured_frames: i + 1,
+        let buf_size = self.roi.width * self.roi.height * self.roi.img_type.bytes_per_pixel();
+        let mut frame = vec![0; buf_size as usize];
+        let file_prefix = self.new_file_prefix("")?;
+        let mut output = self.capture_output(&file_prefix)?;
+
+        self.stop_capture = false;
+        self.ccd.start_video_capture()?;
+        let mut captured_frames = 0;
+        let result = loop {
+            if captured_frames == total_frames || self.stop_capture || self.shutdown {
+                break Ok(());
+            }
+            let saved = self.read_video_frame(&mut frame, 500).and_then(|gps| {
+                let received = SystemTime::now();
+                let file_name = format!("{file_prefix}_{captured_frames}");
+                self.write_capture_frame(&mut output, &file_name, &frame, received, gps)
+            });
+            if let Err(e) = saved {
+                break Err(e);
+            }
+            captured_frames += 1;
+            // Nobody may be listening, as with REST clients
+            let _ = self.tx.send(ClientPacket::CaptureStatus(CaptureStatus {
+                captured_frames,
                 total_frames,
-            }))?;
+            }));
+            // Answers StopCapture, and anything else, between frames
+            if let Err(e) = self.handle_commands() {
+                break Err(e);
+            }
+        };
+        if captured_frames < total_frames {
+            info!("Capture ended after {captured_frames} of {total_frames} frames");
         }
-        self.ccd.stop_video_capture()?;
-        println!("Finished capture loop");
+        let stopped = self.ccd.stop_video_capture();
+        // Keep whatever was recorded, even if the capture was cut short or video
+        // didn't stop
+        let finished = match output {
+            CaptureOutput::Ser(writer) => {
+                info!("Finishing SER file with {} frames", writer.frame_count());
+                writer.finish()
+            }
+            _ => Ok(()),
+        };
 
-        Ok(())
+        result?;
+        stopped?;
+        Ok(finished?)
     }
 
     pub fn run(&mut self) -> Result<()> {
-        let bin = 1;
-        self.width = 1920 / bin;
-        self.height = 1080 / bin;
-
-        self.ccd.set_roi_format(
-            self.width as i32,
-            self.height as i32,
-            bin as i32,
-            asi::IMG_TYPE::RAW8,
-        )?;
+        self.set_format(self.full_frame(1, asi::IMG_TYPE::RAW8))?;
 
-        self.set_gain(200, false)?;
+        for control_type in [
+            asi::CONTROL_TYPE::GAIN,
+            asi::CONTROL_TYPE::WB_B,
+            asi::CONTROL_TYPE::WB_R,
+        ] {
+            self.set_control_to_default(control_type)?;
+        }
         self.set_exposure(10.0, false)?;
-        self.set_white_balance_blue(87, false)?;
-        self.set_white_balance_red(45, false)?;
-        self.ccd
-            .set_control_value(asi::CONTROL_TYPE::BANDWIDTHOVERLOAD, 50, false)?;
-
-        //let buf_size = self.width * self.height * 3;
-        //let mut img = RgbImage::new(self.width as u32, self.height as u32);
-        //let mut img_buffer = vec![0; buf_size as usize];
+        if self
+            .control_caps(asi::CONTROL_TYPE::BANDWIDTHOVERLOAD)
+            .is_some()
+        {
+            self.set_control(asi::CONTROL_TYPE::BANDWIDTHOVERLOAD, 50, false)?;
+        }
 
-        loop {
+        // Shutting down waits for the sensor to warm up
+        while !(self.shutdown && self.cooler.as_ref().is_none_or(Cooler::is_off)) {
+            if self
+                .camera_removed
+                .load(std::sync::atomic::Ordering::Relaxed)
+            {
+                return Err(ASI_ERROR::CAMERA_REMOVED.into());
+            }
+            if let Err(e) = self.step_cooler() {
+                if is_camera_removed(&e) {
+                    return Err(e);
+                }
+                error!("Cooler failed with {e:?}");
+            }
             use CamState::*;
             match self.state {
                 Stopped => sleep(std::time::Duration::from_millis(1)),
                 Preview { show_hist } => {
-                    if self.tx.len() >= 1 {
-                        sleep(std::time::Duration::from_millis(1));
+                    let packet = if show_hist {
+                        self.make_histogram().context("Error making histogram")?
                     } else {
-                        let packet = if show_hist {
-                            self.make_histogram().context("Error making histogram")?
-                        } else {
-                            // let start = std::time::Instant::now();
-                            self.make_preview().context("Error making preview")?
-                            // let stop = std::time::Instant::now();
-                            // println!("Make preview took {:?}", stop - start);
-                        };
-                        match self.tx.send(packet) {
-                            Ok(_) => {}
-                            Err(_) => self.stop_video()?,
-                        };
+                        self.make_preview().context("Error making preview")?
+                    };
+                    // Keeps running without websocket clients, for REST clients
+                    // fetching frames, until StopPreview. Every client takes the
+                    // newest preview at its own pace, so none of them holds this up.
+                    if let Some(packet) = packet {
+                        self.publish_preview(packet);
                     }
                 }
                 Capture { total_frames } => {
-                    self.capture_loop(total_frames)
-                        .context("Calling capture loop")?;
+                    if let Err(e) = self.capture_loop(total_frames) {
+                        if is_camera_removed(&e) {
+                            return Err(e);
+                        }
+                        error!("Capture failed with {e:?}");
+                    }
                     self.state = CamState::Stopped;
                     self.start_video()?;
                 }
+                Exposure(_) => {
+                    if let Err(e) = self.poll_exposure() {
+                        error!("Exposure failed with {e:?}");
+                    }
+                }
+                Triggered(_) => {
+                    if let Err(e) = self.poll_triggered() {
+                        error!("Triggered capture failed with {e:?}");
+                    }
+                }
             }
 
             self.handle_commands().context("Error handling commands.")?;
         }
+
+        info!("Shutting down controller for {}", self.ccd.name());
+        if let CamState::Exposure(_) = self.state {
+            self.ccd.stop_exposure()?;
+            self.state = CamState::Stopped;
+        }
+        self.stop_triggered()?;
+        self.stop_video()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::{
+        protocol::ProtocolError,
+        simulator::{SimulatedCamera, SimulatorConfig},
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn simulator() -> Arc<SimulatedCamera> {
+        Arc::new(SimulatedCamera::new(SimulatorConfig {
+            max_width: 320,
+            max_height: 240,
+            ..Default::default()
+        }))
+    }
+
+    /// A controller running a simulated camera on its own thread, as the server runs
+    /// one.
+    struct Harness {
+        camera: Arc<SimulatedCamera>,
+        commands: Sender<ControlRequest>,
+        packets: Receiver<ClientPacket>,
+        previews: watch::Receiver<Option<LatestPreview>>,
+        thread: JoinHandle<Result<()>>,
+        image_dir: PathBuf,
+        last_id: u64,
+    }
+
+    impl Harness {
+        fn start(name: &str) -> Self {
+            let image_dir =
+                std::env::temp_dir().join(format!("zwo_asi_rs_{name}_{}", std::process::id()));
+            let (commands, rx) = broadcast::channel(32);
+            let (tx, packets) = broadcast::channel(1024);
+            let (previews_tx, previews) = watch::channel(None);
+            let camera = simulator();
+            let mut controller =
+                CameraController::new(camera.clone(), tx, rx, previews_tx).unwrap();
+            controller.set_image_dir(&image_dir);
+            Self {
+                camera,
+                commands,
+                packets,
+                previews,
+                thread: std::thread::spawn(move || controller.run()),
+                image_dir,
+                last_id: 0,
+            }
+        }
+
+        /// Runs `cmd` and waits for the controller's answer.
+        fn request(&mut self, cmd: ControlMessages) -> Result<Option<Reply>, ProtocolError> {
+            self.last_id += 1;
+            let id = self.last_id;
+            self.commands
+                .send(ControlRequest {
+                    command: cmd,
+                    reply_to: Some((0, id)),
+                    expires: None,
+                })
+                .unwrap();
+            let response = self.wait_for(|packet| match packet {
+                ClientPacket::Response(response) if response.id == Some(id) => Some(response),
+                _ => None,
+            });
+            match response.error {
+                None => Ok(response.data.map(|data| *data)),
+                Some(error) => Err(error),
+            }
+        }
+
+        fn status(&mut self) -> CameraStatus {
+            match self.request(ControlMessages::GetStatus) {
+                Ok(Some(Reply::Status(status))) => status,
+                other => panic!("GetStatus was answered with {other:?}"),
+            }
+        }
+
+        fn wait_for_state(&mut self, state: ControllerState) {
+            let deadline = Instant::now() + TIMEOUT;
+            while self.status().state != state {
+                assert!(
+                    Instant::now() < deadline,
+                    "Controller never got to {state:?}"
+                );
+                sleep(Duration::from_millis(10));
+            }
+        }
+
+        /// Waits for the first packet `pick` returns something for.
+        fn wait_for<T>(&mut self, mut pick: impl FnMut(ClientPacket) -> Option<T>) -> T {
+            let deadline = Instant::now() + TIMEOUT;
+            loop {
+                assert!(Instant::now() < deadline, "Timed out waiting for a packet");
+                match self.packets.try_recv() {
+                    Ok(packet) => {
+                        if let Some(picked) = pick(packet) {
+                            return picked;
+                        }
+                    }
+                    Err(broadcast::error::TryRecvError::Empty) => sleep(Duration::from_millis(1)),
+                    Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+                    Err(e) => panic!("Controller went away: {e}"),
+                }
+            }
+        }
+
+        /// Waits for a preview made after the last one taken.
+        fn next_preview(&mut self) -> Arc<ImagePacket> {
+            let deadline = Instant::now() + TIMEOUT;
+            loop {
+                assert!(Instant::now() < deadline, "Timed out waiting for a preview");
+                if self.previews.has_changed().unwrap() {
+                    if let Some(latest) = self.previews.borrow_and_update().clone() {
+                        return latest.packet;
+                    }
+                }
+                sleep(Duration::from_millis(1));
+            }
+        }
+
+        fn shutdown(self) {
+            self.commands
+                .send(ControlMessages::Shutdown.into())
+                .unwrap();
+            self.thread.join().unwrap().unwrap();
+            let _ = std::fs::remove_dir_all(&self.image_dir);
+        }
+    }
+
+    #[test]
+    fn tiffs_need_the_whole_frame() {
+        let roi = ROIFormat {
+            width: 8,
+            height: 2,
+            bin: 1,
+            img_type: asi::IMG_TYPE::RAW16,
+            start_x: 0,
+            start_y: 0,
+        };
+        let file_name = std::env::temp_dir().join(format!("zwo_asi_rs_{}.tif", std::process::id()));
+        let error = write_tiff(file_name.to_str().unwrap(), &[0; 16], &roi, false).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Frame has 16 bytes, a 8x2 TIFF of it needs 32"
+        );
+        assert!(!file_name.exists());
+    }
+
+    #[test]
+    fn hardware_auto_exposure_stays_within_the_camera_limits() {
+        let mut harness = Harness::start("auto");
+        let auto_value = |harness: &Harness, control_type| {
+            harness.camera.get_control_value(control_type).unwrap().0
+        };
+        for (target, max_exposure, brightness, max_ms) in [
+            (0.9, 100., 160, 60_000),
+            (0.1, 0.0001, 50, 1),
+            (0.5, 0.25, 128, 250),
+        ] {
+            harness
+                .request(ControlMessages::SetAutoExposure(Some(AutoExposureConfig {
+                    target,
+                    max_exposure,
+                    ..Default::default()
+                })))
+                .unwrap();
+            let brightness_now = auto_value(&harness, asi::CONTROL_TYPE::AUTO_TARGET_BRIGHTNESS);
+            assert_eq!(brightness_now, brightness);
+            assert_eq!(
+                auto_value(&harness, asi::CONTROL_TYPE::AUTO_MAX_EXP),
+                max_ms
+            );
+        }
+
+        let error = harness
+            .request(ControlMessages::SetAutoExposure(Some(AutoExposureConfig {
+                target: 1.5,
+                ..Default::default()
+            })))
+            .unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidRequest);
+        assert_eq!(harness.status().auto_exposure.unwrap().target, 0.5);
+        harness.shutdown();
+    }
+
+    #[test]
+    fn previews_follow_the_format() {
+        let mut harness = Harness::start("preview");
+        assert_eq!(harness.status().state, ControllerState::Stopped);
+        harness.request(ControlMessages::StartPreview).unwrap();
+        let preview = harness.next_preview();
+        assert_eq!((preview.w, preview.h), (320, 240));
+        // Debayered for display
+        assert!(matches!(preview.pix, PixelOrder::BGR));
+        assert_eq!(preview.img.len(), 320 * 240 * 3);
+        assert_eq!(harness.status().state, ControllerState::Preview);
+
+        let roi = ControlMessages::SetRoi {
+            x: 16,
+            y: 8,
+            w: 160,
+            h: 120,
+            bin: 1,
+        };
+        harness.request(roi).unwrap();
+        let preview = loop {
+            let preview = harness.next_preview();
+            if preview.w == 160 {
+                break preview;
+            }
+        };
+        assert_eq!(preview.h, 120);
+        let status = harness.status();
+        assert_eq!(status.state, ControllerState::Preview);
+        assert_eq!((status.roi.x, status.roi.y), (16, 8));
+
+        let bad_roi = ControlMessages::SetRoi {
+            x: 0,
+            y: 0,
+            w: 100,
+            h: 120,
+            bin: 1,
+        };
+        assert!(harness.request(bad_roi).is_err());
+        harness.shutdown();
+    }
+
+    #[test]
+    fn exposures_can_be_downloaded() {
+        let mut harness = Harness::start("exposure");
+        harness
+            .request(ControlMessages::SetImageType(asi::IMG_TYPE::RAW16))
+            .unwrap();
+        assert!(harness.request(ControlMessages::GetExposure).is_err());
+        harness
+            .request(ControlMessages::StartExposure {
+                seconds: 0.05,
+                count: 1,
+                dark: false,
+                save: false,
+                format: None,
+            })
+            .unwrap();
+        let done = harness.wait_for(|packet| match packet {
+            ClientPacket::ExposureStatus(status) if status.state != ExposureState::Exposing => {
+                Some(status)
+            }
+            _ => None,
+        });
+        assert_eq!(done.state, ExposureState::Complete);
+
+        let Ok(Some(Reply::Frame(frame))) = harness.request(ControlMessages::GetExposure) else {
+            panic!("GetExposure didn't answer with a frame");
+        };
+        assert_eq!((frame.w, frame.h), (320, 240));
+        assert_eq!(frame.img_type, asi::IMG_TYPE::RAW16);
+        assert_eq!(frame.img.len(), 320 * 240 * 2);
+        assert!(frame.img.iter().any(|b| *b != 0));
+        let status = harness.status();
+        assert_eq!(status.state, ControllerState::Stopped);
+        assert_eq!(
+            status.last_exposure.map(|last| last.state),
+            Some(ExposureState::Complete)
+        );
+        harness.shutdown();
+    }
+
+    #[test]
+    fn exposures_can_be_aborted() {
+        let mut harness = Harness::start("abort");
+        harness.request(ControlMessages::StartPreview).unwrap();
+        harness
+            .request(ControlMessages::StartExposure {
+                seconds: 60.,
+                count: 2,
+                dark: false,
+                save: true,
+                format: None,
+            })
+            .unwrap();
+        let status = harness.status();
+        assert_eq!(status.state, ControllerState::Exposure);
+        let exposure = status.exposure.unwrap();
+        assert_eq!((exposure.frame, exposure.total_frames), (1, 2));
+
+        harness.request(ControlMessages::AbortExposure).unwrap();
+        // Preview comes back, at the exposure it had before
+        let status = harness.status();
+        assert_eq!(status.state, ControllerState::Preview);
+        assert_eq!(
+            status.last_exposure.map(|last| last.state),
+            Some(ExposureState::Aborted)
+        );
+        assert_eq!(status.controls.exposure, 10.);
+        harness.shutdown();
+    }
+
+    #[test]
+    fn captures_save_every_frame() {
+        let mut harness = Harness::start("capture");
+        harness
+            .request(ControlMessages::SetCaptureFormat(CaptureFormat::Ser))
+            .unwrap();
+        harness.request(ControlMessages::StartCapture(3)).unwrap();
+        harness.wait_for(|packet| match packet {
+            ClientPacket::CaptureStatus(status) if status.captured_frames == 3 => Some(()),
+            _ => None,
+        });
+        // Back to preview once the file is finished
+        harness.wait_for_state(ControllerState::Preview);
+
+        let files: Vec<_> = std::fs::read_dir(&harness.image_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "ser");
+        let ser = std::fs::read(&files[0]).unwrap();
+        assert_eq!(ser.len(), 178 + 3 * 320 * 240 + 3 * 8);
+        assert_eq!(&ser[38..42], &3i32.to_le_bytes());
+        harness.shutdown();
+    }
+
+    #[test]
+    fn captures_answer_commands_between_frames() {
+        let mut harness = Harness::start("long_capture");
+        harness
+            .request(ControlMessages::SetCaptureFormat(CaptureFormat::Ser))
+            .unwrap();
+        harness
+            .request(ControlMessages::StartCapture(100_000))
+            .unwrap();
+        harness.wait_for_state(ControllerState::Capture);
+
+        harness
+            .request(ControlMessages::SetTargetTemperature(-10.))
+            .unwrap();
+        let status = harness.status();
+        assert_eq!(status.state, ControllerState::Capture);
+        assert_eq!(status.cooler.unwrap().target, Some(-10.));
+        assert!(harness
+            .request(ControlMessages::SetImageType(asi::IMG_TYPE::RAW16))
+            .is_err());
+
+        harness.request(ControlMessages::StopCapture).unwrap();
+        harness.wait_for_state(ControllerState::Preview);
+        harness.request(ControlMessages::WarmUp).unwrap();
+        harness.shutdown();
+    }
+
+    #[test]
+    fn streamer_hands_over_frames_until_stopped() {
+        let camera = simulator();
+        camera.start_video_capture().unwrap();
+        let (stop, stop_rx) = broadcast::channel(1);
+        let latest = Arc::new(Mutex::new(StreamedFrame::default()));
+        let available = Arc::new(AtomicBool::new(false));
+        let mut streamer = VideoStreamer::new(
+            camera.clone(),
+            true,
+            stop_rx,
+            latest.clone(),
+            available.clone(),
+        )
+        .unwrap();
+        let thread = std::thread::spawn(move || streamer.run());
+
+        let deadline = Instant::now() + TIMEOUT;
+        while !available.load(Ordering::Relaxed) {
+            assert!(Instant::now() < deadline, "No frame arrived");
+            sleep(Duration::from_millis(1));
+        }
+        {
+            let frame = latest.lock().unwrap();
+            assert_eq!(frame.data.len(), 320 * 240);
+            let gps = frame.gps.expect("Frame came without its GPS times");
+            assert!(gps.start.time < gps.end.time);
+        }
+
+        stop.send(true).unwrap();
+        thread.join().unwrap().unwrap();
+        // Video was stopped on the way out
+        assert_eq!(
+            camera.get_video_data(&mut vec![0; 320 * 240], 0),
+            Err(ASI_ERROR::INVALID_SEQUENCE)
+        );
     }
 }
@@ -0,0 +1,466 @@
+//! Sensor temperature and cooling.
+//!
+//! A cooled camera holds its sensor at `TARGET_TEMP` by itself, getting there as fast
+//! as its cooler can. Cooling or warming a sensor that quickly can crack it or fog it
+//! up, so [`Cooler`] moves the target a little at a time, at
+//! [`CoolerConfig::ramp_rate`], and warms the sensor back up the same way before the
+//! controller stops. Captures can wait for the temperature to settle, see
+//! [`CoolerConfig::wait_to_settle`].
+//!
+//! Cameras without a cooler still report their sensor temperature.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    asi::{ControlCaps, CONTROL_TYPE},
+    CameraBackend,
+};
+
+/// How often the target moves and clients get a [`CoolerStatus`].
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(2);
+/// Warming up is over once the cooler works less than this, in percent.
+const IDLE_POWER: f64 = 1.;
+
+/// How the cooler gets to a new temperature, set with `SetCoolerConfig`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CoolerConfig {
+    /// How fast the target moves, in °C per minute.
+    #[serde(default = "default_ramp_rate")]
+    pub ramp_rate: f64,
+    /// How close to the target the sensor has to be to count as settled, in °C.
+    #[serde(default = "default_tolerance")]
+    pub tolerance: f64,
+    /// How long it has to stay that close, in seconds.
+    #[serde(default = "default_settle_time")]
+    pub settle_time: f64,
+    /// Whether `StartCapture` and `StartExposure` wait until the temperature settled
+    /// while cooling.
+    #[serde(default)]
+    pub wait_to_settle: bool,
+    /// Longest a capture waits, from when cooling started, in seconds. It starts anyway
+    /// after that, as a camera that can't reach its target would never settle.
+    #[serde(default = "default_settle_timeout")]
+    pub settle_timeout: f64,
+}
+
+fn default_ramp_rate() -> f64 {
+    3.
+}
+
+fn default_tolerance() -> f64 {
+    0.5
+}
+
+fn default_settle_time() -> f64 {
+    30.
+}
+
+fn default_settle_timeout() -> f64 {
+    900.
+}
+
+impl Default for CoolerConfig {
+    fn default() -> Self {
+        Self {
+            ramp_rate: default_ramp_rate(),
+            tolerance: default_tolerance(),
+            settle_time: default_settle_time(),
+            wait_to_settle: false,
+            settle_timeout: default_settle_timeout(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum CoolerState {
+    Off,
+    /// On the way to the target.
+    Cooling,
+    /// Within the tolerance of the target for the settle time.
+    Settled,
+    /// Going back to the temperature cooling started at, or the highest `TARGET_TEMP`
+    /// if that isn't known, after which the cooler is turned off.
+    WarmingUp,
+}
+
+/// Sent to clients every [`REPORT_INTERVAL`], and part of `GetStatus`.
+#[derive(Clone, Debug, Serialize)]
+pub struct CoolerStatus {
+    pub state: CoolerState,
+    /// Sensor temperature in °C.
+    pub temperature: f64,
+    /// `None` for cameras without a cooler.
+    pub power: Option<f64>,
+    /// Where the sensor is going, `None` unless cooling.
+    pub target: Option<f64>,
+    /// The `TARGET_TEMP` the camera holds right now, on the way to `target`.
+    pub setpoint: Option<f64>,
+    /// A capture is waiting for the temperature to settle.
+    pub capture_waiting: bool,
+}
+
+enum Mode {
+    Off,
+    Cooling { target: f64 },
+    WarmingUp,
+}
+
+/// Ramps the cooler of a camera, see the [module docs](self).
+pub struct Cooler {
+    config: CoolerConfig,
+    /// Range of `TARGET_TEMP`, `None` for cameras without a cooler.
+    target_range: Option<(i64, i64)>,
+    has_power: bool,
+    mode: Mode,
+    setpoint: f64,
+    /// The sensor temperature from before cooling started, where warming up stops.
+    ambient: f64,
+    /// When the current target was set.
+    cooling_since: Instant,
+    last_step: Instant,
+    settled_since: Option<Instant>,
+}
+
+impl Cooler {
+    /// `None` for cameras without a temperature sensor. A cooler left on by an earlier
+    /// run carries on at its target.
+    pub fn new(ccd: &dyn CameraBackend, controls: &[ControlCaps]) -> Result<Option<Self>> {
+        let has = |control_type| controls.iter().any(|c| c.control_type == control_type);
+        if !has(CONTROL_TYPE::TEMPERATURE) {
+            return Ok(None);
+        }
+        let target_range = controls
+            .iter()
+            .find(|c| c.control_type == CONTROL_TYPE::TARGET_TEMP && c.is_writable)
+            .filter(|_| has(CONTROL_TYPE::COOLER_ON))
+            .map(|c| (c.min_value, c.max_value));
+        let temperature = read_temperature(ccd)?;
+        let mut cooler = Self {
+            config: CoolerConfig::default(),
+            target_range,
+            has_power: has(CONTROL_TYPE::COOLER_POWER_PERC),
+            mode: Mode::Off,
+            setpoint: temperature,
+            ambient: temperature,
+            cooling_since: Instant::now(),
+            last_step: Instant::now(),
+            settled_since: None,
+        };
+        if target_range.is_some() && ccd.get_control_value(CONTROL_TYPE::COOLER_ON)?.0 != 0 {
+            let target = ccd.get_control_value(CONTROL_TYPE::TARGET_TEMP)?.0 as f64;
+            info!("Cooler is already on, holding {target} °C");
+            cooler.mode = Mode::Cooling { target };
+            cooler.setpoint = target;
+            // Unknown, so warming up goes as far as the camera allows or until the
+            // cooler idles
+            cooler.ambient = target_range.map_or(target, |(_, max)| max as f64);
+        }
+        Ok(Some(cooler))
+    }
+
+    pub fn set_config(&mut self, config: CoolerConfig) -> Result<()> {
+        if config.ramp_rate <= 0.
+            || config.tolerance < 0.
+            || config.settle_time < 0.
+            || config.settle_timeout < 0.
+        {
+            return Err(anyhow!("Invalid cooler config {config:?}"));
+        }
+        self.config = config;
+        Ok(())
+    }
+
+    /// Turns the cooler on and starts ramping to `target` °C, rounded to whole degrees
+    /// as the camera takes them.
+    pub fn cool_to(&mut self, ccd: &dyn CameraBackend, target: f64) -> Result<()> {
+        let (min, max) = self.target_range.ok_or(anyhow!("Camera has no cooler"))?;
+        let target = target.round().clamp(min as f64, max as f64);
+        if let Mode::Off = self.mode {
+            let temperature = read_temperature(ccd)?;
+            self.setpoint = temperature;
+            self.ambient = temperature;
+            ccd.set_control_value(CONTROL_TYPE::TARGET_TEMP, temperature.round() as i64, false)?;
+            ccd.set_control_value(CONTROL_TYPE::COOLER_ON, 1, false)?;
+        }
+        info!("Cooling to {target} °C at {} °C/min", self.config.ramp_rate);
+        self.mode = Mode::Cooling { target };
+        self.cooling_since = Instant::now();
+        self.settled_since = None;
+        Ok(())
+    }
+
+    /// Ramps back up to where cooling started, then turns the cooler off.
+    pub fn warm_up(&mut self) {
+        if let Mode::Cooling { .. } = self.mode {
+            info!("Warming up at {} °C/min", self.config.ramp_rate);
+            self.mode = Mode::WarmingUp;
+            self.settled_since = None;
+        }
+    }
+
+    pub fn is_off(&self) -> bool {
+        matches!(self.mode, Mode::Off)
+    }
+
+    /// Whether captures should wait before starting.
+    pub fn holds_captures(&self) -> bool {
+        self.config.wait_to_settle
+            && matches!(self.mode, Mode::Cooling { .. })
+            && self.state() != CoolerState::Settled
+            && !self.settle_timed_out()
+    }
+
+    /// Whether captures gave up waiting for the temperature to settle.
+    pub fn settle_timed_out(&self) -> bool {
+        self.cooling_since.elapsed() >= Duration::from_secs_f64(self.config.settle_timeout)
+    }
+
+    /// Where warming up stops, never above what the camera can hold.
+    fn warm_up_goal(&self) -> f64 {
+        match self.target_range {
+            Some((_, max)) => self.ambient.min(max as f64),
+            None => self.ambient,
+        }
+    }
+
+    fn state(&self) -> CoolerState {
+        match self.mode {
+            Mode::Off => CoolerState::Off,
+            Mode::Cooling { .. } => match self.settled_since {
+                Some(since)
+                    if since.elapsed() >= Duration::from_secs_f64(self.config.settle_time) =>
+                {
+                    CoolerState::Settled
+                }
+                _ => CoolerState::Cooling,
+            },
+            Mode::WarmingUp => CoolerState::WarmingUp,
+        }
+    }
+
+    pub fn status(&self, ccd: &dyn CameraBackend) -> Result<CoolerStatus> {
+        let power = match self.has_power {
+            true => Some(ccd.get_control_value(CONTROL_TYPE::COOLER_POWER_PERC)?.0 as f64),
+            false => None,
+        };
+        let (target, setpoint) = match self.mode {
+            Mode::Off => (None, None),
+            Mode::Cooling { target } => (Some(target), Some(self.setpoint.round())),
+            Mode::WarmingUp => (None, Some(self.setpoint.round())),
+        };
+        Ok(CoolerStatus {
+            state: self.state(),
+            temperature: read_temperature(ccd)?,
+            power,
+            target,
+            setpoint,
+            capture_waiting: false,
+        })
+    }
+
+    /// Moves the target on, returning the status every [`REPORT_INTERVAL`]. Called
+    /// from the controller's loop.
+    pub fn step(&mut self, ccd: &dyn CameraBackend) -> Result<Option<CoolerStatus>> {
+        let elapsed = self.last_step.elapsed();
+        if elapsed < REPORT_INTERVAL {
+            return Ok(None);
+        }
+        self.last_step = Instant::now();
+        let max_change = self.config.ramp_rate * elapsed.as_secs_f64() / 60.;
+        let goal = match self.mode {
+            Mode::Off => return self.status(ccd).map(Some),
+            Mode::Cooling { target } => target,
+            Mode::WarmingUp => self.warm_up_goal(),
+        };
+        let before = self.setpoint.round();
+        self.setpoint += (goal - self.setpoint).clamp(-max_change, max_change);
+        if self.setpoint.round() != before {
+            ccd.set_control_value(
+                CONTROL_TYPE::TARGET_TEMP,
+                self.setpoint.round() as i64,
+                false,
+            )?;
+            info!(
+                "Cooler target is {} °C, sensor is at {} °C",
+                self.setpoint.round(),
+                read_temperature(ccd)?
+            );
+        }
+
+        let status = self.status(ccd)?;
+        match self.mode {
+            Mode::Cooling { target } => {
+                let close = self.setpoint == target
+                    && (status.temperature - target).abs() <= self.config.tolerance;
+                match (close, self.settled_since) {
+                    (true, None) => self.settled_since = Some(Instant::now()),
+                    (false, Some(_)) => self.settled_since = None,
+                    _ => {}
+                }
+            }
+            Mode::WarmingUp => {
+                let idle = status.power.is_some_and(|power| power < IDLE_POWER);
+                if self.setpoint >= self.warm_up_goal() || idle {
+                    ccd.set_control_value(CONTROL_TYPE::COOLER_ON, 0, false)?;
+                    info!("Warmed up to {} °C, cooler is off", status.temperature);
+                    self.mode = Mode::Off;
+                    return self.status(ccd).map(Some);
+                }
+            }
+            Mode::Off => {}
+        }
+        Ok(Some(CoolerStatus {
+            state: self.state(),
+            ..status
+        }))
+    }
+}
+
+fn read_temperature(ccd: &dyn CameraBackend) -> Result<f64> {
+    Ok(ccd.get_control_value(CONTROL_TYPE::TEMPERATURE)?.0 as f64 / 10.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{SimulatedCamera, SimulatorConfig};
+
+    fn cooler(ccd: &SimulatedCamera) -> Cooler {
+        Cooler::new(ccd, &ccd.controls().unwrap()).unwrap().unwrap()
+    }
+
+    /// Steps as if `secs` passed since the last step.
+    fn step_after(cooler: &mut Cooler, ccd: &SimulatedCamera, secs: u64) -> CoolerStatus {
+        cooler.last_step = Instant::now() - Duration::from_secs(secs);
+        cooler.step(ccd).unwrap().unwrap()
+    }
+
+    fn target_temp(ccd: &SimulatedCamera) -> i64 {
+        ccd.get_control_value(CONTROL_TYPE::TARGET_TEMP).unwrap().0
+    }
+
+    #[test]
+    fn ramps_to_the_target() {
+        let ccd = SimulatedCamera::new(SimulatorConfig::default());
+        let mut cooler = cooler(&ccd);
+        assert!(cooler.is_off());
+        cooler.cool_to(&ccd, -50.4).unwrap();
+        assert_eq!(ccd.get_control_value(CONTROL_TYPE::COOLER_ON).unwrap().0, 1);
+        assert_eq!(target_temp(&ccd), 20);
+
+        let status = step_after(&mut cooler, &ccd, 60);
+        assert_eq!(status.state, CoolerState::Cooling);
+        // Clamped to what the camera can hold
+        assert_eq!(status.target, Some(-40.));
+        assert_eq!(status.setpoint, Some(17.));
+        assert_eq!(target_temp(&ccd), 17);
+
+        // Too soon for another step
+        assert!(cooler.step(&ccd).unwrap().is_none());
+        step_after(&mut cooler, &ccd, 120);
+        assert_eq!(target_temp(&ccd), 11);
+    }
+
+    #[test]
+    fn warms_up_to_where_cooling_started_then_turns_off() {
+        let ccd = SimulatedCamera::new(SimulatorConfig::default());
+        let mut cooler = cooler(&ccd);
+        cooler.cool_to(&ccd, 0.).unwrap();
+        step_after(&mut cooler, &ccd, 120);
+        assert_eq!(target_temp(&ccd), 14);
+
+        cooler.warm_up();
+        // Keep the cooler from looking idle so only the temperature ends warming up
+        cooler.has_power = false;
+        let status = step_after(&mut cooler, &ccd, 60);
+        assert_eq!(status.state, CoolerState::WarmingUp);
+        assert_eq!(target_temp(&ccd), 17);
+        let status = step_after(&mut cooler, &ccd, 90);
+        assert_eq!(status.state, CoolerState::Off);
+        assert_eq!(target_temp(&ccd), 20);
+        assert_eq!(ccd.get_control_value(CONTROL_TYPE::COOLER_ON).unwrap().0, 0);
+        assert!(cooler.is_off());
+    }
+
+    #[test]
+    fn warming_up_stops_at_the_highest_target() {
+        let ccd = SimulatedCamera::new(SimulatorConfig::default());
+        let mut cooler = cooler(&ccd);
+        cooler
+            .set_config(CoolerConfig {
+                ramp_rate: 100.,
+                ..Default::default()
+            })
+            .unwrap();
+        cooler.cool_to(&ccd, 0.).unwrap();
+        cooler.ambient = 45.;
+        cooler.has_power = false;
+        cooler.warm_up();
+        step_after(&mut cooler, &ccd, 60);
+        assert_eq!(target_temp(&ccd), 30);
+        assert!(cooler.is_off());
+    }
+
+    #[test]
+    fn captures_wait_until_the_settle_timeout() {
+        let ccd = SimulatedCamera::new(SimulatorConfig::default());
+        let mut cooler = cooler(&ccd);
+        cooler.cool_to(&ccd, -10.).unwrap();
+        assert!(!cooler.holds_captures());
+
+        cooler
+            .set_config(CoolerConfig {
+                wait_to_settle: true,
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(cooler.holds_captures());
+        assert!(!cooler.settle_timed_out());
+
+        cooler
+            .set_config(CoolerConfig {
+                wait_to_settle: true,
+                settle_timeout: 0.,
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(cooler.settle_timed_out());
+        assert!(!cooler.holds_captures());
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        let ccd = SimulatedCamera::new(SimulatorConfig::default());
+        let mut cooler = cooler(&ccd);
+        for config in [
+            CoolerConfig {
+                ramp_rate: 0.,
+                ..Default::default()
+            },
+            CoolerConfig {
+                settle_timeout: -1.,
+                ..Default::default()
+            },
+        ] {
+            assert!(cooler.set_config(config).is_err());
+        }
+    }
+
+    #[test]
+    fn cameras_without_a_cooler_only_report_temperature() {
+        let ccd = SimulatedCamera::new(SimulatorConfig {
+            cooler: false,
+            ..Default::default()
+        });
+        let mut cooler = cooler(&ccd);
+        assert!(cooler.cool_to(&ccd, 0.).is_err());
+        let status = step_after(&mut cooler, &ccd, 60);
+        assert_eq!(status.state, CoolerState::Off);
+        assert_eq!(status.temperature, 20.);
+        assert_eq!(status.power, None);
+    }
+}
@@ -0,0 +1,339 @@
+//! Debayering of RAW8 and RAW16 frames from colour cameras.
+//!
+//! The SDK's bayer pattern is that of the whole sensor, read without flipping. A frame
+//! starts wherever its ROI does and may be mirrored by the `FLIP` control, so
+//! [`CfaPattern::for_frame`] works out the pattern at the frame's top left corner
+//! before anything looks at its colours.
+//!
+//! Frames are debayered to interleaved BGR, the layout of the SDK's RGB24 frames, at
+//! the depth they came in.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    asi::{self, ROIFormat},
+    frame,
+};
+
+/// A 2x2 colour filter layout, named by its rows from the top left.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CfaPattern {
+    Rggb,
+    Bggr,
+    Grbg,
+    Gbrg,
+}
+
+impl CfaPattern {
+    pub fn from_asi(pattern: asi::ASI_BAYER_PATTERN) -> Self {
+        match pattern {
+            asi::ASI_BAYER_PATTERN_ASI_BAYER_BG => Self::Bggr,
+            asi::ASI_BAYER_PATTERN_ASI_BAYER_GR => Self::Grbg,
+            asi::ASI_BAYER_PATTERN_ASI_BAYER_GB => Self::Gbrg,
+            _ => Self::Rggb,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::Rggb, Self::Bggr, Self::Grbg, Self::Gbrg]
+            .into_iter()
+            .find(|pattern| pattern.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Rggb => "RGGB",
+            Self::Bggr => "BGGR",
+            Self::Grbg => "GRBG",
+            Self::Gbrg => "GBRG",
+        }
+    }
+
+    /// The pattern at the top left of frames read with `roi` and `flip`, the value of
+    /// the `FLIP` control, from a sensor with `pattern`.
+    ///
+    /// ROIs start at a binned pixel, and binned colour frames keep the sensor's
+    /// pattern. Frames are a whole number of 2x2 cells wide and high, so mirroring one
+    /// moves its corner to the other phase.
+    pub fn for_frame(pattern: asi::ASI_BAYER_PATTERN, roi: &ROIFormat, flip: i64) -> Self {
+        let flip_x = (flip & 1 != 0) as usize;
+        let flip_y = (flip & 2 != 0) as usize;
+        Self::from_asi(pattern).shifted(
+            (roi.start_x as usize & 1) ^ flip_x,
+            (roi.start_y as usize & 1) ^ flip_y,
+        )
+    }
+
+    /// Column and row of the red pixel in the top left 2x2 cell.
+    fn red(self) -> (usize, usize) {
+        match self {
+            Self::Rggb => (0, 0),
+            Self::Grbg => (1, 0),
+            Self::Gbrg => (0, 1),
+            Self::Bggr => (1, 1),
+        }
+    }
+
+    /// The pattern seen from `dx` columns and `dy` rows further in.
+    fn shifted(self, dx: usize, dy: usize) -> Self {
+        match ((self.red().0 ^ dx) & 1, (self.red().1 ^ dy) & 1) {
+            (0, 0) => Self::Rggb,
+            (1, 0) => Self::Grbg,
+            (0, 1) => Self::Gbrg,
+            _ => Self::Bggr,
+        }
+    }
+
+    fn site(self, x: usize, y: usize) -> Site {
+        let (red_x, red_y) = self.red();
+        match ((x & 1) == red_x, (y & 1) == red_y) {
+            (true, true) => Site::Red,
+            (false, false) => Site::Blue,
+            (false, true) => Site::GreenRedRow,
+            (true, false) => Site::GreenBlueRow,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DebayerAlgorithm {
+    /// Averages the nearest pixels of each colour. Fast enough for video previews, but
+    /// leaves colour fringes along sharp edges.
+    #[default]
+    Bilinear,
+    /// Malvar, He and Cutler's gradient corrected interpolation, which corrects the
+    /// bilinear estimate with the other colours to keep edges sharp.
+    Malvar,
+}
+
+/// Which colour a pixel has, and what is next to it.
+#[derive(Copy, Clone)]
+enum Site {
+    Red,
+    Blue,
+    /// Red to the left and right, blue above and below.
+    GreenRedRow,
+    GreenBlueRow,
+}
+
+trait Sample: Copy {
+    const WHITE: f32;
+    fn to_f32(self) -> f32;
+    fn from_f32(value: f32) -> Self;
+}
+
+impl Sample for u8 {
+    const WHITE: f32 = u8::MAX as f32;
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+    fn from_f32(value: f32) -> Self {
+        value.round().clamp(0., Self::WHITE) as u8
+    }
+}
+
+impl Sample for u16 {
+    const WHITE: f32 = u16::MAX as f32;
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+    fn from_f32(value: f32) -> Self {
+        value.round().clamp(0., Self::WHITE) as u16
+    }
+}
+
+/// Index `i` mirrored into `0..n` without repeating the edge, which keeps the colour
+/// of the pixel it lands on.
+fn reflect(i: isize, n: usize) -> usize {
+    let n = n as isize;
+    let i = if i < 0 { -i } else { i };
+    let i = if i >= n { 2 * n - 2 - i } else { i };
+    i.clamp(0, n - 1) as usize
+}
+
+/// `[red, green, blue]` at a pixel, from its neighbours `p(dx, dy)`.
+fn bilinear(p: impl Fn(isize, isize) -> f32, site: Site) -> [f32; 3] {
+    let c = p(0, 0);
+    let cross = (p(-1, 0) + p(1, 0) + p(0, -1) + p(0, 1)) / 4.;
+    let diagonal = (p(-1, -1) + p(1, -1) + p(-1, 1) + p(1, 1)) / 4.;
+    let row = (p(-1, 0) + p(1, 0)) / 2.;
+    let column = (p(0, -1) + p(0, 1)) / 2.;
+    match site {
+        Site::Red => [c, cross, diagonal],
+        Site::Blue => [diagonal, cross, c],
+        Site::GreenRedRow => [row, c, column],
+        Site::GreenBlueRow => [column, c, row],
+    }
+}
+
+/// Like [`bilinear`], with the 5x5 kernels from Malvar, He and Cutler, "High-quality
+/// linear interpolation for demosaicing of Bayer-patterned color images", 2004.
+fn malvar(p: impl Fn(isize, isize) -> f32, site: Site) -> [f32; 3] {
+    let c = p(0, 0);
+    let cross = p(-1, 0) + p(1, 0) + p(0, -1) + p(0, 1);
+    let diagonal = p(-1, -1) + p(1, -1) + p(-1, 1) + p(1, 1);
+    let row = p(-1, 0) + p(1, 0);
+    let column = p(0, -1) + p(0, 1);
+    let row2 = p(-2, 0) + p(2, 0);
+    let column2 = p(0, -2) + p(0, 2);
+    // Green at a red or blue pixel, and the other of red and blue
+    let green = (4. * c + 2. * cross - row2 - column2) / 8.;
+    let opposite = (6. * c + 2. * diagonal - 1.5 * (row2 + column2)) / 8.;
+    // Red or blue at a green pixel, from the row or from the column
+    let from_row = (5. * c + 4. * row - diagonal - row2 + 0.5 * column2) / 8.;
+    let from_column = (5. * c + 4. * column - diagonal - column2 + 0.5 * row2) / 8.;
+    match site {
+        Site::Red => [c, green, opposite],
+        Site::Blue => [opposite, green, c],
+        Site::GreenRedRow => [from_row, c, from_column],
+        Site::GreenBlueRow => [from_column, c, from_row],
+    }
+}
+
+fn debayer<T: Sample>(
+    raw: &[T],
+    w: usize,
+    h: usize,
+    pattern: CfaPattern,
+    algorithm: DebayerAlgorithm,
+) -> Vec<T> {
+    let mut out = Vec::with_capacity(w * h * 3);
+    if w == 0 || h == 0 {
+        return out;
+    }
+    // Columns two either side of each pixel, mirrored at the edges
+    let columns: Vec<[usize; 5]> = (0..w as isize)
+        .map(|x| [-2, -1, 0, 1, 2].map(|dx| reflect(x + dx, w)))
+        .collect();
+    for y in 0..h {
+        let rows = [-2, -1, 0, 1, 2].map(|dy| {
+            let row = reflect(y as isize + dy, h) * w;
+            &raw[row..row + w]
+        });
+        for (x, columns) in columns.iter().enumerate() {
+            let p =
+                |dx: isize, dy: isize| rows[(dy + 2) as usize][columns[(dx + 2) as usize]].to_f32();
+            let site = pattern.site(x, y);
+            let [r, g, b] = match algorithm {
+                DebayerAlgorithm::Bilinear => bilinear(p, site),
+                DebayerAlgorithm::Malvar => malvar(p, site),
+            };
+            out.extend([b, g, r].map(T::from_f32));
+        }
+    }
+    out
+}
+
+/// Debayers a `w` by `h` RAW8 frame to 8 bit BGR.
+pub fn debayer_u8(
+    frame: &[u8],
+    w: usize,
+    h: usize,
+    pattern: CfaPattern,
+    algorithm: DebayerAlgorithm,
+) -> Vec<u8> {
+    debayer(frame, w, h, pattern, algorithm)
+}
+
+/// Debayers a `w` by `h` RAW16 frame to 16 bit BGR.
+pub fn debayer_u16(
+    frame: &[u8],
+    w: usize,
+    h: usize,
+    pattern: CfaPattern,
+    algorithm: DebayerAlgorithm,
+) -> Vec<u16> {
+    let raw: Vec<u16> = frame::pixels_u16(frame).collect();
+    debayer(&raw, w, h, pattern, algorithm)
+}
+
+/// Debayers a RAW8 or RAW16 frame read with `roi` to BGR at the same depth, as bytes in
+/// the SDK's order. Other image types are returned as they are.
+pub fn debayer_frame(
+    frame: &[u8],
+    roi: &ROIFormat,
+    pattern: CfaPattern,
+    algorithm: DebayerAlgorithm,
+) -> Vec<u8> {
+    let (w, h) = (roi.width as usize, roi.height as usize);
+    match roi.img_type {
+        asi::IMG_TYPE::RAW8 => debayer_u8(frame, w, h, pattern, algorithm),
+        asi::IMG_TYPE::RAW16 => debayer_u16(frame, w, h, pattern, algorithm)
+            .into_iter()
+            .flat_map(u16::to_le_bytes)
+            .collect(),
+        asi::IMG_TYPE::RGB24 | asi::IMG_TYPE::Y8 => frame.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roi(start_x: i32, start_y: i32) -> ROIFormat {
+        ROIFormat {
+            width: 4,
+            height: 4,
+            bin: 1,
+            img_type: asi::IMG_TYPE::RAW8,
+            start_x,
+            start_y,
+        }
+    }
+
+    #[test]
+    fn for_frame_follows_roi_start() {
+        let rggb = asi::ASI_BAYER_PATTERN_ASI_BAYER_RG;
+        assert_eq!(CfaPattern::for_frame(rggb, &roi(0, 0), 0), CfaPattern::Rggb);
+        assert_eq!(CfaPattern::for_frame(rggb, &roi(1, 0), 0), CfaPattern::Grbg);
+        assert_eq!(CfaPattern::for_frame(rggb, &roi(0, 1), 0), CfaPattern::Gbrg);
+        assert_eq!(CfaPattern::for_frame(rggb, &roi(1, 1), 0), CfaPattern::Bggr);
+        assert_eq!(CfaPattern::for_frame(rggb, &roi(2, 4), 0), CfaPattern::Rggb);
+    }
+
+    #[test]
+    fn for_frame_follows_flip() {
+        let bggr = asi::ASI_BAYER_PATTERN_ASI_BAYER_BG;
+        assert_eq!(CfaPattern::for_frame(bggr, &roi(0, 0), 1), CfaPattern::Gbrg);
+        assert_eq!(CfaPattern::for_frame(bggr, &roi(0, 0), 2), CfaPattern::Grbg);
+        assert_eq!(CfaPattern::for_frame(bggr, &roi(0, 0), 3), CfaPattern::Rggb);
+        // Flipping an odd ROI start back puts the corner on the sensor's phase
+        assert_eq!(CfaPattern::for_frame(bggr, &roi(1, 1), 3), CfaPattern::Bggr);
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for pattern in [
+            CfaPattern::Rggb,
+            CfaPattern::Bggr,
+            CfaPattern::Grbg,
+            CfaPattern::Gbrg,
+        ] {
+            assert_eq!(CfaPattern::from_name(pattern.name()), Some(pattern));
+        }
+        assert_eq!(CfaPattern::from_name("RGB"), None);
+    }
+
+    #[test]
+    fn flat_frames_stay_flat() {
+        for algorithm in [DebayerAlgorithm::Bilinear, DebayerAlgorithm::Malvar] {
+            let bgr = debayer_u8(&[100; 16], 4, 4, CfaPattern::Rggb, algorithm);
+            assert_eq!(bgr, vec![100; 48], "{algorithm:?}");
+        }
+    }
+
+    #[test]
+    fn each_channel_comes_from_its_own_pixels() {
+        // Red pixels at 200, green at 100 and blue at 50
+        let raw: Vec<u8> = (0..16)
+            .map(|i| match (i % 4 % 2, i / 4 % 2) {
+                (0, 0) => 200,
+                (1, 1) => 50,
+                _ => 100,
+            })
+            .collect();
+        let bgr = debayer_u8(&raw, 4, 4, CfaPattern::Rggb, DebayerAlgorithm::Bilinear);
+        assert!(bgr.chunks(3).all(|pixel| pixel == [50, 100, 200]));
+    }
+}
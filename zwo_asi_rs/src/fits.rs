@@ -0,0 +1,413 @@
+//! FITS files for captures and exposures.
+//!
+//! Each file holds a single frame in the primary HDU, with the ROI, controls, sensor
+//! temperature and, from GPS cameras, the exposure's start and end time in its header
+//! (see [`FitsMetadata`]). RAW16 frames are stored as signed 16 bit integers with
+//! `BZERO = 32768`, the way FITS readers expect unsigned data. Debayered and RGB24
+//! frames become three planes, red first.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Timelike, Utc};
+
+use crate::{
+    asi::{FrameGps, ROIFormat, IMG_TYPE},
+    debayer::CfaPattern,
+};
+
+const BLOCK_SIZE: usize = 2880;
+const CARD_SIZE: usize = 80;
+
+/// Acquisition details recorded in the header of every frame.
+#[derive(Clone, Debug)]
+pub struct FitsMetadata {
+    pub instrument: String,
+    /// Start of the exposure.
+    pub date_obs: SystemTime,
+    pub exposure: Duration,
+    pub gain: i64,
+    pub offset: i64,
+    /// Sensor temperature in °C, for cameras that report one.
+    pub ccd_temp: Option<f64>,
+    /// Unbinned pixel size in µm.
+    pub pixel_size: f64,
+    /// The bayer pattern at the top left of the frame, see [`CfaPattern::for_frame`].
+    /// `None` for mono cameras.
+    pub cfa: Option<CfaPattern>,
+    /// The RAW8 or RAW16 frame was debayered to interleaved BGR at the same depth, see
+    /// [`crate::debayer`].
+    pub debayered: bool,
+    /// `IMAGETYP`, e.g. "Light Frame" or "Dark Frame".
+    pub frame_type: String,
+    pub roi: ROIFormat,
+    /// GPS times of the frame, which replace `date_obs` when present.
+    pub gps: Option<FrameGps>,
+}
+
+enum Value<'a> {
+    Logical(bool),
+    Int(i64),
+    Float(f64),
+    Str(&'a str),
+}
+
+/// Header cards, each padded to 80 characters.
+#[derive(Default)]
+struct Header {
+    bytes: Vec<u8>,
+}
+
+impl Header {
+    fn card(&mut self, key: &str, value: Value, comment: &str) {
+        // Fixed format: numbers and logicals right aligned to column 30, strings
+        // quoted starting at column 11 with at least 8 characters between the quotes
+        let value = match value {
+            Value::Logical(v) => format!("{:>20}", if v { "T" } else { "F" }),
+            Value::Int(v) => format!("{v:>20}"),
+            Value::Float(v) => format!("{:>20}", format!("{v:?}").replace('e', "E")),
+            Value::Str(v) => format!("'{:<8}'", v.replace('\'', "''")),
+        };
+        let mut card = format!("{key:<8}= {value}");
+        if !comment.is_empty() {
+            card.push_str(" / ");
+            card.push_str(comment);
+        }
+        self.push(&card);
+    }
+
+    fn push(&mut self, card: &str) {
+        let card: String = card
+            .chars()
+            .map(|c| if c.is_ascii() { c } else { '?' })
+            .take(CARD_SIZE)
+            .collect();
+        self.bytes
+            .extend_from_slice(format!("{card:<CARD_SIZE$}").as_bytes());
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.push("END");
+        pad_to_block(&mut self.bytes, b' ');
+        self.bytes
+    }
+}
+
+fn pad_to_block(bytes: &mut Vec<u8>, fill: u8) {
+    let len = bytes.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+    bytes.resize(len, fill);
+}
+
+/// Formats a time the way FITS expects it: UTC without a zone suffix.
+pub fn fits_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%Y-%m-%dT%H:%M:%S%.6f")
+        .to_string()
+}
+
+/// Like [`fits_date`], to the 0.1 µs the GPS receivers resolve.
+fn gps_date(time: DateTime<Utc>) -> String {
+    format!(
+        "{}.{:07}",
+        time.format("%Y-%m-%dT%H:%M:%S"),
+        time.nanosecond() / 100
+    )
+}
+
+fn header(meta: &FitsMetadata) -> Header {
+    let roi = &meta.roi;
+    let mut h = Header::default();
+    h.card("SIMPLE", Value::Logical(true), "conforms to FITS standard");
+    match roi.img_type {
+        IMG_TYPE::RAW16 => h.card("BITPIX", Value::Int(16), "16 bit unsigned via BZERO"),
+        _ => h.card("BITPIX", Value::Int(8), "8 bit unsigned"),
+    }
+    if roi.img_type == IMG_TYPE::RGB24 || meta.debayered {
+        h.card("NAXIS", Value::Int(3), "");
+        h.card("NAXIS1", Value::Int(roi.width as i64), "");
+        h.card("NAXIS2", Value::Int(roi.height as i64), "");
+        h.card("NAXIS3", Value::Int(3), "R, G and B planes");
+    } else {
+        h.card("NAXIS", Value::Int(2), "");
+        h.card("NAXIS1", Value::Int(roi.width as i64), "");
+        h.card("NAXIS2", Value::Int(roi.height as i64), "");
+    }
+    if roi.img_type == IMG_TYPE::RAW16 {
+        h.card("BZERO", Value::Float(32768.), "");
+        h.card("BSCALE", Value::Float(1.), "");
+    }
+    h.card(
+        "ROWORDER",
+        Value::Str("TOP-DOWN"),
+        "first row is the top of the image",
+    );
+
+    h.card("INSTRUME", Value::Str(&meta.instrument), "camera");
+    match &meta.gps {
+        Some(gps) => {
+            h.card(
+                "DATE-OBS",
+                Value::Str(&gps_date(gps.start.time)),
+                "UTC start of exposure from GPS",
+            );
+            h.card(
+                "DATE-END",
+                Value::Str(&gps_date(gps.end.time)),
+                "UTC end of exposure from GPS",
+            );
+        }
+        None => h.card(
+            "DATE-OBS",
+            Value::Str(&fits_date(meta.date_obs)),
+            "UTC start of exposure",
+        ),
+    }
+    h.card("IMAGETYP", Value::Str(&meta.frame_type), "");
+    h.card(
+        "EXPTIME",
+        Value::Float(meta.exposure.as_secs_f64()),
+        "exposure time in seconds",
+    );
+    h.card("GAIN", Value::Int(meta.gain), "camera gain");
+    h.card("OFFSET", Value::Int(meta.offset), "camera offset");
+    if let Some(temp) = meta.ccd_temp {
+        h.card("CCD-TEMP", Value::Float(temp), "sensor temperature in C");
+    }
+    if let Some(gps) = &meta.gps {
+        h.card(
+            "SITELAT",
+            Value::Float(gps.start.latitude),
+            "GPS latitude in degrees",
+        );
+        h.card(
+            "SITELONG",
+            Value::Float(gps.start.longitude),
+            "GPS longitude in degrees, east positive",
+        );
+        h.card(
+            "SITEELEV",
+            Value::Int(gps.start.altitude as i64),
+            "GPS altitude",
+        );
+        h.card(
+            "GPSSATS",
+            Value::Int(gps.start.satellites as i64),
+            "satellites in the GPS fix",
+        );
+    }
+    h.card("XBINNING", Value::Int(roi.bin as i64), "");
+    h.card("YBINNING", Value::Int(roi.bin as i64), "");
+    let pixel_size = meta.pixel_size * roi.bin as f64;
+    h.card(
+        "XPIXSZ",
+        Value::Float(pixel_size),
+        "binned pixel width in microns",
+    );
+    h.card(
+        "YPIXSZ",
+        Value::Float(pixel_size),
+        "binned pixel height in microns",
+    );
+    h.card(
+        "XORGSUBF",
+        Value::Int(roi.start_x as i64),
+        "ROI origin in binned pixels",
+    );
+    h.card(
+        "YORGSUBF",
+        Value::Int(roi.start_y as i64),
+        "ROI origin in binned pixels",
+    );
+    h.card(
+        "IMGFMT",
+        Value::Str(&format!("{:?}", roi.img_type)),
+        "ASI image type",
+    );
+    match (meta.cfa, roi.img_type) {
+        (Some(cfa), IMG_TYPE::RAW8 | IMG_TYPE::RAW16) if !meta.debayered => {
+            // Of this frame, so already shifted for the ROI and flip
+            h.card("BAYERPAT", Value::Str(cfa.name()), "");
+            h.card("XBAYROFF", Value::Int(0), "");
+            h.card("YBAYROFF", Value::Int(0), "");
+        }
+        (Some(_), _) if meta.debayered => {
+            h.card(
+                "DEBAYER",
+                Value::Logical(true),
+                "debayered from the bayer mosaic",
+            );
+        }
+        _ => {}
+    }
+    h
+}
+
+/// Converts a frame as returned by the SDK, or debayered from one, into FITS data
+/// order.
+fn data(frame: &[u8], img_type: IMG_TYPE, debayered: bool) -> Vec<u8> {
+    let mut data = match (img_type, debayered) {
+        (IMG_TYPE::RAW8 | IMG_TYPE::Y8, false) => frame.to_vec(),
+        // Big endian, stored signed with BZERO = 32768
+        (IMG_TYPE::RAW16, false) => crate::frame::pixels_u16(frame)
+            .flat_map(|v| (v ^ 0x8000).to_be_bytes())
+            .collect(),
+        // Interleaved BGR to planar RGB
+        (IMG_TYPE::RGB24, _) | (IMG_TYPE::RAW8 | IMG_TYPE::Y8, true) => [2, 1, 0]
+            .iter()
+            .flat_map(|channel| frame.iter().skip(*channel).step_by(3).copied())
+            .collect(),
+        (IMG_TYPE::RAW16, true) => {
+            let pixels: Vec<u16> = crate::frame::pixels_u16(frame).collect();
+            [2, 1, 0]
+                .iter()
+                .flat_map(|channel| pixels.iter().skip(*channel).step_by(3))
+                .flat_map(|v| (v ^ 0x8000).to_be_bytes())
+                .collect()
+        }
+    };
+    pad_to_block(&mut data, 0);
+    data
+}
+
+/// Writes a single frame to `path` as a FITS file.
+pub fn write_fits(path: impl AsRef<Path>, frame: &[u8], meta: &FitsMetadata) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write_fits_to(&mut file, frame, meta)?;
+    file.flush()
+}
+
+/// Writes a single frame as FITS to `out`, e.g. to send it over HTTP.
+pub fn write_fits_to(mut out: impl Write, frame: &[u8], meta: &FitsMetadata) -> io::Result<()> {
+    let roi = &meta.roi;
+    let planes = if meta.debayered { 3 } else { 1 };
+    let expected = (roi.width * roi.height * roi.img_type.bytes_per_pixel()) as usize * planes;
+    if frame.len() != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Frame is {} bytes, expected {expected}", frame.len()),
+        ));
+    }
+
+    out.write_all(&header(meta).finish())?;
+    out.write_all(&data(frame, roi.img_type, meta.debayered))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(img_type: IMG_TYPE) -> FitsMetadata {
+        FitsMetadata {
+            instrument: "ZWO ASI120MC-S".to_string(),
+            date_obs: SystemTime::UNIX_EPOCH + Duration::from_micros(1_700_000_000_250_000),
+            exposure: Duration::from_millis(1500),
+            gain: 120,
+            offset: 8,
+            ccd_temp: Some(-10.),
+            pixel_size: 3.75,
+            cfa: Some(CfaPattern::Rggb),
+            debayered: false,
+            frame_type: "Light Frame".to_string(),
+            roi: ROIFormat {
+                width: 4,
+                height: 2,
+                bin: 1,
+                img_type,
+                start_x: 0,
+                start_y: 0,
+            },
+            gps: None,
+        }
+    }
+
+    fn cards(bytes: &[u8]) -> Vec<String> {
+        bytes
+            .chunks(CARD_SIZE)
+            .map(|card| String::from_utf8(card.to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn cards_are_fixed_format() {
+        let mut h = Header::default();
+        h.card("SIMPLE", Value::Logical(true), "");
+        h.card("NAXIS1", Value::Int(1920), "width");
+        h.card("EXPTIME", Value::Float(1.5), "");
+        h.card("BAYERPAT", Value::Str("RG"), "");
+        h.card("OBSERVER", Value::Str("O'Neil"), "");
+        let cards = cards(&h.bytes);
+
+        assert!(cards.iter().all(|card| card.len() == CARD_SIZE));
+        assert_eq!(cards[0].trim_end(), format!("SIMPLE  = {:>20}", "T"));
+        assert_eq!(
+            cards[1].trim_end(),
+            format!("NAXIS1  = {:>20} / width", 1920)
+        );
+        assert_eq!(cards[2].trim_end(), format!("EXPTIME = {:>20}", "1.5"));
+        assert_eq!(cards[3].trim_end(), "BAYERPAT= 'RG      '");
+        assert_eq!(cards[4].trim_end(), "OBSERVER= 'O''Neil '");
+    }
+
+    #[test]
+    fn long_cards_are_cut_and_non_ascii_replaced() {
+        let mut h = Header::default();
+        h.card("COMMENT", Value::Str("x"), &"°".repeat(100));
+        let cards = cards(&h.bytes);
+        assert_eq!(cards.len(), 1);
+        assert!(cards[0].is_ascii());
+        assert!(cards[0].ends_with('?'));
+    }
+
+    #[test]
+    fn header_and_data_fill_whole_blocks() {
+        let frame: Vec<u8> = (0..8).collect();
+        let mut out = Vec::new();
+        write_fits_to(&mut out, &frame, &meta(IMG_TYPE::RAW8)).unwrap();
+        assert_eq!(out.len(), 2 * BLOCK_SIZE);
+
+        let header = cards(&out[..BLOCK_SIZE]);
+        let end = header
+            .iter()
+            .position(|card| card.trim_end() == "END")
+            .unwrap();
+        assert!(header[end + 1..].iter().all(|card| card.trim().is_empty()));
+        assert!(header
+            .iter()
+            .any(|card| card.starts_with("BAYERPAT= 'RGGB    '")));
+        assert!(header
+            .iter()
+            .any(|card| card.starts_with("DATE-OBS= '2023-11-14T22:13:20.250000'")));
+
+        assert_eq!(&out[BLOCK_SIZE..BLOCK_SIZE + 8], &frame[..]);
+        assert!(out[BLOCK_SIZE + 8..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn raw16_is_big_endian_with_bzero() {
+        let frame: Vec<u8> = [0u16, 1, 0x8000, 0xffff, 0, 0, 0, 0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let mut out = Vec::new();
+        write_fits_to(&mut out, &frame, &meta(IMG_TYPE::RAW16)).unwrap();
+        let header = cards(&out[..BLOCK_SIZE]);
+        assert!(header
+            .iter()
+            .any(|card| card.starts_with("BITPIX  =") && card.contains(" 16 ")));
+        assert_eq!(
+            &out[BLOCK_SIZE..BLOCK_SIZE + 8],
+            &[0x80, 0x00, 0x80, 0x01, 0x00, 0x00, 0x7f, 0xff]
+        );
+    }
+
+    #[test]
+    fn frames_of_the_wrong_size_are_rejected() {
+        let mut out = Vec::new();
+        assert!(write_fits_to(&mut out, &[0; 7], &meta(IMG_TYPE::RAW8)).is_err());
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+use serde::Serialize;
+
+use crate::asi::IMG_TYPE;
+
+/// Reads RAW16 frame data, which the SDK hands back as little endian pixels.
+pub fn pixels_u16(frame: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    frame
+        .chunks_exact(2)
+        .map(|pix| u16::from_le_bytes([pix[0], pix[1]]))
+}
+
+/// Summary of a frame at its full bit depth.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct FrameStats {
+    pub min: u16,
+    pub max: u16,
+    pub mean: f64,
+    pub median: u16,
+    /// Largest value the frame's image type can hold, 255 or 65535.
+    pub white: u16,
+}
+
+/// Count of every value in a frame, 256 bins for 8 bit data and 65536 for RAW16.
+///
+/// Colour frames are counted across all channels together.
+pub struct DepthHistogram {
+    counts: Vec<u32>,
+    total: u64,
+}
+
+impl DepthHistogram {
+    pub fn new(frame: &[u8], img_type: IMG_TYPE) -> Self {
+        let mut counts;
+        match img_type {
+            IMG_TYPE::RAW16 => {
+                counts = vec![0u32; 1 << 16];
+                for value in pixels_u16(frame) {
+                    counts[value as usize] += 1;
+                }
+            }
+            IMG_TYPE::RAW8 | IMG_TYPE::Y8 | IMG_TYPE::RGB24 => {
+                counts = vec![0u32; 1 << 8];
+                for value in frame {
+                    counts[*value as usize] += 1;
+                }
+            }
+        }
+        Self::from_counts(counts)
+    }
+
+    /// A histogram of already counted values, one bin per value.
+    pub fn from_counts(counts: Vec<u32>) -> Self {
+        let total = counts.iter().map(|c| *c as u64).sum();
+        Self { counts, total }
+    }
+
+    pub fn counts(&self) -> &[u32] {
+        &self.counts
+    }
+
+    /// The smallest value with at least `fraction` of the pixels at or below it.
+    pub fn percentile(&self, fraction: f64) -> u16 {
+        let target = (self.total as f64 * fraction.clamp(0., 1.)).ceil().max(1.) as u64;
+        let mut seen = 0;
+        for (value, count) in self.counts.iter().enumerate() {
+            seen += *count as u64;
+            if seen >= target {
+                return value as u16;
+            }
+        }
+        (self.counts.len() - 1) as u16
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        let min = self.counts.iter().position(|c| *c > 0).unwrap_or(0);
+        let max = self.counts.iter().rposition(|c| *c > 0).unwrap_or(0);
+        let sum: f64 = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(value, count)| value as f64 * *count as f64)
+            .sum();
+        FrameStats {
+            min: min as u16,
+            max: max as u16,
+            mean: if self.total > 0 {
+                sum / self.total as f64
+            } else {
+                0.
+            },
+            median: self.percentile(0.5),
+            white: (self.counts.len() - 1) as u16,
+        }
+    }
+}
+
+/// Linearly maps `black..=white` of a RAW16 frame onto 0..=255 for display.
+pub fn stretch_u16_to_u8(frame: &[u8], black: u16, white: u16) -> Vec<u8> {
+    let white = white.max(black.saturating_add(1));
+    let scale = 255. / (white - black) as f32;
+    let lut: Vec<u8> = (0..=u16::MAX)
+        .map(|value| ((value.clamp(black, white) - black) as f32 * scale).round() as u8)
+        .collect();
+    pixels_u16(frame).map(|value| lut[value as usize]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_finds_smallest_value_covering_fraction() {
+        // 10 pixels: four at 0, one at 3, five at 7
+        let mut counts = vec![0; 8];
+        counts[0] = 4;
+        counts[3] = 1;
+        counts[7] = 5;
+        let hist = DepthHistogram::from_counts(counts);
+        assert_eq!(hist.percentile(0.), 0);
+        assert_eq!(hist.percentile(0.4), 0);
+        assert_eq!(hist.percentile(0.41), 3);
+        assert_eq!(hist.percentile(0.5), 3);
+        assert_eq!(hist.percentile(0.51), 7);
+        assert_eq!(hist.percentile(1.), 7);
+        // Out of range fractions are clamped
+        assert_eq!(hist.percentile(-1.), 0);
+        assert_eq!(hist.percentile(2.), 7);
+    }
+
+    #[test]
+    fn percentile_of_empty_histogram_is_white() {
+        let hist = DepthHistogram::from_counts(vec![0; 256]);
+        assert_eq!(hist.percentile(0.5), 255);
+    }
+
+    #[test]
+    fn raw16_is_counted_little_endian() {
+        let frame: Vec<u8> = [1u16, 1000, 1000, 65535]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let stats = DepthHistogram::new(&frame, IMG_TYPE::RAW16).stats();
+        assert_eq!((stats.min, stats.max, stats.median), (1, 65535, 1000));
+        assert_eq!(stats.white, 65535);
+        assert_eq!(stats.mean, (1. + 1000. + 1000. + 65535.) / 4.);
+    }
+}
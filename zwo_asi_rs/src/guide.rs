@@ -0,0 +1,307 @@
+use std::{
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use tracing::error;
+
+use crate::{
+    asi::{ASI_ERROR, GUIDE_DIRECTION},
+    CameraBackend,
+};
+
+/// Longer guide pulses are rejected, so a typo can't send the mount running off.
+pub const MAX_PULSE: Duration = Duration::from_secs(10);
+/// How soon turning a direction off is tried again after it failed.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Starts a guide pulse on `ccd`, checking its length.
+///
+/// Clients call this themselves rather than going through the controller, whose loop
+/// may be busy reading or saving a frame. Guiding software times its corrections, so
+/// a pulse that starts late is as bad as a wrong one.
+pub fn pulse(
+    ccd: &dyn CameraBackend,
+    direction: GUIDE_DIRECTION,
+    duration: Duration,
+) -> Result<()> {
+    if duration > MAX_PULSE {
+        return Err(anyhow!(
+            "Guide pulse of {duration:?} is longer than {MAX_PULSE:?}"
+        ));
+    }
+    Ok(ccd.pulse_guide(direction, duration)?)
+}
+
+/// Ends every pulse running on `ccd`.
+pub fn stop(ccd: &dyn CameraBackend) -> Result<()> {
+    for direction in GUIDE_DIRECTION::ALL {
+        ccd.stop_pulse_guide(direction)?;
+    }
+    Ok(())
+}
+
+/// Turns a guide direction on or off.
+type SetGuide = dyn Fn(GUIDE_DIRECTION, bool) -> Result<(), ASI_ERROR> + Send + Sync;
+
+struct GuideState {
+    /// When each direction, indexed by `GUIDE_DIRECTION`, is due to be turned off.
+    deadlines: [Option<Instant>; 4],
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<GuideState>,
+    wake: Condvar,
+    set: Box<SetGuide>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, GuideState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Times ST4 guide pulses.
+///
+/// The SDK only has on and off calls, so a thread turns each direction off again once
+/// its pulse is over. Directions are only switched with the state locked, so a pulse
+/// can't be cut short by the timer of the one it replaced. A direction stays tracked
+/// until it was turned off, so a failed off call is tried again rather than leaving
+/// the mount moving. Everything still on is turned off when the guider is dropped.
+pub struct PulseGuider {
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl PulseGuider {
+    pub fn new(
+        set: impl Fn(GUIDE_DIRECTION, bool) -> Result<(), ASI_ERROR> + Send + Sync + 'static,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(GuideState {
+                deadlines: [None; 4],
+                shutdown: false,
+            }),
+            wake: Condvar::new(),
+            set: Box::new(set),
+        });
+        let worker_shared = shared.clone();
+        let worker = std::thread::spawn(move || Self::run(&worker_shared));
+        Self {
+            shared,
+            worker: Some(worker),
+        }
+    }
+
+    fn run(shared: &Shared) {
+        let mut state = shared.lock();
+        while !state.shutdown {
+            let now = Instant::now();
+            for direction in GUIDE_DIRECTION::ALL {
+                let deadline = &mut state.deadlines[direction as usize];
+                if deadline.is_some_and(|deadline| deadline <= now) {
+                    *deadline = match (shared.set)(direction, false) {
+                        Ok(()) => None,
+                        Err(e) => {
+                            error!("Ending {direction:?} guide pulse failed with {e:?}");
+                            Some(now + RETRY_INTERVAL)
+                        }
+                    };
+                }
+            }
+
+            state = match state.deadlines.iter().flatten().min() {
+                Some(next) => {
+                    let wait = next.saturating_duration_since(now);
+                    shared.wake.wait_timeout(state, wait).unwrap().0
+                }
+                None => shared.wake.wait(state).unwrap(),
+            };
+        }
+    }
+
+    /// Guides in `direction` for `duration`, returning straight away.
+    ///
+    /// A pulse already running in the same direction is extended or shortened to end
+    /// `duration` from now, and one in the opposite direction is stopped first.
+    pub fn pulse(&self, direction: GUIDE_DIRECTION, duration: Duration) -> Result<(), ASI_ERROR> {
+        let mut state = self.shared.lock();
+        let opposite = direction.opposite();
+        if state.deadlines[opposite as usize].is_some() {
+            (self.shared.set)(opposite, false)?;
+            state.deadlines[opposite as usize] = None;
+        }
+        if state.deadlines[direction as usize].is_none() {
+            (self.shared.set)(direction, true)?;
+        }
+        state.deadlines[direction as usize] = Some(Instant::now() + duration);
+        self.shared.wake.notify_one();
+        Ok(())
+    }
+
+    /// Ends the pulse in `direction` early. Does nothing if it isn't guiding. If
+    /// turning it off fails, the timer tries again straight away.
+    pub fn stop(&self, direction: GUIDE_DIRECTION) -> Result<(), ASI_ERROR> {
+        let mut state = self.shared.lock();
+        let deadline = &mut state.deadlines[direction as usize];
+        if deadline.is_some() {
+            if let Err(e) = (self.shared.set)(direction, false) {
+                *deadline = Some(Instant::now());
+                self.shared.wake.notify_one();
+                return Err(e);
+            }
+            *deadline = None;
+        }
+        Ok(())
+    }
+
+    /// Ends every running pulse, trying all directions even if one fails.
+    pub fn stop_all(&self) -> Result<(), ASI_ERROR> {
+        let mut result = Ok(());
+        for direction in GUIDE_DIRECTION::ALL {
+            if let Err(e) = self.stop(direction) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    pub fn is_guiding(&self, direction: GUIDE_DIRECTION) -> bool {
+        self.shared.lock().deadlines[direction as usize].is_some()
+    }
+}
+
+impl Drop for PulseGuider {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop_all() {
+            error!("Stopping guide pulses failed with {e:?}");
+        }
+        self.shared.lock().shutdown = true;
+        self.shared.wake.notify_one();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+    use GUIDE_DIRECTION::*;
+
+    type Calls = Arc<Mutex<Vec<(GUIDE_DIRECTION, bool)>>>;
+
+    /// A guider recording every on and off call, of which the first `failing_offs` off
+    /// calls fail.
+    fn recording(failing_offs: usize) -> (PulseGuider, Calls) {
+        let calls = Calls::default();
+        let recorded = calls.clone();
+        let failing = Mutex::new(failing_offs);
+        let guider = PulseGuider::new(move |direction, on| {
+            recorded.lock().unwrap().push((direction, on));
+            let mut failing = failing.lock().unwrap();
+            if !on && *failing > 0 {
+                *failing -= 1;
+                return Err(ASI_ERROR::GENERAL_ERROR);
+            }
+            Ok(())
+        });
+        (guider, calls)
+    }
+
+    fn calls(calls: &Calls) -> Vec<(GUIDE_DIRECTION, bool)> {
+        calls.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn pulses_end_on_their_own() {
+        let (guider, recorded) = recording(0);
+        guider.pulse(NORTH, Duration::from_millis(20)).unwrap();
+        assert!(guider.is_guiding(NORTH));
+        assert_eq!(calls(&recorded), [(NORTH, true)]);
+        sleep(Duration::from_millis(100));
+        assert!(!guider.is_guiding(NORTH));
+        assert_eq!(calls(&recorded), [(NORTH, true), (NORTH, false)]);
+    }
+
+    #[test]
+    fn pulses_in_the_same_direction_move_the_end() {
+        let (guider, recorded) = recording(0);
+        guider.pulse(EAST, Duration::from_millis(20)).unwrap();
+        guider.pulse(EAST, Duration::from_secs(10)).unwrap();
+        sleep(Duration::from_millis(100));
+        assert!(guider.is_guiding(EAST));
+        assert_eq!(calls(&recorded), [(EAST, true)]);
+    }
+
+    #[test]
+    fn opposite_pulses_cancel_each_other() {
+        let (guider, recorded) = recording(0);
+        guider.pulse(NORTH, Duration::from_secs(10)).unwrap();
+        guider.pulse(SOUTH, Duration::from_secs(10)).unwrap();
+        assert!(!guider.is_guiding(NORTH));
+        assert!(guider.is_guiding(SOUTH));
+        assert_eq!(
+            calls(&recorded),
+            [(NORTH, true), (NORTH, false), (SOUTH, true)]
+        );
+    }
+
+    #[test]
+    fn stop_all_ends_every_pulse() {
+        let (guider, recorded) = recording(0);
+        guider.pulse(NORTH, Duration::from_secs(10)).unwrap();
+        guider.pulse(WEST, Duration::from_secs(10)).unwrap();
+        guider.stop_all().unwrap();
+        assert!(GUIDE_DIRECTION::ALL
+            .iter()
+            .all(|direction| !guider.is_guiding(*direction)));
+        // Directions that weren't guiding aren't touched
+        assert_eq!(
+            calls(&recorded),
+            [(NORTH, true), (WEST, true), (NORTH, false), (WEST, false)]
+        );
+        drop(guider);
+        assert_eq!(calls(&recorded).len(), 4);
+    }
+
+    #[test]
+    fn failed_off_calls_are_retried() {
+        let (guider, recorded) = recording(2);
+        guider.pulse(NORTH, Duration::from_millis(10)).unwrap();
+        sleep(Duration::from_millis(50));
+        // Still on as far as anyone can tell
+        assert!(guider.is_guiding(NORTH));
+        sleep(RETRY_INTERVAL * 3);
+        assert!(!guider.is_guiding(NORTH));
+        assert_eq!(
+            calls(&recorded),
+            [
+                (NORTH, true),
+                (NORTH, false),
+                (NORTH, false),
+                (NORTH, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_stops_are_retried() {
+        let (guider, recorded) = recording(1);
+        guider.pulse(SOUTH, Duration::from_secs(10)).unwrap();
+        assert!(guider.stop(SOUTH).is_err());
+        sleep(Duration::from_millis(50));
+        assert!(!guider.is_guiding(SOUTH));
+        assert_eq!(
+            calls(&recorded),
+            [(SOUTH, true), (SOUTH, false), (SOUTH, false)]
+        );
+    }
+}
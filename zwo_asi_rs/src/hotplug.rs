@@ -0,0 +1,291 @@
+//! Cameras being plugged in and out while the server runs.
+//!
+//! [`DeviceMonitor`] polls a [`CameraSource`], the SDK or a fake one in tests, and
+//! reports cameras as they come and go, so each gets a controller only while it is
+//! connected.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    asi::{self, CameraInfo, ASI_ERROR},
+    Camera, CameraBackend,
+};
+
+/// Somewhere cameras can be plugged into and removed from.
+pub trait CameraSource: Send {
+    /// IDs of the cameras connected right now. An ID stays the same while its camera
+    /// is connected.
+    fn scan(&mut self) -> Vec<i32>;
+
+    /// Opens a camera returned by the last [`CameraSource::scan`].
+    fn open(&mut self, camera_id: i32) -> Result<Arc<dyn CameraBackend>, ASI_ERROR>;
+}
+
+/// The cameras the ZWO SDK can see.
+#[derive(Default)]
+pub struct SdkCameras {
+    cameras: HashMap<i32, Camera>,
+}
+
+impl CameraSource for SdkCameras {
+    fn scan(&mut self) -> Vec<i32> {
+        // The SDK only notices new cameras when they are counted again
+        let num_connected = unsafe { asi::get_num_of_connected_cameras() };
+        self.cameras = (0..num_connected)
+            .filter_map(|i| {
+                let mut info = asi::ASI_CAMERA_INFO::new();
+                unsafe { asi::get_camera_property(&mut info, i) }
+                    .map(|()| (info.CameraID, Camera::new(info)))
+                    .ok()
+            })
+            .collect();
+        self.cameras.keys().copied().collect()
+    }
+
+    fn open(&mut self, camera_id: i32) -> Result<Arc<dyn CameraBackend>, ASI_ERROR> {
+        let camera = self.cameras.get(&camera_id).ok_or(ASI_ERROR::INVALID_ID)?;
+        Ok(Arc::new(camera.open()?))
+    }
+}
+
+pub enum DeviceEvent {
+    /// A camera was plugged in and opened.
+    Added(Arc<dyn CameraBackend>),
+    /// The camera with this ID went away.
+    Removed(i32),
+}
+
+/// Tracks which cameras are connected by polling a [`CameraSource`].
+pub struct DeviceMonitor {
+    source: Box<dyn CameraSource>,
+    /// Only cameras matching one of these are opened, see [`CameraInfo::matches`].
+    /// Empty opens every camera.
+    selectors: Vec<String>,
+    open: HashSet<i32>,
+    /// Connected cameras that didn't match the selectors.
+    ignored: HashSet<i32>,
+}
+
+impl DeviceMonitor {
+    pub fn new(source: impl CameraSource + 'static, selectors: Vec<String>) -> Self {
+        Self {
+            source: Box::new(source),
+            selectors,
+            open: HashSet::new(),
+            ignored: HashSet::new(),
+        }
+    }
+
+    /// What changed since the last poll. The first poll adds every connected camera.
+    pub fn poll(&mut self) -> Vec<DeviceEvent> {
+        let connected: HashSet<i32> = self.source.scan().into_iter().collect();
+        let mut events = Vec::new();
+
+        let removed: Vec<i32> = self.open.difference(&connected).copied().collect();
+        for camera_id in removed {
+            info!("Camera {camera_id} was removed");
+            self.open.remove(&camera_id);
+            events.push(DeviceEvent::Removed(camera_id));
+        }
+        self.ignored
+            .retain(|camera_id| connected.contains(camera_id));
+
+        let mut added: Vec<i32> = connected
+            .into_iter()
+            .filter(|camera_id| !self.open.contains(camera_id) && !self.ignored.contains(camera_id))
+            .collect();
+        added.sort();
+        for camera_id in added {
+            // Serials and custom IDs can only be read once the camera is open
+            let camera = match self.source.open(camera_id) {
+                Ok(camera) => camera,
+                Err(e) => {
+                    // Tried again on the next poll, the camera may still be starting up
+                    warn!("Opening camera {camera_id} failed with {e}");
+                    continue;
+                }
+            };
+            let info = camera.camera_info();
+            if !self.select(&info) {
+                info!("Ignoring {} with serial {:?}", info.name, info.serial);
+                self.ignored.insert(camera_id);
+                continue;
+            }
+            info!(
+                "Camera {camera_id} connected: {} with serial {:?}",
+                info.name, info.serial
+            );
+            self.open.insert(camera_id);
+            events.push(DeviceEvent::Added(camera));
+        }
+        events
+    }
+
+    /// Whether to open the camera with `info`. A selector that only picks it by SDK
+    /// index is pinned to the camera from then on, since the index goes to whichever
+    /// camera is plugged in next once it is removed.
+    fn select(&mut self, info: &CameraInfo) -> bool {
+        if self.selectors.is_empty() || self.selectors.iter().any(|s| info.matches(s)) {
+            return true;
+        }
+        match self.selectors.iter_mut().find(|s| info.matches_index(s)) {
+            Some(selector) => {
+                info!(
+                    "Selecting camera {selector} by {} from now on",
+                    info.stable_selector()
+                );
+                *selector = info.stable_selector();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops tracking a camera whose controller gave up on it, so the next poll opens
+    /// it again if it is still connected.
+    pub fn forget(&mut self, camera_id: i32) {
+        self.open.remove(&camera_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::simulator::{SimulatedCamera, SimulatorConfig};
+
+    /// A USB bus to plug simulated cameras into, by SDK ID and serial.
+    #[derive(Clone, Default)]
+    struct FakeBus {
+        cameras: Arc<Mutex<Vec<(i32, &'static str)>>>,
+        /// Cameras that fail to open, like ones still starting up.
+        failing: Arc<Mutex<HashSet<i32>>>,
+    }
+
+    impl FakeBus {
+        fn plug(&self, camera_id: i32, serial: &'static str) {
+            self.cameras.lock().unwrap().push((camera_id, serial));
+        }
+
+        fn unplug(&self, camera_id: i32) {
+            self.cameras
+                .lock()
+                .unwrap()
+                .retain(|(id, _)| *id != camera_id);
+        }
+    }
+
+    impl CameraSource for FakeBus {
+        fn scan(&mut self) -> Vec<i32> {
+            self.cameras
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(id, _)| *id)
+                .collect()
+        }
+
+        fn open(&mut self, camera_id: i32) -> Result<Arc<dyn CameraBackend>, ASI_ERROR> {
+            if self.failing.lock().unwrap().contains(&camera_id) {
+                return Err(ASI_ERROR::CAMERA_CLOSED);
+            }
+            let cameras = self.cameras.lock().unwrap();
+            let (_, serial) = cameras
+                .iter()
+                .find(|(id, _)| *id == camera_id)
+                .ok_or(ASI_ERROR::INVALID_ID)?;
+            Ok(Arc::new(SimulatedCamera::new(SimulatorConfig {
+                camera_id,
+                serial: serial.to_string(),
+                ..Default::default()
+            })))
+        }
+    }
+
+    /// The events of a poll as serials for added cameras and IDs for removed ones.
+    fn poll(monitor: &mut DeviceMonitor) -> (Vec<String>, Vec<i32>) {
+        let (mut added, mut removed) = (Vec::new(), Vec::new());
+        for event in monitor.poll() {
+            match event {
+                DeviceEvent::Added(camera) => added.push(camera.camera_info().serial.unwrap()),
+                DeviceEvent::Removed(camera_id) => removed.push(camera_id),
+            }
+        }
+        (added, removed)
+    }
+
+    #[test]
+    fn reports_cameras_as_they_come_and_go() {
+        let bus = FakeBus::default();
+        bus.plug(0, "aa");
+        let mut monitor = DeviceMonitor::new(bus.clone(), Vec::new());
+        assert_eq!(poll(&mut monitor), (vec!["aa".to_string()], vec![]));
+        assert_eq!(poll(&mut monitor), (vec![], vec![]));
+
+        bus.plug(1, "bb");
+        assert_eq!(poll(&mut monitor), (vec!["bb".to_string()], vec![]));
+        bus.unplug(0);
+        assert_eq!(poll(&mut monitor), (vec![], vec![0]));
+
+        // Replugged, the SDK may hand out the ID of another camera
+        bus.unplug(1);
+        bus.plug(0, "bb");
+        assert_eq!(poll(&mut monitor), (vec!["bb".to_string()], vec![1]));
+    }
+
+    #[test]
+    fn retries_cameras_that_failed_to_open() {
+        let bus = FakeBus::default();
+        bus.plug(0, "aa");
+        bus.failing.lock().unwrap().insert(0);
+        let mut monitor = DeviceMonitor::new(bus.clone(), Vec::new());
+        assert_eq!(poll(&mut monitor), (vec![], vec![]));
+        bus.failing.lock().unwrap().clear();
+        assert_eq!(poll(&mut monitor), (vec!["aa".to_string()], vec![]));
+
+        // Forgotten cameras are opened again while they are still connected
+        monitor.forget(0);
+        assert_eq!(poll(&mut monitor), (vec!["aa".to_string()], vec![]));
+    }
+
+    #[test]
+    fn only_opens_selected_cameras() {
+        let bus = FakeBus::default();
+        bus.plug(0, "aa");
+        bus.plug(1, "bb");
+        let mut monitor = DeviceMonitor::new(bus.clone(), vec!["BB".to_string()]);
+        assert_eq!(poll(&mut monitor), (vec!["bb".to_string()], vec![]));
+
+        // Ignored cameras aren't reported when they go away, but are looked at again
+        // when they come back
+        bus.unplug(0);
+        assert_eq!(poll(&mut monitor), (vec![], vec![]));
+        bus.unplug(1);
+        bus.plug(0, "bb");
+        assert_eq!(poll(&mut monitor), (vec!["bb".to_string()], vec![1]));
+    }
+
+    #[test]
+    fn cameras_picked_by_index_stay_picked_after_a_replug() {
+        let bus = FakeBus::default();
+        bus.plug(0, "aa");
+        bus.plug(1, "bb");
+        let mut monitor = DeviceMonitor::new(bus.clone(), vec!["1".to_string()]);
+        assert_eq!(poll(&mut monitor), (vec!["bb".to_string()], vec![]));
+
+        // The SDK hands the index to the other camera, which stays ignored
+        bus.unplug(0);
+        bus.unplug(1);
+        assert_eq!(poll(&mut monitor), (vec![], vec![1]));
+        bus.plug(1, "aa");
+        assert_eq!(poll(&mut monitor), (vec![], vec![]));
+        bus.plug(0, "bb");
+        assert_eq!(poll(&mut monitor), (vec!["bb".to_string()], vec![]));
+    }
+}
@@ -0,0 +1,1637 @@
+//! An INDI server, so INDI clients like KStars/Ekos can use the cameras while the web
+//! preview runs.
+//!
+//! Every camera the server has seen is a CCD device named after it, with the
+//! standard `CONNECTION`, `CCD_EXPOSURE`, `CCD_FRAME`, `CCD_BINNING`,
+//! `CCD_TEMPERATURE` and `CCD_CONTROLS` properties, plus `CCD_COOLER` and
+//! `CCD_COOLER_POWER` for cooled cameras. As with [`crate::alpaca`],
+//! properties are read straight from the camera while changes go through its
+//! controller. Finished exposures are sent as FITS in the `CCD1` BLOB to clients that
+//! enabled BLOBs for the device.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::Write as _,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use quick_xml::{
+    escape::escape,
+    events::{BytesStart, Event},
+    Reader,
+};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc},
+};
+use tracing::{info, warn};
+
+use crate::{
+    asi::{CameraInfo, CONTROL_TYPE, GUIDE_DIRECTION},
+    camera_controller::{ControlMessages, ExposureState, FrameFormat, Roi},
+    cooler::CoolerState,
+    protocol::Reply,
+    server::{AppState, CameraSlot},
+};
+
+/// The port INDI clients connect to by default.
+pub const PORT: u16 = 7624;
+
+/// How often the temperature of connected cameras is reported.
+const TEMPERATURE_INTERVAL: Duration = Duration::from_secs(5);
+/// How often running exposures are checked on.
+const EXPOSURE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// `CCD_INTERFACE` and `GUIDER_INTERFACE` of `DRIVER_INFO`.
+const CCD_INTERFACE: i32 = 2;
+const GUIDER_INTERFACE: i32 = 4;
+
+/// Properties every device has, connected or not.
+const BASE_PROPERTIES: [&str; 2] = ["CONNECTION", "DRIVER_INFO"];
+/// Properties of connected devices. Some only exist if the camera supports them.
+const DEVICE_PROPERTIES: [&str; 14] = [
+    "CCD_INFO",
+    "CCD_EXPOSURE",
+    "CCD_ABORT_EXPOSURE",
+    "CCD_FRAME",
+    "CCD_BINNING",
+    "CCD_FRAME_TYPE",
+    "CCD_TEMPERATURE",
+    "CCD_COOLER",
+    "CCD_COOLER_POWER",
+    "CCD_CONTROLS",
+    "CCD_CFA",
+    "CCD1",
+    "TELESCOPE_TIMED_GUIDE_NS",
+    "TELESCOPE_TIMED_GUIDE_WE",
+];
+
+/// Controls in `CCD_CONTROLS`, by the names the SDK gives them.
+const CONTROLS: [(CONTROL_TYPE, &str); 4] = [
+    (CONTROL_TYPE::GAIN, "Gain"),
+    (CONTROL_TYPE::OFFSET, "Offset"),
+    (CONTROL_TYPE::WB_R, "WB_R"),
+    (CONTROL_TYPE::WB_B, "WB_B"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PropertyState {
+    Idle,
+    Ok,
+    Busy,
+    Alert,
+}
+
+impl PropertyState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Idle => "Idle",
+            Self::Ok => "Ok",
+            Self::Busy => "Busy",
+            Self::Alert => "Alert",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Number,
+    Switch,
+    Text,
+    Blob,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Number => "Number",
+            Self::Switch => "Switch",
+            Self::Text => "Text",
+            Self::Blob => "BLOB",
+        }
+    }
+}
+
+enum Value {
+    Number {
+        value: f64,
+        min: f64,
+        max: f64,
+        step: f64,
+        format: &'static str,
+    },
+    Switch(bool),
+    Text(String),
+    Blob,
+}
+
+struct Element {
+    name: String,
+    label: String,
+    value: Value,
+}
+
+fn number(name: &str, label: &str, format: &'static str, range: (f64, f64), value: f64) -> Element {
+    Element {
+        name: name.to_string(),
+        label: label.to_string(),
+        value: Value::Number {
+            value,
+            min: range.0,
+            max: range.1,
+            step: 1.,
+            format,
+        },
+    }
+}
+
+fn switch(name: &str, label: &str, on: bool) -> Element {
+    Element {
+        name: name.to_string(),
+        label: label.to_string(),
+        value: Value::Switch(on),
+    }
+}
+
+fn text(name: &str, label: &str, value: impl ToString) -> Element {
+    Element {
+        name: name.to_string(),
+        label: label.to_string(),
+        value: Value::Text(value.to_string()),
+    }
+}
+
+/// A property vector of a device, with its current values.
+struct Property {
+    name: &'static str,
+    label: &'static str,
+    group: &'static str,
+    kind: Kind,
+    writable: bool,
+    /// Only used by switches.
+    rule: &'static str,
+    elements: Vec<Element>,
+}
+
+impl Property {
+    fn new(
+        name: &'static str,
+        label: &'static str,
+        group: &'static str,
+        kind: Kind,
+        writable: bool,
+        elements: Vec<Element>,
+    ) -> Self {
+        Self {
+            name,
+            label,
+            group,
+            kind,
+            writable,
+            rule: "OneOfMany",
+            elements,
+        }
+    }
+
+    fn rule(self, rule: &'static str) -> Self {
+        Self { rule, ..self }
+    }
+
+    /// `defXVector`, announcing the property to clients.
+    fn def(&self, device: &str) -> String {
+        let kind = self.kind.as_str();
+        let mut attrs = vec![
+            ("device", device),
+            ("name", self.name),
+            ("label", self.label),
+            ("group", self.group),
+            ("state", PropertyState::Idle.as_str()),
+            ("perm", if self.writable { "rw" } else { "ro" }),
+            ("timeout", "60"),
+        ];
+        if self.kind == Kind::Switch {
+            attrs.push(("rule", self.rule));
+        }
+        let timestamp = timestamp();
+        attrs.push(("timestamp", &timestamp));
+
+        let mut out = String::new();
+        tag(&mut out, &format!("def{kind}Vector"), &attrs, ">");
+        for element in &self.elements {
+            let name = format!("def{kind}");
+            let mut attrs = vec![
+                ("name", element.name.as_str()),
+                ("label", element.label.as_str()),
+            ];
+            let (min, max, step);
+            if let Value::Number {
+                min: lo,
+                max: hi,
+                step: s,
+                format,
+                ..
+            } = element.value
+            {
+                (min, max, step) = (lo.to_string(), hi.to_string(), s.to_string());
+                attrs.extend([
+                    ("format", format),
+                    ("min", &min),
+                    ("max", &max),
+                    ("step", &step),
+                ]);
+            }
+            if let Value::Blob = element.value {
+                tag(&mut out, &name, &attrs, "/>");
+            } else {
+                tag(&mut out, &name, &attrs, ">");
+                write!(out, "{}</{name}>", escape(element.value_text())).unwrap();
+            }
+        }
+        writeln!(out, "</def{kind}Vector>").unwrap();
+        out
+    }
+
+    /// `setXVector`, updating the values and state of the property.
+    fn set(&self, device: &str, state: PropertyState, message: Option<&str>) -> String {
+        let kind = self.kind.as_str();
+        let timestamp = timestamp();
+        let mut attrs = vec![
+            ("device", device),
+            ("name", self.name),
+            ("state", state.as_str()),
+            ("timeout", "60"),
+            ("timestamp", &timestamp),
+        ];
+        if let Some(message) = message {
+            attrs.push(("message", message));
+        }
+        let mut out = String::new();
+        tag(&mut out, &format!("set{kind}Vector"), &attrs, ">");
+        for element in &self.elements {
+            tag(
+                &mut out,
+                &format!("one{kind}"),
+                &[("name", &element.name)],
+                ">",
+            );
+            write!(out, "{}</one{kind}>", escape(element.value_text())).unwrap();
+        }
+        writeln!(out, "</set{kind}Vector>").unwrap();
+        out
+    }
+}
+
+impl Element {
+    fn value_text(&self) -> Cow<'_, str> {
+        match &self.value {
+            Value::Number { value, .. } => value.to_string().into(),
+            Value::Switch(true) => "On".into(),
+            Value::Switch(false) => "Off".into(),
+            Value::Text(text) => text.into(),
+            Value::Blob => "".into(),
+        }
+    }
+}
+
+/// Writes the start of element `name`, ended by `end`, which is `>` or `/>`.
+fn tag(out: &mut String, name: &str, attrs: &[(&str, &str)], end: &str) {
+    write!(out, "<{name}").unwrap();
+    for (attr, value) in attrs {
+        write!(out, " {attr}=\"{}\"", escape(*value)).unwrap();
+    }
+    out.push_str(end);
+}
+
+fn timestamp() -> String {
+    chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S").to_string()
+}
+
+fn message_xml(device: &str, message: &str) -> String {
+    let mut out = String::new();
+    let timestamp = timestamp();
+    let attrs = [
+        ("device", device),
+        ("timestamp", &timestamp),
+        ("message", message),
+    ];
+    tag(&mut out, "message", &attrs, "/>\n");
+    out
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum FrameType {
+    #[default]
+    Light,
+    Bias,
+    Dark,
+    Flat,
+}
+
+impl FrameType {
+    const ALL: [(Self, &'static str, &'static str); 4] = [
+        (Self::Light, "FRAME_LIGHT", "Light"),
+        (Self::Bias, "FRAME_BIAS", "Bias"),
+        (Self::Dark, "FRAME_DARK", "Dark"),
+        (Self::Flat, "FRAME_FLAT", "Flat"),
+    ];
+}
+
+/// What clients set up on a device, shared by all of them like INDI drivers do.
+struct Device {
+    connected: bool,
+    /// The sub-frame in unbinned pixels.
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    bin: i32,
+    frame_type: FrameType,
+    /// Of the running exposure, rounded up to whole seconds.
+    remaining: f64,
+    temperature: Option<f64>,
+    /// Last `CCD_TEMPERATURE` set while the cooler was off, used once it is turned on.
+    target_temperature: Option<f64>,
+    cooler_state: Option<CoolerState>,
+    cooler_power: Option<f64>,
+}
+
+impl Device {
+    fn new(info: &CameraInfo) -> Self {
+        Self {
+            connected: false,
+            x: 0,
+            y: 0,
+            width: info.max_width,
+            height: info.max_height,
+            bin: 1,
+            frame_type: FrameType::default(),
+            remaining: 0.,
+            temperature: None,
+            target_temperature: None,
+            cooler_state: None,
+            cooler_power: None,
+        }
+    }
+
+    /// The sub-frame in binned pixels, shrunk to a size the SDK takes.
+    fn roi(&self) -> Roi {
+        let w = self.width / self.bin;
+        let h = self.height / self.bin;
+        Roi {
+            x: self.x / self.bin,
+            y: self.y / self.bin,
+            w: w - w % 8,
+            h: h - h % 2,
+            bin: self.bin,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum BlobMode {
+    #[default]
+    Never,
+    Also,
+    Only,
+}
+
+/// XML for every client, BLOBs only going to those that enabled them for `device`.
+#[derive(Clone)]
+struct Outgoing {
+    device: String,
+    blob: bool,
+    xml: Arc<str>,
+}
+
+enum ToClient {
+    Xml(Arc<str>),
+    EnableBlob(String, BlobMode),
+}
+
+/// A message from a client: `getProperties`, `enableBLOB` or one of the `newXVector`s
+/// with the values of their elements.
+#[derive(Debug, Default)]
+struct Message {
+    tag: String,
+    device: Option<String>,
+    name: Option<String>,
+    values: Vec<(String, String)>,
+    text: String,
+}
+
+fn attribute(e: &BytesStart, name: &str) -> Option<String> {
+    e.try_get_attribute(name)
+        .ok()
+        .flatten()
+        .and_then(|attr| attr.unescape_value().ok())
+        .map(Cow::into_owned)
+}
+
+impl Message {
+    fn new(e: &BytesStart) -> Self {
+        Self {
+            tag: String::from_utf8_lossy(e.name().as_ref()).into_owned(),
+            device: attribute(e, "device"),
+            name: attribute(e, "name"),
+            ..Default::default()
+        }
+    }
+
+    fn number(&self, name: &str) -> Option<Result<f64, String>> {
+        let (_, value) = self.values.iter().find(|(element, _)| element == name)?;
+        Some(
+            value
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid {name} {value}")),
+        )
+    }
+
+    /// The names of the switches turned on.
+    fn switches_on(&self) -> impl Iterator<Item = &str> {
+        self.values
+            .iter()
+            .filter(|(_, value)| value.trim() == "On")
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+#[derive(Clone)]
+struct Indi {
+    app: AppState,
+    /// By camera index.
+    devices: Arc<Mutex<HashMap<usize, Device>>>,
+    out: broadcast::Sender<Outgoing>,
+}
+
+/// Serves the cameras of `app` to INDI clients on `port`.
+pub async fn serve(app: AppState, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    info!("Serving INDI on port {port}");
+    let (out, _) = broadcast::channel(64);
+    let indi = Indi {
+        app,
+        devices: Arc::new(Mutex::new(HashMap::new())),
+        out,
+    };
+    tokio::spawn(indi.clone().watch_cameras());
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        info!("INDI client {addr} connected");
+        tokio::spawn(indi.clone().handle_client(stream, addr));
+    }
+}
+
+/// INDI device names, which must be unique. Cameras that share a name with an
+/// earlier one get their number appended.
+fn device_name(cameras: &[CameraSlot], index: usize) -> String {
+    let name = &cameras[index].info.name;
+    if cameras[..index].iter().any(|slot| &slot.info.name == name) {
+        format!("{name} {}", index + 1)
+    } else {
+        name.clone()
+    }
+}
+
+impl Indi {
+    fn with_device<T>(
+        &self,
+        index: usize,
+        info: &CameraInfo,
+        f: impl FnOnce(&mut Device) -> T,
+    ) -> T {
+        let mut devices = self.devices.lock().unwrap();
+        f(devices.entry(index).or_insert_with(|| Device::new(info)))
+    }
+
+    /// Every camera with its device name.
+    fn devices(&self) -> Vec<(usize, String, CameraSlot)> {
+        let cameras = self.app.cameras.read().unwrap();
+        (0..cameras.len())
+            .map(|index| (index, device_name(&cameras, index), cameras[index].clone()))
+            .collect()
+    }
+
+    fn find(&self, device: &str) -> Option<(usize, String, CameraSlot)> {
+        self.devices()
+            .into_iter()
+            .find(|(_, name, _)| name == device)
+    }
+
+    fn broadcast(&self, device: &str, xml: String) {
+        let _ = self.out.send(Outgoing {
+            device: device.to_string(),
+            blob: false,
+            xml: xml.into(),
+        });
+    }
+
+    fn message(&self, device: &str, message: &str) {
+        self.broadcast(device, message_xml(device, message));
+    }
+
+    /// Sends the current values of property `name` to every client.
+    fn set(&self, index: usize, device: &str, slot: &CameraSlot, name: &str, state: PropertyState) {
+        self.set_with_message(index, device, slot, name, state, None);
+    }
+
+    fn set_with_message(
+        &self,
+        index: usize,
+        device: &str,
+        slot: &CameraSlot,
+        name: &str,
+        state: PropertyState,
+        message: Option<&str>,
+    ) {
+        if let Some(property) = self.property(index, slot, name) {
+            self.broadcast(device, property.set(device, state, message));
+        }
+    }
+
+    /// Property `name` of camera `index` with its current values, `None` if the camera
+    /// doesn't have it.
+    fn property(&self, index: usize, slot: &CameraSlot, name: &str) -> Option<Property> {
+        let info = &slot.info;
+        let camera = slot.camera.as_deref();
+        let caps = |control_type| {
+            camera?
+                .controls()
+                .ok()?
+                .into_iter()
+                .find(|caps| caps.control_type == control_type)
+        };
+        let (connected, roi_x, roi_y, width, height, bin, frame_type, remaining, temperature) =
+            self.with_device(index, info, |d| {
+                (
+                    d.connected,
+                    d.x,
+                    d.y,
+                    d.width,
+                    d.height,
+                    d.bin,
+                    d.frame_type,
+                    d.remaining,
+                    d.temperature,
+                )
+            });
+        let (max_x, max_y) = (info.max_width as f64, info.max_height as f64);
+
+        let property = match name {
+            "CONNECTION" => Property::new(
+                "CONNECTION",
+                "Connection",
+                "Main Control",
+                Kind::Switch,
+                true,
+                vec![
+                    switch("CONNECT", "Connect", connected),
+                    switch("DISCONNECT", "Disconnect", !connected),
+                ],
+            ),
+            "DRIVER_INFO" => {
+                let interface = match info.st4_port {
+                    true => CCD_INTERFACE | GUIDER_INTERFACE,
+                    false => CCD_INTERFACE,
+                };
+                Property::new(
+                    "DRIVER_INFO",
+                    "Driver Info",
+                    "General Info",
+                    Kind::Text,
+                    false,
+                    vec![
+                        text("DRIVER_NAME", "Name", env!("CARGO_PKG_NAME")),
+                        text("DRIVER_EXEC", "Exec", env!("CARGO_PKG_NAME")),
+                        text("DRIVER_VERSION", "Version", env!("CARGO_PKG_VERSION")),
+                        text("DRIVER_INTERFACE", "Interface", interface),
+                    ],
+                )
+            }
+            "CCD_INFO" => Property::new(
+                "CCD_INFO",
+                "CCD Information",
+                "Image Info",
+                Kind::Number,
+                false,
+                vec![
+                    number("CCD_MAX_X", "Max. Width", "%.f", (1., 16000.), max_x),
+                    number("CCD_MAX_Y", "Max. Height", "%.f", (1., 16000.), max_y),
+                    number(
+                        "CCD_PIXEL_SIZE",
+                        "Pixel size (um)",
+                        "%.2f",
+                        (1., 40.),
+                        info.pixel_size,
+                    ),
+                    number(
+                        "CCD_PIXEL_SIZE_X",
+                        "Pixel size X",
+                        "%.2f",
+                        (1., 40.),
+                        info.pixel_size,
+                    ),
+                    number(
+                        "CCD_PIXEL_SIZE_Y",
+                        "Pixel size Y",
+                        "%.2f",
+                        (1., 40.),
+                        info.pixel_size,
+                    ),
+                    number("CCD_BITSPERPIXEL", "Bits per pixel", "%.f", (8., 64.), 16.),
+                ],
+            ),
+            "CCD_EXPOSURE" => {
+                let exposure = caps(CONTROL_TYPE::EXPOSURE)?;
+                let range = (
+                    exposure.min_value as f64 / 1e6,
+                    exposure.max_value as f64 / 1e6,
+                );
+                Property::new(
+                    "CCD_EXPOSURE",
+                    "Expose",
+                    "Main Control",
+                    Kind::Number,
+                    true,
+                    vec![number(
+                        "CCD_EXPOSURE_VALUE",
+                        "Duration (s)",
+                        "%5.2f",
+                        range,
+                        remaining,
+                    )],
+                )
+            }
+            "CCD_ABORT_EXPOSURE" => Property::new(
+                "CCD_ABORT_EXPOSURE",
+                "Abort",
+                "Main Control",
+                Kind::Switch,
+                true,
+                vec![switch("ABORT", "Abort", false)],
+            )
+            .rule("AtMostOne"),
+            "CCD_FRAME" => Property::new(
+                "CCD_FRAME",
+                "Frame",
+                "Image Settings",
+                Kind::Number,
+                true,
+                vec![
+                    number("X", "Left", "%4.0f", (0., max_x - 1.), roi_x as f64),
+                    number("Y", "Top", "%4.0f", (0., max_y - 1.), roi_y as f64),
+                    number("WIDTH", "Width", "%4.0f", (1., max_x), width as f64),
+                    number("HEIGHT", "Height", "%4.0f", (1., max_y), height as f64),
+                ],
+            ),
+            "CCD_BINNING" => {
+                let max_bin = info.supported_bins.iter().max().copied().unwrap_or(1) as f64;
+                Property::new(
+                    "CCD_BINNING",
+                    "Binning",
+                    "Image Settings",
+                    Kind::Number,
+                    true,
+                    vec![
+                        number("HOR_BIN", "X", "%2.0f", (1., max_bin), bin as f64),
+                        number("VER_BIN", "Y", "%2.0f", (1., max_bin), bin as f64),
+                    ],
+                )
+            }
+            "CCD_FRAME_TYPE" => Property::new(
+                "CCD_FRAME_TYPE",
+                "Frame Type",
+                "Image Settings",
+                Kind::Switch,
+                true,
+                FrameType::ALL
+                    .iter()
+                    .map(|(ty, name, label)| switch(name, label, *ty == frame_type))
+                    .collect(),
+            ),
+            "CCD_TEMPERATURE" => {
+                caps(CONTROL_TYPE::TEMPERATURE)?;
+                let settable = caps(CONTROL_TYPE::COOLER_ON).is_some()
+                    && caps(CONTROL_TYPE::TARGET_TEMP).is_some_and(|caps| caps.is_writable);
+                Property::new(
+                    "CCD_TEMPERATURE",
+                    "Temperature",
+                    "Main Control",
+                    Kind::Number,
+                    settable,
+                    vec![number(
+                        "CCD_TEMPERATURE_VALUE",
+                        "Temperature (C)",
+                        "%5.2f",
+                        (-50., 50.),
+                        temperature.unwrap_or(0.),
+                    )],
+                )
+            }
+            "CCD_COOLER" => {
+                caps(CONTROL_TYPE::COOLER_ON)?;
+                let (on, _) = camera?.get_control_value(CONTROL_TYPE::COOLER_ON).ok()?;
+                Property::new(
+                    "CCD_COOLER",
+                    "Cooler",
+                    "Main Control",
+                    Kind::Switch,
+                    true,
+                    vec![
+                        switch("COOLER_ON", "On", on != 0),
+                        switch("COOLER_OFF", "Off", on == 0),
+                    ],
+                )
+            }
+            "CCD_COOLER_POWER" => {
+                caps(CONTROL_TYPE::COOLER_POWER_PERC)?;
+                let (power, _) = camera?
+                    .get_control_value(CONTROL_TYPE::COOLER_POWER_PERC)
+                    .ok()?;
+                Property::new(
+                    "CCD_COOLER_POWER",
+                    "Cooling Power",
+                    "Main Control",
+                    Kind::Number,
+                    false,
+                    vec![number(
+                        "CCD_COOLER_VALUE",
+                        "Cooling Power (%)",
+                        "%+06.2f",
+                        (0., 100.),
+                        power as f64,
+                    )],
+                )
+            }
+            "CCD_CONTROLS" => {
+                let camera = camera?;
+                let controls = CONTROLS
+                    .iter()
+                    .filter_map(|(control_type, name)| {
+                        let caps = caps(*control_type).filter(|caps| caps.is_writable)?;
+                        let (value, _) = camera.get_control_value(*control_type).ok()?;
+                        let range = (caps.min_value as f64, caps.max_value as f64);
+                        Some(number(name, name, "%.f", range, value as f64))
+                    })
+                    .collect();
+                Property::new(
+                    "CCD_CONTROLS",
+                    "Controls",
+                    "Controls",
+                    Kind::Number,
+                    true,
+                    controls,
+                )
+            }
+            "CCD_CFA" => {
+                let (offset_x, offset_y) = info.bayer_offset()?;
+                Property::new(
+                    "CCD_CFA",
+                    "Bayer Info",
+                    "Image Info",
+                    Kind::Text,
+                    false,
+                    vec![
+                        text("CFA_OFFSET_X", "X Offset", offset_x),
+                        text("CFA_OFFSET_Y", "Y Offset", offset_y),
+                        text("CFA_TYPE", "Filter", info.bayer_pattern?),
+                    ],
+                )
+            }
+            "CCD1" => Property::new(
+                "CCD1",
+                "Image Data",
+                "Image Info",
+                Kind::Blob,
+                false,
+                vec![Element {
+                    name: "CCD1".to_string(),
+                    label: "Image".to_string(),
+                    value: Value::Blob,
+                }],
+            ),
+            "TELESCOPE_TIMED_GUIDE_NS" | "TELESCOPE_TIMED_GUIDE_WE" if info.st4_port => {
+                let (name, label, elements) = match name {
+                    "TELESCOPE_TIMED_GUIDE_NS" => (
+                        "TELESCOPE_TIMED_GUIDE_NS",
+                        "Guide N/S",
+                        [
+                            ("TIMED_GUIDE_N", "North (ms)"),
+                            ("TIMED_GUIDE_S", "South (ms)"),
+                        ],
+                    ),
+                    _ => (
+                        "TELESCOPE_TIMED_GUIDE_WE",
+                        "Guide E/W",
+                        [
+                            ("TIMED_GUIDE_W", "West (ms)"),
+                            ("TIMED_GUIDE_E", "East (ms)"),
+                        ],
+                    ),
+                };
+                Property::new(
+                    name,
+                    label,
+                    "Guider Control",
+                    Kind::Number,
+                    true,
+                    elements
+                        .iter()
+                        .map(|(name, label)| number(name, label, "%.f", (0., 60000.), 0.))
+                        .collect(),
+                )
+            }
+            _ => return None,
+        };
+        Some(property)
+    }
+
+    /// The properties camera `index` has, connected or not.
+    fn property_names(&self, index: usize, slot: &CameraSlot) -> Vec<&'static str> {
+        let mut names = BASE_PROPERTIES.to_vec();
+        if self.with_device(index, &slot.info, |d| d.connected) {
+            names.extend(DEVICE_PROPERTIES);
+        }
+        names
+    }
+
+    /// The `defXVector`s of the properties `names` of camera `index`.
+    fn definitions(
+        &self,
+        index: usize,
+        device: &str,
+        slot: &CameraSlot,
+        names: &[&str],
+    ) -> Vec<String> {
+        names
+            .iter()
+            .filter_map(|name| self.property(index, slot, name))
+            .map(|property| property.def(device))
+            .collect()
+    }
+
+    async fn handle_client(self, stream: TcpStream, addr: SocketAddr) {
+        let (read, mut write) = stream.into_split();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut out = self.out.subscribe();
+        let writer = tokio::spawn(async move {
+            let mut blobs: HashMap<String, BlobMode> = HashMap::new();
+            loop {
+                let xml = tokio::select! {
+                    to_client = rx.recv() => match to_client {
+                        Some(ToClient::Xml(xml)) => xml,
+                        Some(ToClient::EnableBlob(device, mode)) => {
+                            blobs.insert(device, mode);
+                            continue;
+                        }
+                        None => break,
+                    },
+                    outgoing = out.recv() => match outgoing {
+                        Ok(outgoing) => {
+                            let mode = blobs.get(&outgoing.device).copied().unwrap_or_default();
+                            let wanted = match outgoing.blob {
+                                true => mode != BlobMode::Never,
+                                false => mode != BlobMode::Only,
+                            };
+                            if !wanted {
+                                continue;
+                            }
+                            outgoing.xml
+                        }
+                        Err(broadcast::error::RecvError::Lagged(n)) => {
+                            warn!("INDI client {addr} missed {n} messages");
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                };
+                if write.write_all(xml.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut reader = Reader::from_reader(BufReader::new(read));
+        reader.config_mut().trim_text(true);
+        let mut buf = Vec::new();
+        let mut message: Option<Message> = None;
+        // The `oneX` element being read
+        let mut element: Option<String> = None;
+        let mut text = String::new();
+        loop {
+            let event = match reader.read_event_into_async(&mut buf).await {
+                Ok(Event::Eof) => break,
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("Invalid XML from INDI client {addr}: {e}");
+                    break;
+                }
+            };
+            match (event, &mut message) {
+                (Event::Start(e), None) => {
+                    message = Some(Message::new(&e));
+                    text.clear();
+                }
+                (Event::Empty(e), None) => self.handle(Message::new(&e), &tx).await,
+                (Event::Start(e), Some(_)) => {
+                    element = attribute(&e, "name");
+                    text.clear();
+                }
+                (Event::Empty(e), Some(message)) => {
+                    if let Some(name) = attribute(&e, "name") {
+                        message.values.push((name, String::new()));
+                    }
+                }
+                (Event::Text(e), _) => {
+                    if let Ok(t) = e.unescape() {
+                        text.push_str(&t);
+                    }
+                }
+                (Event::End(_), Some(message)) if element.is_some() => {
+                    let name = element.take().unwrap();
+                    message.values.push((name, std::mem::take(&mut text)));
+                }
+                (Event::End(_), Some(_)) => {
+                    let mut message = message.take().unwrap();
+                    message.text = std::mem::take(&mut text);
+                    self.handle(message, &tx).await;
+                }
+                _ => {}
+            }
+            buf.clear();
+        }
+        info!("INDI client {addr} disconnected");
+        drop(tx);
+        let _ = writer.await;
+    }
+
+    async fn handle(&self, message: Message, tx: &mpsc::UnboundedSender<ToClient>) {
+        match message.tag.as_str() {
+            "getProperties" => {
+                for (index, device, slot) in self.devices() {
+                    if message.device.as_ref().is_some_and(|name| name != &device) {
+                        continue;
+                    }
+                    let mut names = self.property_names(index, &slot);
+                    if let Some(name) = &message.name {
+                        names.retain(|property| property == name);
+                    }
+                    for def in self.definitions(index, &device, &slot, &names) {
+                        let _ = tx.send(ToClient::Xml(def.into()));
+                    }
+                }
+            }
+            "enableBLOB" => {
+                let mode = match message.text.trim() {
+                    "Also" => BlobMode::Also,
+                    "Only" => BlobMode::Only,
+                    _ => BlobMode::Never,
+                };
+                let devices = match message.device {
+                    Some(device) => vec![device],
+                    None => self
+                        .devices()
+                        .into_iter()
+                        .map(|(_, name, _)| name)
+                        .collect(),
+                };
+                for device in devices {
+                    let _ = tx.send(ToClient::EnableBlob(device, mode));
+                }
+            }
+            "newNumberVector" | "newSwitchVector" | "newTextVector" => {
+                let (Some(device), Some(name)) = (&message.device, &message.name) else {
+                    return;
+                };
+                // Other devices may be served to the client by a chained server
+                let Some((index, device, slot)) = self.find(device) else {
+                    return;
+                };
+                if let Err(e) = self.update(index, &device, &slot, &message).await {
+                    warn!("INDI {device} {name}: {e}");
+                    self.set_with_message(
+                        index,
+                        &device,
+                        &slot,
+                        name,
+                        PropertyState::Alert,
+                        Some(&e),
+                    );
+                }
+            }
+            tag => warn!("Unhandled INDI message {tag}"),
+        }
+    }
+
+    /// Applies the new values of a property from a client.
+    async fn update(
+        &self,
+        index: usize,
+        device: &str,
+        slot: &CameraSlot,
+        message: &Message,
+    ) -> Result<(), String> {
+        let info = &slot.info;
+        let name = message.name.as_deref().unwrap_or_default();
+        if name == "CONNECTION" {
+            let connect = message.switches_on().any(|name| name == "CONNECT");
+            return self.connect(index, device, slot, connect);
+        }
+        if !self.with_device(index, info, |d| d.connected) {
+            return Err(format!("{device} is not connected"));
+        }
+
+        match name {
+            "CCD_EXPOSURE" => {
+                let seconds = message
+                    .number("CCD_EXPOSURE_VALUE")
+                    .ok_or("Missing CCD_EXPOSURE_VALUE")??;
+                self.start_exposure(index, device, slot, seconds).await?;
+            }
+            "CCD_ABORT_EXPOSURE" => {
+                slot.run(ControlMessages::AbortExposure)
+                    .await
+                    .map_err(|e| e.message)?;
+                self.set(index, device, slot, name, PropertyState::Ok);
+            }
+            "CCD_FRAME" => {
+                let mut frame = self.with_device(index, info, |d| [d.x, d.y, d.width, d.height]);
+                for (value, element) in frame.iter_mut().zip(["X", "Y", "WIDTH", "HEIGHT"]) {
+                    if let Some(number) = message.number(element) {
+                        *value = number? as i32;
+                    }
+                }
+                let [x, y, width, height] = frame;
+                if x < 0
+                    || y < 0
+                    || width < 1
+                    || height < 1
+                    || x + width > info.max_width
+                    || y + height > info.max_height
+                {
+                    return Err(format!(
+                        "Frame {width}x{height} at {x},{y} is outside the sensor"
+                    ));
+                }
+                self.with_device(index, info, |d| {
+                    (d.x, d.y, d.width, d.height) = (x, y, width, height)
+                });
+                self.set(index, device, slot, name, PropertyState::Ok);
+            }
+            "CCD_BINNING" => {
+                let bin = self.with_device(index, info, |d| d.bin);
+                let hor = message
+                    .number("HOR_BIN")
+                    .transpose()?
+                    .map_or(bin, |bin| bin as i32);
+                let ver = message
+                    .number("VER_BIN")
+                    .transpose()?
+                    .map_or(hor, |bin| bin as i32);
+                if hor != ver {
+                    return Err("Only symmetric binning is supported".to_string());
+                }
+                if !info.supported_bins.contains(&hor) {
+                    return Err(format!("Bin {hor} is not supported"));
+                }
+                self.with_device(index, info, |d| d.bin = hor);
+                self.set(index, device, slot, name, PropertyState::Ok);
+            }
+            "CCD_FRAME_TYPE" => {
+                let on: Vec<&str> = message.switches_on().collect();
+                let (frame_type, _, _) = FrameType::ALL
+                    .into_iter()
+                    .find(|(_, element, _)| on.contains(element))
+                    .ok_or("No frame type was selected")?;
+                self.with_device(index, info, |d| d.frame_type = frame_type);
+                self.set(index, device, slot, name, PropertyState::Ok);
+            }
+            "CCD_CONTROLS" => {
+                for (element, _) in &message.values {
+                    let value = message.number(element).unwrap()? as i32;
+                    let cmd = match element.as_str() {
+                        "Gain" => ControlMessages::SetGain(value),
+                        "Offset" => ControlMessages::SetOffset(value),
+                        "WB_R" => ControlMessages::SetWbR(value),
+                        "WB_B" => ControlMessages::SetWbB(value),
+                        _ => return Err(format!("Unknown control {element}")),
+                    };
+                    slot.run(cmd).await.map_err(|e| e.message)?;
+                }
+                self.set(index, device, slot, name, PropertyState::Ok);
+            }
+            "TELESCOPE_TIMED_GUIDE_NS" | "TELESCOPE_TIMED_GUIDE_WE" => {
+                self.pulse_guide(index, device, slot, message).await?;
+            }
+            "CCD_TEMPERATURE" => {
+                let target = message
+                    .number("CCD_TEMPERATURE_VALUE")
+                    .ok_or("Missing CCD_TEMPERATURE_VALUE")??;
+                slot.run(ControlMessages::SetTargetTemperature(target))
+                    .await
+                    .map_err(|e| e.message)?;
+                self.with_device(index, info, |d| d.target_temperature = Some(target));
+                self.set(index, device, slot, name, PropertyState::Busy);
+                self.set(index, device, slot, "CCD_COOLER", PropertyState::Ok);
+            }
+            "CCD_COOLER" => {
+                // Turning it off warms the sensor up first
+                let cmd = match message.switches_on().any(|name| name == "COOLER_ON") {
+                    true => {
+                        let target = match self.with_device(index, info, |d| d.target_temperature) {
+                            Some(target) => target,
+                            None => {
+                                slot.camera
+                                    .as_ref()
+                                    .ok_or(format!("{device} is unplugged"))?
+                                    .get_control_value(CONTROL_TYPE::TARGET_TEMP)
+                                    .map_err(|e| e.to_string())?
+                                    .0 as f64
+                            }
+                        };
+                        ControlMessages::SetTargetTemperature(target)
+                    }
+                    false => ControlMessages::WarmUp,
+                };
+                slot.run(cmd).await.map_err(|e| e.message)?;
+                self.set(index, device, slot, name, PropertyState::Busy);
+            }
+            _ => return Err(format!("Unknown property {name}")),
+        }
+        Ok(())
+    }
+
+    fn connect(
+        &self,
+        index: usize,
+        device: &str,
+        slot: &CameraSlot,
+        connect: bool,
+    ) -> Result<(), String> {
+        if connect && slot.camera.is_none() {
+            return Err(format!("{device} is unplugged"));
+        }
+        let was_connected = self.with_device(index, &slot.info, |d| {
+            std::mem::replace(&mut d.connected, connect)
+        });
+        if connect && !was_connected {
+            info!("INDI connected to {device}");
+            self.read_temperature(index, slot);
+            for def in self.definitions(index, device, slot, &DEVICE_PROPERTIES) {
+                self.broadcast(device, def);
+            }
+        } else if !connect && was_connected {
+            info!("INDI disconnected from {device}");
+            for name in DEVICE_PROPERTIES {
+                let mut xml = String::new();
+                tag(
+                    &mut xml,
+                    "delProperty",
+                    &[("device", device), ("name", name)],
+                    "/>\n",
+                );
+                self.broadcast(device, xml);
+            }
+        }
+        let state = if connect {
+            PropertyState::Ok
+        } else {
+            PropertyState::Idle
+        };
+        self.set(index, device, slot, "CONNECTION", state);
+        Ok(())
+    }
+
+    async fn start_exposure(
+        &self,
+        index: usize,
+        device: &str,
+        slot: &CameraSlot,
+        seconds: f64,
+    ) -> Result<(), String> {
+        let (roi, frame_type) = self.with_device(index, &slot.info, |d| (d.roi(), d.frame_type));
+        let dark = matches!(frame_type, FrameType::Bias | FrameType::Dark);
+        slot.expose(roi, seconds, dark)
+            .await
+            .map_err(|e| e.message)?;
+        self.with_device(index, &slot.info, |d| d.remaining = seconds.ceil());
+        self.set(index, device, slot, "CCD_EXPOSURE", PropertyState::Busy);
+        tokio::spawn(
+            self.clone()
+                .watch_exposure(index, device.to_string(), slot.clone()),
+        );
+        Ok(())
+    }
+
+    /// Reports the remaining time of the exposure just started on camera `index`, then
+    /// sends the frame once it finished.
+    async fn watch_exposure(self, index: usize, device: String, slot: CameraSlot) {
+        let set_exposure = |remaining: f64, state, message: Option<&str>| {
+            self.with_device(index, &slot.info, |d| d.remaining = remaining);
+            self.set_with_message(index, &device, &slot, "CCD_EXPOSURE", state, message);
+        };
+        let mut reported = None;
+        let mut waiting = false;
+        loop {
+            tokio::time::sleep(EXPOSURE_INTERVAL).await;
+            let status = match slot.status().await {
+                Ok(status) => status,
+                Err(e) => return set_exposure(0., PropertyState::Alert, Some(&e.message)),
+            };
+            if status.capture_waiting() {
+                if !waiting {
+                    waiting = true;
+                    self.message(&device, "Waiting for the sensor temperature to settle");
+                }
+                continue;
+            }
+            if let Some(exposure) = status.exposure {
+                let remaining = exposure.remaining.ceil();
+                if reported != Some(remaining) {
+                    reported = Some(remaining);
+                    set_exposure(remaining, PropertyState::Busy, None);
+                }
+                continue;
+            }
+            match status.last_exposure.map(|last| last.state) {
+                Some(ExposureState::Complete) => {}
+                Some(ExposureState::Aborted) => return set_exposure(0., PropertyState::Idle, None),
+                _ => return set_exposure(0., PropertyState::Alert, Some("The exposure failed")),
+            }
+            return match self.send_frame(&device, &slot).await {
+                Ok(()) => set_exposure(0., PropertyState::Ok, None),
+                Err(e) => set_exposure(0., PropertyState::Alert, Some(&e)),
+            };
+        }
+    }
+
+    /// Sends the last exposure as FITS to the clients that enabled BLOBs.
+    async fn send_frame(&self, device: &str, slot: &CameraSlot) -> Result<(), String> {
+        let frame = match slot.run(ControlMessages::GetExposure).await {
+            Ok(Some(Reply::Frame(frame))) => frame,
+            Ok(_) => return Err("GetExposure didn't answer with a frame".to_string()),
+            Err(e) => return Err(e.message),
+        };
+        let device_name = device.to_string();
+        // Encoding large frames takes a while
+        let xml = tokio::task::spawn_blocking(move || {
+            let fits = frame.encode(FrameFormat::Fits).map_err(|e| e.to_string())?;
+            let size = fits.len().to_string();
+            let timestamp = timestamp();
+            let mut xml = String::with_capacity(fits.len() * 4 / 3 + 256);
+            let attrs = [
+                ("device", device_name.as_str()),
+                ("name", "CCD1"),
+                ("state", "Ok"),
+                ("timestamp", &timestamp),
+            ];
+            tag(&mut xml, "setBLOBVector", &attrs, ">");
+            let attrs = [("name", "CCD1"), ("size", &size), ("format", ".fits")];
+            tag(&mut xml, "oneBLOB", &attrs, ">");
+            STANDARD.encode_string(&fits, &mut xml);
+            xml.push_str("</oneBLOB></setBLOBVector>\n");
+            Ok::<_, String>(xml)
+        })
+        .await
+        .map_err(|e| e.to_string())??;
+        let _ = self.out.send(Outgoing {
+            device: device.to_string(),
+            blob: true,
+            xml: xml.into(),
+        });
+        Ok(())
+    }
+
+    async fn pulse_guide(
+        &self,
+        index: usize,
+        device: &str,
+        slot: &CameraSlot,
+        message: &Message,
+    ) -> Result<(), String> {
+        let name = message.name.as_deref().unwrap_or_default();
+        let mut longest = Duration::ZERO;
+        for (element, direction) in [
+            ("TIMED_GUIDE_N", GUIDE_DIRECTION::NORTH),
+            ("TIMED_GUIDE_S", GUIDE_DIRECTION::SOUTH),
+            ("TIMED_GUIDE_W", GUIDE_DIRECTION::WEST),
+            ("TIMED_GUIDE_E", GUIDE_DIRECTION::EAST),
+        ] {
+            let Some(ms) = message.number(element).transpose()? else {
+                continue;
+            };
+            if ms <= 0. {
+                continue;
+            }
+            let duration = Duration::from_millis(ms as u64);
+            slot.pulse_guide(direction, duration)
+                .map_err(|e| e.message)?;
+            longest = longest.max(duration);
+        }
+        self.set(index, device, slot, name, PropertyState::Busy);
+        let (indi, device, slot, name) = (
+            self.clone(),
+            device.to_string(),
+            slot.clone(),
+            name.to_string(),
+        );
+        tokio::spawn(async move {
+            tokio::time::sleep(longest).await;
+            indi.set(index, &device, &slot, &name, PropertyState::Ok);
+        });
+        Ok(())
+    }
+
+    /// Reads the sensor temperature of camera `index`, returning whether it changed.
+    fn read_temperature(&self, index: usize, slot: &CameraSlot) -> bool {
+        let Some(camera) = &slot.camera else {
+            return false;
+        };
+        let Ok((value, _)) = camera.get_control_value(CONTROL_TYPE::TEMPERATURE) else {
+            return false;
+        };
+        let temperature = Some(value as f64 / 10.);
+        self.with_device(index, &slot.info, |d| {
+            std::mem::replace(&mut d.temperature, temperature) != temperature
+        })
+    }
+
+    /// Reports changes of the sensor temperature and the cooler of camera `index`.
+    /// `CCD_TEMPERATURE` is busy while the cooler is on its way to a temperature.
+    async fn report_cooler(&self, index: usize, device: &str, slot: &CameraSlot) {
+        let cooler = slot.status().await.ok().and_then(|status| status.cooler);
+        let state = cooler.as_ref().map(|cooler| cooler.state);
+        let power = cooler.and_then(|cooler| cooler.power);
+        let (state_changed, power_changed) = self.with_device(index, &slot.info, |d| {
+            (
+                std::mem::replace(&mut d.cooler_state, state) != state,
+                std::mem::replace(&mut d.cooler_power, power) != power,
+            )
+        });
+        if self.read_temperature(index, slot) || state_changed {
+            let property_state = match state {
+                Some(CoolerState::Cooling | CoolerState::WarmingUp) => PropertyState::Busy,
+                _ => PropertyState::Ok,
+            };
+            self.set(index, device, slot, "CCD_TEMPERATURE", property_state);
+        }
+        if state_changed {
+            self.set(index, device, slot, "CCD_COOLER", PropertyState::Ok);
+        }
+        if power_changed {
+            self.set(index, device, slot, "CCD_COOLER_POWER", PropertyState::Ok);
+        }
+    }
+
+    /// Reports temperature and cooler changes of connected cameras, and disconnects
+    /// devices whose camera was unplugged.
+    async fn watch_cameras(self) {
+        loop {
+            tokio::time::sleep(TEMPERATURE_INTERVAL).await;
+            for (index, device, slot) in self.devices() {
+                if !self.with_device(index, &slot.info, |d| d.connected) {
+                    continue;
+                }
+                if slot.camera.is_none() {
+                    self.message(&device, &format!("{device} was unplugged"));
+                    let _ = self.connect(index, &device, &slot, false);
+                } else {
+                    self.report_cooler(index, &device, &slot).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::{
+        asi::IMG_TYPE,
+        simulator::{SimulatedCamera, SimulatorConfig},
+    };
+
+    const DEVICE: &str = "ZWO ASI Simulator";
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// An INDI client of a server with one simulated 320x240 camera.
+    struct Client {
+        stream: TcpStream,
+        received: String,
+        state: AppState,
+        controller: Option<(usize, std::thread::JoinHandle<()>)>,
+    }
+
+    impl Client {
+        async fn start() -> Self {
+            let state = AppState::default();
+            let controller = state.connect(Arc::new(SimulatedCamera::new(SimulatorConfig {
+                max_width: 320,
+                max_height: 240,
+                ..Default::default()
+            })));
+            let (out, _) = broadcast::channel(64);
+            let indi = Indi {
+                app: state.clone(),
+                devices: Arc::new(Mutex::new(HashMap::new())),
+                out,
+            };
+            let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (stream, addr) = listener.accept().await.unwrap();
+                indi.handle_client(stream, addr).await;
+            });
+            Self {
+                stream: TcpStream::connect(addr).await.unwrap(),
+                received: String::new(),
+                state,
+                controller: Some(controller),
+            }
+        }
+
+        async fn send(&mut self, xml: &str) {
+            self.stream.write_all(xml.as_bytes()).await.unwrap();
+        }
+
+        /// Reads until `needle` arrives, returning everything up to the end of the
+        /// line it is on.
+        async fn expect(&mut self, needle: &str) -> String {
+            let mut buf = [0; 65536];
+            tokio::time::timeout(TIMEOUT, async {
+                loop {
+                    if let Some(start) = self.received.find(needle) {
+                        let end = self.received[start..]
+                            .find('\n')
+                            .map_or(self.received.len(), |end| start + end + 1);
+                        return self.received.drain(..end).collect();
+                    }
+                    let n = self.stream.read(&mut buf).await.unwrap();
+                    assert!(n > 0, "The server hung up waiting for {needle}");
+                    self.received.push_str(&String::from_utf8_lossy(&buf[..n]));
+                }
+            })
+            .await
+            .unwrap_or_else(|_| panic!("No {needle} in {}", self.received))
+        }
+
+        async fn connect(&mut self) {
+            self.send(&format!(
+                r#"<newSwitchVector device="{DEVICE}" name="CONNECTION"><oneSwitch name="CONNECT">On</oneSwitch><oneSwitch name="DISCONNECT">Off</oneSwitch></newSwitchVector>"#
+            ))
+            .await;
+            self.expect(r#"name="CONNECTION" state="Ok""#).await;
+        }
+
+        fn slot(&self) -> CameraSlot {
+            self.state.find(None).unwrap()
+        }
+
+        async fn stop(mut self) {
+            let (index, thread) = self.controller.take().unwrap();
+            let state = self.state.clone();
+            tokio::task::spawn_blocking(move || state.disconnect(index, thread))
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_properties_defines_the_devices() {
+        let mut client = Client::start().await;
+        client.send(r#"<getProperties version="1.7"/>"#).await;
+        let def = client
+            .expect(r#"<defSwitchVector device="ZWO ASI Simulator" name="CONNECTION""#)
+            .await;
+        assert!(def.contains(r#"<defSwitch name="DISCONNECT" label="Disconnect">On</defSwitch>"#));
+        client.expect(r#"name="DRIVER_INFO""#).await;
+
+        // Device properties only once connected
+        client.connect().await;
+        client
+            .send(&format!(
+                r#"<getProperties version="1.7" device="{DEVICE}" name="CCD_BINNING"/>"#
+            ))
+            .await;
+        let def = client
+            .expect(r#"<defNumberVector device="ZWO ASI Simulator" name="CCD_BINNING""#)
+            .await;
+        assert!(def.contains(r#"name="HOR_BIN""#));
+        client.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn new_vectors_update_the_device() {
+        let mut client = Client::start().await;
+        client.connect().await;
+        client
+            .send(&format!(
+                r#"<newNumberVector device="{DEVICE}" name="CCD_BINNING"><oneNumber name="HOR_BIN">2</oneNumber><oneNumber name="VER_BIN">2</oneNumber></newNumberVector>"#
+            ))
+            .await;
+        let set = client.expect(r#"name="CCD_BINNING" state="Ok""#).await;
+        assert!(set.contains(r#"<oneNumber name="HOR_BIN">2</oneNumber>"#));
+
+        client
+            .send(&format!(
+                r#"<newNumberVector device="{DEVICE}" name="CCD_CONTROLS"><oneNumber name="Gain">
+                  120
+                </oneNumber></newNumberVector>"#
+            ))
+            .await;
+        let set = client.expect(r#"name="CCD_CONTROLS" state="Ok""#).await;
+        assert!(set.contains(r#"<oneNumber name="Gain">120</oneNumber>"#));
+        assert_eq!(client.slot().status().await.unwrap().controls.gain, 120);
+
+        client
+            .send(&format!(
+                r#"<newSwitchVector device="{DEVICE}" name="CCD_FRAME_TYPE"><oneSwitch name="FRAME_LIGHT">Off</oneSwitch><oneSwitch name="FRAME_DARK">On</oneSwitch></newSwitchVector>"#
+            ))
+            .await;
+        let set = client.expect(r#"name="CCD_FRAME_TYPE" state="Ok""#).await;
+        assert!(set.contains(r#"<oneSwitch name="FRAME_DARK">On</oneSwitch>"#));
+        client.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn malformed_messages_are_rejected() {
+        let mut client = Client::start().await;
+        let binning = |hor: &str, ver: &str| {
+            format!(
+                r#"<newNumberVector device="{DEVICE}" name="CCD_BINNING"><oneNumber name="HOR_BIN">{hor}</oneNumber><oneNumber name="VER_BIN">{ver}</oneNumber></newNumberVector>"#
+            )
+        };
+        client.send(&binning("2", "2")).await;
+        client.expect("ZWO ASI Simulator is not connected").await;
+        client.connect().await;
+        client.send(&binning("two", "2")).await;
+        let set = client.expect(r#"name="CCD_BINNING" state="Alert""#).await;
+        assert!(set.contains("Invalid HOR_BIN two"));
+        client.send(&binning("2", "1")).await;
+        client.expect("Only symmetric binning is supported").await;
+        client
+            .send(&format!(
+                r#"<newNumberVector device="{DEVICE}" name="CCD_FRAME"><oneNumber name="WIDTH">400</oneNumber></newNumberVector>"#
+            ))
+            .await;
+        client
+            .expect("Frame 400x240 at 0,0 is outside the sensor")
+            .await;
+
+        // Broken XML ends the connection
+        client
+            .send(r#"<newNumberVector device="x" name="y"><oneNumber name="z">1</oneSwitch>"#)
+            .await;
+        let mut buf = [0; 1024];
+        let hung_up = tokio::time::timeout(TIMEOUT, async {
+            while client.stream.read(&mut buf).await.unwrap() > 0 {}
+        })
+        .await;
+        assert!(hung_up.is_ok());
+        client.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exposures_keep_the_shared_format() {
+        let mut client = Client::start().await;
+        let slot = client.slot();
+        slot.run(ControlMessages::SetImageType(IMG_TYPE::RAW8))
+            .await
+            .unwrap();
+        let before = slot.status().await.unwrap();
+        client.connect().await;
+        client
+            .send(&format!(
+                r#"<enableBLOB device="{DEVICE}">Also</enableBLOB>"#
+            ))
+            .await;
+        client
+            .send(&format!(
+                r#"<newNumberVector device="{DEVICE}" name="CCD_BINNING"><oneNumber name="HOR_BIN">2</oneNumber><oneNumber name="VER_BIN">2</oneNumber></newNumberVector>"#
+            ))
+            .await;
+        client.expect(r#"name="CCD_BINNING" state="Ok""#).await;
+        client
+            .send(&format!(
+                r#"<newNumberVector device="{DEVICE}" name="CCD_EXPOSURE"><oneNumber name="CCD_EXPOSURE_VALUE">0.01</oneNumber></newNumberVector>"#
+            ))
+            .await;
+        client.expect(r#"name="CCD_EXPOSURE" state="Busy""#).await;
+        let blob = client.expect("<setBLOBVector").await;
+        assert!(blob.contains(r#"format=".fits""#));
+        client.expect(r#"name="CCD_EXPOSURE" state="Ok""#).await;
+
+        let after = slot.status().await.unwrap();
+        assert_eq!(after.roi, before.roi);
+        assert_eq!(after.img_type, IMG_TYPE::RAW8);
+        client.stop().await;
+    }
+}
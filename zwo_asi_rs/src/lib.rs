@@ -1,10 +1,36 @@
-use std::{backtrace::Backtrace, thread::sleep};
+use std::{
+    backtrace::Backtrace,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
 
-use asi::{ASICloseCamera, ROIFormat, ASI_ERROR, CONTROL_TYPE, EXPOSURE_STATUS};
-use tracing::info;
+use asi::{
+    ASICloseCamera, ControlCaps, FrameGps, GpsData, ROIFormat, TriggerOutputConf, ASI_ERROR,
+    CAMERA_MODE, CONTROL_TYPE, EXPOSURE_STATUS, GUIDE_DIRECTION, TRIG_OUTPUT,
+};
+use guide::PulseGuider;
+use tracing::{info, warn};
 
+pub mod alpaca;
 pub mod asi;
+pub mod auto_exposure;
+pub mod backend;
 pub mod camera_controller;
+pub mod cooler;
+pub mod debayer;
+pub mod fits;
+pub mod frame;
+pub mod guide;
+pub mod hotplug;
+pub mod indi;
+pub mod preview;
+pub mod protocol;
+pub mod ser;
+pub mod server;
+pub mod simulator;
+pub mod stretch;
+
+pub use backend::CameraBackend;
 
 impl asi::_ASI_CAMERA_INFO {
     pub fn new() -> Self {
@@ -29,6 +55,24 @@ impl asi::_ASI_CAMERA_INFO {
             Unused: [0; 16],
         }
     }
+
+    /// The bins listed in `SupportedBins`, which is terminated by a 0.
+    pub fn supported_bins(&self) -> Vec<i32> {
+        self.SupportedBins
+            .iter()
+            .take_while(|bin| **bin != 0)
+            .copied()
+            .collect()
+    }
+
+    /// The image types listed in `SupportedVideoFormat`, which is terminated by `ASI_IMG_END`.
+    pub fn supported_img_types(&self) -> Vec<asi::IMG_TYPE> {
+        self.SupportedVideoFormat
+            .iter()
+            .take_while(|img_type| **img_type != asi::ASI_IMG_TYPE_ASI_IMG_END)
+            .filter_map(|img_type| asi::IMG_TYPE::try_from(*img_type).ok())
+            .collect()
+    }
 }
 
 impl Default for asi::_ASI_CAMERA_INFO {
@@ -37,7 +81,7 @@ impl Default for asi::_ASI_CAMERA_INFO {
     }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct Camera {
     info: asi::_ASI_CAMERA_INFO,
 }
@@ -47,57 +91,156 @@ impl Camera {
         Self { info }
     }
 
-    pub fn open(&self) -> Result<OpenCamera<'_>, ASI_ERROR> {
-        OpenCamera::new(self)
+    pub fn open(&self) -> Result<OpenCamera, ASI_ERROR> {
+        OpenCamera::new(self.clone())
     }
 
     pub fn get_name(&self) -> String {
-        String::from_utf8(self.info.Name.iter().map(|v| *v as u8).collect()).unwrap()
+        asi::c_chars_to_string(&self.info.Name)
     }
 }
 
-pub struct OpenCamera<'a> {
-    camera: &'a Camera,
-    close_on_drop: bool,
+/// Owns the SDK's open state for one camera. Dropped once the last [`OpenCamera`]
+/// sharing it goes away, which closes the camera.
+struct CameraHandle {
+    camera: Camera,
+    sdk_lock: Arc<Mutex<()>>,
+    guider: PulseGuider,
 }
 
-impl OpenCamera<'static> {
-    pub fn make_static<'a>(ccd: &OpenCamera<'a>) -> Self {
+fn lock_sdk(sdk_lock: &Mutex<()>) -> MutexGuard<'_, ()> {
+    // The lock guards no data, so a panic while holding it leaves nothing broken
+    sdk_lock
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Drop for CameraHandle {
+    fn drop(&mut self) {
+        // Don't leave the mount moving once nothing can stop it
+        let _ = self.guider.stop_all();
+        info!(
+            "Closing Camera {}\n{}",
+            self.camera.get_name(),
+            Backtrace::capture()
+        );
+
         unsafe {
-            Self {
-                camera: std::mem::transmute::<&'a Camera, &'static Camera>(ccd.camera),
-                close_on_drop: false,
-            }
+            ASICloseCamera(self.camera.info.CameraID);
         }
     }
 }
 
-impl<'a> OpenCamera<'a> {
-    pub fn new(camera: &'a Camera) -> Result<Self, ASI_ERROR> {
-        unsafe { asi::open_camera(camera.info.CameraID)? }
+/// A shareable handle to an opened camera.
+///
+/// Clones refer to the same camera, so the controller and its streaming threads can
+/// each hold one. SDK calls are serialized through an internal lock, except for the
+/// blocking frame reads which have to run alongside control changes.
+#[derive(Clone)]
+pub struct OpenCamera {
+    handle: Arc<CameraHandle>,
+}
+
+impl OpenCamera {
+    pub fn new(camera: Camera) -> Result<Self, ASI_ERROR> {
+        let id = camera.info.CameraID;
+        unsafe { asi::open_camera(id)? }
+        let sdk_lock = Arc::new(Mutex::new(()));
+        let guide_lock = sdk_lock.clone();
+        let guider = PulseGuider::new(move |direction, on| {
+            let _sdk = lock_sdk(&guide_lock);
+            unsafe {
+                if on {
+                    asi::pulse_guide_on(id, direction)
+                } else {
+                    asi::pulse_guide_off(id, direction)
+                }
+            }
+        });
         Ok(Self {
-            camera,
-            close_on_drop: true,
+            handle: Arc::new(CameraHandle {
+                camera,
+                sdk_lock,
+                guider,
+            }),
         })
     }
 
     pub fn id(&self) -> i32 {
-        self.camera.info.CameraID
+        self.handle.camera.info.CameraID
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        lock_sdk(&self.handle.sdk_lock)
+    }
+}
+
+impl CameraBackend for OpenCamera {
+    fn info(&self) -> asi::ASI_CAMERA_INFO {
+        self.handle.camera.info
+    }
+
+    fn name(&self) -> String {
+        self.handle.camera.get_name()
+    }
+
+    fn serial_number(&self) -> Result<String, ASI_ERROR> {
+        let _sdk = self.lock();
+        unsafe { asi::get_serial_number(self.id()) }
+    }
+
+    fn get_custom_id(&self) -> Result<String, ASI_ERROR> {
+        let _sdk = self.lock();
+        unsafe { asi::get_id(self.id()) }
+    }
+
+    fn set_custom_id(&self, id: &str) -> Result<(), ASI_ERROR> {
+        let _sdk = self.lock();
+        unsafe { asi::set_id(self.id(), id) }
     }
-    pub fn init(&self) -> Result<(), ASI_ERROR> {
+
+    fn init(&self) -> Result<(), ASI_ERROR> {
+        let _sdk = self.lock();
         unsafe { asi::init_camera(self.id()) }
     }
 
-    pub fn set_control_value(
+    fn controls(&self) -> Result<Vec<ControlCaps>, ASI_ERROR> {
+        let _sdk = self.lock();
+        unsafe {
+            let num_controls = asi::get_num_of_controls(self.id())?;
+            let caps = (0..num_controls)
+                .map(|i| asi::get_control_caps(self.id(), i))
+                .collect::<Result<Vec<_>, _>>()?;
+            // Newer SDKs and cameras have controls this crate doesn't know yet
+            Ok(caps
+                .iter()
+                .filter_map(|caps| match ControlCaps::try_from(caps) {
+                    Ok(caps) => Some(caps),
+                    Err(_) => {
+                        warn!(
+                            "Skipping control {} of unknown type {}",
+                            asi::c_chars_to_string(&caps.Name),
+                            caps.ControlType
+                        );
+                        None
+                    }
+                })
+                .collect())
+        }
+    }
+
+    fn set_control_value(
         &self,
         control_type: CONTROL_TYPE,
         value: i64,
         auto: bool,
     ) -> Result<(), ASI_ERROR> {
+        let _sdk = self.lock();
         unsafe { asi::set_control_value(self.id(), control_type, value, auto) }
     }
 
-    pub fn get_control_value(&self, control_type: CONTROL_TYPE) -> Result<(i64, bool), ASI_ERROR> {
+    fn get_control_value(&self, control_type: CONTROL_TYPE) -> Result<(i64, bool), ASI_ERROR> {
+        let _sdk = self.lock();
         unsafe {
             let mut value: i64 = 0;
             let mut auto: i32 = 0;
@@ -106,77 +249,145 @@ impl<'a> OpenCamera<'a> {
         }
     }
 
-    pub fn set_roi_format(
+    fn set_roi_format(
         &self,
         width: i32,
         height: i32,
         bin: i32,
         img_type: asi::IMG_TYPE,
     ) -> Result<(), ASI_ERROR> {
+        let _sdk = self.lock();
         unsafe { asi::set_roi_format(self.id(), width, height, bin, img_type) }
     }
 
-    pub fn get_roi_format(&self) -> Result<ROIFormat, ASI_ERROR> {
+    fn get_roi_format(&self) -> Result<ROIFormat, ASI_ERROR> {
+        let _sdk = self.lock();
         unsafe { asi::get_roi_format(self.id()) }
     }
 
-    pub fn start_exposure(&self) -> Result<(), ASI_ERROR> {
-        unsafe { asi::start_exposure(self.id(), false) }
+    fn set_start_pos(&self, x: i32, y: i32) -> Result<(), ASI_ERROR> {
+        let _sdk = self.lock();
+        unsafe { asi::set_start_pos(self.id(), x, y) }
     }
 
-    pub fn get_exp_status(&self) -> Result<EXPOSURE_STATUS, ASI_ERROR> {
-        unsafe { asi::get_exp_status(self.id()) }
+    fn get_start_pos(&self) -> Result<(i32, i32), ASI_ERROR> {
+        let _sdk = self.lock();
+        unsafe { asi::get_start_pos(self.id()) }
     }
 
-    pub fn get_data_after_exp(&self, data: &mut [u8]) -> Result<(), ASI_ERROR> {
-        unsafe { asi::get_data_after_exp(self.id(), data.as_mut_ptr(), data.len() as i64) }
+    fn start_exposure(&self, is_dark: bool) -> Result<(), ASI_ERROR> {
+        let _sdk = self.lock();
+        unsafe { asi::start_exposure(self.id(), is_dark) }
     }
 
-    pub fn take_exposure(&self, data: &mut [u8]) -> Result<(), ASI_ERROR> {
-        self.start_exposure()?;
-        let mut loop_count = 0;
-        while self.get_exp_status()? == asi::EXPOSURE_STATUS::EXP_WORKING {
-            sleep(std::time::Duration::from_millis(1));
-            loop_count += 1;
-        }
+    fn stop_exposure(&self) -> Result<(), ASI_ERROR> {
+        let _sdk = self.lock();
+        unsafe { asi::stop_exposure(self.id()) }
+    }
 
-        std::dbg!(loop_count);
-        if self.get_exp_status()? == asi::EXPOSURE_STATUS::EXP_SUCCESS {
-            self.get_data_after_exp(data)?;
-            Ok(())
-        } else {
-            Err(ASI_ERROR::UNKNOWN)
-        }
+    fn get_exp_status(&self) -> Result<EXPOSURE_STATUS, ASI_ERROR> {
+        let _sdk = self.lock();
+        unsafe { asi::get_exp_status(self.id()) }
+    }
+
+    fn get_data_after_exp(&self, data: &mut [u8]) -> Result<(), ASI_ERROR> {
+        let _sdk = self.lock();
+        unsafe { asi::get_data_after_exp(self.id(), data.as_mut_ptr(), data.len() as i64) }
     }
 
-    pub fn start_video_capture(&self) -> Result<(), ASI_ERROR> {
+    fn start_video_capture(&self) -> Result<(), ASI_ERROR> {
+        let _sdk = self.lock();
         unsafe { asi::start_video_capture(self.id()) }
     }
-    pub fn stop_video_capture(&self) -> Result<(), ASI_ERROR> {
+    fn stop_video_capture(&self) -> Result<(), ASI_ERROR> {
+        let _sdk = self.lock();
         unsafe { asi::stop_video_capture(self.id()) }
     }
 
-    pub fn get_dropped_frames(&self) -> Result<i32, ASI_ERROR> {
+    fn get_dropped_frames(&self) -> Result<i32, ASI_ERROR> {
+        let _sdk = self.lock();
         unsafe { asi::get_dropped_frames(self.id()) }
     }
 
-    pub fn get_video_data(&self, data: &mut [u8], wait_ms: i32) -> Result<(), ASI_ERROR> {
+    fn get_video_data(&self, data: &mut [u8], wait_ms: i32) -> Result<(), ASI_ERROR> {
+        // Not locked: this blocks until a frame arrives, and controls must stay usable
         unsafe { asi::get_video_data(self.id(), data.as_mut_ptr(), data.len() as i64, wait_ms) }
     }
-}
 
-impl Drop for OpenCamera<'_> {
-    fn drop(&mut self) {
-        if self.close_on_drop {
-            info!(
-                "Closing Camera {}\n{}",
-                self.camera.get_name(),
-                Backtrace::capture()
-            );
+    fn get_video_data_gps(&self, data: &mut [u8], wait_ms: i32) -> Result<GpsData, ASI_ERROR> {
+        // Not locked, for the same reason as get_video_data
+        unsafe { asi::get_video_data_gps(self.id(), data.as_mut_ptr(), data.len() as i64, wait_ms) }
+    }
 
-            unsafe {
-                ASICloseCamera(self.camera.info.CameraID);
-            }
+    fn get_data_after_exp_gps(&self, data: &mut [u8]) -> Result<GpsData, ASI_ERROR> {
+        let _sdk = self.lock();
+        unsafe { asi::get_data_after_exp_gps(self.id(), data.as_mut_ptr(), data.len() as i64) }
+    }
+
+    fn get_gps_data(&self) -> Result<FrameGps, ASI_ERROR> {
+        let _sdk = self.lock();
+        unsafe { asi::gps_get_data(self.id()) }
+    }
+
+    fn supported_camera_modes(&self) -> Result<Vec<CAMERA_MODE>, ASI_ERROR> {
+        // Only trigger cameras implement the mode calls
+        if self.info().IsTriggerCam == 0 {
+            return Ok(vec![CAMERA_MODE::NORMAL]);
         }
+        let _sdk = self.lock();
+        unsafe { asi::get_camera_support_mode(self.id()) }
+    }
+
+    fn get_camera_mode(&self) -> Result<CAMERA_MODE, ASI_ERROR> {
+        if self.info().IsTriggerCam == 0 {
+            return Ok(CAMERA_MODE::NORMAL);
+        }
+        let _sdk = self.lock();
+        unsafe { asi::get_camera_mode(self.id()) }
+    }
+
+    fn set_camera_mode(&self, mode: CAMERA_MODE) -> Result<(), ASI_ERROR> {
+        if self.info().IsTriggerCam == 0 {
+            return match mode {
+                CAMERA_MODE::NORMAL => Ok(()),
+                _ => Err(ASI_ERROR::INVALID_MODE),
+            };
+        }
+        let _sdk = self.lock();
+        unsafe { asi::set_camera_mode(self.id(), mode) }
+    }
+
+    fn send_soft_trigger(&self, start: bool) -> Result<(), ASI_ERROR> {
+        let _sdk = self.lock();
+        unsafe { asi::send_soft_trigger(self.id(), start) }
+    }
+
+    fn set_trigger_output(
+        &self,
+        pin: TRIG_OUTPUT,
+        conf: TriggerOutputConf,
+    ) -> Result<(), ASI_ERROR> {
+        let _sdk = self.lock();
+        unsafe { asi::set_trigger_output_io_conf(self.id(), pin, conf) }
+    }
+
+    fn get_trigger_output(&self, pin: TRIG_OUTPUT) -> Result<TriggerOutputConf, ASI_ERROR> {
+        let _sdk = self.lock();
+        unsafe { asi::get_trigger_output_io_conf(self.id(), pin) }
+    }
+
+    fn pulse_guide(&self, direction: GUIDE_DIRECTION, duration: Duration) -> Result<(), ASI_ERROR> {
+        if self.info().ST4Port == 0 {
+            return Err(ASI_ERROR::GENERAL_ERROR);
+        }
+        self.handle.guider.pulse(direction, duration)
+    }
+
+    fn stop_pulse_guide(&self, direction: GUIDE_DIRECTION) -> Result<(), ASI_ERROR> {
+        self.handle.guider.stop(direction)
+    }
+
+    fn is_pulse_guiding(&self, direction: GUIDE_DIRECTION) -> bool {
+        self.handle.guider.is_guiding(direction)
     }
 }
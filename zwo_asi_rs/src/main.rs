@@ -1,24 +1,46 @@
 use anyhow::Result;
 
-use tokio::{sync::broadcast, time};
-use tracing::{error, info};
+use tokio::{
+    sync::{broadcast, watch},
+    time,
+};
+use tracing::{info, warn};
 use zwo_asi_rs::{
-    asi::{self},
+    alpaca,
+    asi::{self, CameraInfo},
+    auto_exposure::AutoExposureConfig,
     camera_controller::{
-        CameraController, ClientPacket, ControlMessages, ControlValues, ImagePacket, PixelOrder,
+        CameraStatus, CaptureFormat, ClientPacket, ControlMessages, ControlValues, FrameFormat,
+        ImagePacket, Roi,
     },
-    Camera,
+    cooler::{CoolerConfig, CoolerStatus},
+    hotplug::{DeviceEvent, DeviceMonitor, SdkCameras},
+    indi,
+    preview::{self, PreviewFeed, PreviewSettings},
+    protocol::{ErrorCode, ProtocolError, Reply, Request, Response},
+    server::{next_client_id, AppState, CameraSlot},
+    simulator::{SimPattern, SimulatedCamera, SimulatedCameras, SimulatorConfig},
 };
 
 use axum::{
-    extract::{ws::WebSocketUpgrade, State},
-    http::{HeaderValue, Method},
+    extract::{ws::WebSocketUpgrade, Path, Query, State},
+    http::{header, HeaderValue, Method, StatusCode},
     response::IntoResponse,
-    routing::any,
-    Router,
+    routing::{any, get, post, put},
+    Json, Router,
 };
 use axum_extra::{headers, TypedHeader};
-use std::{net::SocketAddr, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
 use tower_http::{
     services::ServeDir,
     trace::{DefaultMakeSpan, TraceLayer},
@@ -32,26 +54,107 @@ use axum::extract::connect_info::ConnectInfo;
 //allows to split the websocket stream into separate TX and RX branches
 use futures::{sink::SinkExt, stream::StreamExt};
 
-fn get_camera_info() -> impl Iterator<Item = Camera> {
-    unsafe {
-        // let num_connected = asi::ASIGetNumOfConnectedCameras();
-        let num_connected = std::dbg!(asi::get_num_of_connected_cameras());
+/// The HTTP port of the frontend, REST and Alpaca APIs.
+const HTTP_PORT: u16 = 3000;
 
-        (0..num_connected).filter_map(|i| {
-            let mut info = asi::ASI_CAMERA_INFO::new();
+/// How often websocket clients are sent their [`preview::PreviewStats`].
+const PREVIEW_STATS_INTERVAL: Duration = Duration::from_secs(1);
 
-            let idx: std::os::raw::c_int = i as std::os::raw::c_int;
-            asi::get_camera_property(&mut info, idx)
-                .map(|()| Camera::new(info))
-                .ok()
+/// How often the connected cameras are checked for changes.
+const MONITOR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Watches for every connected camera, or simulated ones when started with
+/// `--simulate[=stars|gradient|noise|test]` and optionally `--sim-cameras=<count>`.
+///
+/// `--camera=<serial|custom id|name|index>`, which can be repeated, limits this to
+/// the matching cameras. An SDK index picks the camera it belongs to when the server
+/// first sees it, which is then followed by its serial.
+fn device_monitor() -> Result<DeviceMonitor> {
+    let selectors: Vec<String> = std::env::args()
+        .filter_map(|arg| {
+            arg.strip_prefix("--camera=")
+                .map(|selector| selector.to_string())
         })
+        .collect();
+    let simulate = std::env::args().find_map(|arg| {
+        arg.strip_prefix("--simulate")
+            .map(|pattern| pattern.trim_start_matches('=').to_string())
+    });
+    let Some(pattern) = simulate else {
+        info!("ASI SDK version {}", unsafe { asi::get_sdk_version() });
+        return Ok(DeviceMonitor::new(SdkCameras::default(), selectors));
+    };
+
+    let count = match std::env::args().find_map(|arg| {
+        arg.strip_prefix("--sim-cameras=")
+            .map(|count| count.to_string())
+    }) {
+        Some(count) => count.parse()?,
+        None => 1,
+    };
+    let mut cameras = Vec::new();
+    for i in 0..count {
+        let mut config = SimulatorConfig::default();
+        if !pattern.is_empty() {
+            config.pattern = pattern.parse::<SimPattern>()?;
+        }
+        if i > 0 {
+            config.name = format!("{} {}", config.name, i + 1);
+        }
+        config.camera_id = i;
+        config.serial = format!("5a5a{:012x}", i + 1);
+        config.seed += i as u64;
+        info!(
+            "Using simulated camera {} with {:?}",
+            config.name, config.pattern
+        );
+        cameras.push(Arc::new(SimulatedCamera::new(config)));
+    }
+    Ok(DeviceMonitor::new(SimulatedCameras(cameras), selectors))
+}
+
+/// Polls for cameras being plugged in and removed, keeping a controller running for
+/// each connected one.
+///
+/// Once `stop` is set, every controller is shut down, which waits for coolers to warm up.
+fn monitor_cameras(state: AppState, mut monitor: DeviceMonitor, stop: Arc<AtomicBool>) {
+    // Controller threads by camera ID
+    let mut controllers: HashMap<i32, (usize, JoinHandle<()>)> = HashMap::new();
+    while !stop.load(Ordering::Relaxed) {
+        // Controllers stop when their camera stops responding, which is often the
+        // first sign of a USB hiccup
+        let finished: Vec<i32> = controllers
+            .iter()
+            .filter(|(_, (_, thread))| thread.is_finished())
+            .map(|(camera_id, _)| *camera_id)
+            .collect();
+        for camera_id in finished {
+            let (index, thread) = controllers.remove(&camera_id).unwrap();
+            state.disconnect(index, thread);
+            monitor.forget(camera_id);
+        }
+
+        for event in monitor.poll() {
+            match event {
+                DeviceEvent::Added(camera) => {
+                    let camera_id = camera.info().CameraID;
+                    controllers.insert(camera_id, state.connect(camera));
+                }
+                DeviceEvent::Removed(camera_id) => {
+                    if let Some((index, thread)) = controllers.remove(&camera_id) {
+                        state.disconnect(index, thread);
+                    }
+                }
+            }
+        }
+        std::thread::sleep(MONITOR_INTERVAL);
     }
+    state.disconnect_all(controllers.into_values().collect());
 }
 
-#[derive(Clone)]
-struct WebSocketState {
-    tx: broadcast::Sender<ControlMessages>,
-    rx: broadcast::Sender<ClientPacket>,
+#[derive(Deserialize)]
+struct CameraQuery {
+    camera: Option<String>,
 }
 
 #[tokio::main]
@@ -65,52 +168,51 @@ async fn main() -> Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    // Broadcast channel for WebSocket connections
-    let (tx, _) = broadcast::channel(32);
-    let (tx_cmds, rx_cmds) = broadcast::channel(32);
-
-    let tx_thread = tx.clone();
-    let _thread = std::thread::spawn(move || -> Result<()> {
-        println!("In thread!");
-        let camera = get_camera_info()
-            .next()
-            .ok_or(anyhow::anyhow!("No camera available."))?;
-        let mut controller = match CameraController::new(&camera, tx_thread, rx_cmds) {
-            Ok(c) => c,
-            Err(e) => {
-                error!("Initializing CameraController failed with {e:?}");
-                panic!("Initializing CameraController failed with {e:?}");
-            }
-        };
-
-        match controller.run() {
-            Err(e) => {
-                error!("Running CameraController failed with {e:?}");
-                panic!("Running CameraController failed with {e:?}");
-            }
-            Ok(r) => Ok(r),
-        }
-    });
-    // if let Ok(res) = thread.join() {
-    //     res?;
-    // }
+    let state = AppState::default();
+    let monitor = device_monitor()?;
+    let monitor_state = state.clone();
+    let stop = Arc::new(AtomicBool::new(false));
+    let monitor_stop = stop.clone();
+    let monitor_thread =
+        std::thread::spawn(move || monitor_cameras(monitor_state, monitor, monitor_stop));
 
     // Define app routes
     let app = Router::new()
         // .route("/ws", get(handle_ws.with_state(tx.clone())))
         .fallback_service(ServeDir::new("frontend/dist").append_index_html_on_directories(true))
+        .route("/ws", any(ws_handler))
+        .route("/cameras", get(list_cameras_handler))
+        .route("/camera", get(camera_info_handler))
+        .route("/camera/id", post(set_custom_id_handler))
+        .route("/guide", post(guide_handler))
+        .route("/guide/stop", post(stop_guide_handler))
+        .route("/status", get(status_handler))
+        .route("/controls", get(controls_handler))
         .route(
-            "/ws",
-            any(ws_handler).with_state(WebSocketState {
-                tx: tx_cmds.clone(),
-                rx: tx.clone(),
-            }),
+            "/controls/{name}",
+            get(get_control_handler).put(set_control_handler),
         )
+        .route("/roi", get(get_roi_handler).put(set_roi_handler))
+        .route("/format", get(get_format_handler).put(set_format_handler))
+        .route("/preview/start", post(start_preview_handler))
+        .route("/preview/stop", post(stop_preview_handler))
+        .route("/capture/start", post(start_capture_handler))
+        .route("/capture/stop", post(stop_capture_handler))
+        .route("/exposure/start", post(start_exposure_handler))
+        .route("/exposure/abort", post(abort_exposure_handler))
+        .route("/frame", get(frame_handler))
+        .route("/cooler", get(cooler_handler))
+        .route("/cooler/target", put(set_cooler_target_handler))
+        .route("/cooler/config", put(set_cooler_config_handler))
+        .route("/cooler/warm-up", post(warm_up_handler))
+        .route("/auto-exposure", put(set_auto_exposure_handler))
+        .with_state(state.clone())
+        .merge(alpaca::router(state.clone()))
         .layer(
             tower::ServiceBuilder::new().layer(
                 tower_http::cors::CorsLayer::new()
                     .allow_origin(HeaderValue::from_static("*"))
-                    .allow_methods(vec![Method::GET, Method::POST]),
+                    .allow_methods(vec![Method::GET, Method::PUT, Method::POST]),
             ),
         )
         .layer(
@@ -118,170 +220,676 @@ async fn main() -> Result<()> {
                 .make_span_with(DefaultMakeSpan::default().include_headers(false)),
         );
 
+    tokio::spawn(async {
+        if let Err(e) = alpaca::discovery(HTTP_PORT).await {
+            warn!("Alpaca discovery stopped: {e}");
+        }
+    });
+    tokio::spawn(async move {
+        if let Err(e) = indi::serve(state, indi::PORT).await {
+            warn!("INDI server stopped: {e}");
+        }
+    });
+
     // Start the server
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", HTTP_PORT))
+        .await
+        .unwrap();
     tracing::debug!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
+    tokio::select! {
+        result = axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        ) => result.unwrap(),
+        _ = tokio::signal::ctrl_c() => {}
+    }
+
+    info!("Shutting down, press Ctrl-C again to skip warming up the cameras");
+    stop.store(true, Ordering::Relaxed);
+    tokio::select! {
+        _ = tokio::task::spawn_blocking(move || monitor_thread.join()) => {}
+        _ = tokio::signal::ctrl_c() => warn!("Exiting without warming up"),
+    }
 
     Ok(())
 }
 
+#[derive(Serialize)]
+struct CameraInfoResponse {
+    sdk_version: String,
+    /// Unplugged cameras are listed until they come back.
+    connected: bool,
+    #[serde(flatten)]
+    camera: CameraInfo,
+}
+
+impl From<&CameraSlot> for CameraInfoResponse {
+    fn from(slot: &CameraSlot) -> Self {
+        Self {
+            sdk_version: unsafe { asi::get_sdk_version() },
+            connected: slot.camera.is_some(),
+            camera: match &slot.camera {
+                Some(camera) => camera.camera_info(),
+                None => slot.info.clone(),
+            },
+        }
+    }
+}
+
+/// `GET /cameras` lists every camera the server has seen.
+async fn list_cameras_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(
+        state
+            .cameras
+            .read()
+            .unwrap()
+            .iter()
+            .map(CameraInfoResponse::from)
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// `GET /camera?camera=guide` describes one camera.
+async fn camera_info_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+) -> Result<Json<CameraInfoResponse>, StatusCode> {
+    let slot = state
+        .find(query.camera.as_deref())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(CameraInfoResponse::from(&slot)))
+}
+
+#[derive(Deserialize)]
+struct CustomIdQuery {
+    camera: Option<String>,
+    id: String,
+}
+
+/// `POST /camera/id?camera=0&id=guide` stores a custom ID in the camera's flash, so
+/// it can be picked with `--camera=guide` from then on.
+async fn set_custom_id_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CustomIdQuery>,
+) -> impl IntoResponse {
+    let Some(slot) = state.find(query.camera.as_deref()) else {
+        return StatusCode::NOT_FOUND;
+    };
+    let Some(camera) = slot.camera else {
+        return StatusCode::SERVICE_UNAVAILABLE;
+    };
+    match camera.set_custom_id(&query.id) {
+        Ok(()) => {
+            state.update_info(&slot.tx, camera.camera_info());
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => {
+            warn!("Setting custom ID {} failed with {e}", query.id);
+            StatusCode::BAD_REQUEST
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GuideQuery {
+    camera: Option<String>,
+    direction: String,
+    duration_ms: u64,
+}
+
+/// `POST /guide?direction=north&duration_ms=500` sends a guide pulse, answering once
+/// it started.
+async fn guide_handler(
+    State(state): State<AppState>,
+    Query(query): Query<GuideQuery>,
+) -> Result<StatusCode, ApiError> {
+    let direction = query.direction.parse().map_err(|_| {
+        api_error(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+            format!("Unknown guide direction {}", query.direction),
+        )
+    })?;
+    find_slot(&state, query.camera.as_deref())?
+        .pulse_guide(direction, Duration::from_millis(query.duration_ms))
+        .map_err(slot_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `POST /guide/stop` ends any running guide pulses.
+async fn stop_guide_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+) -> Result<StatusCode, ApiError> {
+    find_slot(&state, query.camera.as_deref())?
+        .stop_guiding()
+        .map_err(slot_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The error answer to a REST request, with the same body as a websocket [`Response`]'s
+/// `error`.
+type ApiError = (StatusCode, Json<ProtocolError>);
+
+fn api_error(status: StatusCode, code: ErrorCode, message: impl Into<String>) -> ApiError {
+    (status, Json(ProtocolError::new(code, message)))
+}
+
+fn find_slot(state: &AppState, camera: Option<&str>) -> Result<CameraSlot, ApiError> {
+    state.find(camera).ok_or_else(|| {
+        api_error(
+            StatusCode::NOT_FOUND,
+            ErrorCode::UnknownCamera,
+            format!("No camera matches {}", camera.unwrap_or_default()),
+        )
+    })
+}
+
+/// The REST answer to a command that failed.
+fn slot_error(error: ProtocolError) -> ApiError {
+    let status = match error.code {
+        ErrorCode::CameraUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    (status, Json(error))
+}
+
+/// Runs `cmd` on the controller of `camera` and waits for its answer, the same way
+/// a websocket request is answered.
+async fn run_command(
+    state: &AppState,
+    camera: Option<&str>,
+    cmd: ControlMessages,
+) -> Result<Option<Reply>, ApiError> {
+    find_slot(state, camera)?.run(cmd).await.map_err(slot_error)
+}
+
+/// Runs a command that doesn't answer with data.
+async fn run_action(
+    state: &AppState,
+    camera: Option<&str>,
+    cmd: ControlMessages,
+) -> Result<StatusCode, ApiError> {
+    run_command(state, camera, cmd).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_status(state: &AppState, camera: Option<&str>) -> Result<CameraStatus, ApiError> {
+    match run_command(state, camera, ControlMessages::GetStatus).await? {
+        Some(Reply::Status(status)) => Ok(status),
+        _ => Err(api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::CommandFailed,
+            "GetStatus didn't answer with a status",
+        )),
+    }
+}
+
+/// `GET /status` reports what the camera is doing and all of its settings.
+async fn status_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+) -> Result<Json<CameraStatus>, ApiError> {
+    get_status(&state, query.camera.as_deref()).await.map(Json)
+}
+
+/// `GET /controls` returns gain, exposure (in ms) and white balance.
+async fn controls_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+) -> Result<Json<ControlValues>, ApiError> {
+    let status = get_status(&state, query.camera.as_deref()).await?;
+    Ok(Json(status.controls))
+}
+
+fn unknown_control(name: &str) -> ApiError {
+    api_error(
+        StatusCode::NOT_FOUND,
+        ErrorCode::InvalidRequest,
+        format!("Unknown control {name}, expected gain, exposure, wb_r or wb_b"),
+    )
+}
+
+/// `GET /controls/gain` returns a single control, named as in `GET /controls`.
+async fn get_control_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<CameraQuery>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let status = get_status(&state, query.camera.as_deref()).await?;
+    serde_json::to_value(status.controls)
+        .ok()
+        .and_then(|controls| controls.get(&name).cloned())
+        .map(Json)
+        .ok_or_else(|| unknown_control(&name))
+}
+
+/// `PUT /controls/gain` with a JSON number as the body sets a control.
+async fn set_control_handler(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<CameraQuery>,
+    Json(value): Json<f64>,
+) -> Result<StatusCode, ApiError> {
+    let cmd = match name.as_str() {
+        "gain" => ControlMessages::SetGain(value as i32),
+        "exposure" => ControlMessages::SetExposure(value as f32),
+        "wb_r" => ControlMessages::SetWbR(value as i32),
+        "wb_b" => ControlMessages::SetWbB(value as i32),
+        _ => return Err(unknown_control(&name)),
+    };
+    run_action(&state, query.camera.as_deref(), cmd).await
+}
+
+/// `GET /roi` returns the sub-frame in binned pixels.
+async fn get_roi_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+) -> Result<Json<Roi>, ApiError> {
+    let status = get_status(&state, query.camera.as_deref()).await?;
+    Ok(Json(status.roi))
+}
+
+/// `PUT /roi` with `{"x": 0, "y": 0, "w": 640, "h": 480, "bin": 1}` sets the sub-frame.
+async fn set_roi_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+    Json(roi): Json<Roi>,
+) -> Result<StatusCode, ApiError> {
+    let Roi { x, y, w, h, bin } = roi;
+    let cmd = ControlMessages::SetRoi { x, y, w, h, bin };
+    run_action(&state, query.camera.as_deref(), cmd).await
+}
+
+/// The format frames are read from the camera and saved in.
+#[derive(Serialize, Deserialize)]
+struct FormatSettings {
+    img_type: Option<asi::IMG_TYPE>,
+    capture_format: Option<CaptureFormat>,
+}
+
+/// `GET /format` returns the image type and capture format.
+async fn get_format_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+) -> Result<Json<FormatSettings>, ApiError> {
+    let status = get_status(&state, query.camera.as_deref()).await?;
+    Ok(Json(FormatSettings {
+        img_type: Some(status.img_type),
+        capture_format: Some(status.capture_format),
+    }))
+}
+
+/// `PUT /format` with `{"img_type": "RAW16", "capture_format": "fits"}` changes
+/// either or both.
+async fn set_format_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+    Json(format): Json<FormatSettings>,
+) -> Result<StatusCode, ApiError> {
+    let camera = query.camera.as_deref();
+    if let Some(img_type) = format.img_type {
+        run_command(&state, camera, ControlMessages::SetImageType(img_type)).await?;
+    }
+    if let Some(capture_format) = format.capture_format {
+        let cmd = ControlMessages::SetCaptureFormat(capture_format);
+        run_command(&state, camera, cmd).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `POST /preview/start` starts video, so `GET /frame` has something to return.
+async fn start_preview_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+) -> Result<StatusCode, ApiError> {
+    let cmd = ControlMessages::StartPreview;
+    run_action(&state, query.camera.as_deref(), cmd).await
+}
+
+/// `POST /preview/stop` stops video, also for websocket clients of the camera.
+async fn stop_preview_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+) -> Result<StatusCode, ApiError> {
+    let cmd = ControlMessages::StopPreview;
+    run_action(&state, query.camera.as_deref(), cmd).await
+}
+
+#[derive(Deserialize)]
+struct CaptureQuery {
+    camera: Option<String>,
+    frames: i32,
+}
+
+/// `POST /capture/start?frames=100` saves the next frames in the capture format.
+async fn start_capture_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CaptureQuery>,
+) -> Result<StatusCode, ApiError> {
+    let cmd = ControlMessages::StartCapture(query.frames);
+    run_action(&state, query.camera.as_deref(), cmd).await
+}
+
+/// `POST /capture/stop` ends a capture early.
+async fn stop_capture_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+) -> Result<StatusCode, ApiError> {
+    let cmd = ControlMessages::StopCapture;
+    run_action(&state, query.camera.as_deref(), cmd).await
+}
+
+#[derive(Deserialize)]
+struct ExposureQuery {
+    camera: Option<String>,
+    seconds: f64,
+    #[serde(default = "one")]
+    count: i32,
+    #[serde(default)]
+    dark: bool,
+}
+
+fn one() -> i32 {
+    1
+}
+
+/// `POST /exposure/start?seconds=30&count=5&dark=true` takes long exposures.
+async fn start_exposure_handler(
+    State(state): State<AppState>,
+    Query(query): Query<ExposureQuery>,
+) -> Result<StatusCode, ApiError> {
+    let cmd = ControlMessages::StartExposure {
+        seconds: query.seconds,
+        count: query.count,
+        dark: query.dark,
+        save: true,
+        format: None,
+    };
+    run_action(&state, query.camera.as_deref(), cmd).await
+}
+
+/// `POST /exposure/abort` throws away the running exposure.
+async fn abort_exposure_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+) -> Result<StatusCode, ApiError> {
+    let cmd = ControlMessages::AbortExposure;
+    run_action(&state, query.camera.as_deref(), cmd).await
+}
+
+/// `GET /cooler` returns the sensor temperature and what the cooler is doing.
+async fn cooler_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+) -> Result<Json<CoolerStatus>, ApiError> {
+    let status = get_status(&state, query.camera.as_deref()).await?;
+    status.cooler.map(Json).ok_or_else(|| {
+        api_error(
+            StatusCode::NOT_FOUND,
+            ErrorCode::InvalidRequest,
+            "The camera has no temperature sensor",
+        )
+    })
+}
+
+/// `PUT /cooler/target` with a JSON number as the body cools to that many °C.
+async fn set_cooler_target_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+    Json(target): Json<f64>,
+) -> Result<StatusCode, ApiError> {
+    let cmd = ControlMessages::SetTargetTemperature(target);
+    run_action(&state, query.camera.as_deref(), cmd).await
+}
+
+/// `PUT /cooler/config` with `{"ramp_rate": 2, "wait_to_settle": true}` changes how
+/// the cooler gets there, see [`CoolerConfig`].
+async fn set_cooler_config_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+    Json(config): Json<CoolerConfig>,
+) -> Result<StatusCode, ApiError> {
+    let cmd = ControlMessages::SetCoolerConfig(config);
+    run_action(&state, query.camera.as_deref(), cmd).await
+}
+
+/// `POST /cooler/warm-up` ramps the sensor back up and turns the cooler off.
+async fn warm_up_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+) -> Result<StatusCode, ApiError> {
+    let cmd = ControlMessages::WarmUp;
+    run_action(&state, query.camera.as_deref(), cmd).await
+}
+
+/// `PUT /auto-exposure` with `{"target": 0.4, "max_exposure": 0.05}` turns auto
+/// exposure on, see [`AutoExposureConfig`], and with `null` turns it off.
+async fn set_auto_exposure_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
+    Json(config): Json<Option<AutoExposureConfig>>,
+) -> Result<StatusCode, ApiError> {
+    if let Some(Err(e)) = config.as_ref().map(AutoExposureConfig::check) {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+            e.to_string(),
+        ));
+    }
+    let cmd = ControlMessages::SetAutoExposure(config);
+    run_action(&state, query.camera.as_deref(), cmd).await
+}
+
+#[derive(Deserialize)]
+struct FrameQuery {
+    camera: Option<String>,
+    format: Option<FrameFormat>,
+}
+
+/// `GET /frame?format=fits` downloads the latest frame as `png` (the default),
+/// `jpeg` or `fits`.
+async fn frame_handler(
+    State(state): State<AppState>,
+    Query(query): Query<FrameQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let format = query.format.unwrap_or(FrameFormat::Png);
+    let Some(Reply::Frame(frame)) =
+        run_command(&state, query.camera.as_deref(), ControlMessages::GetFrame).await?
+    else {
+        return Err(api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::CommandFailed,
+            "GetFrame didn't answer with a frame",
+        ));
+    };
+    let encode_failed = |e: String| {
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::CommandFailed,
+            format!("Encoding the frame failed with {e}"),
+        )
+    };
+    let bytes = tokio::task::spawn_blocking(move || frame.encode(format))
+        .await
+        .map_err(|e| encode_failed(e.to_string()))?
+        .map_err(|e| encode_failed(format!("{e:#}")))?;
+    Ok(([(header::CONTENT_TYPE, format.content_type())], bytes))
+}
+
+/// `/ws?camera=guide` streams one camera's previews and takes its commands. Clients
+/// open a socket per camera they want to watch.
 async fn ws_handler(
     ws: WebSocketUpgrade,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    State(state): State<WebSocketState>,
+    State(state): State<AppState>,
+    Query(query): Query<CameraQuery>,
 ) -> impl IntoResponse {
+    let Some(slot) = state.find(query.camera.as_deref()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
     let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
         user_agent.to_string()
     } else {
         String::from("Unknown browser")
     };
-    println!("`{user_agent}` at {addr} connected.");
+    info!("`{user_agent}` at {addr} connected");
     // finalize the upgrade process by returning upgrade callback.
     // we can customize the callback by sending additional info such as address.
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+    ws.on_upgrade(move |socket| handle_socket(socket, slot))
+        .into_response()
 }
 
-async fn handle_socket(stream: axum::extract::ws::WebSocket, state: WebSocketState) {
-    let (mut sender, mut receiver) = stream.split();
-    state.tx.send(ControlMessages::StartPreview).unwrap();
+/// Encodes a preview the way the client asked for, `None` if that failed.
+async fn compress_preview(
+    packet: Arc<ImagePacket>,
+    settings: PreviewSettings,
+) -> Option<Arc<ImagePacket>> {
+    if settings.is_raw() {
+        return Some(packet);
+    }
+    match tokio::task::spawn_blocking(move || preview::compress(&packet, &settings)).await {
+        Ok(Ok(packet)) => Some(Arc::new(packet)),
+        Ok(Err(e)) => {
+            warn!("Compressing preview failed: {e:?}");
+            None
+        }
+        Err(e) => {
+            warn!("Compressing preview panicked: {e}");
+            None
+        }
+    }
+}
 
+async fn handle_socket(stream: axum::extract::ws::WebSocket, state: CameraSlot) {
+    let client = next_client_id();
+    let (mut sender, mut receiver) = stream.split();
     let mut transmit_rx = state.rx.subscribe();
+    let mut previews = PreviewFeed::new(state.previews.subscribe());
+    let (preview_tx, preview_rx) = watch::channel(PreviewSettings::default());
+    // Fails while the camera is unplugged, it starts again once it is back
+    let _ = state.send(ControlMessages::StartPreview);
+
     // Spawn a task to send broadcasted messages to this client
     let tx_task = tokio::spawn(async move {
-        //let controls = ControlValues {
-        //    gain: 0,
-        //    exposure: 0.,
-        //    wb_b: 0,
-        //    wb_r: 0,
-        //};
-        //
-        //let packet = ClientPacket::Preview(ImagePacket {
-        //    w: 1920,
-        //    h: 1080,
-        //    pix: PixelOrder::BGR,
-        //    img: vec![100; 1920 * 1080 * 3],
-        //    controls: controls,
-        //});
-        //
-        //let mut buf = Vec::new();
-        //// rmp::encode::write_map_len(&mut buf, 1);
-        //rmp_serde::encode::write_named(&mut buf, &packet).unwrap();
-        //let bytes: axum::body::Bytes = buf.into();
-        //let num_bytes = bytes.len();
-        //let ws_message = axum::extract::ws::Message::Binary(bytes);
-        //loop {
-        //    let send_start = std::time::Instant::now();
-        //    if sender
-        //        .send(ws_message.clone())
-        //        .await
-        //        .is_err()
-        //    {
-        //        println!("Sending message failed");
-        //        // break;
-        //    }
-        //    let send_end = std::time::Instant::now();
-        //    println!("Sent messsage in {:?}", send_end - send_start);
-        //
-        //}
-        //return;
-
-        // let mut underlying_bytes = BytesMut::new();
-        // let slice: &mut [u8] = &mut underlying_bytes[..];
-        // let mut msgpack_data = Rc::<Vec<u8>>::new(vec![]);
-        // let mut msgpack_data = Rc::<Vec<u8>>::new(vec![]);
-        // let mut serializer = rmp_serde::Serializer::new(msgpack_data)
-        //     .with_bytes(rmp_serde::config::BytesMode::ForceAll);
+        let mut stats_interval = time::interval(PREVIEW_STATS_INTERVAL);
+        let mut reported_stats = previews.stats;
         loop {
-            match transmit_rx.recv().await {
-                Ok(msg) => {
-                    // println!("Recived a msg to trasnmit");
-                    // {
-                    //     msg.serialize(&mut serializer).unwrap();
-                    // }
-                    // let packet = serializer.get_ref();
-                    //let buf: &mut Vec<u8> = msgpack_data.as_mut();
-                    //let start = std::time::Instant::now();
-                    let mut buf = Vec::new();
-                    // rmp::encode::write_map_len(&mut buf, 1);
-                    rmp_serde::encode::write_named(&mut buf, &msg).unwrap();
-                    //let middle = std::time::Instant::now();
-                    //println!("Encoded message in {:?}", middle - start);
-                    let bytes: axum::body::Bytes = buf.into();
-                    let num_bytes = bytes.len();
-
-                    let ws_message = axum::extract::ws::Message::Binary(bytes);
-                    //let end = std::time::Instant::now();
-                    //println!("Generated ws_message in {:?}, size = {}KB", end - middle, num_bytes / 1024);
-
-                    let send_start = std::time::Instant::now();
-                    // time::sleep(Duration::from_millis(500)).await;
-                    if sender.send(ws_message).await.is_err() {
-                        println!("Sending message failed");
-                        // break;
+            let settings = preview_rx.borrow().clone();
+            // Previews are only taken once the last message went out, so a slow link
+            // skips frames rather than falling behind
+            let msg = tokio::select! {
+                msg = transmit_rx.recv() => match msg {
+                    // Answers to other connections' requests
+                    Ok(ClientPacket::Response(response)) if response.client != client => continue,
+                    Ok(msg) => msg,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Client {client} fell behind and missed {skipped} messages");
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Getting a message for client {client} failed with {e:?}");
+                        break;
+                    }
+                },
+                packet = previews.next(&settings) => {
+                    let Some(packet) = packet else {
+                        break;
+                    };
+                    match compress_preview(packet, settings).await {
+                        Some(packet) => ClientPacket::Preview(packet),
+                        None => continue,
                     }
-                    let send_end = std::time::Instant::now();
-                    // println!("Sent messsage in {:?}", send_end - send_start);
                 }
-                Err(e) => {
-                    println!("Getting msg to transmit failed with err {:?}", e);
-                    break;
+                _ = stats_interval.tick() => {
+                    if previews.stats == reported_stats {
+                        continue;
+                    }
+                    reported_stats = previews.stats;
+                    ClientPacket::PreviewStats(reported_stats)
                 }
+            };
+            let mut buf = Vec::new();
+            rmp_serde::encode::write_named(&mut buf, &msg).unwrap();
+            let ws_message = axum::extract::ws::Message::Binary(buf.into());
+            if sender.send(ws_message).await.is_err() {
+                warn!("Sending a message to client {client} failed");
             }
         }
-        // while let Ok(msg) = transmit_rx.recv().await {
-
-        //     println!("Recived a msg to trasnmit");
-        //     // {
-        //     //     msg.serialize(&mut serializer).unwrap();
-        //     // }
-        //     // let packet = serializer.get_ref();
-        //     // let buf: &mut Vec<u8> = msgpack_data.as_mut();
-        //     let mut buf = Vec::new();
-        //     // rmp::encode::write_map_len(&mut buf, 1);
-        //     rmp_serde::encode::write_named(&mut buf, &msg).unwrap();
-        //     println!("Encoded message");
-
-        //     if sender
-        //         .send(axum::extract::ws::Message::Binary(buf.into()))
-        //         .await
-        //         .is_err()
-        //     {
-        //         println!("Sending message failed");
-        //         break;
-        //     }
-        //         println!("Sent messsage");
-        // }
-        println!("Got here????");
+        info!("Stopped sending to client {client}");
     });
 
     // Handle incoming messages
     while let Some(Ok(msg)) = receiver.next().await {
-        if let axum::extract::ws::Message::Text(text) = msg {
-            let str = text.as_str();
-            if let Some((cmd, val)) = str.split_once(':') {
-                let command = match cmd {
-                    "SET_GAIN" => ControlMessages::SetGain(val.parse().unwrap()),
-                    "SET_EXPOSURE" => ControlMessages::SetExposure(val.parse().unwrap()),
-                    "SET_WB_B" => ControlMessages::SetWbB(val.parse().unwrap()),
-                    "SET_WB_R" => ControlMessages::SetWbR(val.parse().unwrap()),
-                    "SWITCH_OUTPUT" => ControlMessages::SwitchOutput,
-                    "START_CAPTURE" => ControlMessages::StartCapture(val.parse().unwrap()),
-                    _ => panic!("Unknown command {}", cmd),
-                };
-                let _ = state.tx.send(command);
+        let request = match msg {
+            axum::extract::ws::Message::Text(text) => Request::from_json(text.as_str()),
+            axum::extract::ws::Message::Binary(bytes) => Request::from_msgpack(&bytes),
+            _ => continue,
+        };
+        // Answers for requests the controller doesn't see
+        let response = match request {
+            Ok(Request {
+                id,
+                command: ControlMessages::SetPreview(settings),
+                ..
+            }) => match settings.check() {
+                Ok(_) => {
+                    info!("Client {client} previews with {settings:?}");
+                    preview_tx.send_replace(settings);
+                    Response::new(client, id, Ok(None))
+                }
+                Err(e) => Response::error(
+                    client,
+                    Some(id),
+                    ProtocolError::new(ErrorCode::InvalidRequest, e.to_string()),
+                ),
+            },
+            // Guiding can't wait in the controller's queue
+            Ok(Request {
+                id,
+                command:
+                    ControlMessages::PulseGuide {
+                        direction,
+                        duration,
+                    },
+                ..
+            }) => match state.pulse_guide(direction, duration) {
+                Ok(_) => Response::new(client, id, Ok(None)),
+                Err(e) => Response::error(client, Some(id), e),
+            },
+            Ok(Request {
+                id,
+                command: ControlMessages::StopGuiding,
+                ..
+            }) => match state.stop_guiding() {
+                Ok(_) => Response::new(client, id, Ok(None)),
+                Err(e) => Response::error(client, Some(id), e),
+            },
+            // The controller answers once it ran the command
+            Ok(request) => match state.request(request.command, client, request.id) {
+                Ok(_) => continue,
+                Err(_) => Response::error(
+                    client,
+                    Some(request.id),
+                    ProtocolError::new(ErrorCode::CameraUnavailable, "The camera is not connected"),
+                ),
+            },
+            Err(rejection) => {
+                warn!("Rejected request: {}", rejection.error.message);
+                Response::error(client, rejection.id, rejection.error)
             }
-        }
+        };
+        let _ = state.rx.send(ClientPacket::Response(response));
     }
 
-    info!("Sending StopPreview message");
-    state.tx.send(ControlMessages::StopPreview).unwrap();
-    // Drop the broadcast receiver to stop the task
+    // Drop the broadcast receiver and this client's preview feed
     tx_task.abort();
+    let _ = tx_task.await;
+    // Other clients may still be watching
+    if state.previews.receiver_count() == 0 {
+        info!("Last client left, sending StopPreview message");
+        let _ = state.send(ControlMessages::StopPreview);
+    }
 }
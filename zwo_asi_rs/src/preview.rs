@@ -0,0 +1,335 @@
+//! Previews for clients on slow links.
+//!
+//! The controller only keeps its newest [`ImagePacket`], see [`LatestPreview`], and
+//! each connection takes the newest one whenever it has sent the last. A client on a
+//! weak link skips frames instead of holding up the others, and can cap its rate
+//! further with `max_fps`.
+//!
+//! Previews hold the frame as the camera read it. That is about 2 MB for a 1080p
+//! RAW8 frame, too much to stream over Wi-Fi. Clients that pick JPEG or WebP with
+//! `SetPreview` instead get each preview debayered, if the controller hasn't already,
+//! shrunk to fit their maximum size
+//! and encoded by their own connection. The same goes for clients with a display
+//! stretch, see [`crate::stretch`], whatever their encoding.
+
+use std::{borrow::Cow, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use opencv::{
+    core::{Mat, MatTraitConst, MatTraitManual, Scalar, Size, Vector, CV_8UC1, CV_8UC3},
+    imgcodecs, imgproc,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, time::Instant};
+
+use crate::{
+    camera_controller::{ImagePacket, PixelOrder},
+    debayer::{self, CfaPattern, DebayerAlgorithm},
+    frame,
+    stretch::Stretch,
+};
+
+/// Lowest `max_fps`, a preview every 100 s.
+pub const MIN_FPS: f64 = 0.01;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PreviewEncoding {
+    /// The frame as the camera read it, for clients that process it themselves.
+    #[default]
+    Raw,
+    Jpeg,
+    Webp,
+}
+
+/// How a client wants its previews, sent with `SetPreview`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PreviewSettings {
+    #[serde(default)]
+    pub encoding: PreviewEncoding,
+    /// Largest size to send, in pixels. Previews are shrunk to fit, keeping their
+    /// aspect ratio, but never enlarged.
+    #[serde(default)]
+    pub max_width: Option<u32>,
+    #[serde(default)]
+    pub max_height: Option<u32>,
+    /// 1 to 100, for JPEG and WebP.
+    #[serde(default = "default_quality")]
+    pub quality: u8,
+    /// Most previews to send a second, as many as the camera makes if `None`. At
+    /// least [`MIN_FPS`].
+    #[serde(default)]
+    pub max_fps: Option<f64>,
+    /// Brightens previews for display. Raw previews come as 8 bit BGR or mono once
+    /// stretched.
+    #[serde(default)]
+    pub stretch: Option<Stretch>,
+}
+
+fn default_quality() -> u8 {
+    80
+}
+
+impl Default for PreviewSettings {
+    fn default() -> Self {
+        Self {
+            encoding: PreviewEncoding::default(),
+            max_width: None,
+            max_height: None,
+            quality: default_quality(),
+            max_fps: None,
+            stretch: None,
+        }
+    }
+}
+
+impl PreviewSettings {
+    /// Whether previews are sent as the controller made them.
+    pub fn is_raw(&self) -> bool {
+        self.encoding == PreviewEncoding::Raw && self.stretch.is_none()
+    }
+
+    /// Rejects settings previews can't be made with.
+    pub fn check(&self) -> Result<()> {
+        match self.max_fps {
+            Some(fps) if fps.is_nan() || fps < MIN_FPS => {
+                Err(anyhow!("max_fps is {fps}, it must be at least {MIN_FPS}"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The shortest time between two previews.
+    fn min_interval(&self) -> Duration {
+        match self.max_fps {
+            Some(fps) => Duration::from_secs_f64(1. / fps.max(MIN_FPS)),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+/// The newest preview of a camera, replaced by every one the controller makes.
+#[derive(Clone, Debug)]
+pub struct LatestPreview {
+    /// Counts the previews the controller made, so clients know how many they skipped.
+    pub seq: u64,
+    pub packet: Arc<ImagePacket>,
+}
+
+/// Sent to each client about once a second while it gets previews.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PreviewStats {
+    pub sent: u64,
+    /// Previews made while the client was busy with an earlier one or held back by
+    /// its `max_fps`, which it never got.
+    pub dropped: u64,
+}
+
+/// One client's view of a camera's [`LatestPreview`].
+pub struct PreviewFeed {
+    latest: watch::Receiver<Option<LatestPreview>>,
+    last_seq: Option<u64>,
+    next_allowed: Instant,
+    pub stats: PreviewStats,
+}
+
+impl PreviewFeed {
+    /// Starts with the next preview the controller makes.
+    pub fn new(latest: watch::Receiver<Option<LatestPreview>>) -> Self {
+        Self {
+            latest,
+            last_seq: None,
+            next_allowed: Instant::now(),
+            stats: PreviewStats::default(),
+        }
+    }
+
+    /// Waits for a preview newer than the last one and for `settings.max_fps` to allow
+    /// another, then takes the newest. `None` once the camera is gone for good.
+    ///
+    /// Cancel safe, so it can wait next to other work in `select!`.
+    pub async fn next(&mut self, settings: &PreviewSettings) -> Option<Arc<ImagePacket>> {
+        loop {
+            tokio::time::sleep_until(self.next_allowed).await;
+            self.latest.changed().await.ok()?;
+            let Some(latest) = self.latest.borrow_and_update().clone() else {
+                continue;
+            };
+            if let Some(last_seq) = self.last_seq {
+                self.stats.dropped += latest.seq.saturating_sub(last_seq + 1);
+            }
+            self.last_seq = Some(latest.seq);
+            self.stats.sent += 1;
+            self.next_allowed = Instant::now() + settings.min_interval();
+            return Some(latest.packet);
+        }
+    }
+}
+
+fn to_mat(w: u32, h: u32, typ: i32, data: &[u8]) -> Result<Mat> {
+    let mut mat = Mat::new_rows_cols_with_default(h as i32, w as i32, typ, Scalar::all(0.))?;
+    let bytes = mat.data_bytes_mut()?;
+    if bytes.len() != data.len() {
+        return Err(anyhow!(
+            "Preview has {} bytes, {w}x{h} needs {}",
+            data.len(),
+            bytes.len()
+        ));
+    }
+    bytes.copy_from_slice(data);
+    Ok(mat)
+}
+
+fn convert(src: &Mat, code: i32) -> Result<Mat> {
+    let mut dst = Mat::default();
+    imgproc::cvt_color_def(src, &mut dst, code)?;
+    Ok(dst)
+}
+
+/// 8 bit BGR, or mono for mono cameras, brightened with `stretch` from the full depth
+/// of the preview.
+fn stretched(packet: &ImagePacket, stretch: &Stretch) -> Result<Mat> {
+    let (w, h) = (packet.w, packet.h);
+    let (width, height) = (w as usize, h as usize);
+    let widen = |levels: &[u8]| levels.iter().map(|level| *level as u16).collect::<Vec<_>>();
+    let bilinear = DebayerAlgorithm::Bilinear;
+    let (pixels, channels, white) =
+        match (&packet.pix, packet.bayer.and_then(CfaPattern::from_name)) {
+            (PixelOrder::BGR, _) => (widen(&packet.img), 3, u8::MAX as u16),
+            (PixelOrder::RGB, _) => {
+                let bgr: Vec<u8> = packet
+                    .img
+                    .chunks_exact(3)
+                    .flat_map(|pixel| [pixel[2], pixel[1], pixel[0]])
+                    .collect();
+                (widen(&bgr), 3, u8::MAX as u16)
+            }
+            (PixelOrder::RAW8, Some(pattern)) => {
+                let bgr = debayer::debayer_u8(&packet.img, width, height, pattern, bilinear);
+                (widen(&bgr), 3, u8::MAX as u16)
+            }
+            (PixelOrder::RAW8, None) => (widen(&packet.img), 1, u8::MAX as u16),
+            (PixelOrder::RAW16, Some(pattern)) => (
+                debayer::debayer_u16(&packet.img, width, height, pattern, bilinear),
+                3,
+                u16::MAX,
+            ),
+            (PixelOrder::RAW16, None) => (frame::pixels_u16(&packet.img).collect(), 1, u16::MAX),
+            (PixelOrder::JPEG | PixelOrder::WEBP, _) => unreachable!("Preview is already encoded"),
+        };
+    let typ = match channels {
+        3 => CV_8UC3,
+        _ => CV_8UC1,
+    };
+    to_mat(w, h, typ, &stretch.apply(&pixels, channels, white))
+}
+
+/// 8 bit BGR, or mono for mono cameras.
+fn to_bgr(packet: &ImagePacket, stretch: Option<&Stretch>) -> Result<Mat> {
+    if let Some(stretch) = stretch {
+        return stretched(packet, stretch);
+    }
+    let (w, h) = (packet.w, packet.h);
+    let mosaic = match packet.pix {
+        PixelOrder::BGR => return to_mat(w, h, CV_8UC3, &packet.img),
+        PixelOrder::RGB => {
+            return convert(&to_mat(w, h, CV_8UC3, &packet.img)?, imgproc::COLOR_RGB2BGR)
+        }
+        PixelOrder::RAW8 => Cow::Borrowed(&packet.img[..]),
+        // Unstretched previews are scaled between the darkest and brightest pixel
+        PixelOrder::RAW16 => Cow::Owned(frame::stretch_u16_to_u8(
+            &packet.img,
+            packet.stats.min,
+            packet.stats.max,
+        )),
+        PixelOrder::JPEG | PixelOrder::WEBP => unreachable!("Preview is already encoded"),
+    };
+    match packet.bayer.and_then(CfaPattern::from_name) {
+        Some(pattern) => {
+            let (width, height) = (w as usize, h as usize);
+            let bgr =
+                debayer::debayer_u8(&mosaic, width, height, pattern, DebayerAlgorithm::Bilinear);
+            to_mat(w, h, CV_8UC3, &bgr)
+        }
+        None => to_mat(w, h, CV_8UC1, &mosaic),
+    }
+}
+
+/// Shrinks `image` to fit within the maximum size of `settings`.
+fn fit(image: Mat, settings: &PreviewSettings) -> Result<Mat> {
+    let (w, h) = (image.cols() as f64, image.rows() as f64);
+    let scale = [
+        settings.max_width.map(|max| max as f64 / w),
+        settings.max_height.map(|max| max as f64 / h),
+    ]
+    .into_iter()
+    .flatten()
+    .fold(1., f64::min);
+    if scale >= 1. {
+        return Ok(image);
+    }
+    let size = Size::new(
+        ((w * scale).round() as i32).max(1),
+        ((h * scale).round() as i32).max(1),
+    );
+    let mut scaled = Mat::default();
+    imgproc::resize(&image, &mut scaled, size, 0., 0., imgproc::INTER_AREA)?;
+    Ok(scaled)
+}
+
+/// Turns a preview from the controller into what a client with `settings` asked for.
+pub fn compress(packet: &ImagePacket, settings: &PreviewSettings) -> Result<ImagePacket> {
+    if settings.is_raw() || matches!(packet.pix, PixelOrder::JPEG | PixelOrder::WEBP) {
+        return Ok(packet.clone());
+    }
+    let image = to_bgr(packet, settings.stretch.as_ref())?;
+    let (ext, quality, pix) = match settings.encoding {
+        // Stretched, but otherwise as the controller made it
+        PreviewEncoding::Raw => {
+            let img = image.data_bytes()?.to_vec();
+            let mono = img.len() == (packet.w * packet.h) as usize;
+            return Ok(ImagePacket {
+                pix: if mono {
+                    PixelOrder::RAW8
+                } else {
+                    PixelOrder::BGR
+                },
+                img,
+                bayer: None,
+                ..packet.clone()
+            });
+        }
+        PreviewEncoding::Jpeg => (".jpg", imgcodecs::IMWRITE_JPEG_QUALITY, PixelOrder::JPEG),
+        PreviewEncoding::Webp => (".webp", imgcodecs::IMWRITE_WEBP_QUALITY, PixelOrder::WEBP),
+    };
+    let image = fit(image, settings)?;
+
+    let mut params = Vector::<i32>::new();
+    params.push(quality);
+    params.push(settings.quality.clamp(1, 100) as i32);
+    let mut encoded = Vector::<u8>::new();
+    imgcodecs::imencode(ext, &image, &mut encoded, &params)?;
+    Ok(ImagePacket {
+        w: image.cols() as u32,
+        h: image.rows() as u32,
+        pix,
+        img: encoded.to_vec(),
+        bayer: None,
+        controls: packet.controls.clone(),
+        stats: packet.stats,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mats_need_the_whole_preview() {
+        assert!(to_mat(4, 2, CV_8UC1, &[1; 8]).is_ok());
+        let Err(error) = to_mat(4, 2, CV_8UC3, &[1; 8]) else {
+            panic!("A preview too short for its size made a Mat");
+        };
+        assert_eq!(error.to_string(), "Preview has 8 bytes, 4x2 needs 24");
+    }
+}
@@ -0,0 +1,243 @@
+//! The websocket protocol.
+//!
+//! Clients send a [`Request`] per message, as JSON in text frames or MessagePack in
+//! binary frames:
+//!
+//! ```json
+//! {"v": 1, "id": 7, "cmd": "SetGain", "args": 200}
+//! {"v": 1, "id": 8, "cmd": "SetRoi", "args": {"x": 0, "y": 0, "w": 640, "h": 480, "bin": 1}}
+//! {"v": 1, "id": 9, "cmd": "PulseGuide", "args": {"direction": "NORTH", "duration_ms": 500}}
+//! {"v": 1, "id": 10, "cmd": "AbortExposure"}
+//! ```
+//!
+//! `cmd` names a [`ControlMessages`] variant and `args` holds its value, left out for
+//! variants without one. Enums in `args` are given by name, e.g. `"RAW16"`,
+//! `"TRIG_SOFT_EDGE"`, `"PINA"` or `"fits"`.
+//!
+//! Every request is answered with a [`Response`] carrying its `id`, once the command
+//! has run or was rejected. The server sends responses and all other
+//! [`crate::camera_controller::ClientPacket`]s as MessagePack maps in binary frames,
+//! with the packet kind in their `type` field:
+//!
+//! ```json
+//! {"type": "Response", "id": 7, "ok": true, "error": null, "data": null}
+//! {"type": "Response", "id": 8, "ok": false, "error": {"code": "CommandFailed", "message": "..."}, "data": null}
+//! ```
+//!
+//! Queries like `GetStatus` and `GetFrame` put their answer in `data`, see [`Reply`].
+//!
+//! Requests with a `v` other than [`PROTOCOL_VERSION`] are rejected, so the contract
+//! can change without old clients misreading it.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::camera_controller::{CameraStatus, ControlMessages, RawFrame};
+
+/// Bumped on every incompatible change to requests or packets.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Request {
+    /// Always [`PROTOCOL_VERSION`].
+    pub v: u32,
+    /// Chosen by the client and echoed in the [`Response`].
+    pub id: u64,
+    #[serde(flatten)]
+    pub command: ControlMessages,
+}
+
+/// The parts of a request that can be read even when the command can't, so a
+/// rejection can still say which request it is for.
+#[derive(Deserialize)]
+struct Envelope {
+    v: Option<u32>,
+    id: Option<u64>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum ErrorCode {
+    /// Not a request, an unknown `cmd`, or `args` that don't fit it.
+    InvalidRequest,
+    UnsupportedVersion,
+    /// The camera is unplugged.
+    CameraUnavailable,
+    /// No camera matches the selector of a REST request.
+    UnknownCamera,
+    /// The controller ran the command and it failed.
+    CommandFailed,
+    /// The controller didn't answer a REST request in time.
+    Timeout,
+}
+
+/// Also an error of its own, so the controller can fail a command with a code other
+/// than [`ErrorCode::CommandFailed`].
+#[derive(Clone, Debug, Error, Serialize)]
+#[error("{message}")]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// A request that couldn't be accepted.
+#[derive(Clone, Debug)]
+pub struct Rejection {
+    /// `None` when the request was too malformed to read its `id`.
+    pub id: Option<u64>,
+    pub error: ProtocolError,
+}
+
+impl Request {
+    pub fn from_json(text: &str) -> Result<Self, Rejection> {
+        Self::check(
+            serde_json::from_str(text).ok(),
+            serde_json::from_str(text).map_err(|e| e.to_string()),
+        )
+    }
+
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Self, Rejection> {
+        Self::check(
+            rmp_serde::from_slice(bytes).ok(),
+            rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+        )
+    }
+
+    fn check(envelope: Option<Envelope>, request: Result<Self, String>) -> Result<Self, Rejection> {
+        let (v, id) = envelope.map_or((None, None), |envelope| (envelope.v, envelope.id));
+        if let Some(v) = v.filter(|v| *v != PROTOCOL_VERSION) {
+            return Err(Rejection {
+                id,
+                error: ProtocolError::new(
+                    ErrorCode::UnsupportedVersion,
+                    format!("Version {v} is not supported, expected {PROTOCOL_VERSION}"),
+                ),
+            });
+        }
+        request.map_err(|message| Rejection {
+            id,
+            error: ProtocolError::new(ErrorCode::InvalidRequest, message),
+        })
+    }
+}
+
+/// What a query answers with.
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum Reply {
+    Status(CameraStatus),
+    Frame(RawFrame),
+}
+
+/// The answer to a [`Request`].
+#[derive(Clone, Debug, Serialize)]
+pub struct Response {
+    /// Which connection sent the request. Responses go out on a camera's shared
+    /// channel, and each connection only passes on its own.
+    #[serde(skip)]
+    pub client: u64,
+    pub id: Option<u64>,
+    pub ok: bool,
+    pub error: Option<ProtocolError>,
+    pub data: Option<Box<Reply>>,
+}
+
+impl Response {
+    /// The outcome of running request `id`.
+    pub fn new(client: u64, id: u64, result: anyhow::Result<Option<Reply>>) -> Self {
+        match result {
+            Ok(data) => Self {
+                client,
+                id: Some(id),
+                ok: true,
+                error: None,
+                data: data.map(Box::new),
+            },
+            Err(e) => {
+                let error = match e.downcast_ref::<ProtocolError>() {
+                    Some(error) => error.clone(),
+                    None => ProtocolError::new(ErrorCode::CommandFailed, format!("{e:#}")),
+                };
+                Self::error(client, Some(id), error)
+            }
+        }
+    }
+
+    pub fn error(client: u64, id: Option<u64>, error: ProtocolError) -> Self {
+        Self {
+            client,
+            id,
+            ok: false,
+            error: Some(error),
+            data: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejection(text: &str) -> Rejection {
+        Request::from_json(text).expect_err("request should be rejected")
+    }
+
+    #[test]
+    fn accepts_commands_with_and_without_args() {
+        let request =
+            Request::from_json(r#"{"v": 1, "id": 7, "cmd": "SetGain", "args": 200}"#).unwrap();
+        assert_eq!(request.id, 7);
+        assert_eq!(request.command, ControlMessages::SetGain(200));
+
+        let request = Request::from_json(r#"{"v": 1, "id": 10, "cmd": "AbortExposure"}"#).unwrap();
+        assert_eq!(request.command, ControlMessages::AbortExposure);
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let rejection = rejection(r#"{"v": 2, "id": 3, "cmd": "AbortExposure"}"#);
+        assert_eq!(rejection.id, Some(3));
+        assert_eq!(rejection.error.code, ErrorCode::UnsupportedVersion);
+    }
+
+    #[test]
+    fn rejects_unknown_commands_and_bad_args() {
+        for text in [
+            r#"{"v": 1, "id": 4, "cmd": "Explode"}"#,
+            r#"{"v": 1, "id": 4, "cmd": "SetGain", "args": "high"}"#,
+            r#"{"v": 1, "id": 4, "cmd": "SetGain"}"#,
+            r#"{"id": 4, "cmd": "AbortExposure"}"#,
+        ] {
+            let rejection = rejection(text);
+            assert_eq!(rejection.id, Some(4), "{text}");
+            assert_eq!(rejection.error.code, ErrorCode::InvalidRequest, "{text}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_json_without_an_id() {
+        let rejection = rejection(r#"{"v": 1, "id": 5, "cmd": "#);
+        assert_eq!(rejection.id, None);
+        assert_eq!(rejection.error.code, ErrorCode::InvalidRequest);
+    }
+
+    #[test]
+    fn reads_msgpack_like_json() {
+        let json: serde_json::Value =
+            serde_json::from_str(r#"{"v": 1, "id": 8, "cmd": "SetGain", "args": 100}"#).unwrap();
+        let bytes = rmp_serde::to_vec_named(&json).unwrap();
+        let request = Request::from_msgpack(&bytes).unwrap();
+        assert_eq!(
+            (request.id, request.command),
+            (8, ControlMessages::SetGain(100))
+        );
+    }
+}
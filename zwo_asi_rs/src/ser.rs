@@ -0,0 +1,240 @@
+//! SER v3 video files for planetary captures.
+//!
+//! [`SerWriter`] streams frames to disk as they arrive, so long captures don't have
+//! to fit in memory, and writes the frame count and the per frame timestamps once the
+//! capture finishes. RAW frames keep their bayer pattern in the header, see
+//! [`ColorId`].
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::Local;
+
+use crate::{
+    asi::{ROIFormat, IMG_TYPE},
+    debayer::CfaPattern,
+};
+
+const HEADER_SIZE: usize = 178;
+const FRAME_COUNT_OFFSET: u64 = 38;
+const TEXT_FIELD_SIZE: usize = 40;
+
+/// .NET ticks (100ns since 0001-01-01) at the Unix epoch, which SER timestamps use.
+const UNIX_EPOCH_TICKS: i64 = 621_355_968_000_000_000;
+
+#[repr(i32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorId {
+    Mono = 0,
+    BayerRggb = 8,
+    BayerGrbg = 9,
+    BayerGbrg = 10,
+    BayerBggr = 11,
+    Rgb = 100,
+    Bgr = 101,
+}
+
+impl ColorId {
+    /// The colour layout of frames in `roi`, where `cfa` is the bayer pattern at the
+    /// top left of the frame, see [`CfaPattern::for_frame`].
+    pub fn for_format(roi: &ROIFormat, cfa: Option<CfaPattern>) -> Self {
+        match (roi.img_type, cfa) {
+            (IMG_TYPE::RGB24, _) => Self::Bgr,
+            (IMG_TYPE::Y8, _) | (_, None) => Self::Mono,
+            (IMG_TYPE::RAW8 | IMG_TYPE::RAW16, Some(cfa)) => match cfa {
+                CfaPattern::Rggb => Self::BayerRggb,
+                CfaPattern::Grbg => Self::BayerGrbg,
+                CfaPattern::Gbrg => Self::BayerGbrg,
+                CfaPattern::Bggr => Self::BayerBggr,
+            },
+        }
+    }
+}
+
+/// Free text fields of the SER header. Each is truncated to 40 bytes.
+#[derive(Clone, Debug, Default)]
+pub struct SerInfo {
+    pub observer: String,
+    pub instrument: String,
+    pub telescope: String,
+}
+
+fn ticks(time: SystemTime) -> i64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    UNIX_EPOCH_TICKS + (since_epoch.as_nanos() / 100) as i64
+}
+
+fn text_field(text: &str) -> [u8; TEXT_FIELD_SIZE] {
+    let mut field = [0; TEXT_FIELD_SIZE];
+    for (dst, src) in field.iter_mut().zip(text.bytes()) {
+        *dst = src;
+    }
+    field
+}
+
+/// Streams frames into a SER v3 file.
+///
+/// Frames go straight to disk; only the 8 byte per frame timestamps are kept until
+/// [`SerWriter::finish`] writes them as the trailer and fills in the frame count.
+pub struct SerWriter {
+    file: BufWriter<File>,
+    frame_size: usize,
+    timestamps: Vec<i64>,
+}
+
+impl SerWriter {
+    pub fn create(
+        path: impl AsRef<Path>,
+        roi: &ROIFormat,
+        cfa: Option<CfaPattern>,
+        info: &SerInfo,
+    ) -> io::Result<Self> {
+        let color_id = ColorId::for_format(roi, cfa);
+        let pixel_depth: i32 = match roi.img_type {
+            IMG_TYPE::RAW16 => 16,
+            _ => 8,
+        };
+        let now = SystemTime::now();
+        let utc_offset = Local::now().offset().local_minus_utc() as i64;
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(b"LUCAM-RECORDER");
+        header.extend_from_slice(&0i32.to_le_bytes()); // LuID
+        header.extend_from_slice(&(color_id as i32).to_le_bytes());
+        // The spec says 1 means little endian, but readers all follow the original
+        // software, which wrote 0 for the little endian data the SDK gives us
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&roi.width.to_le_bytes());
+        header.extend_from_slice(&roi.height.to_le_bytes());
+        header.extend_from_slice(&pixel_depth.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes()); // FrameCount, set by finish()
+        header.extend_from_slice(&text_field(&info.observer));
+        header.extend_from_slice(&text_field(&info.instrument));
+        header.extend_from_slice(&text_field(&info.telescope));
+        header.extend_from_slice(&(ticks(now) + utc_offset * 10_000_000).to_le_bytes());
+        header.extend_from_slice(&ticks(now).to_le_bytes());
+        debug_assert_eq!(header.len(), HEADER_SIZE);
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&header)?;
+        Ok(Self {
+            file,
+            frame_size: (roi.width * roi.height * roi.img_type.bytes_per_pixel()) as usize,
+            timestamps: Vec::new(),
+        })
+    }
+
+    /// Appends a frame, as returned by the SDK, taken at `timestamp`.
+    pub fn write_frame(&mut self, frame: &[u8], timestamp: SystemTime) -> io::Result<()> {
+        if frame.len() != self.frame_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Frame is {} bytes, expected {}",
+                    frame.len(),
+                    self.frame_size
+                ),
+            ));
+        }
+        self.file.write_all(frame)?;
+        self.timestamps.push(ticks(timestamp));
+        Ok(())
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.timestamps.len()
+    }
+
+    /// Writes the timestamp trailer and frame count. Without this the file has no
+    /// frames as far as readers are concerned.
+    pub fn finish(mut self) -> io::Result<()> {
+        for timestamp in &self.timestamps {
+            self.file.write_all(&timestamp.to_le_bytes())?;
+        }
+        self.file.seek(SeekFrom::Start(FRAME_COUNT_OFFSET))?;
+        self.file
+            .write_all(&(self.timestamps.len() as i32).to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roi(img_type: IMG_TYPE) -> ROIFormat {
+        ROIFormat {
+            width: 4,
+            height: 2,
+            bin: 1,
+            img_type,
+            start_x: 0,
+            start_y: 0,
+        }
+    }
+
+    fn i32_at(bytes: &[u8], offset: usize) -> i32 {
+        i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn writes_header_frames_and_timestamps() {
+        let path = std::env::temp_dir().join(format!("ser_test_{}.ser", std::process::id()));
+        let info = SerInfo {
+            observer: "Observer".to_string(),
+            instrument: "x".repeat(50),
+            telescope: String::new(),
+        };
+        let mut writer =
+            SerWriter::create(&path, &roi(IMG_TYPE::RAW16), Some(CfaPattern::Grbg), &info).unwrap();
+        let second = UNIX_EPOCH + std::time::Duration::from_secs(1);
+        writer.write_frame(&[1; 16], UNIX_EPOCH).unwrap();
+        writer.write_frame(&[2; 16], second).unwrap();
+        assert!(writer.write_frame(&[3; 15], second).is_err());
+        assert_eq!(writer.frame_count(), 2);
+        writer.finish().unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(bytes.len(), HEADER_SIZE + 2 * 16 + 2 * 8);
+        assert_eq!(&bytes[..14], b"LUCAM-RECORDER");
+        assert_eq!(i32_at(&bytes, 18), ColorId::BayerGrbg as i32);
+        assert_eq!(i32_at(&bytes, 26), 4);
+        assert_eq!(i32_at(&bytes, 30), 2);
+        assert_eq!(i32_at(&bytes, 34), 16);
+        assert_eq!(i32_at(&bytes, FRAME_COUNT_OFFSET as usize), 2);
+        assert_eq!(&bytes[42..50], b"Observer");
+        assert_eq!(&bytes[82..122], "x".repeat(40).as_bytes());
+        assert!(bytes[122..162].iter().all(|b| *b == 0));
+
+        let frames = &bytes[HEADER_SIZE..HEADER_SIZE + 32];
+        assert_eq!(frames, [[1; 16], [2; 16]].concat());
+        let trailer: Vec<i64> = bytes[HEADER_SIZE + 32..]
+            .chunks(8)
+            .map(|t| i64::from_le_bytes(t.try_into().unwrap()))
+            .collect();
+        assert_eq!(trailer, [UNIX_EPOCH_TICKS, UNIX_EPOCH_TICKS + 10_000_000]);
+    }
+
+    #[test]
+    fn color_id_follows_format() {
+        let cfa = Some(CfaPattern::Bggr);
+        assert_eq!(
+            ColorId::for_format(&roi(IMG_TYPE::RAW8), cfa),
+            ColorId::BayerBggr
+        );
+        assert_eq!(
+            ColorId::for_format(&roi(IMG_TYPE::RAW8), None),
+            ColorId::Mono
+        );
+        assert_eq!(ColorId::for_format(&roi(IMG_TYPE::Y8), cfa), ColorId::Mono);
+        assert_eq!(
+            ColorId::for_format(&roi(IMG_TYPE::RGB24), cfa),
+            ColorId::Bgr
+        );
+    }
+}
@@ -0,0 +1,494 @@
+//! The cameras the server runs, shared by its websocket, REST, Alpaca and INDI front
+//! ends.
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use tokio::sync::{broadcast, watch};
+use tracing::{error, info, warn};
+
+use crate::{
+    asi::{CameraInfo, ROIFormat, GUIDE_DIRECTION, IMG_TYPE},
+    camera_controller::{
+        is_camera_removed, CameraController, CameraStatus, ClientPacket, ConnectionEvent,
+        ControlMessages, ControlRequest, Roi,
+    },
+    guide,
+    preview::LatestPreview,
+    protocol::{ErrorCode, ProtocolError, Reply},
+    CameraBackend,
+};
+
+/// How long [`CameraSlot::run`] waits for the controller to answer. The controller
+/// drops commands it gets to later than this, so they don't run after the client
+/// gave up on them.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+static NEXT_CLIENT: AtomicU64 = AtomicU64::new(0);
+
+/// A new ID for a client of the controllers, so [`crate::protocol::Response`]s find
+/// their way back to it.
+pub fn next_client_id() -> u64 {
+    NEXT_CLIENT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A camera the server has seen, and the channels to the controller running it.
+///
+/// Slots outlive unplugging, so clients stay subscribed and the settings they made
+/// are applied again when the camera comes back.
+#[derive(Clone)]
+pub struct CameraSlot {
+    /// As of when the camera was last connected.
+    pub info: CameraInfo,
+    /// `None` while the camera is unplugged.
+    pub camera: Option<Arc<dyn CameraBackend>>,
+    pub tx: broadcast::Sender<ControlRequest>,
+    pub rx: broadcast::Sender<ClientPacket>,
+    /// Previews go to clients separately from `rx`, so each can skip the ones it has
+    /// no time for, see [`crate::preview`].
+    pub previews: watch::Sender<Option<LatestPreview>>,
+    /// The latest of each setting clients made, in the order they were made.
+    settings: Arc<Mutex<Vec<ControlMessages>>>,
+}
+
+impl CameraSlot {
+    fn new(info: CameraInfo) -> Self {
+        // Broadcast channel for WebSocket connections
+        let (tx, _) = broadcast::channel(32);
+        let (tx_cmds, _) = broadcast::channel(32);
+        Self {
+            info,
+            camera: None,
+            tx: tx_cmds,
+            rx: tx,
+            previews: watch::Sender::new(None),
+            settings: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Passes `cmd` on to the controller, remembering it if it is a setting.
+    pub fn send(
+        &self,
+        cmd: ControlMessages,
+    ) -> Result<usize, broadcast::error::SendError<ControlRequest>> {
+        self.submit(ControlRequest::from(cmd))
+    }
+
+    /// Like [`CameraSlot::send`], with the controller answering request `id` of
+    /// `client` once it ran the command.
+    pub fn request(
+        &self,
+        cmd: ControlMessages,
+        client: u64,
+        id: u64,
+    ) -> Result<usize, broadcast::error::SendError<ControlRequest>> {
+        self.submit(ControlRequest {
+            command: cmd,
+            reply_to: Some((client, id)),
+            expires: None,
+        })
+    }
+
+    fn submit(
+        &self,
+        request: ControlRequest,
+    ) -> Result<usize, broadcast::error::SendError<ControlRequest>> {
+        self.remember(&request.command);
+        self.tx.send(request)
+    }
+
+    /// Keeps `cmd` to apply again on a reconnect, if it is a setting.
+    fn remember(&self, cmd: &ControlMessages) {
+        if cmd.is_setting() {
+            let mut settings = self.settings.lock().unwrap();
+            settings.retain(|setting| !cmd.replaces(setting));
+            settings.push(cmd.clone());
+        }
+    }
+
+    /// Runs `cmd` and waits for the controller's answer, for clients that don't
+    /// keep a websocket open. Settings are only kept for a reconnect once the
+    /// controller accepted them.
+    pub async fn run(&self, cmd: ControlMessages) -> Result<Option<Reply>, ProtocolError> {
+        let unavailable =
+            || ProtocolError::new(ErrorCode::CameraUnavailable, "The camera is not connected");
+        let client = next_client_id();
+        // Subscribed before sending, so the answer can't be missed
+        let mut packets = self.rx.subscribe();
+        self.tx
+            .send(ControlRequest {
+                command: cmd.clone(),
+                reply_to: Some((client, 0)),
+                expires: Some(Instant::now() + COMMAND_TIMEOUT),
+            })
+            .map_err(|_| unavailable())?;
+        let response = tokio::time::timeout(COMMAND_TIMEOUT, async {
+            loop {
+                match packets.recv().await {
+                    Ok(ClientPacket::Response(response)) if response.client == client => {
+                        return Some(response)
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .await
+        .map_err(|_| ProtocolError::new(ErrorCode::Timeout, "The camera didn't answer in time"))?
+        .ok_or_else(unavailable)?;
+        match response.error {
+            None => {
+                self.remember(&cmd);
+                Ok(response.data.map(|data| *data))
+            }
+            Some(error) => Err(error),
+        }
+    }
+
+    /// Sends a guide pulse straight to the camera, see [`guide::pulse`].
+    pub fn pulse_guide(
+        &self,
+        direction: GUIDE_DIRECTION,
+        duration: Duration,
+    ) -> Result<(), ProtocolError> {
+        let camera = self.connected_camera()?;
+        guide::pulse(camera.as_ref(), direction, duration).map_err(command_failed)
+    }
+
+    /// Ends the running guide pulses straight away.
+    pub fn stop_guiding(&self) -> Result<(), ProtocolError> {
+        let camera = self.connected_camera()?;
+        guide::stop(camera.as_ref()).map_err(command_failed)
+    }
+
+    fn connected_camera(&self) -> Result<&Arc<dyn CameraBackend>, ProtocolError> {
+        self.camera.as_ref().ok_or_else(|| {
+            ProtocolError::new(ErrorCode::CameraUnavailable, "The camera is not connected")
+        })
+    }
+
+    pub async fn status(&self) -> Result<CameraStatus, ProtocolError> {
+        match self.run(ControlMessages::GetStatus).await? {
+            Some(Reply::Status(status)) => Ok(status),
+            _ => Err(ProtocolError::new(
+                ErrorCode::CommandFailed,
+                "GetStatus didn't answer with a status",
+            )),
+        }
+    }
+
+    /// Takes a single RAW16 exposure of `roi` without saving it, for clients that
+    /// download it with `GetExposure`. The camera only takes that format for the
+    /// exposure, so other clients, and the settings restored on a reconnect, keep
+    /// theirs. Fails with [`ErrorCode::InvalidRequest`] if the camera can't take `roi`.
+    pub async fn expose(&self, roi: Roi, seconds: f64, dark: bool) -> Result<(), ProtocolError> {
+        let Roi { x, y, w, h, bin } = roi;
+        self.run(ControlMessages::StartExposure {
+            seconds,
+            count: 1,
+            dark,
+            save: false,
+            format: Some(ROIFormat {
+                width: w,
+                height: h,
+                bin,
+                img_type: IMG_TYPE::RAW16,
+                start_x: x,
+                start_y: y,
+            }),
+        })
+        .await?;
+        Ok(())
+    }
+
+    fn connection_event(&self) -> ClientPacket {
+        ClientPacket::Connection(ConnectionEvent {
+            camera_id: self.info.camera_id,
+            name: self.info.name.clone(),
+            serial: self.info.serial.clone(),
+            connected: self.camera.is_some(),
+        })
+    }
+}
+
+/// Like a failed command's [`crate::protocol::Response`], for the ones the server runs
+/// itself.
+fn command_failed(e: anyhow::Error) -> ProtocolError {
+    ProtocolError::new(ErrorCode::CommandFailed, format!("{e:#}"))
+}
+
+/// The directory under `./images` a camera's captures go to, named by its custom
+/// ID, or its serial when it has none.
+fn image_dir_name(info: &CameraInfo) -> String {
+    let name = match (&info.custom_id, &info.serial) {
+        (Some(id), _) if !id.is_empty() => id.clone(),
+        (_, Some(serial)) => serial.clone(),
+        _ => info.camera_id.to_string(),
+    };
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Runs a [`CameraController`] for `camera` on its own thread, taking commands from
+/// and sending packets to `slot`'s channels.
+fn spawn_controller(slot: &CameraSlot, camera: Arc<dyn CameraBackend>) -> JoinHandle<()> {
+    let image_dir = Path::new("./images").join(image_dir_name(&slot.info));
+    // Subscribed before returning so no command sent after this is missed
+    let rx_cmds = slot.tx.subscribe();
+    let tx_thread = slot.rx.clone();
+    let previews = slot.previews.clone();
+    std::thread::spawn(move || {
+        let result = CameraController::new(camera, tx_thread, rx_cmds, previews)
+            .context("Initializing CameraController")
+            .and_then(|mut controller| {
+                controller.set_image_dir(image_dir);
+                controller.run().context("Running CameraController")
+            });
+        match result {
+            Ok(()) => {}
+            Err(e) if is_camera_removed(&e) => warn!("Camera was removed: {e:?}"),
+            Err(e) => error!("CameraController failed with {e:?}"),
+        }
+    })
+}
+
+/// Every camera the server has seen, in the order they were first connected.
+/// Requests pick one with a `camera` parameter, see [`CameraInfo::matches`], and go
+/// to the first camera without it.
+#[derive(Clone, Default)]
+pub struct AppState {
+    pub cameras: Arc<RwLock<Vec<CameraSlot>>>,
+}
+
+impl AppState {
+    pub fn find(&self, selector: Option<&str>) -> Option<CameraSlot> {
+        let cameras = self.cameras.read().unwrap();
+        match selector {
+            None => cameras.first().cloned(),
+            Some(selector) => cameras
+                .iter()
+                .find(|slot| slot.info.matches(selector))
+                .or_else(|| {
+                    cameras
+                        .iter()
+                        .find(|slot| slot.camera.is_some() && slot.info.matches_index(selector))
+                })
+                .cloned(),
+        }
+    }
+
+    /// Replaces the info of the slot using `tx`, after the camera's ID changed.
+    pub fn update_info(&self, tx: &broadcast::Sender<ControlRequest>, info: CameraInfo) {
+        let mut cameras = self.cameras.write().unwrap();
+        if let Some(slot) = cameras.iter_mut().find(|slot| slot.tx.same_channel(tx)) {
+            slot.info = info;
+        }
+    }
+
+    /// Tells clients of every camera that `slot` was connected or removed.
+    fn broadcast_connection(&self, slot: &CameraSlot) {
+        let event = slot.connection_event();
+        for other in self.cameras.read().unwrap().iter() {
+            let _ = other.rx.send(event.clone());
+        }
+    }
+
+    /// Starts a controller for a newly connected camera, in the slot it had before if
+    /// it was connected earlier. Returns the slot's index and the controller thread.
+    pub fn connect(&self, camera: Arc<dyn CameraBackend>) -> (usize, JoinHandle<()>) {
+        let info = camera.camera_info();
+        let (index, slot) = {
+            let mut cameras = self.cameras.write().unwrap();
+            let index = match cameras
+                .iter()
+                .position(|slot| slot.camera.is_none() && slot.info.is_same_camera(&info))
+            {
+                Some(index) => index,
+                None => {
+                    cameras.push(CameraSlot::new(info.clone()));
+                    cameras.len() - 1
+                }
+            };
+            cameras[index].info = info;
+            cameras[index].camera = Some(camera.clone());
+            (index, cameras[index].clone())
+        };
+
+        let thread = spawn_controller(&slot, camera);
+        let settings = slot.settings.lock().unwrap().clone();
+        if !settings.is_empty() {
+            info!(
+                "Restoring {} settings on {}",
+                settings.len(),
+                slot.info.name
+            );
+        }
+        for setting in settings {
+            let _ = slot.tx.send(setting.into());
+        }
+        if slot.rx.receiver_count() > 0 {
+            let _ = slot.tx.send(ControlMessages::StartPreview.into());
+        }
+        self.broadcast_connection(&slot);
+        (index, thread)
+    }
+
+    /// Stops the controllers of `controllers`, given as by [`AppState::connect`], all
+    /// at once so their coolers warm up together.
+    pub fn disconnect_all(&self, controllers: Vec<(usize, JoinHandle<()>)>) {
+        for (index, _) in &controllers {
+            let tx = self.cameras.read().unwrap()[*index].tx.clone();
+            let _ = tx.send(ControlMessages::Shutdown.into());
+        }
+        for (index, thread) in controllers {
+            self.disconnect(index, thread);
+        }
+    }
+
+    /// Stops the controller of an unplugged camera and closes it. Controllers of
+    /// cameras that are still there warm the sensor up first.
+    pub fn disconnect(&self, index: usize, thread: JoinHandle<()>) {
+        let tx = self.cameras.read().unwrap()[index].tx.clone();
+        let _ = tx.send(ControlMessages::Shutdown.into());
+        if thread.join().is_err() {
+            error!("CameraController panicked");
+        }
+        let slot = {
+            let mut cameras = self.cameras.write().unwrap();
+            cameras[index].camera = None;
+            cameras[index].clone()
+        };
+        info!("{} disconnected", slot.info.name);
+        self.broadcast_connection(&slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{SimulatedCamera, SimulatorConfig};
+
+    fn simulator() -> Arc<SimulatedCamera> {
+        Arc::new(SimulatedCamera::new(SimulatorConfig {
+            max_width: 320,
+            max_height: 240,
+            ..Default::default()
+        }))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn slots_run_commands_and_restore_settings_on_reconnect() {
+        let state = AppState::default();
+        let camera = simulator();
+        let (index, thread) = state.connect(camera.clone());
+        let slot = state.find(None).unwrap();
+        assert!(state.find(Some("no such camera")).is_none());
+        assert!(state.find(Some("5A5A000000000001")).is_some());
+        assert!(state.find(Some("0")).is_some());
+
+        slot.run(ControlMessages::SetGain(100)).await.unwrap();
+        slot.run(ControlMessages::SetBin(2)).await.unwrap();
+        let status = slot.status().await.unwrap();
+        assert_eq!(status.controls.gain, 100);
+        assert_eq!((status.roi.w, status.roi.h, status.roi.bin), (160, 120, 2));
+        // Rejected, so it doesn't replace the bin that worked
+        let error = slot.run(ControlMessages::SetBin(7)).await.unwrap_err();
+        assert_eq!(error.code, ErrorCode::CommandFailed);
+
+        let mut events = slot.rx.subscribe();
+        tokio::task::spawn_blocking({
+            let state = state.clone();
+            move || state.disconnect(index, thread)
+        })
+        .await
+        .unwrap();
+        let unplugged = state.find(None).unwrap();
+        assert!(unplugged.camera.is_none());
+        // The SDK index may already belong to another camera
+        assert!(state.find(Some("0")).is_none());
+        assert!(state.find(Some("5a5a000000000001")).is_some());
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ClientPacket::Connection(ConnectionEvent {
+                connected: false,
+                ..
+            })
+        ));
+        let error = unplugged.pulse_guide(GUIDE_DIRECTION::NORTH, Duration::from_millis(10));
+        assert_eq!(error.unwrap_err().code, ErrorCode::CameraUnavailable);
+
+        // Plugged back in, the camera gets its slot and settings back
+        let (index, thread) = state.connect(camera);
+        assert_eq!(index, 0);
+        assert_eq!(state.cameras.read().unwrap().len(), 1);
+        let status = slot.status().await.unwrap();
+        assert_eq!(status.controls.gain, 100);
+        assert_eq!(status.roi.bin, 2);
+
+        tokio::task::spawn_blocking(move || state.disconnect(index, thread))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exposures_keep_the_shared_format() {
+        let state = AppState::default();
+        let (index, thread) = state.connect(simulator());
+        let slot = state.find(None).unwrap();
+        slot.run(ControlMessages::SetImageType(IMG_TYPE::RAW8))
+            .await
+            .unwrap();
+        slot.run(ControlMessages::SetBin(2)).await.unwrap();
+        let before = slot.status().await.unwrap();
+        let settings = slot.settings.lock().unwrap().len();
+
+        let full = Roi {
+            x: 0,
+            y: 0,
+            w: 320,
+            h: 240,
+            bin: 1,
+        };
+        let error = slot.expose(Roi { w: 330, ..full }, 0.01, false).await;
+        assert_eq!(error.unwrap_err().code, ErrorCode::InvalidRequest);
+        slot.expose(full, 0.01, false).await.unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while slot.status().await.unwrap().last_exposure.is_none() {
+            assert!(Instant::now() < deadline, "The exposure didn't finish");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let Some(Reply::Frame(frame)) = slot.run(ControlMessages::GetExposure).await.unwrap()
+        else {
+            panic!("GetExposure didn't answer with a frame");
+        };
+        assert_eq!(
+            (frame.w, frame.h, frame.img_type),
+            (320, 240, IMG_TYPE::RAW16)
+        );
+
+        // Other clients and a reconnect still get the format they had
+        let after = slot.status().await.unwrap();
+        assert_eq!(after.roi, before.roi);
+        assert_eq!(after.img_type, before.img_type);
+        assert_eq!(slot.settings.lock().unwrap().len(), settings);
+
+        tokio::task::spawn_blocking(move || state.disconnect(index, thread))
+            .await
+            .unwrap();
+    }
+}
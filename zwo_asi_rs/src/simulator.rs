@@ -0,0 +1,1491 @@
+use std::{
+    collections::HashMap,
+    ffi::c_char,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::sleep,
+    time::{Duration, Instant, SystemTime},
+};
+
+use chrono::{DateTime, Timelike, Utc};
+use tracing::info;
+
+use crate::{
+    asi::{
+        self, ControlCaps, FrameGps, GpsData, ROIFormat, TriggerOutputConf, ASI_ERROR, CAMERA_MODE,
+        CONTROL_TYPE, EXPOSURE_STATUS, GUIDE_DIRECTION, IMG_TYPE, TRIG_OUTPUT,
+    },
+    guide::PulseGuider,
+    hotplug::CameraSource,
+    CameraBackend,
+};
+
+/// What the simulated sensor is looking at.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SimPattern {
+    StarField,
+    Gradient,
+    Noise,
+    TestPattern,
+}
+
+impl FromStr for SimPattern {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "stars" | "starfield" => Ok(Self::StarField),
+            "gradient" => Ok(Self::Gradient),
+            "noise" => Ok(Self::Noise),
+            "test" | "testpattern" => Ok(Self::TestPattern),
+            _ => Err(anyhow::anyhow!("Unknown simulator pattern {s}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SimulatorConfig {
+    pub name: String,
+    pub camera_id: i32,
+    /// 16 hex digits, like the SDK reports.
+    pub serial: String,
+    /// Initial custom ID, at most 8 bytes.
+    pub custom_id: String,
+    pub max_width: i32,
+    pub max_height: i32,
+    pub is_color: bool,
+    pub bayer_pattern: asi::ASI_BAYER_PATTERN,
+    pub bit_depth: i32,
+    pub pixel_size: f64,
+    pub max_fps: f64,
+    pub pattern: SimPattern,
+    pub seed: u64,
+    /// Latitude, longitude and altitude of the simulated GPS receiver, `None` for a
+    /// camera without one.
+    pub gps_location: Option<(f64, f64, i32)>,
+    /// Whether the sensor has a cooler, with its fan and anti-dew heater.
+    pub cooler: bool,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            name: "ZWO ASI Simulator".to_string(),
+            camera_id: 0,
+            serial: "5a5a000000000001".to_string(),
+            custom_id: String::new(),
+            max_width: 1920,
+            max_height: 1080,
+            is_color: true,
+            bayer_pattern: asi::ASI_BAYER_PATTERN_ASI_BAYER_RG,
+            bit_depth: 12,
+            pixel_size: 2.9,
+            max_fps: 30.,
+            pattern: SimPattern::StarField,
+            seed: 0x5eed_cafe,
+            gps_location: Some((51.4779, -0.0015, 46)),
+            cooler: true,
+        }
+    }
+}
+
+/// Full scale of the simulated scene, in electrons per second.
+const SCENE_FLUX: f32 = 4000.;
+const SKY_FLUX: f32 = 20.;
+const READ_NOISE: f32 = 2.;
+const ELEC_PER_ADU: f32 = 1.;
+
+/// The simulator has no trigger input, so only the soft trigger modes are offered.
+const CAMERA_MODES: [CAMERA_MODE; 3] = [
+    CAMERA_MODE::NORMAL,
+    CAMERA_MODE::TRIG_SOFT_EDGE,
+    CAMERA_MODE::TRIG_SOFT_LEVEL,
+];
+/// How often a triggered read checks for a trigger while waiting for one.
+const TRIGGER_POLL: Duration = Duration::from_millis(5);
+/// How fast guide pulses move the stars, in sensor pixels per second.
+const GUIDE_RATE: f32 = 5.;
+/// Rolling shutter delay between rows, which offsets the GPS start and end lines.
+const LINE_TIME: Duration = Duration::from_micros(10);
+/// In °C, what the sensor warms up to with the cooler off.
+const AMBIENT_TEMPERATURE: f64 = 20.;
+/// How far below ambient the cooler can get the sensor, in °C.
+const MAX_COOLING: f64 = 35.;
+/// How quickly the sensor follows the cooler, in seconds.
+const THERMAL_TIME_CONSTANT: f64 = 20.;
+/// Largest change of brightness auto exposure makes from one video frame to the next.
+const AUTO_STEP: f64 = 1.5;
+
+struct Star {
+    x: f32,
+    y: f32,
+    flux: f32,
+    color: [f32; 3],
+}
+
+/// Small xorshift generator, good enough for sensor noise.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn uniform(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Approximately normal, zero mean and unit variance.
+    fn normal(&mut self) -> f32 {
+        (self.uniform() + self.uniform() + self.uniform() + self.uniform() - 2.) * 3f32.sqrt()
+    }
+}
+
+/// How far guiding has moved the simulated mount.
+#[derive(Default)]
+struct GuideDrift {
+    /// Offset in sensor pixels from pulses that have ended.
+    offset: (f32, f32),
+    /// Start of the pulse running in each direction.
+    active: [Option<Instant>; 4],
+}
+
+impl GuideDrift {
+    fn step(direction: GUIDE_DIRECTION, duration: Duration) -> (f32, f32) {
+        let pixels = duration.as_secs_f32() * GUIDE_RATE;
+        match direction {
+            GUIDE_DIRECTION::NORTH => (0., -pixels),
+            GUIDE_DIRECTION::SOUTH => (0., pixels),
+            GUIDE_DIRECTION::EAST => (pixels, 0.),
+            GUIDE_DIRECTION::WEST => (-pixels, 0.),
+        }
+    }
+
+    fn set(&mut self, direction: GUIDE_DIRECTION, on: bool) {
+        let now = Instant::now();
+        let active = &mut self.active[direction as usize];
+        if on {
+            active.get_or_insert(now);
+        } else if let Some(start) = active.take() {
+            let (dx, dy) = Self::step(direction, now - start);
+            self.offset = (self.offset.0 + dx, self.offset.1 + dy);
+        }
+    }
+
+    fn offset(&self) -> (f32, f32) {
+        GUIDE_DIRECTION::ALL
+            .into_iter()
+            .filter_map(|direction| {
+                let start = self.active[direction as usize]?;
+                Some(Self::step(direction, start.elapsed()))
+            })
+            .fold(self.offset, |(x, y), (dx, dy)| (x + dx, y + dy))
+    }
+}
+
+struct SimState {
+    values: HashMap<CONTROL_TYPE, (i64, bool)>,
+    roi: ROIFormat,
+    video_running: bool,
+    next_frame: Instant,
+    dropped_frames: i32,
+    exposure: Option<(Instant, Duration)>,
+    exp_status: EXPOSURE_STATUS,
+    /// The running exposure is a dark frame, as if the shutter were closed.
+    dark_frame: bool,
+    camera_mode: CAMERA_MODE,
+    /// When the triggered frame is ready to read, and its exposure time.
+    trigger: Option<(Instant, Duration)>,
+    /// Start of a soft level trigger that hasn't been released yet.
+    level_start: Option<Instant>,
+    trigger_outputs: [TriggerOutputConf; 2],
+    /// Exposure start and end of the last frame read, for the GPS timestamps.
+    frame_times: Option<(Instant, Instant)>,
+    custom_id: String,
+    frame_count: u64,
+    rng: Rng,
+    sensor_temperature: f64,
+    temperature_updated: Instant,
+}
+
+impl SimState {
+    fn value(&self, control_type: CONTROL_TYPE) -> i64 {
+        self.values.get(&control_type).map(|v| v.0).unwrap_or(0)
+    }
+
+    fn exposure(&self) -> Duration {
+        Duration::from_micros(self.value(CONTROL_TYPE::EXPOSURE).max(1) as u64)
+    }
+
+    /// Moves `EXPOSURE` and `GAIN` that are in auto mode towards
+    /// `AUTO_TARGET_BRIGHTNESS` after a video frame. Like the SDK it lengthens the
+    /// exposure up to `AUTO_MAX_EXP` before it raises the gain up to `AUTO_MAX_GAIN`.
+    fn auto_expose(&mut self, frame: &[u8]) {
+        let (exposure, exposure_auto) = self.values[&CONTROL_TYPE::EXPOSURE];
+        let (gain, gain_auto) = self.values[&CONTROL_TYPE::GAIN];
+        if !exposure_auto && !gain_auto {
+            return;
+        }
+        // The mean of the 8 bit values, or of the high bytes of RAW16 data
+        let (skip, step) = match self.roi.img_type {
+            IMG_TYPE::RAW16 => (1, 2 * 7),
+            _ => (0, 7),
+        };
+        let (sum, count) = frame
+            .iter()
+            .skip(skip)
+            .step_by(step)
+            .fold((0u64, 0u64), |(sum, count), v| (sum + *v as u64, count + 1));
+        let brightness = sum as f64 / count.max(1) as f64;
+        let target = self.value(CONTROL_TYPE::AUTO_TARGET_BRIGHTNESS) as f64;
+        let mut factor = (target / brightness.max(1.)).clamp(1. / AUTO_STEP, AUTO_STEP);
+        if (factor - 1.).abs() < 0.05 {
+            return;
+        }
+        if exposure_auto {
+            let max = self.value(CONTROL_TYPE::AUTO_MAX_EXP) * 1000;
+            let next = ((exposure as f64 * factor).round() as i64).clamp(32, max.max(32));
+            factor *= exposure as f64 / next as f64;
+            self.values.insert(CONTROL_TYPE::EXPOSURE, (next, true));
+        }
+        if gain_auto {
+            let max = self.value(CONTROL_TYPE::AUTO_MAX_GAIN);
+            let next = (gain as f64 + 200. * factor.log10()).round() as i64;
+            self.values
+                .insert(CONTROL_TYPE::GAIN, (next.clamp(0, max), true));
+        }
+    }
+
+    /// Moves the sensor towards the cooler's target, or towards ambient with the cooler
+    /// off, and updates `TEMPERATURE` and `COOLER_POWER_PERC` to match.
+    fn update_temperature(&mut self) {
+        let elapsed = self.temperature_updated.elapsed().as_secs_f64();
+        self.temperature_updated = Instant::now();
+        let cooler_on = self.value(CONTROL_TYPE::COOLER_ON) != 0;
+        let goal = match cooler_on {
+            true => (self.value(CONTROL_TYPE::TARGET_TEMP) as f64)
+                .max(AMBIENT_TEMPERATURE - MAX_COOLING),
+            false => AMBIENT_TEMPERATURE,
+        };
+        self.sensor_temperature +=
+            (goal - self.sensor_temperature) * (1. - (-elapsed / THERMAL_TIME_CONSTANT).exp());
+        let power = match cooler_on {
+            true => (AMBIENT_TEMPERATURE - self.sensor_temperature) / MAX_COOLING * 100.,
+            false => 0.,
+        };
+        let temperature = (self.sensor_temperature * 10.).round() as i64;
+        self.values
+            .insert(CONTROL_TYPE::TEMPERATURE, (temperature, false));
+        if let Some(value) = self.values.get_mut(&CONTROL_TYPE::COOLER_POWER_PERC) {
+            *value = (power.clamp(0., 100.).round() as i64, false);
+        }
+    }
+}
+
+/// A camera backend that synthesizes frames instead of talking to hardware.
+///
+/// Frames honour the configured ROI, binning, image type, gain, offset, exposure and
+/// white balance, and video is paced to the exposure time or `max_fps`, whichever is
+/// slower. Guide pulses move the star field.
+pub struct SimulatedCamera {
+    config: SimulatorConfig,
+    info: asi::ASI_CAMERA_INFO,
+    controls: Vec<ControlCaps>,
+    stars: Vec<Star>,
+    state: Mutex<SimState>,
+    guide_drift: Arc<Mutex<GuideDrift>>,
+    guider: PulseGuider,
+    connected: AtomicBool,
+}
+
+fn control(
+    control_type: CONTROL_TYPE,
+    name: &str,
+    description: &str,
+    (min_value, max_value, default_value): (i64, i64, i64),
+    is_auto_supported: bool,
+    is_writable: bool,
+) -> ControlCaps {
+    ControlCaps {
+        name: name.to_string(),
+        description: description.to_string(),
+        min_value,
+        max_value,
+        default_value,
+        is_auto_supported,
+        is_writable,
+        control_type,
+    }
+}
+
+impl SimulatedCamera {
+    pub fn new(config: SimulatorConfig) -> Self {
+        let mut info = asi::ASI_CAMERA_INFO::new();
+        for (dst, src) in info.Name.iter_mut().zip(config.name.bytes().take(63)) {
+            *dst = src as c_char;
+        }
+        info.CameraID = config.camera_id;
+        info.MaxWidth = config.max_width.into();
+        info.MaxHeight = config.max_height.into();
+        info.IsColorCam = config.is_color.into();
+        info.BayerPattern = config.bayer_pattern;
+        info.SupportedBins[..4].copy_from_slice(&[1, 2, 3, 4]);
+        let formats: &[IMG_TYPE] = if config.is_color {
+            &[
+                IMG_TYPE::RAW8,
+                IMG_TYPE::RGB24,
+                IMG_TYPE::RAW16,
+                IMG_TYPE::Y8,
+            ]
+        } else {
+            &[IMG_TYPE::RAW8, IMG_TYPE::RAW16]
+        };
+        info.SupportedVideoFormat = [asi::ASI_IMG_TYPE_ASI_IMG_END; 8];
+        for (dst, src) in info.SupportedVideoFormat.iter_mut().zip(formats) {
+            *dst = *src as i32;
+        }
+        info.PixelSize = config.pixel_size;
+        info.ST4Port = 1;
+        info.IsTriggerCam = 1;
+        info.IsCoolerCam = config.cooler.into();
+        info.IsUSB3Host = 1;
+        info.IsUSB3Camera = 1;
+        info.ElecPerADU = ELEC_PER_ADU;
+        info.BitDepth = config.bit_depth;
+
+        let mut controls = vec![
+            control(
+                CONTROL_TYPE::GAIN,
+                "Gain",
+                "Gain",
+                (0, 510, 200),
+                true,
+                true,
+            ),
+            control(
+                CONTROL_TYPE::EXPOSURE,
+                "Exposure",
+                "Exposure Time(us)",
+                (32, 2_000_000_000, 10_000),
+                true,
+                true,
+            ),
+            control(
+                CONTROL_TYPE::OFFSET,
+                "Offset",
+                "offset",
+                (0, 80, 8),
+                false,
+                true,
+            ),
+            control(
+                CONTROL_TYPE::BANDWIDTHOVERLOAD,
+                "BandWidth",
+                "The total data transfer rate percentage",
+                (40, 100, 50),
+                true,
+                true,
+            ),
+            control(
+                CONTROL_TYPE::AUTO_MAX_GAIN,
+                "AutoExpMaxGain",
+                "Auto exposure maximum gain value",
+                (0, 510, 255),
+                false,
+                true,
+            ),
+            control(
+                CONTROL_TYPE::AUTO_MAX_EXP,
+                "AutoExpMaxExpMS",
+                "Auto exposure maximum exposure value(unit ms)",
+                (1, 60_000, 100),
+                false,
+                true,
+            ),
+            control(
+                CONTROL_TYPE::AUTO_TARGET_BRIGHTNESS,
+                "AutoExpTargetBrightness",
+                "Auto exposure target brightness value",
+                (50, 160, 100),
+                false,
+                true,
+            ),
+            control(
+                CONTROL_TYPE::FLIP,
+                "Flip",
+                "Flip: 0->None 1->Horiz 2->Vert 3->Both",
+                (0, 3, 0),
+                false,
+                true,
+            ),
+            control(
+                CONTROL_TYPE::TEMPERATURE,
+                "Temperature",
+                "Sensor temperature(degrees Celsius) * 10",
+                (-500, 1000, 200),
+                false,
+                false,
+            ),
+        ];
+        if config.gps_location.is_some() {
+            let last_line = config.max_height as i64 - 1;
+            controls.push(control(
+                CONTROL_TYPE::GPS_SUPPORT,
+                "GPS",
+                "the camera has a GPS receiver",
+                (0, 1, 1),
+                false,
+                false,
+            ));
+            controls.push(control(
+                CONTROL_TYPE::GPS_START_LINE,
+                "GPSStartLine",
+                "line the start GPS time is latched at",
+                (0, last_line, 0),
+                false,
+                true,
+            ));
+            controls.push(control(
+                CONTROL_TYPE::GPS_END_LINE,
+                "GPSEndLine",
+                "line the end GPS time is latched at",
+                (0, last_line, last_line),
+                false,
+                true,
+            ));
+        }
+        if config.cooler {
+            controls.extend([
+                control(
+                    CONTROL_TYPE::TARGET_TEMP,
+                    "TargetTemp",
+                    "Target temperature(cool camera only)",
+                    (-40, 30, 0),
+                    false,
+                    true,
+                ),
+                control(
+                    CONTROL_TYPE::COOLER_ON,
+                    "CoolerOn",
+                    "turn on/off cooler(cool camera only)",
+                    (0, 1, 0),
+                    false,
+                    true,
+                ),
+                control(
+                    CONTROL_TYPE::COOLER_POWER_PERC,
+                    "CoolPowerPerc",
+                    "cooler power percent",
+                    (0, 100, 0),
+                    false,
+                    false,
+                ),
+                control(
+                    CONTROL_TYPE::FAN_ON,
+                    "FanOn",
+                    "turn on/off fan(cool camera only)",
+                    (0, 1, 1),
+                    false,
+                    true,
+                ),
+                control(
+                    CONTROL_TYPE::ANTI_DEW_HEATER,
+                    "AntiDewHeater",
+                    "turn on/off anti dew heater(cool camera only)",
+                    (0, 1, 0),
+                    false,
+                    true,
+                ),
+            ]);
+        }
+        if config.is_color {
+            controls.push(control(
+                CONTROL_TYPE::WB_R,
+                "WB_R",
+                "White balance: Red component",
+                (1, 99, 52),
+                true,
+                true,
+            ));
+            controls.push(control(
+                CONTROL_TYPE::WB_B,
+                "WB_B",
+                "White balance: Blue component",
+                (1, 99, 95),
+                true,
+                true,
+            ));
+        }
+
+        let mut rng = Rng(config.seed.max(1));
+        let area = (config.max_width * config.max_height) as f32;
+        let stars = (0..(area / 4000.) as usize)
+            .map(|_| {
+                // Many faint stars, few bright ones
+                let brightness = rng.uniform().powi(6);
+                let tint = rng.uniform() - 0.5;
+                Star {
+                    x: rng.uniform() * config.max_width as f32,
+                    y: rng.uniform() * config.max_height as f32,
+                    flux: 50. + brightness * 200_000.,
+                    color: [1. + tint, 1., 1. - tint],
+                }
+            })
+            .collect();
+
+        let state = SimState {
+            values: controls
+                .iter()
+                .map(|c| (c.control_type, (c.default_value, false)))
+                .collect(),
+            roi: ROIFormat {
+                width: config.max_width,
+                height: config.max_height,
+                bin: 1,
+                img_type: IMG_TYPE::RAW8,
+                start_x: 0,
+                start_y: 0,
+            },
+            video_running: false,
+            next_frame: Instant::now(),
+            dropped_frames: 0,
+            exposure: None,
+            exp_status: EXPOSURE_STATUS::EXP_IDLE,
+            dark_frame: false,
+            camera_mode: CAMERA_MODE::NORMAL,
+            trigger: None,
+            level_start: None,
+            trigger_outputs: [TriggerOutputConf::default(); 2],
+            frame_times: None,
+            custom_id: config.custom_id.clone(),
+            frame_count: 0,
+            rng,
+            sensor_temperature: AMBIENT_TEMPERATURE,
+            temperature_updated: Instant::now(),
+        };
+
+        let guide_drift = Arc::new(Mutex::new(GuideDrift::default()));
+        let guider_drift = guide_drift.clone();
+        let guider = PulseGuider::new(move |direction, on| {
+            guider_drift.lock().unwrap().set(direction, on);
+            Ok(())
+        });
+
+        Self {
+            config,
+            info,
+            controls,
+            stars,
+            state: Mutex::new(state),
+            guide_drift,
+            guider,
+            connected: AtomicBool::new(true),
+        }
+    }
+
+    /// Simulates unplugging the camera, after which reads and control changes fail
+    /// with `CAMERA_REMOVED` until it is connected again.
+    pub fn set_connected(&self, connected: bool) {
+        if !connected {
+            // Like a real camera losing power, nothing is left running
+            let mut state = self.state.lock().unwrap();
+            state.video_running = false;
+            state.exposure = None;
+            state.exp_status = EXPOSURE_STATUS::EXP_IDLE;
+            state.trigger = None;
+            state.level_start = None;
+        }
+        self.connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    fn check_connected(&self) -> Result<(), ASI_ERROR> {
+        match self.is_connected() {
+            true => Ok(()),
+            false => Err(ASI_ERROR::CAMERA_REMOVED),
+        }
+    }
+
+    fn caps(&self, control_type: CONTROL_TYPE) -> Result<&ControlCaps, ASI_ERROR> {
+        self.controls
+            .iter()
+            .find(|c| c.control_type == control_type)
+            .ok_or(ASI_ERROR::INVALID_CONTROL_TYPE)
+    }
+
+    /// Which CFA colour the sensor pixel at (x, y) sits under.
+    fn cfa_channel(&self, x: i32, y: i32) -> usize {
+        // Indexed by [row parity][column parity], 0 = R, 1 = G, 2 = B
+        let layout = match self.config.bayer_pattern {
+            asi::ASI_BAYER_PATTERN_ASI_BAYER_BG => [[2, 1], [1, 0]],
+            asi::ASI_BAYER_PATTERN_ASI_BAYER_GR => [[1, 0], [2, 1]],
+            asi::ASI_BAYER_PATTERN_ASI_BAYER_GB => [[1, 2], [0, 1]],
+            _ => [[0, 1], [1, 2]],
+        };
+        layout[(y & 1) as usize][(x & 1) as usize]
+    }
+
+    /// Scene flux in electrons per second at sensor position (x, y), for each colour.
+    fn scene(&self, x: f32, y: f32, frame_count: u64) -> [f32; 3] {
+        let w = self.config.max_width as f32;
+        let h = self.config.max_height as f32;
+        match self.config.pattern {
+            SimPattern::StarField => [SKY_FLUX; 3],
+            SimPattern::Noise => [0.; 3],
+            SimPattern::Gradient => {
+                let (u, v) = (x / w, y / h);
+                [u * SCENE_FLUX, v * SCENE_FLUX, (1. - u) * SCENE_FLUX]
+            }
+            SimPattern::TestPattern => {
+                // A square bouncing along the bottom so motion is visible in the preview
+                let size = h / 8.;
+                let travel = (w - size) as u64;
+                let pos = (frame_count * 8) % (2 * travel.max(1));
+                let left = if pos > travel { 2 * travel - pos } else { pos } as f32;
+                if y > h - size && x >= left && x < left + size {
+                    return [SCENE_FLUX; 3];
+                }
+
+                if y < h * 2. / 3. {
+                    // White, yellow, cyan, green, magenta, red, blue, black
+                    const BARS: [[f32; 3]; 8] = [
+                        [1., 1., 1.],
+                        [1., 1., 0.],
+                        [0., 1., 1.],
+                        [0., 1., 0.],
+                        [1., 0., 1.],
+                        [1., 0., 0.],
+                        [0., 0., 1.],
+                        [0., 0., 0.],
+                    ];
+                    let bar = BARS[((x / w * 8.) as usize).min(7)];
+                    bar.map(|c| c * SCENE_FLUX * 0.75)
+                } else {
+                    let step = ((x / w * 8.).floor() / 7.).min(1.);
+                    [step * SCENE_FLUX; 3]
+                }
+            }
+        }
+    }
+
+    fn render(&self, state: &mut SimState, data: &mut [u8]) -> Result<(), ASI_ERROR> {
+        let ROIFormat {
+            width,
+            height,
+            bin,
+            img_type,
+            start_x,
+            start_y,
+        } = state.roi;
+        let (w, h) = (width as usize, height as usize);
+        let bytes_per_pixel = img_type.bytes_per_pixel() as usize;
+        if data.len() < w * h * bytes_per_pixel {
+            return Err(ASI_ERROR::BUFFER_TOO_SMALL);
+        }
+
+        let exposure_s = state.exposure().as_secs_f32();
+        let gain = 10f32.powf(state.value(CONTROL_TYPE::GAIN) as f32 / 200.);
+        let bit_depth = self.config.bit_depth;
+        let max_adu = ((1 << bit_depth) - 1) as f32;
+        // Offset is in ADU at 10 bits, like the ZWO cameras
+        let bias = state.value(CONTROL_TYPE::OFFSET) as f32 * max_adu / 1023.;
+        let wb = if self.config.is_color {
+            [
+                state.value(CONTROL_TYPE::WB_R) as f32 / 50.,
+                1.,
+                state.value(CONTROL_TYPE::WB_B) as f32 / 50.,
+            ]
+        } else {
+            [1.; 3]
+        };
+        let flip = state.value(CONTROL_TYPE::FLIP);
+        let (flip_x, flip_y) = (flip & 1 != 0, flip & 2 != 0);
+        let bin_f = bin as f32;
+        let frame_count = state.frame_count;
+        let dark_frame = state.dark_frame;
+
+        // Stars are painted into a flux layer first so each only touches nearby pixels
+        let mut stars = vec![
+            [0f32; 3];
+            if self.config.pattern == SimPattern::StarField && !dark_frame {
+                w * h
+            } else {
+                0
+            }
+        ];
+        if !stars.is_empty() {
+            let drift = self.guide_drift.lock().unwrap().offset();
+            let jitter = (
+                drift.0 + state.rng.normal() * 0.3,
+                drift.1 + state.rng.normal() * 0.3,
+            );
+            let sigma = (1.5 / bin_f).max(0.6);
+            let radius = (sigma * 4.).ceil() as i32;
+            let norm = 1. / (2. * std::f32::consts::PI * sigma * sigma);
+            for star in &self.stars {
+                let sx = (star.x + jitter.0) / bin_f - start_x as f32;
+                let sy = (star.y + jitter.1) / bin_f - start_y as f32;
+                if sx < -(radius as f32)
+                    || sy < -(radius as f32)
+                    || sx > (w as i32 + radius) as f32
+                    || sy > (h as i32 + radius) as f32
+                {
+                    continue;
+                }
+                for py in (sy as i32 - radius)..=(sy as i32 + radius) {
+                    for px in (sx as i32 - radius)..=(sx as i32 + radius) {
+                        if px < 0 || py < 0 || px >= w as i32 || py >= h as i32 {
+                            continue;
+                        }
+                        let (dx, dy) = (px as f32 + 0.5 - sx, py as f32 + 0.5 - sy);
+                        let v =
+                            star.flux * norm * (-(dx * dx + dy * dy) / (2. * sigma * sigma)).exp();
+                        let pixel = &mut stars[py as usize * w + px as usize];
+                        for (p, color) in pixel.iter_mut().zip(star.color) {
+                            *p += v * color;
+                        }
+                    }
+                }
+            }
+        }
+
+        let to_adu = |electrons: f32, rng: &mut Rng| -> u32 {
+            let shot = electrons.max(0.).sqrt() * rng.normal();
+            let read = READ_NOISE * rng.normal();
+            let adu = (electrons + shot + read) / ELEC_PER_ADU * gain + bias;
+            adu.clamp(0., max_adu) as u32
+        };
+
+        for y in 0..h {
+            for x in 0..w {
+                let (ox, oy) = (
+                    if flip_x { w - 1 - x } else { x },
+                    if flip_y { h - 1 - y } else { y },
+                );
+                let sensor_x = (start_x + x as i32) as f32 * bin_f + bin_f / 2.;
+                let sensor_y = (start_y + y as i32) as f32 * bin_f + bin_f / 2.;
+                let mut flux = if dark_frame {
+                    [0.; 3]
+                } else {
+                    self.scene(sensor_x, sensor_y, frame_count)
+                };
+                if let Some(star) = stars.get(y * w + x) {
+                    for (f, s) in flux.iter_mut().zip(star) {
+                        *f += s;
+                    }
+                }
+                // Binned pixels sum the charge of bin x bin photosites
+                let electrons = flux.map(|f| f * exposure_s * bin_f * bin_f);
+
+                let offset = (oy * w + ox) * bytes_per_pixel;
+                match img_type {
+                    IMG_TYPE::RGB24 => {
+                        for (i, c) in [2, 1, 0].into_iter().enumerate() {
+                            let v = to_adu(electrons[c] * wb[c], &mut state.rng);
+                            data[offset + i] = (v >> (bit_depth - 8)) as u8;
+                        }
+                    }
+                    _ => {
+                        let v = if self.config.is_color && img_type != IMG_TYPE::Y8 {
+                            let c = self.cfa_channel(start_x + x as i32, start_y + y as i32);
+                            to_adu(electrons[c] * wb[c], &mut state.rng)
+                        } else {
+                            let luma = (electrons[0] + electrons[1] + electrons[2]) / 3.;
+                            to_adu(luma, &mut state.rng)
+                        };
+                        if img_type == IMG_TYPE::RAW16 {
+                            // ZWO cameras left align the ADC value in the 16 bit word
+                            let v = (v << (16 - bit_depth)) as u16;
+                            data[offset..offset + 2].copy_from_slice(&v.to_le_bytes());
+                        } else {
+                            data[offset] = (v >> (bit_depth - 8)) as u8;
+                        }
+                    }
+                }
+            }
+        }
+
+        state.frame_count += 1;
+        Ok(())
+    }
+
+    /// What the GPS receiver would have latched at `instant`.
+    fn gps_at(&self, instant: Instant) -> Result<GpsData, ASI_ERROR> {
+        let (latitude, longitude, altitude) = self
+            .config
+            .gps_location
+            .ok_or(ASI_ERROR::GPS_NOT_SUPPORTED)?;
+        let now = Instant::now();
+        let time = if instant <= now {
+            SystemTime::now() - (now - instant)
+        } else {
+            SystemTime::now() + (instant - now)
+        };
+        let time = DateTime::<Utc>::from(time);
+        // The receiver resolves 0.1 µs
+        let time = time
+            .with_nanosecond(time.nanosecond() / 100 * 100)
+            .unwrap_or(time);
+        Ok(GpsData {
+            time,
+            latitude,
+            longitude,
+            altitude,
+            satellites: 9,
+        })
+    }
+
+    /// Renders a frame exposed for `exposure` rather than the current `EXPOSURE` value.
+    fn render_exposure(
+        &self,
+        state: &mut SimState,
+        data: &mut [u8],
+        exposure: Duration,
+    ) -> Result<(), ASI_ERROR> {
+        let current = state.value(CONTROL_TYPE::EXPOSURE);
+        state
+            .values
+            .insert(CONTROL_TYPE::EXPOSURE, (exposure.as_micros() as i64, false));
+        let result = self.render(state, data);
+        state
+            .values
+            .entry(CONTROL_TYPE::EXPOSURE)
+            .and_modify(|v| v.0 = current);
+        result
+    }
+}
+
+impl CameraBackend for SimulatedCamera {
+    fn info(&self) -> asi::ASI_CAMERA_INFO {
+        self.info
+    }
+
+    fn name(&self) -> String {
+        self.config.name.clone()
+    }
+
+    fn serial_number(&self) -> Result<String, ASI_ERROR> {
+        Ok(self.config.serial.clone())
+    }
+
+    fn get_custom_id(&self) -> Result<String, ASI_ERROR> {
+        Ok(self.state.lock().unwrap().custom_id.clone())
+    }
+
+    fn set_custom_id(&self, id: &str) -> Result<(), ASI_ERROR> {
+        if id.len() > asi::ASI_ID_LEN {
+            return Err(ASI_ERROR::GENERAL_ERROR);
+        }
+        self.state.lock().unwrap().custom_id = id.to_string();
+        Ok(())
+    }
+
+    fn init(&self) -> Result<(), ASI_ERROR> {
+        self.check_connected()?;
+        info!("Initialized simulated camera {}", self.config.name);
+        Ok(())
+    }
+
+    fn controls(&self) -> Result<Vec<ControlCaps>, ASI_ERROR> {
+        Ok(self.controls.clone())
+    }
+
+    fn set_control_value(
+        &self,
+        control_type: CONTROL_TYPE,
+        value: i64,
+        auto: bool,
+    ) -> Result<(), ASI_ERROR> {
+        self.check_connected()?;
+        let caps = self.caps(control_type)?;
+        if !caps.is_writable {
+            return Err(ASI_ERROR::GENERAL_ERROR);
+        }
+        // Like the SDK, out of range values are clamped rather than rejected
+        let value = caps.clamp(value);
+        let auto = auto && caps.is_auto_supported;
+        let mut state = self.state.lock().unwrap();
+        if matches!(
+            control_type,
+            CONTROL_TYPE::COOLER_ON | CONTROL_TYPE::TARGET_TEMP
+        ) {
+            // The sensor followed the old target until now
+            state.update_temperature();
+        }
+        state.values.insert(control_type, (value, auto));
+        Ok(())
+    }
+
+    fn get_control_value(&self, control_type: CONTROL_TYPE) -> Result<(i64, bool), ASI_ERROR> {
+        self.check_connected()?;
+        self.caps(control_type)?;
+        let mut state = self.state.lock().unwrap();
+        if matches!(
+            control_type,
+            CONTROL_TYPE::TEMPERATURE | CONTROL_TYPE::COOLER_POWER_PERC
+        ) {
+            state.update_temperature();
+        }
+        Ok(state
+            .values
+            .get(&control_type)
+            .copied()
+            .unwrap_or((0, false)))
+    }
+
+    fn set_roi_format(
+        &self,
+        width: i32,
+        height: i32,
+        bin: i32,
+        img_type: IMG_TYPE,
+    ) -> Result<(), ASI_ERROR> {
+        if !self.info.SupportedBins.contains(&bin) || bin <= 0 {
+            return Err(ASI_ERROR::INVALID_SIZE);
+        }
+        if width <= 0
+            || height <= 0
+            || width % 8 != 0
+            || height % 2 != 0
+            || width * bin > self.config.max_width
+            || height * bin > self.config.max_height
+        {
+            return Err(ASI_ERROR::INVALID_SIZE);
+        }
+        if !self.info.SupportedVideoFormat.contains(&(img_type as i32)) {
+            return Err(ASI_ERROR::INVALID_IMGTYPE);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.video_running {
+            return Err(ASI_ERROR::INVALID_SEQUENCE);
+        }
+        // The SDK centres a new ROI on the sensor
+        state.roi = ROIFormat {
+            width,
+            height,
+            bin,
+            img_type,
+            start_x: (self.config.max_width / bin - width) / 2,
+            start_y: (self.config.max_height / bin - height) / 2,
+        };
+        Ok(())
+    }
+
+    fn get_roi_format(&self) -> Result<ROIFormat, ASI_ERROR> {
+        Ok(self.state.lock().unwrap().roi)
+    }
+
+    fn set_start_pos(&self, x: i32, y: i32) -> Result<(), ASI_ERROR> {
+        let mut state = self.state.lock().unwrap();
+        let ROIFormat {
+            width, height, bin, ..
+        } = state.roi;
+        if x < 0
+            || y < 0
+            || x + width > self.config.max_width / bin
+            || y + height > self.config.max_height / bin
+        {
+            return Err(ASI_ERROR::OUTOF_BOUNDARY);
+        }
+        state.roi.start_x = x;
+        state.roi.start_y = y;
+        Ok(())
+    }
+
+    fn get_start_pos(&self) -> Result<(i32, i32), ASI_ERROR> {
+        let state = self.state.lock().unwrap();
+        Ok((state.roi.start_x, state.roi.start_y))
+    }
+
+    fn start_exposure(&self, is_dark: bool) -> Result<(), ASI_ERROR> {
+        self.check_connected()?;
+        let mut state = self.state.lock().unwrap();
+        if state.video_running {
+            return Err(ASI_ERROR::VIDEO_MODE_ACTIVE);
+        }
+        if state.exp_status == EXPOSURE_STATUS::EXP_WORKING {
+            return Err(ASI_ERROR::EXPOSURE_IN_PROGRESS);
+        }
+        state.exposure = Some((Instant::now(), state.exposure()));
+        state.exp_status = EXPOSURE_STATUS::EXP_WORKING;
+        state.dark_frame = is_dark;
+        Ok(())
+    }
+
+    fn stop_exposure(&self) -> Result<(), ASI_ERROR> {
+        let mut state = self.state.lock().unwrap();
+        if state.exp_status == EXPOSURE_STATUS::EXP_WORKING {
+            state.exposure = None;
+            state.exp_status = EXPOSURE_STATUS::EXP_IDLE;
+            state.dark_frame = false;
+        }
+        Ok(())
+    }
+
+    fn get_exp_status(&self) -> Result<EXPOSURE_STATUS, ASI_ERROR> {
+        self.check_connected()?;
+        let mut state = self.state.lock().unwrap();
+        if let (EXPOSURE_STATUS::EXP_WORKING, Some((start, duration))) =
+            (state.exp_status, state.exposure)
+        {
+            if start.elapsed() >= duration {
+                state.exp_status = EXPOSURE_STATUS::EXP_SUCCESS;
+            }
+        }
+        Ok(state.exp_status)
+    }
+
+    fn get_data_after_exp(&self, data: &mut [u8]) -> Result<(), ASI_ERROR> {
+        self.check_connected()?;
+        let mut state = self.state.lock().unwrap();
+        if state.exp_status != EXPOSURE_STATUS::EXP_SUCCESS {
+            return Err(ASI_ERROR::GENERAL_ERROR);
+        }
+        // Read out with the exposure time the frame was started with
+        let (started, exposure) = state
+            .exposure
+            .take()
+            .unwrap_or_else(|| (Instant::now(), state.exposure()));
+        state.frame_times = Some((started, started + exposure));
+        let result = self.render_exposure(&mut state, data, exposure);
+        state.exp_status = EXPOSURE_STATUS::EXP_IDLE;
+        state.dark_frame = false;
+        result
+    }
+
+    fn start_video_capture(&self) -> Result<(), ASI_ERROR> {
+        self.check_connected()?;
+        let mut state = self.state.lock().unwrap();
+        if state.exp_status == EXPOSURE_STATUS::EXP_WORKING {
+            return Err(ASI_ERROR::EXPOSURE_IN_PROGRESS);
+        }
+        if !state.video_running {
+            state.video_running = true;
+            state.dropped_frames = 0;
+            state.next_frame = Instant::now() + state.exposure();
+        }
+        Ok(())
+    }
+
+    fn stop_video_capture(&self) -> Result<(), ASI_ERROR> {
+        let mut state = self.state.lock().unwrap();
+        state.video_running = false;
+        state.trigger = None;
+        state.level_start = None;
+        Ok(())
+    }
+
+    fn get_dropped_frames(&self) -> Result<i32, ASI_ERROR> {
+        Ok(self.state.lock().unwrap().dropped_frames)
+    }
+
+    fn get_video_data(&self, data: &mut [u8], wait_ms: i32) -> Result<(), ASI_ERROR> {
+        let deadline =
+            (wait_ms >= 0).then(|| Instant::now() + Duration::from_millis(wait_ms as u64));
+        loop {
+            self.check_connected()?;
+            let ready = {
+                let state = self.state.lock().unwrap();
+                if !state.video_running {
+                    return Err(ASI_ERROR::INVALID_SEQUENCE);
+                }
+                match state.camera_mode {
+                    CAMERA_MODE::NORMAL => Some(state.next_frame),
+                    _ => state.trigger.map(|(ready, _)| ready),
+                }
+            };
+            let now = Instant::now();
+            if ready.is_some_and(|ready| ready <= now) {
+                break;
+            }
+            if deadline.is_some_and(|deadline| deadline <= now) {
+                return Err(ASI_ERROR::TIMEOUT);
+            }
+            // Wait without holding the lock so controls can change mid exposure. Until
+            // a trigger arrives there is no frame to wait for, so check back regularly
+            let wake = ready.unwrap_or(now + TRIGGER_POLL);
+            let wake = deadline.map_or(wake, |deadline| wake.min(deadline));
+            sleep(wake - now);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.camera_mode != CAMERA_MODE::NORMAL {
+            // Video may have been stopped while waiting
+            let Some((ready, exposure)) = state.trigger.take() else {
+                return Err(ASI_ERROR::INVALID_SEQUENCE);
+            };
+            state.frame_times = Some((ready - exposure, ready));
+            return self.render_exposure(&mut state, data, exposure);
+        }
+        let period = state
+            .exposure()
+            .max(Duration::from_secs_f64(1. / self.config.max_fps));
+        let now = Instant::now();
+        let exposure = state.exposure();
+        state.frame_times = Some((state.next_frame - exposure, state.next_frame));
+        let late = now.saturating_duration_since(state.next_frame);
+        if late >= period {
+            // The caller didn't keep up, so the frames in between were lost
+            state.dropped_frames += (late.as_secs_f64() / period.as_secs_f64()) as i32;
+            state.next_frame = now + period;
+        } else {
+            state.next_frame += period;
+        }
+        self.render(&mut state, data)?;
+        state.auto_expose(data);
+        Ok(())
+    }
+
+    fn supported_camera_modes(&self) -> Result<Vec<CAMERA_MODE>, ASI_ERROR> {
+        Ok(CAMERA_MODES.to_vec())
+    }
+
+    fn get_camera_mode(&self) -> Result<CAMERA_MODE, ASI_ERROR> {
+        Ok(self.state.lock().unwrap().camera_mode)
+    }
+
+    fn set_camera_mode(&self, mode: CAMERA_MODE) -> Result<(), ASI_ERROR> {
+        if !CAMERA_MODES.contains(&mode) {
+            return Err(ASI_ERROR::INVALID_MODE);
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.video_running {
+            return Err(ASI_ERROR::INVALID_SEQUENCE);
+        }
+        state.camera_mode = mode;
+        state.trigger = None;
+        state.level_start = None;
+        Ok(())
+    }
+
+    fn send_soft_trigger(&self, start: bool) -> Result<(), ASI_ERROR> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match state.camera_mode {
+            CAMERA_MODE::TRIG_SOFT_EDGE => {
+                // Triggers only start exposures while video is running, and are ignored
+                // while one is already under way
+                if start && state.video_running && state.trigger.is_none() {
+                    let exposure = state.exposure();
+                    state.trigger = Some((now + exposure, exposure));
+                }
+            }
+            CAMERA_MODE::TRIG_SOFT_LEVEL => {
+                if start {
+                    if state.video_running && state.trigger.is_none() {
+                        state.level_start = Some(now);
+                    }
+                } else if let Some(started) = state.level_start.take() {
+                    state.trigger = Some((now, (now - started).max(Duration::from_micros(1))));
+                }
+            }
+            _ => return Err(ASI_ERROR::INVALID_MODE),
+        }
+        Ok(())
+    }
+
+    fn set_trigger_output(
+        &self,
+        pin: TRIG_OUTPUT,
+        conf: TriggerOutputConf,
+    ) -> Result<(), ASI_ERROR> {
+        const MAX_US: i64 = 2_000_000_000;
+        if !(0..=MAX_US).contains(&conf.delay_us) || !(0..=MAX_US).contains(&conf.duration_us) {
+            return Err(ASI_ERROR::GENERAL_ERROR);
+        }
+        self.state.lock().unwrap().trigger_outputs[pin as usize] = conf;
+        Ok(())
+    }
+
+    fn get_trigger_output(&self, pin: TRIG_OUTPUT) -> Result<TriggerOutputConf, ASI_ERROR> {
+        Ok(self.state.lock().unwrap().trigger_outputs[pin as usize])
+    }
+
+    fn pulse_guide(&self, direction: GUIDE_DIRECTION, duration: Duration) -> Result<(), ASI_ERROR> {
+        self.guider.pulse(direction, duration)
+    }
+
+    fn stop_pulse_guide(&self, direction: GUIDE_DIRECTION) -> Result<(), ASI_ERROR> {
+        self.guider.stop(direction)
+    }
+
+    fn is_pulse_guiding(&self, direction: GUIDE_DIRECTION) -> bool {
+        self.guider.is_guiding(direction)
+    }
+
+    fn get_video_data_gps(&self, data: &mut [u8], wait_ms: i32) -> Result<GpsData, ASI_ERROR> {
+        if self.config.gps_location.is_none() {
+            return Err(ASI_ERROR::GPS_NOT_SUPPORTED);
+        }
+        self.get_video_data(data, wait_ms)?;
+        Ok(self.get_gps_data()?.start)
+    }
+
+    fn get_data_after_exp_gps(&self, data: &mut [u8]) -> Result<GpsData, ASI_ERROR> {
+        if self.config.gps_location.is_none() {
+            return Err(ASI_ERROR::GPS_NOT_SUPPORTED);
+        }
+        self.get_data_after_exp(data)?;
+        Ok(self.get_gps_data()?.start)
+    }
+
+    fn get_gps_data(&self) -> Result<FrameGps, ASI_ERROR> {
+        let (times, start_line, end_line) = {
+            let state = self.state.lock().unwrap();
+            (
+                state.frame_times,
+                state.value(CONTROL_TYPE::GPS_START_LINE),
+                state.value(CONTROL_TYPE::GPS_END_LINE),
+            )
+        };
+        let (started, ended) = times.ok_or(ASI_ERROR::GPS_DATA_INVALID)?;
+        Ok(FrameGps {
+            start: self.gps_at(started + LINE_TIME * start_line as u32)?,
+            end: self.gps_at(ended + LINE_TIME * end_line as u32)?,
+        })
+    }
+}
+
+/// Simulated cameras as a [`CameraSource`], listing the ones currently connected.
+pub struct SimulatedCameras(pub Vec<Arc<SimulatedCamera>>);
+
+impl CameraSource for SimulatedCameras {
+    fn scan(&mut self) -> Vec<i32> {
+        self.0
+            .iter()
+            .filter(|camera| camera.is_connected())
+            .map(|camera| camera.config.camera_id)
+            .collect()
+    }
+
+    fn open(&mut self, camera_id: i32) -> Result<Arc<dyn CameraBackend>, ASI_ERROR> {
+        let camera = self
+            .0
+            .iter()
+            .find(|camera| camera.config.camera_id == camera_id)
+            .ok_or(ASI_ERROR::INVALID_ID)?;
+        Ok(camera.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(config: SimulatorConfig) -> SimulatedCamera {
+        let camera = SimulatedCamera::new(config);
+        camera.init().unwrap();
+        camera
+    }
+
+    fn wait_for_exposure(camera: &SimulatedCamera) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while camera.get_exp_status().unwrap() == EXPOSURE_STATUS::EXP_WORKING {
+            assert!(Instant::now() < deadline, "Exposure never finished");
+            sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn lists_controls_for_its_hardware() {
+        let types = |camera: &SimulatedCamera| {
+            camera
+                .controls()
+                .unwrap()
+                .iter()
+                .map(|c| c.control_type)
+                .collect::<Vec<_>>()
+        };
+        let full = types(&open(SimulatorConfig::default()));
+        for control_type in [
+            CONTROL_TYPE::GAIN,
+            CONTROL_TYPE::EXPOSURE,
+            CONTROL_TYPE::TEMPERATURE,
+            CONTROL_TYPE::TARGET_TEMP,
+            CONTROL_TYPE::COOLER_ON,
+            CONTROL_TYPE::GPS_SUPPORT,
+            CONTROL_TYPE::WB_R,
+        ] {
+            assert!(full.contains(&control_type), "{control_type:?} missing");
+        }
+
+        let bare = types(&open(SimulatorConfig {
+            is_color: false,
+            gps_location: None,
+            cooler: false,
+            ..Default::default()
+        }));
+        assert!(bare.contains(&CONTROL_TYPE::TEMPERATURE));
+        for control_type in [
+            CONTROL_TYPE::TARGET_TEMP,
+            CONTROL_TYPE::GPS_SUPPORT,
+            CONTROL_TYPE::WB_R,
+        ] {
+            assert!(!bare.contains(&control_type), "{control_type:?} listed");
+        }
+    }
+
+    #[test]
+    fn control_values_are_clamped() {
+        let camera = open(SimulatorConfig::default());
+        camera
+            .set_control_value(CONTROL_TYPE::GAIN, 10_000, false)
+            .unwrap();
+        assert_eq!(
+            camera.get_control_value(CONTROL_TYPE::GAIN).unwrap(),
+            (510, false)
+        );
+        camera
+            .set_control_value(CONTROL_TYPE::GAIN, -5, true)
+            .unwrap();
+        assert_eq!(
+            camera.get_control_value(CONTROL_TYPE::GAIN).unwrap(),
+            (0, true)
+        );
+        // Offset has no auto mode
+        camera
+            .set_control_value(CONTROL_TYPE::OFFSET, 10, true)
+            .unwrap();
+        assert_eq!(
+            camera.get_control_value(CONTROL_TYPE::OFFSET).unwrap(),
+            (10, false)
+        );
+        assert_eq!(
+            camera.set_control_value(CONTROL_TYPE::TEMPERATURE, 0, false),
+            Err(ASI_ERROR::GENERAL_ERROR)
+        );
+    }
+
+    #[test]
+    fn roi_must_fit_the_sensor() {
+        let camera = open(SimulatorConfig::default());
+        camera.set_roi_format(640, 480, 2, IMG_TYPE::RAW16).unwrap();
+        let roi = camera.get_roi_format().unwrap();
+        assert_eq!((roi.width, roi.height, roi.bin), (640, 480, 2));
+        assert_eq!(roi.img_type, IMG_TYPE::RAW16);
+        // Centred on the binned sensor
+        assert_eq!((roi.start_x, roi.start_y), (160, 30));
+
+        for (width, height, bin) in [(644, 480, 1), (640, 481, 1), (640, 480, 5), (1920, 1080, 2)] {
+            assert_eq!(
+                camera.set_roi_format(width, height, bin, IMG_TYPE::RAW8),
+                Err(ASI_ERROR::INVALID_SIZE),
+                "{width}x{height} bin {bin}"
+            );
+        }
+        let mono = open(SimulatorConfig {
+            is_color: false,
+            ..Default::default()
+        });
+        assert_eq!(
+            mono.set_roi_format(640, 480, 1, IMG_TYPE::RGB24),
+            Err(ASI_ERROR::INVALID_IMGTYPE)
+        );
+
+        camera.start_video_capture().unwrap();
+        assert_eq!(
+            camera.set_roi_format(320, 240, 1, IMG_TYPE::RAW8),
+            Err(ASI_ERROR::INVALID_SEQUENCE)
+        );
+    }
+
+    #[test]
+    fn start_position_stays_on_the_sensor() {
+        let camera = open(SimulatorConfig::default());
+        camera.set_roi_format(640, 480, 2, IMG_TYPE::RAW8).unwrap();
+        camera.set_start_pos(320, 60).unwrap();
+        assert_eq!(camera.get_start_pos().unwrap(), (320, 60));
+        for (x, y) in [(-1, 0), (0, -1), (321, 0), (0, 61)] {
+            assert_eq!(
+                camera.set_start_pos(x, y),
+                Err(ASI_ERROR::OUTOF_BOUNDARY),
+                "{x}, {y}"
+            );
+        }
+        assert_eq!(camera.get_start_pos().unwrap(), (320, 60));
+    }
+
+    #[test]
+    fn exposures_finish_and_read_out_once() {
+        let camera = open(SimulatorConfig::default());
+        camera.set_roi_format(64, 32, 1, IMG_TYPE::RAW16).unwrap();
+        camera
+            .set_control_value(CONTROL_TYPE::EXPOSURE, 1000, false)
+            .unwrap();
+        let mut frame = vec![0; 64 * 32 * 2];
+        assert_eq!(
+            camera.get_data_after_exp(&mut frame),
+            Err(ASI_ERROR::GENERAL_ERROR)
+        );
+
+        camera.start_exposure(false).unwrap();
+        assert_eq!(
+            camera.start_exposure(false),
+            Err(ASI_ERROR::EXPOSURE_IN_PROGRESS)
+        );
+        wait_for_exposure(&camera);
+        assert_eq!(
+            camera.get_exp_status().unwrap(),
+            EXPOSURE_STATUS::EXP_SUCCESS
+        );
+        let gps = camera.get_data_after_exp_gps(&mut frame).unwrap();
+        assert!(frame.iter().any(|b| *b != 0));
+        assert_eq!((gps.latitude, gps.longitude), (51.4779, -0.0015));
+        assert_eq!(camera.get_exp_status().unwrap(), EXPOSURE_STATUS::EXP_IDLE);
+        assert_eq!(
+            camera.get_data_after_exp(&mut frame),
+            Err(ASI_ERROR::GENERAL_ERROR)
+        );
+    }
+
+    #[test]
+    fn cameras_without_gps_say_so() {
+        let camera = open(SimulatorConfig {
+            gps_location: None,
+            ..Default::default()
+        });
+        camera.set_roi_format(64, 32, 1, IMG_TYPE::RAW8).unwrap();
+        camera
+            .set_control_value(CONTROL_TYPE::EXPOSURE, 100, false)
+            .unwrap();
+        camera.start_exposure(false).unwrap();
+        wait_for_exposure(&camera);
+        let mut frame = vec![0; 64 * 32];
+        assert_eq!(
+            camera.get_data_after_exp_gps(&mut frame),
+            Err(ASI_ERROR::GPS_NOT_SUPPORTED)
+        );
+    }
+
+    #[test]
+    fn unplugged_cameras_fail_until_reconnected() {
+        let camera = open(SimulatorConfig::default());
+        camera.start_video_capture().unwrap();
+        camera.set_connected(false);
+        assert_eq!(
+            camera.get_control_value(CONTROL_TYPE::GAIN),
+            Err(ASI_ERROR::CAMERA_REMOVED)
+        );
+        assert_eq!(camera.start_exposure(false), Err(ASI_ERROR::CAMERA_REMOVED));
+        let mut cameras = SimulatedCameras(vec![Arc::new(camera)]);
+        assert!(cameras.scan().is_empty());
+
+        cameras.0[0].set_connected(true);
+        assert_eq!(cameras.scan(), [0]);
+        // Nothing was left running
+        let mut frame = vec![0; 1920 * 1080];
+        assert_eq!(
+            cameras.0[0].get_video_data(&mut frame, 0),
+            Err(ASI_ERROR::INVALID_SEQUENCE)
+        );
+    }
+}
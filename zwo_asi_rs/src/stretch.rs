@@ -0,0 +1,247 @@
+//! Display stretches for previews.
+//!
+//! Deep-sky frames hold their signal in the bottom few percent of the camera's range,
+//! so they look black when shown as they are. A client can pick a [`Stretch`] with
+//! `SetPreview` to have its previews brightened for display. Only that client's
+//! previews change: saved files and frames downloaded with `GetFrame` keep the data
+//! as the camera read it.
+
+use serde::{Deserialize, Serialize};
+
+use crate::frame::DepthHistogram;
+
+/// How the levels of a channel are mapped onto 0..=255 for display. Percentiles are
+/// fractions of the pixels, e.g. `0.999`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum StretchMode {
+    /// The darkest pixel to black and the brightest to white.
+    Linear,
+    /// Like `Linear` between the levels at the `low` and `high` percentiles, clipping
+    /// the pixels outside of them.
+    Percentile {
+        #[serde(default = "default_low")]
+        low: f64,
+        #[serde(default = "default_high")]
+        high: f64,
+    },
+    /// `asinh(beta * x) / asinh(beta)` of the level clipped like `Percentile`, which
+    /// lifts faint signal while keeping bright stars from blowing out. Higher `beta`
+    /// stretches harder.
+    Asinh {
+        #[serde(default = "default_low")]
+        low: f64,
+        #[serde(default = "default_high")]
+        high: f64,
+        #[serde(default = "default_beta")]
+        beta: f64,
+    },
+    /// PixInsight's automatic screen transfer function. Shadows are clipped `shadows`
+    /// normalized median absolute deviations from the median, below it when negative,
+    /// and a midtones transfer function moves the median to `background`.
+    Stf {
+        #[serde(default = "default_shadows")]
+        shadows: f64,
+        #[serde(default = "default_background")]
+        background: f64,
+    },
+}
+
+fn default_low() -> f64 {
+    0.001
+}
+
+fn default_high() -> f64 {
+    0.999
+}
+
+fn default_beta() -> f64 {
+    100.
+}
+
+fn default_shadows() -> f64 {
+    -2.8
+}
+
+fn default_background() -> f64 {
+    0.25
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// A client's stretch, part of its `SetPreview` settings.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Stretch {
+    #[serde(flatten)]
+    pub mode: StretchMode,
+    /// Whether the channels of colour previews are stretched alike, from all of them
+    /// together, which keeps their colour balance. Unlinked, each channel is stretched
+    /// on its own, which also takes out a colour cast of the sky background.
+    #[serde(default = "yes")]
+    pub linked: bool,
+}
+
+/// PixInsight's midtones transfer function, which maps 0 to 0, `m` to 0.5 and 1 to 1.
+fn mtf(m: f64, x: f64) -> f64 {
+    match x {
+        x if x <= 0. => 0.,
+        x if x >= 1. => 1.,
+        x => (m - 1.) * x / ((2. * m - 1.) * x - m),
+    }
+}
+
+/// Median of the distances of each level from the median, in levels.
+fn median_deviation(hist: &DepthHistogram) -> f64 {
+    let median = hist.percentile(0.5) as usize;
+    let mut deviations = vec![0u32; hist.counts().len()];
+    for (level, count) in hist.counts().iter().enumerate() {
+        deviations[level.abs_diff(median)] += count;
+    }
+    DepthHistogram::from_counts(deviations).percentile(0.5) as f64
+}
+
+fn histogram<'a>(levels: impl Iterator<Item = &'a u16>, white: u16) -> DepthHistogram {
+    let mut counts = vec![0u32; white as usize + 1];
+    for level in levels {
+        counts[(*level).min(white) as usize] += 1;
+    }
+    DepthHistogram::from_counts(counts)
+}
+
+impl StretchMode {
+    /// Display value of each level of a channel with `hist`.
+    fn lut(&self, hist: &DepthHistogram) -> Vec<u8> {
+        let white = (hist.counts().len() - 1) as f64;
+        let clip = |low: f64, high: f64| {
+            let black = hist.percentile(low) as f64;
+            let top = (hist.percentile(high) as f64).max(black + 1.);
+            move |level: f64| ((level - black) / (top - black)).clamp(0., 1.)
+        };
+        let curve: Box<dyn Fn(f64) -> f64> = match *self {
+            Self::Linear => Box::new(clip(0., 1.)),
+            Self::Percentile { low, high } => Box::new(clip(low, high)),
+            Self::Asinh { low, high, beta } => {
+                let clip = clip(low, high);
+                let beta = beta.max(f64::EPSILON);
+                Box::new(move |level| (beta * clip(level)).asinh() / beta.asinh())
+            }
+            Self::Stf {
+                shadows,
+                background,
+            } => {
+                let median = hist.percentile(0.5) as f64 / white;
+                // Scaled to match the standard deviation of normally distributed noise,
+                // and at least a level so flat frames don't end up all white
+                let madn = 1.4826 * median_deviation(hist).max(1.) / white;
+                let shadows = (median + shadows * madn).clamp(0., median);
+                let midtones = mtf(background.clamp(0.001, 0.999), median - shadows);
+                Box::new(move |level| {
+                    mtf(
+                        midtones,
+                        (level / white - shadows) / (1. - shadows).max(f64::EPSILON),
+                    )
+                })
+            }
+        };
+        (0..=white as usize)
+            .map(|level| (curve(level as f64) * 255.).round() as u8)
+            .collect()
+    }
+}
+
+impl Stretch {
+    /// Stretches `pixels` to 8 bits. Each pixel has `channels` interleaved values up to
+    /// `white`.
+    pub fn apply(&self, pixels: &[u16], channels: usize, white: u16) -> Vec<u8> {
+        let luts = match self.linked || channels == 1 {
+            true => vec![self.mode.lut(&histogram(pixels.iter(), white)); channels],
+            false => (0..channels)
+                .map(|channel| {
+                    let levels = pixels.iter().skip(channel).step_by(channels);
+                    self.mode.lut(&histogram(levels, white))
+                })
+                .collect(),
+        };
+        pixels
+            .chunks_exact(channels)
+            .flat_map(|pixel| {
+                pixel
+                    .iter()
+                    .zip(&luts)
+                    .map(|(level, lut)| lut[*level as usize])
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stf() -> StretchMode {
+        StretchMode::Stf {
+            shadows: default_shadows(),
+            background: default_background(),
+        }
+    }
+
+    /// A 10 bit sky: most pixels around `median`, with a few stars.
+    fn sky(median: u16) -> Vec<u16> {
+        let mut pixels: Vec<u16> = (0..1000).map(|i| median - 5 + i % 11).collect();
+        pixels.extend([1023; 10]);
+        pixels
+    }
+
+    #[test]
+    fn mtf_moves_midtones_to_half() {
+        assert_eq!(mtf(0.2, 0.), 0.);
+        assert_eq!(mtf(0.2, 1.), 1.);
+        assert!((mtf(0.2, 0.2) - 0.5).abs() < 1e-12);
+        assert!(mtf(0.2, 0.1) < mtf(0.2, 0.3));
+    }
+
+    #[test]
+    fn stf_puts_background_at_target() {
+        let white = 1023;
+        let lut = stf().lut(&histogram(sky(100).iter(), white));
+        assert_eq!(lut.len(), white as usize + 1);
+        // The median goes to about 25% grey, a little above as the midtones are found
+        // before the shadows are clipped, and stars stay white
+        assert!((60..=75).contains(&lut[100]), "{}", lut[100]);
+        assert_eq!(lut[1023], 255);
+        // Shadows are clipped to black
+        assert_eq!(lut[0], 0);
+        assert!(lut.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn stf_of_flat_frame_isnt_white() {
+        let lut = stf().lut(&histogram([500u16; 100].iter(), 1023));
+        assert!(lut[500] < 255);
+    }
+
+    #[test]
+    fn linear_maps_range_to_full_scale() {
+        let lut = StretchMode::Linear.lut(&histogram([10u16, 20, 30].iter(), 255));
+        assert_eq!((lut[10], lut[20], lut[30]), (0, 128, 255));
+        assert_eq!((lut[0], lut[255]), (0, 255));
+    }
+
+    #[test]
+    fn unlinked_channels_are_stretched_separately() {
+        // Blue sits at 10 to 20 and red at 100 to 200
+        let pixels = [10u16, 0, 100, 20, 0, 200];
+        let stretch = |linked| {
+            Stretch {
+                mode: StretchMode::Linear,
+                linked,
+            }
+            .apply(&pixels, 3, 255)
+        };
+        assert_eq!(stretch(false), [0, 0, 0, 255, 0, 255]);
+        let linked = stretch(true);
+        assert_eq!((linked[0], linked[5]), (13, 255));
+    }
+}